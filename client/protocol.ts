@@ -1,5 +1,7 @@
 import React from 'react'
 
+import { version as clientVersion } from '../package.json'
+
 import.meta.hot && import.meta.hot.decline()
 
 export const useProtocol = ({
@@ -81,6 +83,7 @@ export type Json =
 type ProtocolState =
   | { type: 'Disconnected' }
   | { type: 'Starting' }
+  | { type: 'Resuming' }
   | { type: 'Snapshotting', objects: Array<[string, Record<string, any>]> }
   | { type: 'Streaming' }
 
@@ -88,24 +91,108 @@ export type Change =
   | { type: 'Insert', id: string, object: JsonObject }
   | { type: 'Update', id: string, key: string, value: Json }
   | { type: 'Delete', id: string }
+  | { type: 'SetIndex', id: string, index: number }
+  | { type: 'Replace', id: string, object: JsonObject, expected_revision: number }
+  | { type: 'Transaction', changes: Array<Change> }
+  | { type: 'RestoreObject', id: string, object?: JsonObject, index?: number }
+
+type Rect = { min_x: number, min_y: number, max_x: number, max_y: number }
+
+type OwnChanges = 'Echo' | 'Acknowledge' | 'Skip'
+
+type ChangeAck = {
+  version: string,
+  revisions: Array<[string, number]>,
+  timestamp: number,
+}
 
 type ClientMessage =
-  | { type: 'ClientReady', username: string }
+  | { type: 'ClientReady', username?: string, own_changes?: OwnChanges }
+  | { type: 'ResumeSession', username: string, from_version: string, own_changes?: OwnChanges }
   | { type: 'StartSnapshot' }
-  | { type: 'ApplyChange', change: Change }
+  | { type: 'SnapshotViewport', viewport: Rect }
+  | { type: 'Resync', from_version: string }
+  | { type: 'SubscribeBoard', board_id: string }
+  | { type: 'UnsubscribeBoard', board_id: string }
+  | { type: 'ApplyChange', change: Change, idempotency_key?: string }
   | { type: 'CursorChanged', x: number, y: number }
   | { type: 'CursorLeft' }
-  | { type: 'Ping' }
+  | { type: 'LockObject', id: string }
+  | { type: 'UnlockObject', id: string }
+  | { type: 'RequestPresence' }
+  | { type: 'Ping', client_time?: number, rtt?: number }
 
 type ServerMessage =
-  | { type: 'ServerReady' }
+  | { type: 'ServerReady', capabilities: Capabilities, role: 'viewer' | 'editor', username: string }
   | { type: 'SnapshotChunk', entries: Array<[string, JsonObject]> }
-  | { type: 'SnapshotFinished', version: string | null }
-  | { type: 'ChangeAccepted', change: Change, session_id: string }
+  | { type: 'SnapshotOrder', order: Array<[string, number]> }
+  | { type: 'SnapshotRevisions', revisions: Array<[string, number]> }
+  | { type: 'SnapshotFinished', version: string | null, object_count: number, hash: string }
+  | { type: 'ResyncStarted', version: string }
+  | { type: 'ChangesAccepted', changes: Array<AcceptedChange> }
+  | { type: 'ChangesAcknowledged', changes: Array<ChangeAck> }
   | { type: 'UserJoined', session_id: string, username: String }
   | { type: 'UserLeft', session_id: string }
   | { type: 'UserCursorChanged', session_id: string, x: number, y: number }
   | { type: 'UserCursorLeft', session_id: string }
+  | { type: 'ObjectLocked', id: string, session_id: string }
+  | { type: 'ObjectUnlocked', id: string, session_id: string }
+  | { type: 'ChangeRejected', change: Change, reason: RejectionReason }
+  | {
+    type: 'PresenceSnapshot',
+    users: Array<[string, string]>,
+    cursors: Array<[string, { x: number, y: number }]>,
+    locks: Array<[string, string]>,
+    latencies: Array<[string, number]>,
+  }
+  | { type: 'Pong', client_time: number | null, server_time: number }
+  | { type: 'BoardDeleted' }
+  | { type: 'ResyncRequired' }
+  | { type: 'UserLatencyChanged', session_id: string, rtt: number }
+  | { type: 'SessionKicked', session_id: string, reason: string | null }
+  | { type: 'RateLimited', change: Change | null, retry_after_ms: number }
+  | { type: 'BoardReadOnly', change: Change }
+  | { type: 'WaitingRoom', position: number }
+  | { type: 'IdleWarning', closes_in_ms: number }
+  | { type: 'IdleTimeout' }
+  | { type: 'ServerShutdown', retry_after_ms: number }
+  | { type: 'Degraded' }
+  | { type: 'Recovered' }
+  | { type: 'Error', code: ErrorCode, retryable: boolean }
+
+type AcceptedChange = {
+  change: Change,
+  session_id: string,
+  username: string | null,
+  user_id: string | null,
+  version: string,
+  revisions: Array<[string, number]>,
+  timestamp: number,
+}
+
+type Capabilities = {
+  max_message_size: number | null,
+  max_objects_per_board: number | null,
+  max_sessions_per_board: number | null,
+  max_object_bytes: number | null,
+  supported_change_types: Array<string>,
+  heartbeat_interval_seconds: number,
+  object_lock_ttl_seconds: number,
+  cursor_publishes_per_second: number,
+  max_changes_per_second: number,
+  max_cursor_updates_per_second: number,
+}
+
+type RejectionReason =
+  | { type: 'ObjectLocked', session_id: string }
+  | { type: 'RevisionMismatch', id: string, current_revision: number }
+  | { type: 'ReadOnly' }
+  | { type: 'NotInTrash', id: string }
+  | { type: 'InvalidKey', key: string }
+  | { type: 'TooManyObjects', max_objects: number }
+  | { type: 'ObjectTooLarge', id: string, size: number, max_size: number }
+
+type ErrorCode = 'NotFound' | 'Conflict' | 'Unavailable' | 'Internal' | 'OutOfOrder' | 'InvalidUsername' | 'MessageTooLarge' | 'SessionTaken' | 'Forbidden'
 
 type Work =
   | ServerMessage
@@ -125,6 +212,8 @@ class Protocol {
   _connecting: boolean
   _lastMessage: ServerMessage | null
   _pingInterval: number | null
+  _retryAfterMs: number | null
+  _lastVersion: string | null
   _working: boolean
   _workQueue: Array<Work>
 
@@ -148,6 +237,8 @@ class Protocol {
     this._isDisposed = false
     this._lastMessage = null
     this._pingInterval = null
+    this._retryAfterMs = null
+    this._lastVersion = null
     this._websocket = null
     this._connecting = false
     this._working = false
@@ -197,7 +288,12 @@ class Protocol {
     let closed = false
     const host = import.meta.env.DEV ? 'localhost:8080' : location.host
     const protocol = location.protocol === 'https:' ? 'wss' : 'ws'
-    const socket = new WebSocket(`${protocol}://${host}/api/board/${this._boardId}?session_id=${this._sessionId}`)
+    // Boards opened from a share link pass the link's token along in place of other credentials
+    const shareToken = new URLSearchParams(location.search).get('share_token')
+    const shareQuery = shareToken ? `&share_token=${encodeURIComponent(shareToken)}` : ''
+    // Anyone can choose to only watch, say when the board is up on a shared screen
+    const roleQuery = new URLSearchParams(location.search).get('role') === 'viewer' ? '&role=viewer' : ''
+    const socket = new WebSocket(`${protocol}://${host}/api/board/${this._boardId}?session_id=${this._sessionId}&client_version=${clientVersion}${shareQuery}${roleQuery}`)
     const closedListener = () => {
       if (closed) return
       closed = true
@@ -220,7 +316,8 @@ class Protocol {
   private _step = async (message: Work) => {
     if (message === 'Wait') {
       if (this._state.type !== 'Disconnected') return
-      await this._wait(2000)
+      await this._wait(this._retryAfterMs ?? 2000)
+      this._retryAfterMs = null
       return
     }
 
@@ -236,6 +333,7 @@ class Protocol {
       this._state = { type: 'Disconnected' }
       window.clearInterval(this._pingInterval ?? undefined)
       this._emitter.dispatchEvent(new CustomEvent('disconnected'))
+      if (this._isDisposed) return
       this._pushWork('Wait')
       this._pushWork('Connect')
       return
@@ -245,7 +343,68 @@ class Protocol {
 
     if (message === 'Opened') {
       this._pingInterval = window.setInterval(this._ping, 20000)
-      this._send({ type: 'ClientReady', username: this._username })
+      // Picking up after the last change seen keeps the session from leaving and rejoining, and
+      // skips a whole new snapshot if the server still has the changes that were missed
+      if (this._lastVersion) {
+        this._send({ type: 'ResumeSession', username: this._username, from_version: this._lastVersion })
+        this._state = { type: 'Resuming' }
+      } else {
+        // Guests without a name get one from the server
+        this._send({ type: 'ClientReady', username: this._username || undefined })
+      }
+      return
+    }
+
+    if (message.type === 'SessionKicked') {
+      // Don't come right back after being kicked
+      this._isDisposed = true
+      this._emitter.dispatchEvent(new CustomEvent('kicked', {
+        detail: { reason: message.reason }
+      }))
+      return
+    }
+
+    if (message.type === 'WaitingRoom') {
+      this._emitter.dispatchEvent(new CustomEvent('waitingroom', {
+        detail: { position: message.position }
+      }))
+      return
+    }
+
+    if (message.type === 'IdleWarning') {
+      this._emitter.dispatchEvent(new CustomEvent('idlewarning', {
+        detail: { closesInMs: message.closes_in_ms }
+      }))
+      return
+    }
+
+    if (message.type === 'IdleTimeout') {
+      // Coming right back would defeat the point, so it's up to the page to start over
+      this._isDisposed = true
+      this._emitter.dispatchEvent(new CustomEvent('idletimeout'))
+      return
+    }
+
+    if (message.type === 'ServerShutdown') {
+      // The server closes the socket right after this, so hold off reconnecting for as long as it asks
+      this._retryAfterMs = message.retry_after_ms
+      return
+    }
+
+    if (message.type === 'Degraded') {
+      this._emitter.dispatchEvent(new CustomEvent('degraded'))
+      return
+    }
+
+    if (message.type === 'Recovered') {
+      this._emitter.dispatchEvent(new CustomEvent('recovered'))
+      return
+    }
+
+    if (message.type === 'Error') {
+      this._emitter.dispatchEvent(new CustomEvent('servererror', {
+        detail: { code: message.code, retryable: message.retryable }
+      }))
       return
     }
 
@@ -284,12 +443,36 @@ class Protocol {
       return
     }
 
+    // The server may have numbered the name to tell it apart from someone else's
+    if (message.type === 'ServerReady') {
+      this._username = message.username
+    }
+
     if (this._state.type === 'Starting' && message.type === 'ServerReady') {
       this._send({ type: 'StartSnapshot' })
       this._state = { type: 'Snapshotting', objects: [] }
       return
     }
 
+    // Nothing more is coming until the board is downloaded again
+    if (this._state.type === 'Streaming' && message.type === 'ResyncRequired') {
+      this._emitter.dispatchEvent(new CustomEvent('resyncrequired'))
+      this._send({ type: 'StartSnapshot' })
+      this._state = { type: 'Snapshotting', objects: [] }
+      return
+    }
+
+    if (this._state.type === 'Resuming' && message.type === 'ResyncStarted') {
+      this._state = { type: 'Streaming' }
+      this._emitter.dispatchEvent(new CustomEvent('streamingresumed'))
+      return
+    }
+
+    // The server falls back to a snapshot when the missed changes are gone
+    if (this._state.type === 'Resuming' && (message.type === 'SnapshotChunk' || message.type === 'SnapshotFinished')) {
+      this._state = { type: 'Snapshotting', objects: [] }
+    }
+
     if (this._state.type === 'Snapshotting' && message.type === 'SnapshotChunk') {
       this._state.objects = this._state.objects.concat(message.entries)
       return
@@ -305,16 +488,26 @@ class Protocol {
         }))
       })
       this._state = { type: 'Streaming' }
+      this._lastVersion = message.version
       this._emitter.dispatchEvent(new CustomEvent('streamingstarted'))
     }
 
-    if (this._state.type === 'Streaming' && message.type === 'ChangeAccepted') {
-      this._emitter.dispatchEvent(new CustomEvent('changereceived', {
-        detail: {
-          change: message.change,
-          source: message.session_id,
-        }
-      }))
+    if (this._state.type === 'Streaming' && message.type === 'ChangesAccepted') {
+      message.changes.forEach((accepted) => {
+        this._lastVersion = accepted.version
+        this._emitter.dispatchEvent(new CustomEvent('changereceived', {
+          detail: {
+            change: accepted.change,
+            source: accepted.session_id,
+          }
+        }))
+      })
+    }
+
+    if (this._state.type === 'Streaming' && message.type === 'ChangesAcknowledged') {
+      message.changes.forEach((ack) => {
+        this._lastVersion = ack.version
+      })
     }
   }
 
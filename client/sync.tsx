@@ -119,6 +119,8 @@ export const ObjectProtocolSync = ({
       objects.current.set(change.id, { ...current, [change.key]: change.value })
     } else if (change.type === 'Insert') {
       objects.current.set(change.id, change.object)
+    } else if (change.type === 'RestoreObject' && change.object) {
+      objects.current.set(change.id, change.object)
     }
   }
 
@@ -264,7 +266,8 @@ export const ObjectIdsProtocolSync = ({
 
   const onChangeReceived = useCallback((change: Change) => {
     switch (change.type) {
-      case 'Insert': {
+      case 'Insert':
+      case 'RestoreObject': {
         objectIds.current.add(change.id)
         break
       }
@@ -0,0 +1,837 @@
+use axum::{
+    async_trait,
+    body::StreamBody,
+    extract::{Extension, FromRequest, Json, Path, Query, RequestParts},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use futures::TryStreamExt;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::api::ApiError;
+use crate::auth::{ApiKey, Caller, Scope};
+use crate::checkpointer::Checkpointer;
+use crate::export;
+use crate::repository::{Ban, BanTarget, BoardInconsistency, Repository, RepositoryError};
+
+/// The bearer token that admin requests have to present, from the ADMIN_TOKEN environment
+/// variable. Every admin request is rejected if it isn't set.
+#[derive(Clone)]
+pub struct AdminToken(pub Option<String>);
+
+/// Extracting this from a request proves that the request carried the admin token or an API key
+/// with the admin scope
+pub struct Admin;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Admin {
+    type Rejection = ApiError;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Caller::from_request(request).await?.require(Scope::Admin)?;
+        Ok(Admin)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AdminBoardPath {
+    board_id: Uuid,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionSummary {
+    session_id: Uuid,
+    username: String,
+    /// The version the client reported when it connected
+    client_version: Option<String>,
+    user_agent: Option<String>,
+    /// The peer address of the session's connection, which can be banned
+    ip: Option<String>,
+    /// When the session's websocket was opened, in milliseconds since the epoch, if it was
+    /// recorded
+    connected_at: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListSessionsResponse {
+    sessions: Vec<SessionSummary>,
+}
+
+/// List the sessions connected to a board
+#[utoipa::path(
+    get,
+    path = "/api/admin/board/{board_id}/sessions",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    security(("admin_token" = [])),
+    responses((status = 200, description = "The connected sessions", body = ListSessionsResponse)),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn list_sessions(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+) -> Result<Json<ListSessionsResponse>, ApiError> {
+    let mut clients = repo
+        .get_session_clients_for_board(path.board_id)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    let sessions = repo
+        .get_sessions_for_board(path.board_id)
+        .await?
+        .into_iter()
+        .map(|(session_id, username)| {
+            let client = clients.remove(&session_id);
+            SessionSummary {
+                session_id,
+                username,
+                client_version: client
+                    .as_ref()
+                    .and_then(|client| client.client_version.clone()),
+                user_agent: client.as_ref().and_then(|client| client.user_agent.clone()),
+                ip: client.as_ref().and_then(|client| client.ip.clone()),
+                connected_at: client.map(|client| client.connected_at),
+            }
+        })
+        .collect();
+
+    Ok(Json(ListSessionsResponse { sessions }))
+}
+
+#[derive(Deserialize)]
+pub struct AdminSessionPath {
+    board_id: Uuid,
+    session_id: Uuid,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct KickSessionQuery {
+    /// Told to the session along with the close frame
+    reason: Option<String>,
+}
+
+/// Force a session to disconnect from a board
+#[utoipa::path(
+    delete,
+    path = "/api/admin/board/{board_id}/sessions/{session_id}",
+    tag = "admin",
+    params(
+        ("board_id" = Uuid, Path, description = "ID of the board"),
+        ("session_id" = Uuid, Path, description = "ID of the session"),
+        KickSessionQuery,
+    ),
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "The session was kicked"),
+        (status = 404, description = "The session isn't on the board"),
+    ),
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(path.board_id = %path.board_id, path.session_id = %path.session_id)
+)]
+pub async fn kick_session(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminSessionPath>,
+    Query(query): Query<KickSessionQuery>,
+) -> Result<StatusCode, ApiError> {
+    if repo
+        .kick_session_for_board(path.board_id, path.session_id, query.reason)
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListBansResponse {
+    bans: Vec<Ban>,
+}
+
+/// List the sessions, users, and addresses that are banned from a board
+#[utoipa::path(
+    get,
+    path = "/api/admin/board/{board_id}/bans",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    security(("admin_token" = [])),
+    responses((status = 200, description = "The board's bans", body = ListBansResponse)),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn list_bans(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+) -> Result<Json<ListBansResponse>, ApiError> {
+    let bans = repo.get_bans_for_board(path.board_id).await?;
+    Ok(Json(ListBansResponse { bans }))
+}
+
+/// Ban a session, user, or address from a board, which disconnects any session it matches right
+/// away and keeps it from connecting again
+#[utoipa::path(
+    post,
+    path = "/api/admin/board/{board_id}/bans",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = Ban,
+    security(("admin_token" = [])),
+    responses((status = 204, description = "The ban was added")),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn add_ban(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Json(ban): Json<Ban>,
+) -> Result<StatusCode, ApiError> {
+    repo.add_ban_for_board(path.board_id, ban).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RemoveBanRequest {
+    target: BanTarget,
+}
+
+/// Lift a ban from a board
+#[utoipa::path(
+    delete,
+    path = "/api/admin/board/{board_id}/bans",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = RemoveBanRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "The ban was lifted"),
+        (status = 404, description = "The board has no such ban"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn remove_ban(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Json(request): Json<RemoveBanRequest>,
+) -> Result<StatusCode, ApiError> {
+    if repo
+        .remove_ban_for_board(path.board_id, request.target)
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FreezeBoardRequest {
+    frozen: bool,
+}
+
+/// Freeze a board so that nobody can change it, or let changes through again. Sessions can still
+/// watch a frozen board, and their changes are answered with `BoardReadOnly`.
+#[utoipa::path(
+    put,
+    path = "/api/admin/board/{board_id}/frozen",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = FreezeBoardRequest,
+    security(("admin_token" = [])),
+    responses((status = 204, description = "The board was frozen or unfrozen")),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn set_board_frozen(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Json(request): Json<FreezeBoardRequest>,
+) -> Result<StatusCode, ApiError> {
+    repo.set_board_frozen(path.board_id, request.frozen).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ReliablePresenceRequest {
+    reliable: bool,
+}
+
+/// Send a board's presence through a stream so that sessions get every join, leave, and lock even
+/// if they fall behind or the server loses touch with the store for a moment, or go back to
+/// sending it once. Only the Redis store can.
+#[utoipa::path(
+    put,
+    path = "/api/admin/board/{board_id}/reliable_presence",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = ReliablePresenceRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "Reliable presence was turned on or off"),
+        (status = 400, description = "The store can't send presence reliably"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn set_board_reliable_presence(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Json(request): Json<ReliablePresenceRequest>,
+) -> Result<StatusCode, ApiError> {
+    match repo
+        .set_board_reliable_presence(path.board_id, request.reliable)
+        .await
+    {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(RepositoryError::Conflict(message)) => Err(ApiError::BadRequest(message)),
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CheckpointResponse {
+    version: String,
+    changes_applied: usize,
+}
+
+/// Checkpoint a board right away instead of waiting for the checkpointer to get to it, applying
+/// everything that was pending in its change stream when the request came in
+#[utoipa::path(
+    post,
+    path = "/api/admin/board/{board_id}/checkpoint",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The board was checkpointed", body = CheckpointResponse),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn checkpoint_board(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+) -> Result<Json<CheckpointResponse>, ApiError> {
+    let changes_applied = Checkpointer::new(repo.clone())
+        .checkpoint_board_to_latest(path.board_id)
+        .await?;
+
+    Ok(Json(CheckpointResponse {
+        version: repo.get_version_for_board(path.board_id).await?,
+        changes_applied,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct AdminCheckpointPath {
+    board_id: Uuid,
+    version: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListCheckpointsResponse {
+    /// Versions of the checkpoints the board can be rolled back to, newest first
+    versions: Vec<String>,
+}
+
+/// List the checkpoints kept in a board's checkpoint history
+#[utoipa::path(
+    get,
+    path = "/api/admin/board/{board_id}/checkpoints",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The board's checkpoints", body = ListCheckpointsResponse),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn list_checkpoints(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+) -> Result<Json<ListCheckpointsResponse>, ApiError> {
+    let versions = repo
+        .get_checkpoint_versions_for_board(path.board_id)
+        .await?;
+
+    Ok(Json(ListCheckpointsResponse { versions }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RestoreCheckpointResponse {
+    /// The board's version after the rollback
+    version: String,
+}
+
+/// Roll a board back to one of the checkpoints in its checkpoint history. The rollback is
+/// published as a single change, so everyone on the board sees it right away and it can be found
+/// in the board's history like any other change.
+#[utoipa::path(
+    post,
+    path = "/api/admin/board/{board_id}/checkpoints/{version}/restore",
+    tag = "admin",
+    params(
+        ("board_id" = Uuid, Path, description = "ID of the board"),
+        ("version" = String, Path, description = "Version of the checkpoint"),
+    ),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The board was rolled back", body = RestoreCheckpointResponse),
+        (status = 404, description = "The checkpoint isn't in the board's checkpoint history"),
+    ),
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(path.board_id = %path.board_id, path.version = %path.version)
+)]
+pub async fn restore_checkpoint(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminCheckpointPath>,
+) -> Result<Json<RestoreCheckpointResponse>, ApiError> {
+    let version = repo
+        .restore_board_to_version(path.board_id, &path.version)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    Ok(Json(RestoreCheckpointResponse { version }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyBoardQuery {
+    /// Fix whatever can be fixed instead of only reporting it
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VerifyBoardResponse {
+    inconsistencies: Vec<BoardInconsistency>,
+    /// The version of the change that repaired the board, if anything was repaired
+    repaired_version: Option<String>,
+}
+
+/// Check that a board's version, change stream, objects, and stacking order agree with each
+/// other, and optionally repair them. Only problems in the stacking order can be repaired, which
+/// is done with a single change that everyone on the board receives.
+#[utoipa::path(
+    post,
+    path = "/api/admin/board/{board_id}/verify",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board"), VerifyBoardQuery),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "What was found and repaired", body = VerifyBoardResponse),
+    ),
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(path.board_id = %path.board_id, query.repair = query.repair)
+)]
+pub async fn verify_board(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Query(query): Query<VerifyBoardQuery>,
+) -> Result<Json<VerifyBoardResponse>, ApiError> {
+    let verification = repo.verify_board(path.board_id, query.repair).await?;
+
+    Ok(Json(VerifyBoardResponse {
+        inconsistencies: verification.inconsistencies,
+        repaired_version: verification.repaired_version,
+    }))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct WebhookRequest {
+    url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListWebhooksResponse {
+    urls: Vec<String>,
+}
+
+/// List the webhook URLs that receive a board's events, not counting the ones for every board
+#[utoipa::path(
+    get,
+    path = "/api/admin/board/{board_id}/webhooks",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    security(("admin_token" = [])),
+    responses((status = 200, description = "The board's webhooks", body = ListWebhooksResponse)),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn list_webhooks(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+) -> Result<Json<ListWebhooksResponse>, ApiError> {
+    let urls = repo.get_webhooks_for_board(path.board_id).await?;
+
+    Ok(Json(ListWebhooksResponse { urls }))
+}
+
+/// Start sending a board's events to a webhook URL
+#[utoipa::path(
+    post,
+    path = "/api/admin/board/{board_id}/webhooks",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = WebhookRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "The webhook was added"),
+        (status = 400, description = "The URL is invalid"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn add_webhook(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Json(request): Json<WebhookRequest>,
+) -> Result<StatusCode, ApiError> {
+    match Url::parse(&request.url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => return Err(ApiError::BadRequest("Invalid webhook URL".to_string())),
+    }
+
+    repo.add_webhook_for_board(path.board_id, request.url)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stop sending a board's events to a webhook URL
+#[utoipa::path(
+    delete,
+    path = "/api/admin/board/{board_id}/webhooks",
+    tag = "admin",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = WebhookRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "The webhook was removed"),
+        (status = 404, description = "The board has no such webhook"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn delete_webhook(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<AdminBoardPath>,
+    Json(request): Json<WebhookRequest>,
+) -> Result<StatusCode, ApiError> {
+    if repo
+        .delete_webhook_for_board(path.board_id, request.url)
+        .await?
+    {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+/// The key itself is only ever shown in this response
+#[derive(Serialize, ToSchema)]
+pub struct CreateApiKeyResponse {
+    id: String,
+    key: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiKeySummary {
+    /// The SHA-256 of the key, which is how it's identified once it's been created
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListApiKeysResponse {
+    api_keys: Vec<ApiKeySummary>,
+}
+
+/// List the API keys stored in Redis, not counting the ones from API_KEYS
+#[utoipa::path(
+    get,
+    path = "/api/admin/api_keys",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses((status = 200, description = "The API keys", body = ListApiKeysResponse)),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn list_api_keys(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+) -> Result<Json<ListApiKeysResponse>, ApiError> {
+    let api_keys = repo
+        .get_api_keys()
+        .await?
+        .into_iter()
+        .map(|(id, api_key)| ApiKeySummary {
+            id,
+            name: api_key.name,
+            scopes: api_key.scopes,
+        })
+        .collect();
+
+    Ok(Json(ListApiKeysResponse { api_keys }))
+}
+
+/// Generate a new API key
+#[utoipa::path(
+    post,
+    path = "/api/admin/api_keys",
+    tag = "admin",
+    request_body = CreateApiKeyRequest,
+    security(("admin_token" = [])),
+    responses(
+        (status = 201, description = "The key was created", body = CreateApiKeyResponse),
+        (status = 400, description = "The name or scopes are empty"),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn create_api_key(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreateApiKeyResponse>), ApiError> {
+    if request.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("API key name is required".to_string()));
+    }
+    if request.scopes.is_empty() {
+        return Err(ApiError::BadRequest("API key needs a scope".to_string()));
+    }
+
+    // Two random UUIDs make for plenty of entropy
+    let key = format!(
+        "rb_{}{}",
+        Uuid::new_v4().as_simple(),
+        Uuid::new_v4().as_simple()
+    );
+    let id = ApiKey::hash(&key);
+    repo.add_api_key(
+        &id,
+        ApiKey {
+            name: request.name,
+            scopes: request.scopes,
+        },
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(CreateApiKeyResponse { id, key })))
+}
+
+#[derive(Deserialize)]
+pub struct ApiKeyPath {
+    api_key_id: String,
+}
+
+/// Revoke an API key stored in Redis
+#[utoipa::path(
+    delete,
+    path = "/api/admin/api_keys/{api_key_id}",
+    tag = "admin",
+    params(("api_key_id" = String, Path, description = "SHA-256 of the key")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 204, description = "The key was revoked"),
+        (status = 404, description = "There was no such key"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.api_key_id = %path.api_key_id))]
+pub async fn delete_api_key(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<ApiKeyPath>,
+) -> Result<StatusCode, ApiError> {
+    if repo.delete_api_key(&path.api_key_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+/// Download every board as newline delimited JSON for backups and migrations. Each board is a
+/// `board` record with its metadata, order, revisions, and pending changes, followed by `objects`
+/// records with its objects a chunk at a time.
+#[utoipa::path(
+    get,
+    path = "/api/admin/export",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (
+            status = 200,
+            description = "One JSON record per line",
+            body = String,
+            content_type = "application/x-ndjson",
+        ),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn export_boards(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+) -> impl IntoResponse {
+    // An error partway through can only cut the response short, so make sure it gets logged
+    let body =
+        export::export_boards(repo).inspect_err(|error| tracing::error!(%error, "Export failed"));
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(body),
+    )
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PoolStatsResponse {
+    max_size: u32,
+    connections: u32,
+    idle_connections: u32,
+    /// Connections handed out since the server started
+    checkouts: u64,
+    /// Checkouts that gave up waiting for a connection
+    timed_out_checkouts: u64,
+    average_wait_ms: f64,
+    max_wait_ms: f64,
+    /// Store calls that were tried again after a transient error
+    retries: u64,
+    /// Store calls that were given up on after running out of attempts
+    exhausted_retries: u64,
+    /// Whether the store can't be reached right now, so calls to it are failing fast
+    degraded: bool,
+    /// How many times the store has become degraded since the server started
+    breaker_trips: u64,
+}
+
+/// Report how busy this server's connection pool to the store is, which isn't there for the
+/// memory store
+#[utoipa::path(
+    get,
+    path = "/api/admin/pool",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The pool's utilization", body = PoolStatsResponse),
+        (status = 404, description = "The store doesn't use a connection pool"),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn get_pool_stats(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+) -> Result<Json<PoolStatsResponse>, ApiError> {
+    let stats = repo.get_pool_stats().ok_or(ApiError::NotFound)?;
+
+    Ok(Json(PoolStatsResponse {
+        max_size: stats.max_size,
+        connections: stats.connections,
+        idle_connections: stats.idle_connections,
+        checkouts: stats.checkouts,
+        timed_out_checkouts: stats.timed_out_checkouts,
+        average_wait_ms: stats.average_wait_ms,
+        max_wait_ms: stats.max_wait_ms,
+        retries: stats.retries,
+        exhausted_retries: stats.exhausted_retries,
+        degraded: stats.degraded,
+        breaker_trips: stats.breaker_trips,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CheckpointerStatsResponse {
+    /// Times a board was checkpointed until it was caught up since the server started
+    checkpointed_boards: u64,
+    applied_changes: u64,
+    /// Times a board couldn't be checkpointed, which the checkpointer moved on from
+    failed_boards: u64,
+}
+
+/// Report how much checkpointing this server has done, and how often it failed
+#[utoipa::path(
+    get,
+    path = "/api/admin/checkpointer",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The checkpointer's progress", body = CheckpointerStatsResponse),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn get_checkpointer_stats(_admin: Admin) -> Json<CheckpointerStatsResponse> {
+    let stats = Checkpointer::stats();
+
+    Json(CheckpointerStatsResponse {
+        checkpointed_boards: stats.checkpointed_boards,
+        applied_changes: stats.applied_changes,
+        failed_boards: stats.failed_boards,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PresenceStatsResponse {
+    /// How many presence messages can wait for whoever on this server is furthest behind
+    channel_capacity: usize,
+    /// Presence messages dropped for sessions that fell too far behind, since the server started
+    dropped_messages: u64,
+    /// Every board that had presence messages dropped, most first
+    boards: Vec<BoardPresenceStats>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BoardPresenceStats {
+    board_id: Uuid,
+    dropped_messages: u64,
+}
+
+/// Report how many presence messages this server has dropped, which is a sign that
+/// PRESENCE_CHANNEL_CAPACITY is too small for its busiest boards
+#[utoipa::path(
+    get,
+    path = "/api/admin/presence",
+    tag = "admin",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The presence messages dropped", body = PresenceStatsResponse),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn get_presence_stats(
+    _admin: Admin,
+    Extension(repo): Extension<Repository>,
+) -> Json<PresenceStatsResponse> {
+    let stats = repo.get_presence_stats();
+
+    Json(PresenceStatsResponse {
+        channel_capacity: stats.capacity,
+        dropped_messages: stats.dropped_messages,
+        boards: stats
+            .boards
+            .into_iter()
+            .map(|(board_id, dropped_messages)| BoardPresenceStats {
+                board_id,
+                dropped_messages,
+            })
+            .collect(),
+    })
+}
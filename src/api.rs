@@ -0,0 +1,726 @@
+use anyhow::{anyhow, Error};
+use axum::{
+    extract::{Extension, Json, Path, Query},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use chrono::{DateTime, Utc};
+use futures::stream::Stream;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::auth::{CanRead, CanWrite};
+use crate::board_meta::{BoardMeta, BoardMetaPatch, BoardSummary};
+use crate::change::Change;
+use crate::events;
+use crate::message::{AcceptedChange, JsonObject};
+use crate::render;
+use crate::repository::{PublishOutcome, Repository};
+use crate::search::{self, SearchHit};
+use crate::share::{self, Role, ShareClaims};
+use crate::snapshot::{self, BoardSnapshot};
+
+/// Errors that make it out of a REST handler. Anything unexpected is reported as a 500 and logged.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    /// The caller is known but isn't allowed in, like when they're banned from a board
+    Forbidden,
+    NotFound,
+    /// The server is shutting down and can't take on anything new
+    Unavailable,
+    Internal(Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED.into_response(),
+            ApiError::Forbidden => StatusCode::FORBIDDEN.into_response(),
+            ApiError::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ApiError::Unavailable => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            ApiError::Internal(error) => {
+                tracing::error!(%error, "API request failed");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+            }
+        }
+    }
+}
+
+impl<E> From<E> for ApiError
+where
+    E: Into<Error>,
+{
+    fn from(error: E) -> Self {
+        ApiError::Internal(error.into())
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateBoardRequest {
+    name: String,
+    creator: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreateBoardResponse {
+    board_id: Uuid,
+    meta: BoardMeta,
+}
+
+/// Mint a new board and record its metadata
+#[utoipa::path(
+    post,
+    path = "/api/boards",
+    tag = "boards",
+    request_body = CreateBoardRequest,
+    responses((status = 201, description = "The board was created", body = CreateBoardResponse)),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn create_board(
+    _can_write: CanWrite,
+    Extension(repo): Extension<Repository>,
+    Json(request): Json<CreateBoardRequest>,
+) -> Result<(StatusCode, Json<CreateBoardResponse>), ApiError> {
+    let board_id = Uuid::new_v4();
+    let meta = BoardMeta {
+        name: request.name,
+        owner: request.creator.clone(),
+        creator: request.creator,
+        created_at: Utc::now(),
+        description: request.description,
+        tags: request.tags,
+    };
+
+    repo.create_board(board_id, meta.clone()).await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateBoardResponse { board_id, meta }),
+    ))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListBoardsQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ListBoardsResponse {
+    boards: Vec<BoardSummary>,
+    next_cursor: Option<String>,
+}
+
+/// List boards, most recently active first, a page at a time
+#[utoipa::path(
+    get,
+    path = "/api/boards",
+    tag = "boards",
+    params(ListBoardsQuery),
+    responses(
+        (status = 200, description = "A page of boards", body = ListBoardsResponse),
+        (status = 400, description = "The cursor is invalid"),
+    ),
+)]
+#[tracing::instrument(skip_all)]
+pub async fn list_boards(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Query(query): Query<ListBoardsQuery>,
+) -> Result<Json<ListBoardsResponse>, ApiError> {
+    // Cursors are opaque to clients so that the pagination scheme can change later
+    let cursor = match query.cursor.as_deref() {
+        None | Some("") => 0,
+        Some(cursor) => cursor
+            .parse()
+            .map_err(|_| ApiError::BadRequest("Invalid cursor".to_string()))?,
+    };
+    let limit = query.limit.unwrap_or(50).min(200);
+
+    let (boards, next_cursor) = repo.get_boards(cursor, limit).await?;
+
+    Ok(Json(ListBoardsResponse {
+        boards,
+        next_cursor: next_cursor.map(|cursor| cursor.to_string()),
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    /// Words that every matching object's text has to contain
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SearchResponse {
+    hits: Vec<SearchHit>,
+}
+
+/// Search the text of objects on every board, most recently changed first
+#[utoipa::path(
+    get,
+    path = "/api/search",
+    tag = "boards",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Objects with every word in the query", body = SearchResponse),
+        (status = 400, description = "The query has no words to search for"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(query.q = %query.q))]
+pub async fn search(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let terms = search::tokenize(&query.q);
+    if terms.is_empty() {
+        return Err(ApiError::BadRequest(
+            "The query has no words to search for".to_string(),
+        ));
+    }
+    let limit = query.limit.unwrap_or(50).min(200);
+
+    let hits = repo.search_objects(&terms, limit).await?;
+
+    Ok(Json(SearchResponse { hits }))
+}
+
+#[derive(Deserialize)]
+pub struct BoardPath {
+    board_id: Uuid,
+}
+
+/// Delete a board and everything in it, disconnecting anyone who has it open
+#[utoipa::path(
+    delete,
+    path = "/api/board/{board_id}",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 204, description = "The board was deleted"),
+        (status = 404, description = "There was no such board"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn delete_board(
+    _can_write: CanWrite,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<StatusCode, ApiError> {
+    if repo.delete_board(path.board_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+/// Get the whole current contents of a board as one JSON document. The board version is used as
+/// the ETag, so clients can cheaply check whether anything changed since they last looked.
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/snapshot",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 200, description = "The contents of the board", body = BoardSnapshot),
+        (status = 304, description = "The board hasn't changed since the version in If-None-Match"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn get_snapshot(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let snapshot = snapshot::read_snapshot(path.board_id, &repo).await?;
+    let etag = HeaderValue::from_str(format!("\"{}\"", snapshot.version).as_str())?;
+
+    if headers.get(header::IF_NONE_MATCH) == Some(&etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    Ok(([(header::ETAG, etag)], Json::<BoardSnapshot>(snapshot)).into_response())
+}
+
+/// Render the current contents of a board as an SVG image
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/export.svg",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 200, description = "The board as an SVG image", content_type = "image/svg+xml"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn export_svg(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<Response, ApiError> {
+    let snapshot = snapshot::read_snapshot(path.board_id, &repo).await?;
+    let svg = render::render_svg(&snapshot);
+
+    Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response())
+}
+
+/// Render the current contents of a board as a PNG image
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/export.png",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses((status = 200, description = "The board as a PNG image", content_type = "image/png")),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn export_png(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<Response, ApiError> {
+    let snapshot = snapshot::read_snapshot(path.board_id, &repo).await?;
+    let svg = render::render_svg(&snapshot);
+    // Rasterizing is CPU-bound, so keep it off of the async workers
+    let png = tokio::task::spawn_blocking(move || render::render_png(&svg)).await??;
+
+    Ok(([(header::CONTENT_TYPE, "image/png")], png).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DuplicateBoardResponse {
+    board_id: Uuid,
+}
+
+/// Copy a board's contents into a brand new board
+#[utoipa::path(
+    post,
+    path = "/api/board/{board_id}/duplicate",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 201, description = "The copy was created", body = DuplicateBoardResponse),
+        (status = 404, description = "There was no such board"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn duplicate_board(
+    _can_write: CanWrite,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<(StatusCode, Json<DuplicateBoardResponse>), ApiError> {
+    let board_id = Uuid::new_v4();
+
+    if !repo.duplicate_board(path.board_id, board_id).await? {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(DuplicateBoardResponse { board_id }),
+    ))
+}
+
+/// Get the name, description, owner, and tags of a board
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/meta",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 200, description = "The board's metadata", body = BoardMeta),
+        (status = 404, description = "The board has no metadata"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn get_board_meta(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<Json<BoardMeta>, ApiError> {
+    repo.get_meta_for_board(path.board_id)
+        .await?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+/// Change some of the name, description, owner, and tags of a board
+#[utoipa::path(
+    patch,
+    path = "/api/board/{board_id}/meta",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    request_body = BoardMetaPatch,
+    responses(
+        (status = 200, description = "The updated metadata", body = BoardMeta),
+        (status = 400, description = "The name is empty"),
+        (status = 404, description = "The board has no metadata"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn update_board_meta(
+    _can_write: CanWrite,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+    Json(patch): Json<BoardMetaPatch>,
+) -> Result<Json<BoardMeta>, ApiError> {
+    if patch.name.as_deref() == Some("") {
+        return Err(ApiError::BadRequest("Name can't be empty".to_string()));
+    }
+
+    repo.update_meta_for_board(path.board_id, patch)
+        .await?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BoardStatsResponse {
+    /// Objects in the checkpointed snapshot
+    object_count: usize,
+    /// Changes in the change stream that haven't been checkpointed yet
+    pending_changes: usize,
+    session_count: usize,
+    last_activity_at: Option<DateTime<Utc>>,
+    /// Approximately how many bytes the board's keys take up in Redis
+    memory_usage_bytes: u64,
+    /// Archived boards have nothing in Redis but their metadata until they're opened again
+    archived: bool,
+}
+
+/// Get numbers about a board's size and activity for dashboards and capacity planning. This
+/// doesn't restore archived boards.
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/stats",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 200, description = "The board's statistics", body = BoardStatsResponse),
+        (status = 404, description = "There was no such board"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn get_board_stats(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<Json<BoardStatsResponse>, ApiError> {
+    let last_activity_at = repo.get_last_activity_for_board(path.board_id).await?;
+    let memory_usage_bytes = repo.get_memory_usage_for_board(path.board_id).await?;
+    if last_activity_at.is_none() && memory_usage_bytes == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(Json(BoardStatsResponse {
+        object_count: repo.get_object_count_for_board(path.board_id).await?,
+        pending_changes: repo
+            .get_pending_change_count_for_board(path.board_id)
+            .await?,
+        session_count: repo.get_session_count_for_board(path.board_id).await?,
+        last_activity_at,
+        memory_usage_bytes,
+        archived: repo.get_board_archived(path.board_id).await?,
+    }))
+}
+
+/// Follow a board's changes and presence as Server-Sent Events. Clients that reconnect pick up
+/// after the last change they saw, as long as it hasn't been checkpointed away in the meantime.
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/events",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses(
+        (status = 200, description = "A stream of events", content_type = "text/event-stream"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn board_events(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = serde_json::Result<Event>>>, ApiError> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok());
+    let version = match last_event_id {
+        Some(last_event_id)
+            if repo
+                .get_version_available_for_board(path.board_id, last_event_id)
+                .await? =>
+        {
+            last_event_id.to_string()
+        }
+        // Otherwise there's no way to fill the gap, so only new changes are sent
+        _ => repo.get_latest_version_for_board(path.board_id).await?,
+    };
+
+    Ok(Sse::new(events::board_events(path.board_id, version, repo))
+        .keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ChangeHistoryQuery {
+    since: Option<String>,
+    until: Option<String>,
+    session_id: Option<Uuid>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChangeHistoryResponse {
+    #[schema(value_type = Vec<Object>)]
+    changes: Vec<AcceptedChange>,
+}
+
+/// List the changes made to a board, oldest first, optionally only between two versions or
+/// millisecond timestamps and only from one session. This reaches back past checkpoints as far as
+/// the board's history goes.
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/changes",
+    tag = "boards",
+    params(
+        ("board_id" = Uuid, Path, description = "ID of the board"),
+        ChangeHistoryQuery,
+    ),
+    responses(
+        (status = 200, description = "The matching changes", body = ChangeHistoryResponse),
+        (status = 400, description = "A version is invalid"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn get_change_history(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+    Query(query): Query<ChangeHistoryQuery>,
+) -> Result<Json<ChangeHistoryResponse>, ApiError> {
+    for bound in [&query.since, &query.until].into_iter().flatten() {
+        if Repository::parse_stream_id(bound).is_none() {
+            return Err(ApiError::BadRequest(format!("Invalid version {bound}")));
+        }
+    }
+    let limit = query.limit.unwrap_or(100).min(1000);
+
+    let changes = repo
+        .get_change_history_for_board(
+            path.board_id,
+            query.since,
+            query.until,
+            query.session_id,
+            limit,
+        )
+        .await?
+        .into_iter()
+        .map(AcceptedChange::from)
+        .collect();
+
+    Ok(Json(ChangeHistoryResponse { changes }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrashedObjectSummary {
+    id: Uuid,
+    #[schema(value_type = Object)]
+    object: JsonObject,
+    /// Where the object was in the stacking order, if it had a place
+    index: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrashResponse {
+    objects: Vec<TrashedObjectSummary>,
+}
+
+/// List the objects deleted from a board that can still be restored. Objects only show up here
+/// once their delete has been checkpointed.
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/trash",
+    tag = "boards",
+    params(("board_id" = Uuid, Path, description = "ID of the board")),
+    responses((status = 200, description = "The board's trash", body = TrashResponse)),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn get_trash(
+    _can_read: CanRead,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+) -> Result<Json<TrashResponse>, ApiError> {
+    let objects = repo
+        .get_trash_for_board(path.board_id)
+        .await?
+        .into_iter()
+        .map(|(id, trashed)| TrashedObjectSummary {
+            id,
+            object: trashed.object,
+            index: trashed.index,
+        })
+        .collect();
+
+    Ok(Json(TrashResponse { objects }))
+}
+
+#[derive(Deserialize)]
+pub struct ObjectPath {
+    board_id: Uuid,
+    object_id: Uuid,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RestoreObjectResponse {
+    /// The board's version after the object was restored
+    version: String,
+}
+
+/// Bring a deleted object back from a board's trash by publishing a `RestoreObject` change for it
+/// that doesn't come from any session
+#[utoipa::path(
+    post,
+    path = "/api/board/{board_id}/objects/{object_id}/restore",
+    tag = "boards",
+    params(
+        ("board_id" = Uuid, Path, description = "ID of the board"),
+        ("object_id" = Uuid, Path, description = "ID of the deleted object"),
+    ),
+    responses(
+        (status = 200, description = "The object was restored", body = RestoreObjectResponse),
+        (status = 400, description = "Restoring the object would put the board over its quotas, or the board is frozen"),
+        (status = 404, description = "The object isn't in the board's trash"),
+    ),
+)]
+#[tracing::instrument(
+    skip_all,
+    fields(path.board_id = %path.board_id, path.object_id = %path.object_id)
+)]
+pub async fn restore_object(
+    _can_write: CanWrite,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<ObjectPath>,
+) -> Result<Json<RestoreObjectResponse>, ApiError> {
+    if repo.get_board_frozen(path.board_id).await? {
+        return Err(ApiError::BadRequest("The board is frozen".to_string()));
+    }
+
+    let mut change = Change::RestoreObject {
+        id: path.object_id,
+        object: None,
+        index: None,
+    };
+    if repo
+        .fill_restored_objects(path.board_id, &mut change)
+        .await?
+        .is_some()
+    {
+        return Err(ApiError::NotFound);
+    }
+    if repo.check_quotas(path.board_id, &change).await?.is_some() {
+        return Err(ApiError::BadRequest(
+            "Restoring the object would put the board over its quotas".to_string(),
+        ));
+    }
+
+    match repo
+        .publish_change_for_board(path.board_id, Uuid::nil(), change, None)
+        .await?
+    {
+        PublishOutcome::Accepted { version, .. } | PublishOutcome::Duplicate { version } => {
+            Ok(Json(RestoreObjectResponse { version }))
+        }
+        PublishOutcome::RevisionMismatch { id, .. } => Err(ApiError::Internal(anyhow!(
+            "Object {id} was expected to be at a different revision"
+        ))),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ShareQuery {
+    /// What the link lets people do, defaults to viewer
+    role: Option<Role>,
+    /// How long the link works for, or forever if left out
+    expires_in_seconds: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ShareResponse {
+    token: String,
+    role: Role,
+    expires_at: Option<i64>,
+}
+
+/// Make a signed token that lets anyone open a board's websocket with a particular role, without
+/// any other credentials
+#[utoipa::path(
+    get,
+    path = "/api/board/{board_id}/share",
+    tag = "boards",
+    params(
+        ("board_id" = Uuid, Path, description = "ID of the board"),
+        ShareQuery,
+    ),
+    responses(
+        (status = 200, description = "The share token", body = ShareResponse),
+        (status = 400, description = "The expiration isn't positive or is more than a year off"),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(path.board_id = %path.board_id))]
+pub async fn share_board(
+    _can_write: CanWrite,
+    Extension(repo): Extension<Repository>,
+    Path(path): Path<BoardPath>,
+    Query(query): Query<ShareQuery>,
+) -> Result<Json<ShareResponse>, ApiError> {
+    let role = query.role.unwrap_or(Role::Viewer);
+    let expires_at = match query.expires_in_seconds {
+        Some(expires_in_seconds)
+            if !(1..=share::MAX_EXPIRES_IN_SECONDS).contains(&expires_in_seconds) =>
+        {
+            return Err(ApiError::BadRequest(format!(
+                "Expiration must be between 1 and {} seconds",
+                share::MAX_EXPIRES_IN_SECONDS
+            )))
+        }
+        Some(expires_in_seconds) => Some(
+            Utc::now()
+                .timestamp()
+                .checked_add(expires_in_seconds)
+                .ok_or_else(|| ApiError::BadRequest("Expiration is too far off".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let secret = repo.get_share_secret().await?;
+    let token = share::sign(
+        &ShareClaims {
+            board_id: path.board_id,
+            role,
+            expires_at,
+        },
+        secret.as_bytes(),
+    )?;
+
+    Ok(Json(ShareResponse {
+        token,
+        role,
+        expires_at,
+    }))
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use s3::{creds::Credentials, Bucket, Region};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::board_meta::BoardMeta;
+use crate::message::JsonObject;
+
+/// Everything needed to bring a board back exactly as it was when it was archived. The change
+/// stream is always empty when a board is archived, and its history is not kept. Backups are
+/// written the same way, as of the board's latest checkpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedBoard {
+    pub version: String,
+    pub meta: Option<BoardMeta>,
+    pub objects: BTreeMap<Uuid, JsonObject>,
+    /// Object ID - index pairs, back to front
+    pub order: Vec<(Uuid, f64)>,
+    pub revisions: Vec<(Uuid, u64)>,
+    /// When the board was archived or backed up
+    pub archived_at: DateTime<Utc>,
+}
+
+/// Keeps archived boards as JSON files at `boards/{board_id}.json` in an S3-compatible bucket, or
+/// under some other prefix for backups
+#[derive(Clone)]
+pub struct ArchiveStore {
+    bucket: Bucket,
+    prefix: String,
+}
+
+impl ArchiveStore {
+    /// Credentials that aren't given are looked up the usual AWS ways, from the environment, the
+    /// shared credentials file, or the instance. A custom endpoint is for S3-compatible services
+    /// like MinIO, which are addressed by path rather than by subdomain.
+    pub fn new(
+        bucket_name: &str,
+        region: String,
+        endpoint: Option<String>,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+    ) -> Result<Self> {
+        let credentials = Credentials::new(
+            access_key_id.as_deref(),
+            secret_access_key.as_deref(),
+            None,
+            None,
+            None,
+        )?;
+        let bucket = match endpoint {
+            Some(endpoint) => Bucket::new(
+                bucket_name,
+                Region::Custom { region, endpoint },
+                credentials,
+            )?
+            .with_path_style(),
+            None => Bucket::new(bucket_name, region.parse()?, credentials)?,
+        };
+        Ok(Self {
+            bucket,
+            prefix: "boards".to_string(),
+        })
+    }
+
+    /// Keep boards at `{prefix}/{board_id}.json` instead
+    pub fn with_prefix(self, prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            ..self
+        }
+    }
+
+    #[tracing::instrument(skip(self, archived_board), err)]
+    pub async fn put(&self, board_id: Uuid, archived_board: &ArchivedBoard) -> Result<()> {
+        let response = self
+            .bucket
+            .put_object(self.path(board_id), &serde_json::to_vec(archived_board)?)
+            .await?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(anyhow!("Uploading board {board_id} failed with {status}")),
+        }
+    }
+
+    /// Read a board's archive, or nothing if there isn't one
+    #[tracing::instrument(skip(self), err)]
+    pub async fn get(&self, board_id: Uuid) -> Result<Option<ArchivedBoard>> {
+        let response = self.bucket.get_object(self.path(board_id)).await?;
+        match response.status_code() {
+            200..=299 => Ok(Some(serde_json::from_slice(response.bytes())?)),
+            404 => Ok(None),
+            status => Err(anyhow!(
+                "Reading the archive of board {board_id} failed with {status}"
+            )),
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn delete(&self, board_id: Uuid) -> Result<()> {
+        let response = self.bucket.delete_object(self.path(board_id)).await?;
+        match response.status_code() {
+            200..=299 | 404 => Ok(()),
+            status => Err(anyhow!(
+                "Deleting the archive of board {board_id} failed with {status}"
+            )),
+        }
+    }
+
+    fn path(&self, board_id: Uuid) -> String {
+        format!("{}/{board_id}.json", self.prefix)
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::archive::{ArchiveStore, ArchivedBoard};
+use crate::checkpointer::Checkpointer;
+use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
+use crate::snapshot;
+
+/// Moves boards that nobody has touched in a while out of Redis and into object storage. They're
+/// restored by the repository the next time anything reads or changes them.
+pub struct Archiver {
+    repo: Repository,
+    store: ArchiveStore,
+    inactive_after: chrono::Duration,
+}
+
+impl Archiver {
+    #[tracing::instrument(skip_all)]
+    pub fn new(repo: Repository, store: ArchiveStore, inactive_after: chrono::Duration) -> Self {
+        Self {
+            repo,
+            store,
+            inactive_after,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(self) {
+        let mut restarts = Restarts::new("archiver", RestartPolicy::BACKGROUND);
+        loop {
+            if let Err(error) = self.run().await {
+                restarts.failed(&error).await;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn run(&self) -> Result<()> {
+        loop {
+            let cutoff = Utc::now() - self.inactive_after;
+            for board_id in self.repo.get_inactive_board_ids(cutoff).await? {
+                // One board that can't be archived shouldn't hold up the rest
+                if let Err(error) = self.archive_board(board_id).await {
+                    tracing::warn!(%board_id, %error, "Could not archive board");
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    }
+
+    /// Checkpoint everything left in the board's change stream, upload what's left, and then drop
+    /// the board's contents from Redis. Returns whether the board was archived, which it won't be
+    /// if anyone showed up in the meantime.
+    #[tracing::instrument(skip(self), err)]
+    async fn archive_board(&self, board_id: Uuid) -> Result<bool> {
+        let checkpointer = Checkpointer::new(self.repo.clone());
+        while checkpointer.checkpoint_board(board_id).await? > 0 {}
+
+        let snapshot = snapshot::read_snapshot(board_id, &self.repo).await?;
+        let archived_board = ArchivedBoard {
+            version: snapshot.version,
+            meta: self.repo.get_meta_for_board(board_id).await?,
+            objects: snapshot.objects,
+            order: snapshot.order,
+            revisions: self.repo.get_revisions_for_board(board_id).await?,
+            archived_at: Utc::now(),
+        };
+
+        // Upload first so that the board is never missing from both places. If the board changes
+        // before it's dropped from Redis, the upload is left behind and overwritten next time.
+        self.store.put(board_id, &archived_board).await?;
+        let archived = self
+            .repo
+            .archive_board(board_id, &archived_board.version)
+            .await?;
+        if archived {
+            tracing::info!(%board_id, "Archived board");
+        }
+
+        Ok(archived)
+    }
+}
@@ -0,0 +1,506 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    async_trait,
+    extract::{Extension, FromRequest, RequestParts},
+    http::{header, HeaderMap},
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use utoipa::ToSchema;
+
+use crate::admin::AdminToken;
+use crate::api::ApiError;
+use crate::repository::Repository;
+
+/// Keys are refetched this often even if every token's key is already known, so that revoked keys
+/// stop working eventually
+const JWKS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A token signed with an unknown key triggers a refetch, but no more often than this
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Only asymmetric algorithms make sense with keys published in a JWKS
+const ALLOWED_ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+/// The cookie that holds the token from signing in with OIDC
+pub const TOKEN_COOKIE: &str = "redboard_token";
+
+/// The JWT validator for users, which is empty when JWT authentication is turned off
+#[derive(Clone)]
+pub struct JwtAuth(pub Option<JwtValidator>);
+
+/// Who made a request, as identified by the `sub` claim of their token
+#[derive(Debug, Clone)]
+pub struct UserIdentity {
+    pub user_id: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+/// Checks tokens against the signing keys published by an issuer
+#[derive(Clone)]
+pub struct JwtValidator {
+    issuer: String,
+    audience: Option<String>,
+    jwks_url: String,
+    http_client: reqwest::Client,
+    jwks: Arc<RwLock<Option<(JwkSet, Instant)>>>,
+}
+
+impl JwtValidator {
+    pub fn new(issuer: String, audience: Option<String>, jwks_url: String) -> Self {
+        Self {
+            issuer,
+            audience,
+            jwks_url,
+            http_client: reqwest::Client::new(),
+            jwks: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Check the token's signature, expiration, issuer, and audience, and pull out who it's for
+    #[tracing::instrument(skip_all, err)]
+    pub async fn validate(&self, token: &str) -> Result<UserIdentity> {
+        let header = decode_header(token)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(anyhow!("Unsupported algorithm {:?}", header.alg));
+        }
+        let kid = header.kid.ok_or_else(|| anyhow!("Token has no key ID"))?;
+        let jwk = self.get_key(&kid).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        let claims = decode::<Claims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?.claims;
+
+        Ok(UserIdentity {
+            user_id: claims.sub,
+        })
+    }
+
+    /// Look up a signing key by ID, fetching the issuer's keys again if they're stale or the key
+    /// is new
+    async fn get_key(&self, kid: &str) -> Result<Jwk> {
+        if let Some((jwks, fetched_at)) = &*self.jwks.read().await {
+            if fetched_at.elapsed() < JWKS_MAX_AGE {
+                if let Some(jwk) = jwks.find(kid) {
+                    return Ok(jwk.clone());
+                }
+                if fetched_at.elapsed() < JWKS_MIN_REFRESH_INTERVAL {
+                    return Err(anyhow!("Unknown key ID {kid}"));
+                }
+            }
+        }
+
+        let body = self
+            .http_client
+            .get(&self.jwks_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let jwks = serde_json::from_slice::<JwkSet>(&body)?;
+        let jwk = jwks.find(kid).cloned();
+        *self.jwks.write().await = Some((jwks, Instant::now()));
+
+        jwk.ok_or_else(|| anyhow!("Unknown key ID {kid}"))
+    }
+}
+
+/// What a caller is allowed to do. Admin covers everything.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+/// A static key for a non-interactive client. Only the SHA-256 of the key itself is ever stored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    /// The hex SHA-256 of a key, which is what keys are stored and looked up by
+    pub fn hash(key: &str) -> String {
+        format!("{:x}", Sha256::digest(key.as_bytes()))
+    }
+}
+
+/// API keys from the API_KEYS environment variable, by hash, in addition to the ones in Redis
+#[derive(Clone, Default)]
+pub struct ConfiguredApiKeys(pub HashMap<String, ApiKey>);
+
+impl ConfiguredApiKeys {
+    /// Parse a comma separated list of `name:sha256:scope+scope` entries
+    pub fn parse(config: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        for entry in config
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let mut parts = entry.split(':');
+            let (name, hash, scopes) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), Some(hash), Some(scopes)) => (name, hash, scopes),
+                _ => return Err(anyhow!("Invalid API key entry {entry}")),
+            };
+            let scopes = scopes
+                .split('+')
+                .map(|scope| serde_json::from_value(scope.into()))
+                .collect::<serde_json::Result<_>>()
+                .map_err(|_| anyhow!("Invalid scopes for API key {name}"))?;
+            keys.insert(
+                hash.to_lowercase(),
+                ApiKey {
+                    name: name.to_string(),
+                    scopes,
+                },
+            );
+        }
+        Ok(Self(keys))
+    }
+}
+
+/// Whoever made a request and what they're allowed to do, worked out by an `Authenticator`. Over
+/// HTTP the token is the bearer token in the `Authorization` header, the `access_token` query
+/// parameter for websockets and event streams since browsers can't set headers on those, or the
+/// cookie set by signing in with OIDC.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// The authenticated user, for callers that presented a JWT
+    pub user_id: Option<String>,
+    scopes: Vec<Scope>,
+}
+
+impl Caller {
+    pub fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin) {
+            Ok(())
+        } else {
+            Err(ApiError::Unauthorized)
+        }
+    }
+}
+
+/// Everything a token is checked against, shared by every API so that they all work out callers
+/// the same way. The token can be the admin token, an API key, or a JWT from the configured
+/// issuer. Requests without a token can't do anything when JWT authentication is turned on. When
+/// it's off they can read and write if no credentials are configured at all, but only read once
+/// there's an admin token or API keys, so that leaving a key out never gets more than sending it.
+#[derive(Clone)]
+pub struct Authenticator {
+    repo: Repository,
+    admin_token: AdminToken,
+    jwt_auth: JwtAuth,
+    configured_api_keys: ConfiguredApiKeys,
+    /// Lets requests without a token write even when there are credentials to check
+    allow_anonymous_write: bool,
+}
+
+impl Authenticator {
+    pub fn new(
+        repo: Repository,
+        admin_token: AdminToken,
+        jwt_auth: JwtAuth,
+        configured_api_keys: ConfiguredApiKeys,
+    ) -> Self {
+        Self {
+            repo,
+            admin_token,
+            jwt_auth,
+            configured_api_keys,
+            allow_anonymous_write: false,
+        }
+    }
+
+    pub fn with_anonymous_write(self, allow_anonymous_write: bool) -> Self {
+        Self {
+            allow_anonymous_write,
+            ..self
+        }
+    }
+
+    /// Work out who presented a token and what they can do, or what callers without one can do
+    pub async fn caller(&self, token: Option<&str>) -> Result<Caller, ApiError> {
+        let token = match token {
+            Some(token) => token,
+            None => return self.anonymous_caller(),
+        };
+
+        // Compare digests rather than the tokens themselves so that the time taken doesn't give
+        // away how much of the token was right
+        let hash = ApiKey::hash(token);
+        if let AdminToken(Some(admin_token)) = &self.admin_token {
+            if ApiKey::hash(admin_token) == hash {
+                return Ok(Caller {
+                    user_id: None,
+                    scopes: vec![Scope::Admin],
+                });
+            }
+        }
+
+        let api_key = match self.configured_api_keys.0.get(&hash) {
+            Some(api_key) => Some(api_key.clone()),
+            None => self.repo.get_api_key(&hash).await?,
+        };
+        if let Some(api_key) = api_key {
+            return Ok(Caller {
+                user_id: None,
+                scopes: api_key.scopes,
+            });
+        }
+
+        match &self.jwt_auth.0 {
+            Some(jwt_validator) => {
+                let identity = jwt_validator
+                    .validate(token)
+                    .await
+                    .map_err(|_| ApiError::Unauthorized)?;
+                Ok(Caller {
+                    user_id: Some(identity.user_id),
+                    scopes: vec![Scope::Read, Scope::Write],
+                })
+            }
+            None => Err(ApiError::Unauthorized),
+        }
+    }
+
+    fn anonymous_caller(&self) -> Result<Caller, ApiError> {
+        if self.jwt_auth.0.is_some() {
+            return Err(ApiError::Unauthorized);
+        }
+        let has_credentials =
+            self.admin_token.0.is_some() || !self.configured_api_keys.0.is_empty();
+        let scopes = if has_credentials && !self.allow_anonymous_write {
+            vec![Scope::Read]
+        } else {
+            vec![Scope::Read, Scope::Write]
+        };
+        Ok(Caller {
+            user_id: None,
+            scopes,
+        })
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Caller {
+    type Rejection = ApiError;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(authenticator) = Extension::<Authenticator>::from_request(request).await?;
+
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| {
+                request.uri().query().and_then(|query| {
+                    query
+                        .split('&')
+                        .find_map(|pair| pair.strip_prefix("access_token="))
+                })
+            })
+            .or_else(|| cookie(request.headers(), TOKEN_COOKIE));
+
+        authenticator.caller(token).await
+    }
+}
+
+/// Find a cookie's value in the request's `Cookie` headers
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Extracting this from a request proves that the caller can read boards
+pub struct CanRead;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for CanRead {
+    type Rejection = ApiError;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let caller = Caller::from_request(request).await?;
+        caller.require(Scope::Read)?;
+        Ok(CanRead)
+    }
+}
+
+/// Extracting this from a request proves that the caller can change boards
+pub struct CanWrite;
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for CanWrite {
+    type Rejection = ApiError;
+
+    async fn from_request(request: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let caller = Caller::from_request(request).await?;
+        caller.require(Scope::Write)?;
+        Ok(CanWrite)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_repository::MemoryRepository;
+
+    fn authenticator(admin_token: Option<&str>, api_keys: &str) -> Authenticator {
+        let repo = Repository::new(MemoryRepository::new(
+            0,
+            Default::default(),
+            Default::default(),
+            Duration::from_secs(60),
+            100,
+        ));
+        Authenticator::new(
+            repo,
+            AdminToken(admin_token.map(str::to_string)),
+            JwtAuth(None),
+            ConfiguredApiKeys::parse(api_keys).unwrap(),
+        )
+    }
+
+    fn can(caller: &Caller, scope: Scope) -> bool {
+        caller.require(scope).is_ok()
+    }
+
+    #[test]
+    fn parses_api_keys() {
+        let reader_hash = ApiKey::hash("reader");
+        let writer_hash = ApiKey::hash("writer");
+        let keys = ConfiguredApiKeys::parse(&format!(
+            " reader:{reader_hash}:read , writer:{}:read+write,",
+            writer_hash.to_uppercase()
+        ))
+        .unwrap();
+
+        assert_eq!(keys.0.len(), 2);
+        assert_eq!(keys.0[&reader_hash].name, "reader");
+        assert_eq!(keys.0[&reader_hash].scopes, vec![Scope::Read]);
+        assert_eq!(keys.0[&writer_hash].name, "writer");
+        assert_eq!(keys.0[&writer_hash].scopes, vec![Scope::Read, Scope::Write]);
+    }
+
+    #[test]
+    fn parses_empty_api_keys() {
+        assert!(ConfiguredApiKeys::parse("").unwrap().0.is_empty());
+        assert!(ConfiguredApiKeys::parse(" , ").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_api_keys() {
+        assert!(ConfiguredApiKeys::parse("name:hash").is_err());
+        assert!(ConfiguredApiKeys::parse("name:hash:read+owner").is_err());
+    }
+
+    #[test]
+    fn admin_scope_covers_everything() {
+        let caller = Caller {
+            user_id: None,
+            scopes: vec![Scope::Admin],
+        };
+        assert!(can(&caller, Scope::Read));
+        assert!(can(&caller, Scope::Write));
+        assert!(can(&caller, Scope::Admin));
+    }
+
+    #[tokio::test]
+    async fn admin_token_gets_admin_scope() {
+        let authenticator = authenticator(Some("admin"), "");
+        let caller = authenticator.caller(Some("admin")).await.unwrap();
+
+        assert!(can(&caller, Scope::Admin));
+    }
+
+    #[tokio::test]
+    async fn api_key_gets_its_scopes() {
+        let hash = ApiKey::hash("key");
+        let authenticator = authenticator(None, &format!("reader:{hash}:read"));
+        let caller = authenticator.caller(Some("key")).await.unwrap();
+
+        assert!(can(&caller, Scope::Read));
+        assert!(!can(&caller, Scope::Write));
+        assert!(!can(&caller, Scope::Admin));
+    }
+
+    #[tokio::test]
+    async fn unknown_token_is_rejected() {
+        let authenticator = authenticator(Some("admin"), "");
+
+        assert!(matches!(
+            authenticator.caller(Some("wrong")).await,
+            Err(ApiError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn anonymous_callers_read_and_write_without_credentials() {
+        let caller = authenticator(None, "").caller(None).await.unwrap();
+
+        assert!(can(&caller, Scope::Read));
+        assert!(can(&caller, Scope::Write));
+        assert!(!can(&caller, Scope::Admin));
+    }
+
+    #[tokio::test]
+    async fn anonymous_callers_only_read_once_credentials_are_configured() {
+        let hash = ApiKey::hash("key");
+        for authenticator in [
+            authenticator(Some("admin"), ""),
+            authenticator(None, &format!("reader:{hash}:read")),
+        ] {
+            let caller = authenticator.caller(None).await.unwrap();
+            assert!(can(&caller, Scope::Read));
+            assert!(!can(&caller, Scope::Write));
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_write_can_be_allowed() {
+        let caller = authenticator(Some("admin"), "")
+            .with_anonymous_write(true)
+            .caller(None)
+            .await
+            .unwrap();
+
+        assert!(can(&caller, Scope::Write));
+        assert!(!can(&caller, Scope::Admin));
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Utc;
+use futures::TryStreamExt;
+use uuid::Uuid;
+
+use crate::archive::{ArchiveStore, ArchivedBoard};
+use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
+
+/// Copies the latest checkpoint of every board with a change stream to object storage on a
+/// schedule, so that boards can be recovered even if the store itself loses them. Each board's
+/// backup is overwritten in place, so keeping older ones is up to the bucket's versioning.
+pub struct BackupExporter {
+    repo: Repository,
+    store: ArchiveStore,
+    interval: Duration,
+    /// The version each board was at when it was last backed up, so that boards that haven't
+    /// changed since aren't uploaded again
+    exported_versions: HashMap<Uuid, String>,
+}
+
+impl BackupExporter {
+    #[tracing::instrument(skip_all)]
+    pub fn new(repo: Repository, store: ArchiveStore, interval: Duration) -> Self {
+        Self {
+            repo,
+            store,
+            interval,
+            exported_versions: HashMap::new(),
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(mut self) {
+        let mut restarts = Restarts::new("backup_exporter", RestartPolicy::BACKGROUND);
+        loop {
+            if let Err(error) = self.run().await {
+                restarts.failed(&error).await;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn run(&mut self) -> Result<()> {
+        loop {
+            let mut board_ids_stream = self.repo.stream_all_board_ids().await;
+            while let Some(board_id) = board_ids_stream.try_next().await? {
+                // One board that can't be backed up shouldn't hold up the rest
+                if let Err(error) = self.export_board(board_id).await {
+                    tracing::warn!(%board_id, %error, "Could not back up board");
+                }
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// Upload the board as of its latest checkpoint, unless it's already been uploaded at that
+    /// version. Changes that haven't been checkpointed yet go out with the next backup.
+    #[tracing::instrument(skip(self), err)]
+    async fn export_board(&mut self, board_id: Uuid) -> Result<()> {
+        // The version is read first so that it's never ahead of the objects
+        let version = self.repo.get_version_for_board(board_id).await?;
+        if self.exported_versions.get(&board_id) == Some(&version) {
+            return Ok(());
+        }
+
+        let mut objects = Vec::new();
+        let mut chunks_stream = self.repo.stream_object_chunks_for_board(board_id).await;
+        while let Some(entries) = chunks_stream.try_next().await? {
+            objects.extend(entries);
+        }
+        let backup = ArchivedBoard {
+            version: version.clone(),
+            meta: self.repo.get_meta_for_board(board_id).await?,
+            objects: objects.into_iter().collect(),
+            order: self.repo.get_order_for_board(board_id).await?,
+            revisions: self.repo.get_revisions_for_board(board_id).await?,
+            archived_at: Utc::now(),
+        };
+
+        self.store.put(board_id, &backup).await?;
+        self.exported_versions.insert(board_id, version);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use uuid::Uuid;
+
+use crate::repository::{BanTarget, ClientInfo, Repository, RepositoryResult};
+use crate::share::{Role, ShareClaims};
+
+/// What a websocket connection proved about itself when it was opened. Every board the connection
+/// opens, its own and any it subscribes to, is checked against this separately, so that no board
+/// gets let in on the strength of the checks made for another.
+#[derive(Clone, Debug)]
+pub struct ConnectionGrant {
+    /// The authenticated user behind the connection, if authentication is turned on
+    pub user_id: Option<String>,
+    /// The claims of the share link the connection was opened with, if it was, which keep it to
+    /// the one board the link was made for
+    pub share_claims: Option<ShareClaims>,
+    /// The most the connection can do on any board it's let onto
+    pub role: Role,
+}
+
+/// What a connection can do on one board it was let onto
+#[derive(Debug)]
+pub struct BoardAccess {
+    pub role: Role,
+    /// Every way the connection could be banned from the board
+    pub ban_targets: Vec<BanTarget>,
+}
+
+impl ConnectionGrant {
+    pub fn new(user_id: Option<String>, share_claims: Option<ShareClaims>, role: Role) -> Self {
+        Self {
+            user_id,
+            share_claims,
+            role,
+        }
+    }
+
+    /// Check whether the connection can open a board, which it can't if it was opened with a share
+    /// link for another board, or if the session, its user, or its address is banned from it
+    #[tracing::instrument(skip(self, repo, client), err)]
+    pub async fn check_board(
+        &self,
+        repo: &Repository,
+        board_id: Uuid,
+        session_id: Uuid,
+        client: &ClientInfo,
+    ) -> RepositoryResult<Option<BoardAccess>> {
+        if matches!(&self.share_claims, Some(claims) if claims.board_id != board_id) {
+            return Ok(None);
+        }
+
+        let ban_targets =
+            BanTarget::for_connection(session_id, self.user_id.as_deref(), client.ip.as_deref());
+        if repo
+            .get_ban_for_connection(board_id, &ban_targets)
+            .await?
+            .is_some()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(BoardAccess {
+            role: self.role,
+            ban_targets,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_repository::MemoryRepository;
+    use std::time::Duration;
+
+    fn repo() -> Repository {
+        Repository::new(MemoryRepository::new(
+            0,
+            Default::default(),
+            Default::default(),
+            Duration::from_secs(60),
+            100,
+        ))
+    }
+
+    fn share_claims(board_id: Uuid) -> ShareClaims {
+        ShareClaims {
+            board_id,
+            role: Role::Viewer,
+            expires_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn share_link_only_opens_its_own_board() {
+        let repo = repo();
+        let board_id = Uuid::new_v4();
+        let grant = ConnectionGrant::new(None, Some(share_claims(board_id)), Role::Viewer);
+
+        let access = grant
+            .check_board(&repo, board_id, Uuid::new_v4(), &ClientInfo::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            access,
+            Some(BoardAccess {
+                role: Role::Viewer,
+                ..
+            })
+        ));
+
+        let access = grant
+            .check_board(
+                &repo,
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                &ClientInfo::default(),
+            )
+            .await
+            .unwrap();
+        assert!(access.is_none());
+    }
+
+    #[tokio::test]
+    async fn own_credentials_open_any_board() {
+        let grant = ConnectionGrant::new(None, None, Role::Editor);
+
+        let access = grant
+            .check_board(
+                &repo(),
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                &ClientInfo::default(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            access,
+            Some(BoardAccess {
+                role: Role::Editor,
+                ..
+            })
+        ));
+    }
+}
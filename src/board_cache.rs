@@ -0,0 +1,182 @@
+use anyhow::Result;
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver as MpscReceiver, Sender as MpscSender};
+use tokio::sync::oneshot::{self, Sender as OneshotSender};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::change::ChangeEntry;
+use crate::message::{JsonObject, ServerMessage};
+use crate::repository::Repository;
+use crate::snapshot;
+
+/// How many snapshots can wait for a board's cache to load before whoever asks next has to wait
+/// for room
+const REQUEST_CAPACITY: usize = 100;
+
+/// The contents of a board as of a version, kept up to date by its `BoardCache`
+#[derive(Clone, Debug)]
+pub struct CachedBoard {
+    pub version: String,
+    pub objects: BTreeMap<Uuid, JsonObject>,
+    pub order: HashMap<Uuid, f64>,
+    pub revisions: HashMap<Uuid, u64>,
+}
+
+impl CachedBoard {
+    fn apply(&mut self, entry: ChangeEntry) {
+        for change in entry.change.flatten() {
+            snapshot::apply_change(&mut self.objects, &mut self.order, change);
+        }
+        // Objects that haven't been checkpointed since checkpointed revisions started being kept
+        // were loaded at their latest revision, which can already be ahead of this change
+        for (id, revision) in entry.revisions {
+            let current = self.revisions.entry(id).or_default();
+            *current = (*current).max(revision);
+        }
+        self.version = entry.version;
+    }
+}
+
+struct CacheEntry {
+    /// Tells apart a cache that stopped from the one started in its place
+    id: Uuid,
+    requests: MpscSender<OneshotSender<Arc<CachedBoard>>>,
+}
+
+/// The boards this server keeps in memory, one task for each board that's had a snapshot taken
+/// recently. Each task reads the board from the store once and then keeps its copy up to date
+/// from the change stream, so that snapshots of a popular board don't read every object from the
+/// store every time someone joins.
+#[derive(Clone)]
+pub struct BoardCaches {
+    entries: Arc<Mutex<HashMap<Uuid, CacheEntry>>>,
+    /// How long a board is kept after the last snapshot taken from it
+    idle_timeout: Duration,
+}
+
+impl BoardCaches {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            entries: Arc::default(),
+            idle_timeout,
+        }
+    }
+
+    /// Get the contents of a board from its cache, loading it from the store if it isn't cached
+    /// yet. Empty if the cache couldn't be loaded or stopped, in which case the caller should
+    /// read the board from the store itself.
+    pub async fn get(&self, repo: &Repository, board_id: Uuid) -> Option<Arc<CachedBoard>> {
+        let requests = {
+            let mut entries = self.lock();
+            match entries.get(&board_id) {
+                Some(entry) => entry.requests.clone(),
+                None => {
+                    let (requests, receiver) = mpsc::channel(REQUEST_CAPACITY);
+                    let id = Uuid::new_v4();
+                    entries.insert(
+                        board_id,
+                        CacheEntry {
+                            id,
+                            requests: requests.clone(),
+                        },
+                    );
+                    tokio::task::spawn(
+                        BoardCache {
+                            id,
+                            board_id,
+                            repo: repo.clone(),
+                            caches: self.clone(),
+                        }
+                        .start(receiver),
+                    );
+                    requests
+                }
+            }
+        };
+
+        let (reply, board) = oneshot::channel();
+        requests.send(reply).await.ok()?;
+        board.await.ok()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, CacheEntry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Keeps one board's cached contents up to date and hands them out, until nobody has asked for
+/// them in a while or the board is deleted
+struct BoardCache {
+    id: Uuid,
+    board_id: Uuid,
+    repo: Repository,
+    caches: BoardCaches,
+}
+
+impl BoardCache {
+    #[tracing::instrument(skip_all, fields(board_id = %self.board_id))]
+    async fn start(self, mut requests: MpscReceiver<OneshotSender<Arc<CachedBoard>>>) {
+        // There's nothing to gain from trying again, since the next snapshot starts another
+        if let Err(error) = self.run(&mut requests).await {
+            tracing::warn!(%error, "Board cache stopped");
+        }
+
+        // Snapshots still waiting are dropped, which sends them to the store instead
+        let mut entries = self.caches.lock();
+        if entries.get(&self.board_id).map(|entry| entry.id) == Some(self.id) {
+            entries.remove(&self.board_id);
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn run(
+        &self,
+        requests: &mut MpscReceiver<OneshotSender<Arc<CachedBoard>>>,
+    ) -> Result<()> {
+        let contents = snapshot::read_snapshot(self.board_id, &self.repo).await?;
+        let revisions = self
+            .repo
+            .get_snapshot_revisions_for_board(self.board_id)
+            .await?;
+        let mut board = Arc::new(CachedBoard {
+            version: contents.version,
+            objects: contents.objects,
+            order: contents.order.into_iter().collect(),
+            revisions: revisions.into_iter().collect(),
+        });
+
+        let mut changes = self
+            .repo
+            .stream_changes_for_board(self.board_id, board.version.clone());
+        let mut presence = self
+            .repo
+            .stream_presence_messages_for_board(self.board_id)
+            .await;
+        let mut idle_at = Instant::now() + self.caches.idle_timeout;
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(reply) => {
+                        reply.send(board.clone()).ok();
+                        idle_at = Instant::now() + self.caches.idle_timeout;
+                    }
+                    None => return Ok(()),
+                },
+                // Copies the board only if a snapshot is still being sent from the old contents
+                Some(entry) = changes.next() => Arc::make_mut(&mut board).apply(entry),
+                Some(message) = presence.next() => {
+                    if matches!(message.message, ServerMessage::BoardDeleted) {
+                        return Ok(());
+                    }
+                }
+                _ = tokio::time::sleep_until(idle_at) => return Ok(()),
+            }
+        }
+    }
+}
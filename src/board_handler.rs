@@ -1,79 +1,273 @@
 use anyhow::Result;
 use futures::stream::TryStreamExt;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch::{self, Receiver as WatchReceiver, Sender as WatchSender};
 use tokio::task::JoinHandle;
 use uuid::Uuid;
 
-use crate::message::{ClientMessage, ServerMessage};
+use crate::board_access::{BoardAccess, ConnectionGrant};
+use crate::cursor_publisher::CursorPublisher;
+use crate::degraded_notifier::DegradedNotifier;
+use crate::idle_timeout::IdleTimeout;
+use crate::message::{
+    Capabilities, ClientMessage, Cursor, ErrorCode, OwnChanges, RejectionReason, ServerMessage,
+};
 use crate::presence::Presence;
-use crate::repository::Repository;
+use crate::rate_limit::{
+    TokenBucket, CHANGES_PER_SECOND, CHANGE_BURST, CURSOR_UPDATES_PER_SECOND, CURSOR_UPDATE_BURST,
+};
+use crate::repository::{BanTarget, ClientInfo, PublishOutcome, Repository, RepositoryError};
+use crate::restart::{RestartPolicy, Restarts};
+use crate::share::Role;
+use crate::shutdown::{Shutdown, ShutdownGuard, RECONNECT_AFTER};
+use crate::snapshot;
 use crate::socket::{is_broken_connection_error, SocketMessage, SocketSender, SocketStream};
+use crate::spatial::Rect;
+use crate::subscription::Subscription;
+use crate::username::{self, UsernamePolicy};
 use crate::{broadcaster::Broadcaster, change::Change};
 
+/// How often the session's checkin is refreshed while the client keeps sending messages, or more
+/// often if the session's TTL is short enough that this wouldn't be well within it
+const TOUCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the connection can go without hearing from the client before the server sends a
+/// websocket ping, so that proxies with idle timeouts don't drop quiet connections
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often a session waiting for a spot on a full board checks whether one has opened up
+const WAITING_ROOM_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Where a connection is in its lifecycle, which decides what the client may send next
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SessionState {
+    /// Waiting for `ClientReady` or `ResumeSession`
+    Connected,
+    /// In line for a spot on a full board
+    Waiting,
+    /// On the board, but not following its changes yet
+    Joined,
+    /// Following the board's changes since a snapshot or resync
+    Streaming,
+}
+
+/// What a session asked to join the board with, held onto while it waits for a spot
+#[derive(Debug)]
+struct PendingJoin {
+    username: String,
+    /// Where to pick the change stream back up, for sessions resuming after theirs expired
+    from_version: Option<String>,
+}
+
+impl SessionState {
+    fn allows(self, message: &ClientMessage) -> bool {
+        match message {
+            ClientMessage::ClientReady { .. } | ClientMessage::ResumeSession { .. } => {
+                self == SessionState::Connected
+            }
+            // Heartbeats start as soon as the socket opens, but are only recorded once joined
+            ClientMessage::Ping { .. } => true,
+            // Changes are made against what the client got in its snapshot
+            ClientMessage::ApplyChange { .. } => self == SessionState::Streaming,
+            _ => self >= SessionState::Joined,
+        }
+    }
+}
+
 pub struct BoardHandler {
     board_id: Uuid,
     session_id: Uuid,
+    /// What the connection proved when it was opened, which every board it subscribes to is
+    /// checked against
+    grant: ConnectionGrant,
+    role: Role,
+    /// Every way the connection could be banned from the board
+    ban_targets: Vec<BanTarget>,
+    /// What the client connected with, which is recorded once the session joins
+    client: ClientInfo,
     repo: Repository,
     socket_sender: SocketSender,
     socket_stream: SocketStream,
+    state: SessionState,
+    /// Set while the session waits for a spot on the board
+    pending_join: Option<PendingJoin>,
+    /// The session's last known place in line, so it's only told when that changes
+    waiting_position: usize,
     is_closed: bool,
+    locked_objects: HashSet<Uuid>,
     broadcaster_handle: Option<JoinHandle<()>>,
     presence_handle: Option<JoinHandle<()>>,
+    cursor_sender: WatchSender<Option<Cursor>>,
+    cursor_publisher_handle: Option<JoinHandle<()>>,
+    degraded_notifier_handle: Option<JoinHandle<()>>,
+    /// Whether the store is unreachable, for stores that can tell
+    degraded_receiver: Option<WatchReceiver<bool>>,
+    /// The latest round trip the client measured while the store was unreachable, published once
+    /// it's back
+    pending_rtt: Option<f64>,
+    /// When the session's checkin was last refreshed, if it has been yet
+    last_touched: Option<Instant>,
+    subscription_handles: HashMap<Uuid, JoinHandle<()>>,
+    change_bucket: TokenBucket,
+    cursor_bucket: TokenBucket,
+    /// Set while cursor updates are being dropped so the client is only told once per stretch
+    is_cursor_limited: bool,
+    username_policy: UsernamePolicy,
+    idle_timeout: Option<IdleTimeout>,
+    /// When the client last sent anything besides a heartbeat
+    last_activity: Instant,
+    /// Set once the client has been warned that it's about to be closed for being idle
+    is_idle_warned: bool,
+    /// What the client asked to hear about its own changes when it joined
+    own_changes: OwnChanges,
+    shutdown: Shutdown,
+    /// Keeps the server from exiting while the handler is still wrapping up
+    _shutdown_guard: Option<ShutdownGuard>,
 }
 
 impl BoardHandler {
-    #[tracing::instrument(skip(repo, socket_sender, socket_stream))]
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(repo, socket_sender, socket_stream, username_policy, shutdown))]
     pub fn new(
         board_id: Uuid,
         session_id: Uuid,
+        grant: ConnectionGrant,
+        access: BoardAccess,
+        client: ClientInfo,
         repo: Repository,
         socket_sender: SocketSender,
         socket_stream: SocketStream,
+        username_policy: UsernamePolicy,
+        idle_timeout: Option<IdleTimeout>,
+        shutdown: Shutdown,
     ) -> Self {
+        let degraded_receiver = repo.watch_degraded();
         Self {
             board_id,
             session_id,
+            grant,
+            role: access.role,
+            ban_targets: access.ban_targets,
+            client,
             repo,
             socket_sender,
             socket_stream,
+            state: SessionState::Connected,
+            pending_join: None,
+            waiting_position: 0,
             is_closed: false,
+            locked_objects: HashSet::new(),
             broadcaster_handle: None,
             presence_handle: None,
+            cursor_sender: watch::channel(None).0,
+            cursor_publisher_handle: None,
+            degraded_notifier_handle: None,
+            degraded_receiver,
+            pending_rtt: None,
+            last_touched: None,
+            subscription_handles: HashMap::new(),
+            change_bucket: TokenBucket::new(CHANGES_PER_SECOND, CHANGE_BURST),
+            cursor_bucket: TokenBucket::new(CURSOR_UPDATES_PER_SECOND, CURSOR_UPDATE_BURST),
+            is_cursor_limited: false,
+            username_policy,
+            idle_timeout,
+            last_activity: Instant::now(),
+            is_idle_warned: false,
+            own_changes: OwnChanges::default(),
+            _shutdown_guard: shutdown.guard(),
+            shutdown,
         }
     }
 
     #[tracing::instrument(skip_all)]
     pub async fn start(mut self) {
-        self.presence_handle = Some(tokio::task::spawn(
-            Presence::new(
+        self.cursor_publisher_handle = Some(tokio::task::spawn(
+            CursorPublisher::new(
                 self.board_id,
                 self.session_id,
                 self.repo.clone(),
-                self.socket_sender.clone(),
+                self.cursor_sender.subscribe(),
             )
             .start(),
         ));
 
+        if let Some(degraded_receiver) = self.degraded_receiver.clone() {
+            self.degraded_notifier_handle = Some(tokio::task::spawn(
+                DegradedNotifier::new(degraded_receiver, self.socket_sender.clone()).start(),
+            ));
+        }
+
+        let mut restarts = Restarts::new("board_handler", RestartPolicy::CONNECTION);
         loop {
             if self.is_closed {
                 break;
             }
 
-            self.run().await.ok();
+            if let Err(error) = self.run().await {
+                self.report_error(&error).await;
+                // Leave the session behind so that the client can resume it once it reconnects
+                if !restarts.failed(&error).await {
+                    self.socket_sender.disconnect().await.ok();
+                    self.on_drop().await.ok();
+                    break;
+                }
+            }
         }
 
         self.shutdown().await;
     }
 
+    /// Let the client know when something it asked for failed because of the store, so that it
+    /// isn't left waiting for an answer. Anything else, like the socket breaking, isn't reported.
+    #[tracing::instrument(skip_all)]
+    async fn report_error(&mut self, error: &anyhow::Error) {
+        let error = match error
+            .chain()
+            .find_map(|error| error.downcast_ref::<RepositoryError>())
+        {
+            Some(error) => error,
+            None => return,
+        };
+        let code = match error {
+            RepositoryError::NotFound(_) => ErrorCode::NotFound,
+            RepositoryError::Conflict(_) => ErrorCode::Conflict,
+            RepositoryError::Timeout(_) => ErrorCode::Unavailable,
+            RepositoryError::Serialization(_) | RepositoryError::Backend { .. } => {
+                ErrorCode::Internal
+            }
+        };
+        self.socket_sender
+            .send(ServerMessage::Error {
+                code,
+                retryable: error.is_transient(),
+            })
+            .await
+            .ok();
+    }
+
     #[tracing::instrument(skip_all)]
     async fn shutdown(&mut self) {
         if let Some(presence_handle) = self.presence_handle.take() {
             presence_handle.abort();
             presence_handle.await.ok();
         }
+        if let Some(cursor_publisher_handle) = self.cursor_publisher_handle.take() {
+            cursor_publisher_handle.abort();
+            cursor_publisher_handle.await.ok();
+        }
         if let Some(broadcaster_handle) = self.broadcaster_handle.take() {
             broadcaster_handle.abort();
             broadcaster_handle.await.ok();
         }
+        if let Some(degraded_notifier_handle) = self.degraded_notifier_handle.take() {
+            degraded_notifier_handle.abort();
+            degraded_notifier_handle.await.ok();
+        }
+        for (_, subscription_handle) in self.subscription_handles.drain() {
+            subscription_handle.abort();
+            subscription_handle.await.ok();
+        }
     }
 
     #[tracing::instrument(skip_all, err)]
@@ -83,17 +277,83 @@ impl BoardHandler {
                 return Ok(());
             }
 
-            match self.socket_stream.try_next().await {
-                Ok(Some(SocketMessage::Close)) | Ok(None) => {
+            let idle_deadline = self.idle_deadline();
+
+            // Whatever the client already sent is handled before the shutdown, so that none of its
+            // changes are lost
+            let message = tokio::select! {
+                biased;
+                message = self.socket_stream.try_next() => message,
+                _ = self.shutdown.wait() => {
+                    self.on_shutdown().await?;
+                    break;
+                }
+                _ = self.socket_sender.wait_saturated() => {
+                    self.on_drop().await?;
+                    break;
+                }
+                _ = tokio::time::sleep(WAITING_ROOM_INTERVAL),
+                    if self.state == SessionState::Waiting =>
+                {
+                    self.on_waiting_room_tick().await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(tokio::time::Instant::from_std(
+                    idle_deadline.unwrap_or_else(Instant::now),
+                )), if idle_deadline.is_some() => {
+                    self.on_idle().await?;
+                    continue;
+                }
+                _ = tokio::time::sleep(KEEPALIVE_INTERVAL) => {
+                    self.socket_sender.ping().await?;
+                    continue;
+                }
+            };
+
+            // Heartbeats are sent on a timer, so they don't show that anyone is there
+            if let Ok(Some(SocketMessage::Data(message))) = &message {
+                if !matches!(message, ClientMessage::Ping { .. }) {
+                    self.last_activity = Instant::now();
+                    self.is_idle_warned = false;
+                }
+            }
+
+            match message {
+                Ok(Some(SocketMessage::Close)) => {
                     self.on_close().await?;
                     break;
                 }
+                Ok(None) => {
+                    self.on_drop().await?;
+                    break;
+                }
                 Err(error) if is_broken_connection_error(&error) => {
-                    self.on_close().await?;
+                    self.on_drop().await?;
+                    break;
+                }
+                Ok(Some(SocketMessage::Ping(payload))) => {
+                    self.socket_sender.pong(payload).await?;
+                }
+                Ok(Some(SocketMessage::TooLarge)) => {
+                    self.on_message_too_large().await?;
                     break;
                 }
-                Ok(Some(SocketMessage::Data(ClientMessage::ClientReady { username }))) => {
-                    self.on_client_ready(username).await?;
+                Ok(Some(SocketMessage::Data(message))) if !self.state.allows(&message) => {
+                    self.on_out_of_order().await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::ClientReady {
+                    username,
+                    own_changes,
+                }))) => {
+                    self.on_client_ready(username, own_changes).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::ResumeSession {
+                    username,
+                    from_version,
+                    own_changes,
+                }))) => {
+                    self.on_resume_session(username, from_version, own_changes)
+                        .await?;
                 }
                 Ok(Some(SocketMessage::Data(ClientMessage::CursorChanged { x, y }))) => {
                     self.on_cursor_changed(x, y).await?;
@@ -101,11 +361,38 @@ impl BoardHandler {
                 Ok(Some(SocketMessage::Data(ClientMessage::CursorLeft))) => {
                     self.on_cursor_left().await?;
                 }
+                Ok(Some(SocketMessage::Data(ClientMessage::LockObject { id }))) => {
+                    self.on_lock_object(id).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::UnlockObject { id }))) => {
+                    self.on_unlock_object(id).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::Ping { client_time, rtt }))) => {
+                    self.on_ping(client_time, rtt).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::RequestPresence))) => {
+                    self.on_request_presence().await?;
+                }
                 Ok(Some(SocketMessage::Data(ClientMessage::StartSnapshot))) => {
-                    self.on_start_snapshot().await?;
+                    self.on_start_snapshot(None).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::SnapshotViewport { viewport }))) => {
+                    self.on_start_snapshot(Some(viewport)).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::Resync { from_version }))) => {
+                    self.on_resync(from_version).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::SubscribeBoard { board_id }))) => {
+                    self.on_subscribe_board(board_id).await?;
                 }
-                Ok(Some(SocketMessage::Data(ClientMessage::ApplyChange { change }))) => {
-                    self.on_apply_change(change).await?;
+                Ok(Some(SocketMessage::Data(ClientMessage::UnsubscribeBoard { board_id }))) => {
+                    self.on_unsubscribe_board(board_id).await?;
+                }
+                Ok(Some(SocketMessage::Data(ClientMessage::ApplyChange {
+                    change,
+                    idempotency_key,
+                }))) => {
+                    self.on_apply_change(change, idempotency_key).await?;
                 }
                 Ok(_) => {}
                 Err(error) => return Err(error),
@@ -119,27 +406,364 @@ impl BoardHandler {
 
     #[tracing::instrument(skip_all, err)]
     async fn on_close(&mut self) -> Result<()> {
-        self.is_closed = true;
-        self.socket_sender.close().await;
-        self.shutdown().await;
+        self.disconnect().await?;
         self.repo
-            .delete_session_for_board(self.board_id, self.session_id)
+            .remove_session_from_board(self.board_id, self.session_id)
             .await?;
         Ok(())
     }
 
+    /// The connection went away without the client closing it, which is usually the network
+    /// dropping out or the client falling too far behind on what it's sent, so the session is left
+    /// on the board for the client to resume. If it doesn't come back, the session checker
+    /// removes it once it stops checking in.
+    #[tracing::instrument(skip_all, err)]
+    async fn on_drop(&mut self) -> Result<()> {
+        self.disconnect().await?;
+        self.repo
+            .delete_session_cursor_for_board(self.board_id, self.session_id)
+            .await?;
+        Ok(())
+    }
+
+    /// Stop everything the connection was doing and give up its locks, or its place in line
+    #[tracing::instrument(skip_all, err)]
+    async fn disconnect(&mut self) -> Result<()> {
+        self.is_closed = true;
+        self.socket_sender.close();
+        self.shutdown().await;
+        if self.state == SessionState::Waiting {
+            self.repo
+                .leave_waiting_room_for_board(self.board_id, self.session_id)
+                .await?;
+        }
+        for object_id in self.locked_objects.drain() {
+            self.repo
+                .unlock_object_for_board(self.board_id, self.session_id, object_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the store can't be reached right now
+    fn is_degraded(&self) -> bool {
+        matches!(&self.degraded_receiver, Some(degraded_receiver) if *degraded_receiver.borrow())
+    }
+
+    /// Tell the client when to come back, then leave the session on the board for it to resume
+    /// once it reconnects to another server. The connection is wrapped up even if the client
+    /// can't be told.
+    #[tracing::instrument(skip_all, err)]
+    async fn on_shutdown(&mut self) -> Result<()> {
+        self.socket_sender
+            .send(ServerMessage::ServerShutdown {
+                retry_after_ms: RECONNECT_AFTER.as_millis() as u64,
+            })
+            .await
+            .ok();
+        self.socket_sender.disconnect().await.ok();
+        self.on_drop().await
+    }
+
+    /// When the client is next due to be warned or closed for being idle, if ever
+    fn idle_deadline(&self) -> Option<Instant> {
+        let idle_timeout = self.idle_timeout?;
+        if self.is_idle_warned {
+            Some(self.last_activity + idle_timeout.timeout)
+        } else {
+            Some(self.last_activity + idle_timeout.timeout - idle_timeout.warning)
+        }
+    }
+
+    /// Warn the client the first time, and close the connection the next. Like kicked sessions,
+    /// idle ones leave the board for good rather than waiting to be resumed.
+    #[tracing::instrument(skip_all, err)]
+    async fn on_idle(&mut self) -> Result<()> {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return Ok(()),
+        };
+
+        if !self.is_idle_warned {
+            self.is_idle_warned = true;
+            self.socket_sender
+                .send(ServerMessage::IdleWarning {
+                    closes_in_ms: idle_timeout.warning.as_millis() as u64,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        self.socket_sender
+            .send(ServerMessage::IdleTimeout)
+            .await
+            .ok();
+        self.socket_sender.disconnect().await.ok();
+        self.on_close().await
+    }
+
+    /// Anything after an oversized message can't be trusted to line up with what the client meant
+    /// to send, so the connection is closed and the session leaves the board
+    #[tracing::instrument(skip_all, err)]
+    async fn on_message_too_large(&mut self) -> Result<()> {
+        tracing::debug!("Closing connection after a message that was too large");
+        self.socket_sender
+            .send(ServerMessage::Error {
+                code: ErrorCode::MessageTooLarge,
+                retryable: false,
+            })
+            .await
+            .ok();
+        self.socket_sender.disconnect_too_large().await.ok();
+        self.on_close().await
+    }
+
     #[tracing::instrument(skip_all, err)]
     async fn touch_session(&mut self) -> Result<()> {
-        self.repo.touch_session(self.session_id).await?;
+        // Keeping the session alive can wait until the store is back, which is sooner than the
+        // session would expire in all but the longest outages
+        if self.is_degraded() {
+            return Ok(());
+        }
+
+        // Every cursor movement comes through here, so the checkin is only refreshed every so often
+        // rather than once per message
+        let touch_due = match self.last_touched {
+            Some(last_touched) => {
+                last_touched.elapsed() >= TOUCH_INTERVAL.min(self.repo.session_ttl() / 3)
+            }
+            None => true,
+        };
+        if touch_due {
+            self.repo.touch_session(self.session_id).await?;
+            self.last_touched = Some(Instant::now());
+        }
+
+        if let Some(rtt) = self.pending_rtt.take() {
+            self.repo
+                .update_session_latency_for_board(self.board_id, self.session_id, rtt)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn on_out_of_order(&mut self) -> Result<()> {
+        tracing::debug!(state = ?self.state, "Ignoring message sent out of order");
+        self.socket_sender
+            .send(ServerMessage::Error {
+                code: ErrorCode::OutOfOrder,
+                retryable: false,
+            })
+            .await?;
+
         Ok(())
     }
 
     #[tracing::instrument(skip(self), err)]
-    async fn on_client_ready(&mut self, username: String) -> Result<()> {
+    async fn on_client_ready(
+        &mut self,
+        username: Option<String>,
+        own_changes: OwnChanges,
+    ) -> Result<()> {
+        self.own_changes = own_changes;
+        self.join(PendingJoin {
+            username: username.unwrap_or_else(username::guest),
+            from_version: None,
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn on_resume_session(
+        &mut self,
+        username: String,
+        from_version: String,
+        own_changes: OwnChanges,
+    ) -> Result<()> {
+        self.own_changes = own_changes;
+        let username_on_board = if self.repo.get_session_exists(self.session_id).await? {
+            self.repo
+                .get_sessions_for_board(self.board_id)
+                .await?
+                .into_iter()
+                .find(|(session_id, _)| *session_id == self.session_id)
+                .map(|(_, username)| username)
+        } else {
+            None
+        };
+
+        let username_on_board = match username_on_board {
+            Some(username_on_board) => username_on_board,
+            None => {
+                // The session expired before the client came back, so it joins like a new one
+                return self
+                    .join(PendingJoin {
+                        username,
+                        from_version: Some(from_version),
+                    })
+                    .await;
+            }
+        };
+
+        // Session IDs are broadcast to everyone on the board, so only whoever the session belongs
+        // to can pick it back up
+        let user_on_board = self
+            .repo
+            .get_session_user_for_board(self.board_id, self.session_id)
+            .await?;
+        if user_on_board != self.grant.user_id {
+            self.socket_sender
+                .send(ServerMessage::Error {
+                    code: ErrorCode::SessionTaken,
+                    retryable: false,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // Everyone else still sees the session, so there's nothing to tell them
+        self.repo.touch_session(self.session_id).await?;
+        self.start_presence();
+        self.state = SessionState::Joined;
+
+        self.send_server_ready(username_on_board).await?;
+        self.on_resync(from_version).await
+    }
+
+    /// Join the board, or get in line for a spot if it's full
+    #[tracing::instrument(skip(self), err)]
+    async fn join(&mut self, mut pending_join: PendingJoin) -> Result<()> {
+        // Usernames are shown to everyone on the board, so the client gets to pick another if its
+        // choice doesn't pass
+        pending_join.username = match self.username_policy.validate(&pending_join.username) {
+            Some(username) => username,
+            None => {
+                self.socket_sender
+                    .send(ServerMessage::Error {
+                        code: ErrorCode::InvalidUsername,
+                        retryable: false,
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        // The session checker leaves a waiting session's place in line alone as long as it checks
+        // in
+        self.touch_session().await?;
+
+        match self.get_waiting_room_position().await? {
+            Some(position) => {
+                self.state = SessionState::Waiting;
+                self.pending_join = Some(pending_join);
+                self.waiting_position = position;
+                self.socket_sender
+                    .send(ServerMessage::WaitingRoom { position })
+                    .await?;
+                Ok(())
+            }
+            None => self.finish_join(pending_join).await,
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn finish_join(&mut self, pending_join: PendingJoin) -> Result<()> {
+        // Two people going by the same name are told apart by numbering the newer one
+        let taken_usernames = self
+            .repo
+            .get_sessions_for_board(self.board_id)
+            .await?
+            .into_iter()
+            .filter(|(session_id, _)| *session_id != self.session_id)
+            .map(|(_, username)| username)
+            .collect::<Vec<_>>();
+        let username = username::disambiguate(pending_join.username, &taken_usernames);
+
         self.repo
-            .create_session_for_board(self.board_id, self.session_id, username)
+            .create_session_for_board(
+                self.board_id,
+                self.session_id,
+                username.clone(),
+                self.grant.user_id.clone(),
+                self.client.clone(),
+            )
             .await?;
+        self.start_presence();
+        self.state = SessionState::Joined;
+
+        self.send_server_ready(username).await?;
+        match pending_join.from_version {
+            Some(from_version) => self.on_resync(from_version).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Find the session's place in line for the board, or nothing if there's a spot for it. Only
+    /// sessions that got in line first are let in ahead of it.
+    #[tracing::instrument(skip_all, err)]
+    async fn get_waiting_room_position(&mut self) -> Result<Option<usize>> {
+        let max_sessions = match self.repo.quotas().max_sessions {
+            Some(max_sessions) => max_sessions,
+            None => return Ok(None),
+        };
+
+        let position = self
+            .repo
+            .join_waiting_room_for_board(self.board_id, self.session_id)
+            .await?;
+        let session_count = self.repo.get_session_count_for_board(self.board_id).await?;
+        if session_count + position > max_sessions {
+            return Ok(Some(position));
+        }
 
+        self.repo
+            .leave_waiting_room_for_board(self.board_id, self.session_id)
+            .await?;
+        Ok(None)
+    }
+
+    /// Let the session in if a spot has opened up, or tell it if it has moved up in line
+    #[tracing::instrument(skip_all, err)]
+    async fn on_waiting_room_tick(&mut self) -> Result<()> {
+        match self.get_waiting_room_position().await? {
+            Some(position) if position != self.waiting_position => {
+                self.waiting_position = position;
+                self.socket_sender
+                    .send(ServerMessage::WaitingRoom { position })
+                    .await?;
+            }
+            Some(_) => {}
+            None => {
+                if let Some(pending_join) = self.pending_join.take() {
+                    self.finish_join(pending_join).await?;
+                }
+            }
+        }
+
+        self.touch_session().await
+    }
+
+    /// Start passing along what everyone else on the board is doing, once the session is on it
+    #[tracing::instrument(skip_all)]
+    fn start_presence(&mut self) {
+        self.presence_handle = Some(tokio::task::spawn(
+            Presence::new(
+                self.board_id,
+                self.session_id,
+                self.repo.clone(),
+                self.socket_sender.clone(),
+                true,
+                self.ban_targets.clone(),
+            )
+            .start(),
+        ));
+    }
+
+    /// Introduce the client to everyone else on the board and let it know it can start
+    #[tracing::instrument(skip_all, err)]
+    async fn send_server_ready(&mut self, username: String) -> Result<()> {
         let sessions = self.repo.get_sessions_for_board(self.board_id).await?;
 
         for (session_id, username) in sessions {
@@ -154,53 +778,285 @@ impl BoardHandler {
                 .await?;
         }
 
-        self.socket_sender.send(ServerMessage::ServerReady).await?;
+        self.socket_sender
+            .send(ServerMessage::ServerReady {
+                capabilities: Capabilities::current(self.repo.quotas(), self.repo.session_ttl()),
+                role: self.role,
+                username,
+            })
+            .await?;
+
+        // Everyone else's cursor would otherwise stay hidden until they next moved it
+        let cursors = self
+            .repo
+            .get_session_cursors_for_board(self.board_id)
+            .await?;
+        for (session_id, cursor) in cursors {
+            if session_id == self.session_id {
+                continue;
+            }
+            self.socket_sender
+                .send(ServerMessage::UserCursorChanged {
+                    session_id,
+                    x: cursor.x,
+                    y: cursor.y,
+                })
+                .await?;
+        }
 
         Ok(())
     }
 
     #[tracing::instrument(skip(self), err)]
     async fn on_cursor_changed(&mut self, x: f64, y: f64) -> Result<()> {
-        self.repo
-            .update_session_cursor_for_board(self.board_id, self.session_id, x, y)
-            .await?;
+        // Viewers are only watching, so nobody else needs to see where they're pointing
+        if self.role == Role::Viewer {
+            return Ok(());
+        }
+
+        if !self.cursor_bucket.try_take() {
+            if !self.is_cursor_limited {
+                self.is_cursor_limited = true;
+                self.socket_sender
+                    .send(ServerMessage::RateLimited {
+                        change: None,
+                        retry_after_ms: self.cursor_bucket.retry_after().as_millis() as u64,
+                    })
+                    .await?;
+            }
+            return Ok(());
+        }
+        self.is_cursor_limited = false;
+
+        // The cursor publisher picks this up at its own pace, so sending can only fail if it has
+        // already been shut down
+        self.cursor_sender.send(Some(Cursor { x, y })).ok();
 
         Ok(())
     }
 
     #[tracing::instrument(skip_all, err)]
     async fn on_cursor_left(&mut self) -> Result<()> {
-        self.repo
-            .delete_session_cursor_for_board(self.board_id, self.session_id)
-            .await?;
+        if self.role == Role::Viewer {
+            return Ok(());
+        }
+
+        self.cursor_sender.send(None).ok();
 
         Ok(())
     }
 
-    #[tracing::instrument(skip_all, err)]
-    async fn on_start_snapshot(&mut self) -> Result<()> {
-        if let Some(handle) = self.broadcaster_handle.take() {
-            handle.abort();
-            handle.await.ok();
+    #[tracing::instrument(skip(self), err)]
+    async fn on_lock_object(&mut self, id: Uuid) -> Result<()> {
+        // Viewers can't edit, so there's no reason for them to hold a lease
+        if self.role == Role::Viewer {
+            return Ok(());
         }
 
-        let version = self.repo.get_version_for_board(self.board_id).await?;
-        let mut chunks_stream = self
+        let holder = self
             .repo
-            .stream_object_chunks_for_board(self.board_id)
-            .await;
-        while let Some(entries) = chunks_stream.try_next().await? {
+            .lock_object_for_board(self.board_id, self.session_id, id)
+            .await?;
+
+        if holder == self.session_id {
+            self.locked_objects.insert(id);
+        }
+
+        // Either way, let the client know who ended up holding the lease
+        self.socket_sender
+            .send(ServerMessage::ObjectLocked {
+                id,
+                session_id: holder,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn on_unlock_object(&mut self, id: Uuid) -> Result<()> {
+        self.locked_objects.remove(&id);
+        let released = self
+            .repo
+            .unlock_object_for_board(self.board_id, self.session_id, id)
+            .await?;
+
+        if released {
             self.socket_sender
-                .send(ServerMessage::SnapshotChunk { entries })
+                .send(ServerMessage::ObjectUnlocked {
+                    id,
+                    session_id: self.session_id,
+                })
                 .await?;
         }
 
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn on_ping(&mut self, client_time: Option<f64>, rtt: Option<f64>) -> Result<()> {
+        let server_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        self.socket_sender
+            .send(ServerMessage::Pong {
+                client_time,
+                server_time,
+            })
+            .await?;
+
+        // A session that hasn't joined has nothing in the store to record its latency on. Only
+        // the latest measurement matters, so it's held onto while the store is unreachable and
+        // published along with the next touch of the session.
+        if self.state < SessionState::Joined {
+            return Ok(());
+        }
+        if let Some(rtt) = rtt {
+            if self.is_degraded() {
+                self.pending_rtt = Some(rtt);
+            } else {
+                self.repo
+                    .update_session_latency_for_board(self.board_id, self.session_id, rtt)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn on_request_presence(&mut self) -> Result<()> {
+        let users = self
+            .repo
+            .get_sessions_for_board(self.board_id)
+            .await?
+            .into_iter()
+            .filter(|(session_id, _)| *session_id != self.session_id)
+            .collect();
+        let cursors = self
+            .repo
+            .get_session_cursors_for_board(self.board_id)
+            .await?
+            .into_iter()
+            .filter(|(session_id, _)| *session_id != self.session_id)
+            .collect();
+        let locks = self.repo.get_object_locks_for_board(self.board_id).await?;
+        let latencies = self
+            .repo
+            .get_session_latencies_for_board(self.board_id)
+            .await?
+            .into_iter()
+            .filter(|(session_id, _)| *session_id != self.session_id)
+            .collect();
+
+        self.socket_sender
+            .send(ServerMessage::PresenceSnapshot {
+                users,
+                cursors,
+                locks,
+                latencies,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn on_start_snapshot(&mut self, viewport: Option<Rect>) -> Result<()> {
+        self.stop_broadcaster().await;
+
+        let version =
+            snapshot::send_snapshot(self.board_id, &self.repo, &self.socket_sender, viewport)
+                .await?;
+
+        self.start_broadcaster(version);
+        self.state = SessionState::Streaming;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn on_resync(&mut self, from_version: String) -> Result<()> {
+        // If the checkpointer has already trimmed the stream past the client's version then
+        // there's no way to replay what it missed, so it has to start over with a snapshot
+        let is_available = self
+            .repo
+            .get_version_available_for_board(self.board_id, from_version.as_str())
+            .await?;
+        if !is_available {
+            return self.on_start_snapshot(None).await;
+        }
+
+        self.stop_broadcaster().await;
+
         self.socket_sender
-            .send(ServerMessage::SnapshotFinished {
-                version: Some(version.clone()),
+            .send(ServerMessage::ResyncStarted {
+                version: from_version.clone(),
             })
             .await?;
 
+        self.start_broadcaster(from_version);
+        self.state = SessionState::Streaming;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn on_subscribe_board(&mut self, board_id: Uuid) -> Result<()> {
+        // The handler's own board is already being sent over this socket
+        if board_id == self.board_id {
+            return Ok(());
+        }
+
+        // Subscribing again restarts the subscription with a fresh snapshot
+        self.on_unsubscribe_board(board_id).await?;
+
+        // Being let onto this board says nothing about any other
+        let access = match self
+            .grant
+            .check_board(&self.repo, board_id, self.session_id, &self.client)
+            .await?
+        {
+            Some(access) => access,
+            None => {
+                self.socket_sender
+                    .for_board(board_id)
+                    .send(ServerMessage::Error {
+                        code: ErrorCode::Forbidden,
+                        retryable: false,
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        self.subscription_handles.insert(
+            board_id,
+            tokio::task::spawn(
+                Subscription::new(
+                    board_id,
+                    self.session_id,
+                    self.repo.clone(),
+                    self.socket_sender.clone(),
+                    access.ban_targets,
+                )
+                .start(),
+            ),
+        );
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn on_unsubscribe_board(&mut self, board_id: Uuid) -> Result<()> {
+        if let Some(handle) = self.subscription_handles.remove(&board_id) {
+            handle.abort();
+            handle.await.ok();
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn start_broadcaster(&mut self, version: String) {
         self.broadcaster_handle = Some(tokio::task::spawn(
             Broadcaster::new(
                 self.board_id,
@@ -208,17 +1064,216 @@ impl BoardHandler {
                 self.repo.clone(),
                 self.socket_sender.clone(),
             )
+            .with_own_changes(self.session_id, self.own_changes)
             .start(),
         ));
+    }
 
-        Ok(())
+    #[tracing::instrument(skip_all)]
+    async fn stop_broadcaster(&mut self) {
+        if let Some(handle) = self.broadcaster_handle.take() {
+            handle.abort();
+            handle.await.ok();
+        }
     }
 
     #[tracing::instrument(skip(self), err)]
-    async fn on_apply_change(&mut self, change: Change) -> Result<()> {
-        self.repo
-            .publish_change_for_board(self.board_id, self.session_id, change)
+    async fn on_apply_change(
+        &mut self,
+        change: Change,
+        idempotency_key: Option<String>,
+    ) -> Result<()> {
+        if self.role == Role::Viewer {
+            self.socket_sender
+                .send(ServerMessage::ChangeRejected {
+                    change,
+                    reason: RejectionReason::ReadOnly,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        if self.repo.get_board_frozen(self.board_id).await? {
+            self.socket_sender
+                .send(ServerMessage::BoardReadOnly { change })
+                .await?;
+            return Ok(());
+        }
+
+        // Keys end up in JSONPaths when the change is checkpointed, so anything that could reach
+        // outside of the object being updated is refused
+        if let Some(key) = change.invalid_key() {
+            let key = key.to_string();
+            self.socket_sender
+                .send(ServerMessage::ChangeRejected {
+                    change,
+                    reason: RejectionReason::InvalidKey { key },
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // Changes past the session's limit are dropped before they get anywhere near Redis
+        if !self.change_bucket.try_take() {
+            self.socket_sender
+                .send(ServerMessage::RateLimited {
+                    change: Some(change),
+                    retry_after_ms: self.change_bucket.retry_after().as_millis() as u64,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // Edits are refused while another session holds a lease on any of the objects
+        let conflicting_lock = self
+            .repo
+            .get_conflicting_lock_for_change(self.board_id, self.session_id, &change)
             .await?;
+        if let Some(session_id) = conflicting_lock {
+            self.socket_sender
+                .send(ServerMessage::ChangeRejected {
+                    change,
+                    reason: RejectionReason::ObjectLocked { session_id },
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // Restored objects are filled in from the trash so that everyone else can show them, but
+        // the client hears about its change the way it sent it
+        let mut filled_change = change.clone();
+        if let Some(id) = self
+            .repo
+            .fill_restored_objects(self.board_id, &mut filled_change)
+            .await?
+        {
+            self.socket_sender
+                .send(ServerMessage::ChangeRejected {
+                    change,
+                    reason: RejectionReason::NotInTrash { id },
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // Changes that would grow the board past its limits are refused outright
+        if let Some(reason) = self
+            .repo
+            .check_quotas(self.board_id, &filled_change)
+            .await?
+        {
+            self.socket_sender
+                .send(ServerMessage::ChangeRejected { change, reason })
+                .await?;
+            return Ok(());
+        }
+
+        let outcome = self
+            .repo
+            .publish_change_for_board(
+                self.board_id,
+                self.session_id,
+                filled_change,
+                idempotency_key,
+            )
+            .await?;
+
+        match outcome {
+            PublishOutcome::RevisionMismatch {
+                id,
+                current_revision,
+            } => {
+                self.socket_sender
+                    .send(ServerMessage::ChangeRejected {
+                        change,
+                        reason: RejectionReason::RevisionMismatch {
+                            id,
+                            current_revision,
+                        },
+                    })
+                    .await?;
+            }
+            // Accepted changes and retries of them reach the client through the broadcaster
+            PublishOutcome::Accepted { .. } | PublishOutcome::Duplicate { .. } => {}
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [SessionState; 4] = [
+        SessionState::Connected,
+        SessionState::Waiting,
+        SessionState::Joined,
+        SessionState::Streaming,
+    ];
+
+    fn allowed_in(message: ClientMessage) -> Vec<SessionState> {
+        STATES
+            .into_iter()
+            .filter(|state| state.allows(&message))
+            .collect()
+    }
+
+    #[test]
+    fn joining_is_only_allowed_once() {
+        let expected = vec![SessionState::Connected];
+        assert_eq!(
+            allowed_in(ClientMessage::ClientReady {
+                username: None,
+                own_changes: Default::default(),
+            }),
+            expected
+        );
+        assert_eq!(
+            allowed_in(ClientMessage::ResumeSession {
+                username: "someone".to_string(),
+                from_version: "0".to_string(),
+                own_changes: Default::default(),
+            }),
+            expected
+        );
+    }
+
+    #[test]
+    fn pings_are_always_allowed() {
+        assert_eq!(
+            allowed_in(ClientMessage::Ping {
+                client_time: None,
+                rtt: None,
+            }),
+            STATES
+        );
+    }
+
+    #[test]
+    fn changes_need_a_snapshot_first() {
+        assert_eq!(
+            allowed_in(ClientMessage::ApplyChange {
+                change: Change::Delete { id: Uuid::new_v4() },
+                idempotency_key: None,
+            }),
+            vec![SessionState::Streaming]
+        );
+    }
+
+    #[test]
+    fn everything_else_needs_the_session_to_have_joined() {
+        let expected = vec![SessionState::Joined, SessionState::Streaming];
+        for message in [
+            ClientMessage::StartSnapshot,
+            ClientMessage::RequestPresence,
+            ClientMessage::CursorLeft,
+            ClientMessage::LockObject { id: Uuid::new_v4() },
+            ClientMessage::Resync {
+                from_version: "0".to_string(),
+            },
+        ] {
+            assert_eq!(allowed_in(message), expected);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Descriptive information about a board that isn't part of its objects
+#[derive(Serialize, Deserialize, SimpleObject, ToSchema, Debug, Clone)]
+pub struct BoardMeta {
+    pub name: String,
+    /// Whoever made the board, which never changes
+    pub creator: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // Boards created before these were added won't have them
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Whoever is responsible for the board now, which starts out as the creator
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Changes to a board's metadata. Fields that are left out are kept as they are, and the optional
+/// fields can be cleared by setting them to null.
+#[derive(Deserialize, ToSchema, Debug, Clone, Default)]
+pub struct BoardMetaPatch {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub owner: Option<Option<String>>,
+    pub tags: Option<Vec<String>>,
+}
+
+impl BoardMetaPatch {
+    pub fn apply(self, meta: &mut BoardMeta) {
+        if let Some(name) = self.name {
+            meta.name = name;
+        }
+        if let Some(description) = self.description {
+            meta.description = description;
+        }
+        if let Some(owner) = self.owner {
+            meta.owner = owner;
+        }
+        if let Some(tags) = self.tags {
+            meta.tags = tags;
+        }
+    }
+}
+
+/// Any value that's present, including null, is wrapped in `Some` so it can be told apart from a
+/// missing field
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// A board as it appears in the board listing
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct BoardSummary {
+    pub board_id: Uuid,
+    pub meta: Option<BoardMeta>,
+    pub last_activity_at: DateTime<Utc>,
+}
@@ -1,15 +1,25 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 
-use crate::message::ServerMessage;
+use crate::change::ChangeEntry;
+use crate::message::{AcceptedChange, ChangeAck, OwnChanges, ServerMessage};
 use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
 use crate::socket::SocketSender;
 
+/// How much older the oldest change a session hasn't been sent yet can be than the newest change
+/// on the board before the session is better off starting over from a snapshot
+const MAX_LAG: Duration = Duration::from_secs(5 * 60);
+
 pub struct Broadcaster {
     board_id: Uuid,
     repo: Repository,
     current_version: String,
     socket_sender: SocketSender,
+    /// The session being sent to, and what it wants to hear about its own changes
+    own_changes: Option<(Uuid, OwnChanges)>,
 }
 
 impl Broadcaster {
@@ -25,38 +35,163 @@ impl Broadcaster {
             current_version,
             repo,
             socket_sender,
+            own_changes: None,
+        }
+    }
+
+    /// Send the given session's own changes the way it asked for, rather than like everyone else's
+    pub fn with_own_changes(self, session_id: Uuid, own_changes: OwnChanges) -> Self {
+        Self {
+            own_changes: Some((session_id, own_changes)),
+            ..self
         }
     }
 
     #[tracing::instrument(skip_all)]
     pub async fn start(mut self) {
-        loop {
-            self.run().await.ok();
+        let mut restarts = Restarts::new("broadcaster", RestartPolicy::CONNECTION);
+        while let Err(error) = self.run().await {
+            if !restarts.failed(&error).await {
+                // Better for the client to reconnect than to quietly stop getting changes
+                self.socket_sender.disconnect().await.ok();
+                return;
+            }
         }
     }
 
+    /// Send changes until the session has fallen too far behind to catch up, which is the only
+    /// time this returns without an error
     #[tracing::instrument(skip_all, err)]
     async fn run(&mut self) -> Result<()> {
-        let repo = self.repo.clone();
+        let (mut receiver, reader_version) = self
+            .repo
+            .subscribe_to_changes_for_board(self.board_id, self.current_version.clone());
+        if !self.catch_up(&reader_version).await? {
+            return Ok(());
+        }
 
         loop {
-            let changes = repo
+            let batch = match receiver.recv().await {
+                Ok(batch) => batch,
+                // Whatever was missed is read from the store when the next batch comes in
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => bail!("The change reader for the board stopped"),
+            };
+            if !self.catch_up(&batch.after).await? {
+                return Ok(());
+            }
+
+            // The reader may have started out behind this session
+            let changes = batch
+                .changes
+                .iter()
+                .filter(|entry| is_after(&entry.version, &self.current_version))
+                .cloned()
+                .collect::<Vec<_>>();
+            self.send(changes).await?;
+        }
+    }
+
+    /// Read the changes up to `version` from the store, if this session hasn't seen them yet.
+    /// Returns false if some of them have been trimmed from the stream already, or there are too
+    /// many to be worth sending, in which case the client is told to start over.
+    #[tracing::instrument(skip(self), err)]
+    async fn catch_up(&mut self, version: &str) -> Result<bool> {
+        if !is_after(version, &self.current_version) {
+            return Ok(true);
+        }
+
+        if !self
+            .repo
+            .get_version_available_for_board(self.board_id, &self.current_version)
+            .await?
+        {
+            return self.require_resync().await;
+        }
+
+        while is_after(version, &self.current_version) {
+            let changes = self
+                .repo
                 .get_changes_for_board(self.board_id, 100, Some(self.current_version.clone()))
                 .await?;
+            let oldest_timestamp = match changes.first() {
+                Some(entry) => entry.timestamp(),
+                None => break,
+            };
 
-            if changes.is_empty() {
-                return Ok(());
+            let latest_version = self
+                .repo
+                .get_latest_version_for_board(self.board_id)
+                .await?;
+            let latest_timestamp = Repository::parse_stream_id(&latest_version)
+                .map(|(timestamp, _)| timestamp)
+                .unwrap_or_default();
+            let lag = Duration::from_millis(latest_timestamp.saturating_sub(oldest_timestamp));
+            if lag > MAX_LAG {
+                return self.require_resync().await;
             }
 
-            if let Some((current_version, _, _)) = changes.last() {
-                self.current_version = current_version.clone();
+            self.send(changes).await?;
+        }
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn require_resync(&mut self) -> Result<bool> {
+        tracing::info!(version = %self.current_version, "Session is too far behind to catch up");
+        self.socket_sender
+            .send(ServerMessage::ResyncRequired)
+            .await?;
+        Ok(false)
+    }
+
+    async fn send(&mut self, changes: Vec<ChangeEntry>) -> Result<()> {
+        self.current_version = match changes.last() {
+            Some(entry) => entry.version.clone(),
+            None => return Ok(()),
+        };
+
+        let (session_id, own_changes) = match self.own_changes {
+            Some((session_id, own_changes)) if own_changes != OwnChanges::Echo => {
+                (session_id, own_changes)
+            }
+            // Send the whole batch in one frame, which matters a lot when catching up on a backlog
+            _ => {
+                return self
+                    .socket_sender
+                    .send(ServerMessage::ChangesAccepted {
+                        changes: changes.into_iter().map(AcceptedChange::from).collect(),
+                    })
+                    .await
             }
+        };
 
-            for (_, session_id, change) in changes {
-                self.socket_sender
-                    .send(ServerMessage::ChangeAccepted { change, session_id })
-                    .await?;
+        // Runs of the session's own changes and everyone else's go in separate frames, in order
+        let mut changes = changes.into_iter().peekable();
+        while let Some(first) = changes.peek() {
+            let is_own = first.session_id == session_id;
+            let mut run = Vec::new();
+            while let Some(entry) =
+                changes.next_if(|entry| (entry.session_id == session_id) == is_own)
+            {
+                run.push(entry);
             }
+
+            let message = match (is_own, own_changes) {
+                (false, _) => ServerMessage::ChangesAccepted {
+                    changes: run.into_iter().map(AcceptedChange::from).collect(),
+                },
+                (true, OwnChanges::Acknowledge) => ServerMessage::ChangesAcknowledged {
+                    changes: run.into_iter().map(ChangeAck::from).collect(),
+                },
+                (true, _) => continue,
+            };
+            self.socket_sender.send(message).await?;
         }
+        Ok(())
     }
 }
+
+fn is_after(version: &str, other: &str) -> bool {
+    Repository::parse_stream_id(version) > Repository::parse_stream_id(other)
+}
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Map as JsonMap, Value as JsonValue};
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// The longest key that an update can set
+pub const MAX_KEY_LENGTH: usize = 128;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum Change {
@@ -17,4 +21,279 @@ pub enum Change {
     Delete {
         id: Uuid,
     },
+    /// Move an object to a new position in the board's stacking order. Indices are arbitrary
+    /// floats, higher is closer to the front, so that an object can be placed between any two
+    /// neighbors without renumbering the rest.
+    SetIndex {
+        id: Uuid,
+        index: f64,
+    },
+    /// Overwrite an entire object, but only if nobody else has changed it since the client last
+    /// saw it at `expected_revision`
+    Replace {
+        id: Uuid,
+        object: JsonMap<String, JsonValue>,
+        expected_revision: u64,
+    },
+    /// Apply several changes as one, so that nobody can ever see some of them without the rest
+    Transaction {
+        changes: Vec<Change>,
+    },
+    /// Bring a deleted object back from the board's trash, along with its place in the stacking
+    /// order. Clients only send the ID, and the server fills in the rest from the trash before the
+    /// change is published so that everyone else can show the object again.
+    RestoreObject {
+        id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        object: Option<JsonMap<String, JsonValue>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<f64>,
+    },
+}
+
+impl Change {
+    /// The `type` of every kind of change the server understands
+    pub const TYPES: &'static [&'static str] = &[
+        "Insert",
+        "Update",
+        "Delete",
+        "SetIndex",
+        "Replace",
+        "Transaction",
+        "RestoreObject",
+    ];
+
+    /// Determine whether a key is safe to set with an update. Keys end up in JSONPaths, so they're
+    /// kept to ASCII letters, digits, `_`, and `-` to make sure they can only ever name a
+    /// property of the object being updated.
+    pub fn is_valid_key(key: &str) -> bool {
+        !key.is_empty()
+            && key.len() <= MAX_KEY_LENGTH
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    /// The first key this change sets that isn't valid, if there is one
+    pub fn invalid_key(&self) -> Option<&str> {
+        match self {
+            Change::Update { key, .. } if !Change::is_valid_key(key) => Some(key),
+            Change::Transaction { changes } => changes.iter().find_map(Change::invalid_key),
+            _ => None,
+        }
+    }
+
+    /// Break a change down into the individual object changes that make it up, unpacking any
+    /// transactions
+    pub fn flatten(self) -> Vec<Change> {
+        match self {
+            Change::Transaction { changes } => {
+                changes.into_iter().flat_map(Change::flatten).collect()
+            }
+            change => vec![change],
+        }
+    }
+
+    /// Merge a batch of flattened changes into fewer changes that have the same effect when
+    /// they're applied in order, so that checkpointing a board where somebody dragged an object
+    /// around for minutes takes one write instead of thousands. An update is dropped when a later
+    /// update sets the same key, or folded into the insert, replace, or restore that wrote the
+    /// object earlier in the batch. An update, insert, or replace is dropped when a later insert or
+    /// replace overwrites the whole object, and an index change is dropped when a later one moves
+    /// the same object. A delete has to put the object in the trash as it was, so nothing before
+    /// one is merged with anything after it, and an insert that's deleted in the same batch is
+    /// still written, with every update in between folded into it.
+    pub fn coalesce(changes: Vec<Change>) -> Vec<Change> {
+        let mut coalesced = Vec::<Option<Change>>::with_capacity(changes.len());
+        // Where the latest change that writes each object whole, each update to each key of an
+        // object, and each index change are in `coalesced`, since the object was last deleted
+        let mut whole_writes = HashMap::<Uuid, usize>::new();
+        let mut updates = HashMap::<Uuid, HashMap<String, usize>>::new();
+        let mut index_changes = HashMap::<Uuid, usize>::new();
+
+        for change in changes {
+            match change {
+                Change::Update { id, key, value } if Change::is_valid_key(&key) => {
+                    if let Some(&at) = whole_writes.get(&id) {
+                        if let Some(
+                            Change::Insert { object, .. }
+                            | Change::Replace { object, .. }
+                            | Change::RestoreObject {
+                                object: Some(object),
+                                ..
+                            },
+                        ) = coalesced[at].as_mut()
+                        {
+                            object.insert(key, value);
+                            continue;
+                        }
+                    }
+                    let object_updates = updates.entry(id).or_default();
+                    if let Some(at) = object_updates.insert(key.clone(), coalesced.len()) {
+                        coalesced[at] = None;
+                    }
+                    coalesced.push(Some(Change::Update { id, key, value }));
+                }
+                Change::Insert { id, .. }
+                | Change::Replace { id, .. }
+                | Change::RestoreObject {
+                    id,
+                    object: Some(_),
+                    ..
+                } => {
+                    for (_, at) in updates.remove(&id).unwrap_or_default() {
+                        coalesced[at] = None;
+                    }
+                    // A restore also takes the object out of the trash, so it's always kept
+                    if let Some(at) = whole_writes.insert(id, coalesced.len()) {
+                        if !matches!(coalesced[at], Some(Change::RestoreObject { .. })) {
+                            coalesced[at] = None;
+                        }
+                    }
+                    coalesced.push(Some(change));
+                }
+                Change::SetIndex { id, index } => {
+                    if let Some(at) = index_changes.insert(id, coalesced.len()) {
+                        coalesced[at] = None;
+                    }
+                    coalesced.push(Some(Change::SetIndex { id, index }));
+                }
+                Change::Delete { id } => {
+                    whole_writes.remove(&id);
+                    updates.remove(&id);
+                    index_changes.remove(&id);
+                    coalesced.push(Some(Change::Delete { id }));
+                }
+                change => coalesced.push(Some(change)),
+            }
+        }
+
+        coalesced.into_iter().flatten().collect()
+    }
+
+    /// The ID of every object this change applies to, paired with the revision the object must be
+    /// at for the change to be accepted if the change cares about that
+    pub fn expected_revisions(&self) -> Vec<(Uuid, Option<u64>)> {
+        match self {
+            Change::Insert { id, .. }
+            | Change::Update { id, .. }
+            | Change::Delete { id }
+            | Change::SetIndex { id, .. }
+            | Change::RestoreObject { id, .. } => vec![(*id, None)],
+            Change::Replace {
+                id,
+                expected_revision,
+                ..
+            } => vec![(*id, Some(*expected_revision))],
+            Change::Transaction { changes } => changes
+                .iter()
+                .flat_map(Change::expected_revisions)
+                .collect(),
+        }
+    }
+
+    /// The ID of every object this change brings back from the trash
+    pub fn restored_object_ids(&self) -> Vec<Uuid> {
+        match self {
+            Change::RestoreObject { id, .. } => vec![*id],
+            Change::Transaction { changes } => changes
+                .iter()
+                .flat_map(Change::restored_object_ids)
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Fill in the object and index of every restored object from what was found in the trash.
+    /// Returns the ID of the first restored object that wasn't found, if any.
+    pub fn fill_restored_objects(&mut self, trash: &HashMap<Uuid, TrashedObject>) -> Option<Uuid> {
+        match self {
+            Change::RestoreObject { id, object, index } => match trash.get(id) {
+                Some(trashed) => {
+                    *object = Some(trashed.object.clone());
+                    *index = trashed.index;
+                    None
+                }
+                None => Some(*id),
+            },
+            Change::Transaction { changes } => changes
+                .iter_mut()
+                .find_map(|change| change.fill_restored_objects(trash)),
+            _ => None,
+        }
+    }
+
+    /// How many objects this change adds to a board, or removes if it's negative, counting every
+    /// insert as a new object
+    pub fn object_count_delta(&self) -> i64 {
+        match self {
+            Change::Insert { .. } | Change::RestoreObject { .. } => 1,
+            Change::Delete { .. } => -1,
+            Change::Update { .. } | Change::SetIndex { .. } | Change::Replace { .. } => 0,
+            Change::Transaction { changes } => changes.iter().map(Change::object_count_delta).sum(),
+        }
+    }
+
+    /// The size in bytes of the JSON this change writes into each object it touches, for checking
+    /// against the per-object size limit. Updates only count the key and value they set.
+    pub fn payload_sizes(&self) -> Vec<(Uuid, usize)> {
+        let size = |value: &JsonMap<String, JsonValue>| {
+            serde_json::to_vec(value).map_or(0, |json| json.len())
+        };
+        match self {
+            Change::Insert { id, object } | Change::Replace { id, object, .. } => {
+                vec![(*id, size(object))]
+            }
+            Change::RestoreObject {
+                id,
+                object: Some(object),
+                ..
+            } => vec![(*id, size(object))],
+            Change::Update { id, key, value } => {
+                let value_size = serde_json::to_vec(value).map_or(0, |json| json.len());
+                vec![(*id, key.len() + value_size)]
+            }
+            Change::Transaction { changes } => {
+                changes.iter().flat_map(Change::payload_sizes).collect()
+            }
+            Change::Delete { .. } | Change::SetIndex { .. } | Change::RestoreObject { .. } => {
+                vec![]
+            }
+        }
+    }
+}
+
+/// An object that was deleted, as it was kept in its board's trash
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashedObject {
+    pub object: JsonMap<String, JsonValue>,
+    /// Where the object was in the stacking order, if it had a place
+    pub index: Option<f64>,
+}
+
+/// A change as it was recorded in a board's change stream
+#[derive(Debug, Clone)]
+pub struct ChangeEntry {
+    /// The stream entry ID, which doubles as the board version after this change
+    pub version: String,
+    pub session_id: Uuid,
+    /// The username of the session at the time it made this change, if it had one
+    pub username: Option<String>,
+    /// The authenticated user behind the session, if authentication was turned on
+    pub user_id: Option<String>,
+    /// The revision of each changed object after this change
+    pub revisions: Vec<(Uuid, u64)>,
+    pub change: Change,
+}
+
+impl ChangeEntry {
+    /// The server time when this change was accepted, in milliseconds since the Unix epoch. Redis
+    /// generates stream entry IDs from its own clock, so this is just the first half of the ID.
+    pub fn timestamp(&self) -> u64 {
+        self.version
+            .split('-')
+            .next()
+            .and_then(|timestamp| timestamp.parse().ok())
+            .unwrap_or_default()
+    }
 }
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast::{self, Receiver as BroadcastReceiver, Sender as BroadcastSender};
+use uuid::Uuid;
+
+use crate::change::ChangeEntry;
+use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
+
+/// How many batches a session can fall behind before it misses some, after which it reads what it
+/// missed from the store itself
+const CHANNEL_CAPACITY: usize = 100;
+
+/// Changes read from a board's change stream in one go, and the version they were read after
+#[derive(Clone, Debug)]
+pub struct ChangeBatch {
+    pub after: String,
+    pub changes: Arc<Vec<ChangeEntry>>,
+}
+
+struct ReaderEntry {
+    /// Tells apart a reader that stopped from the one started in its place
+    id: Uuid,
+    sender: BroadcastSender<ChangeBatch>,
+    /// The last version the reader sent out
+    version: String,
+}
+
+/// The change readers running on this server, one for each board anyone here is watching. Every
+/// session on a board gets its changes from the same reader, so the store is read once per board
+/// rather than once per session, the same way presence is.
+#[derive(Clone, Default)]
+pub struct ChangeReaders {
+    entries: Arc<Mutex<HashMap<Uuid, ReaderEntry>>>,
+}
+
+impl ChangeReaders {
+    /// Start getting a board's changes, starting a reader for it at `version` if there isn't one
+    /// yet. Also returns the last version the reader sent out, which the caller has to catch up to
+    /// on its own if it's behind.
+    pub fn subscribe(
+        &self,
+        repo: &Repository,
+        board_id: Uuid,
+        version: String,
+    ) -> (BroadcastReceiver<ChangeBatch>, String) {
+        let mut entries = self.lock();
+        if let Some(entry) = entries.get(&board_id) {
+            return (entry.sender.subscribe(), entry.version.clone());
+        }
+
+        let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+        let id = Uuid::new_v4();
+        entries.insert(
+            board_id,
+            ReaderEntry {
+                id,
+                sender: sender.clone(),
+                version: version.clone(),
+            },
+        );
+        tokio::task::spawn(
+            ChangeReader {
+                id,
+                board_id,
+                version: version.clone(),
+                repo: repo.clone(),
+                readers: self.clone(),
+                sender,
+            }
+            .start(),
+        );
+        (receiver, version)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, ReaderEntry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Reads one board's change stream and sends every batch to the sessions watching it, until there
+/// aren't any left
+struct ChangeReader {
+    id: Uuid,
+    board_id: Uuid,
+    version: String,
+    repo: Repository,
+    readers: ChangeReaders,
+    sender: BroadcastSender<ChangeBatch>,
+}
+
+impl ChangeReader {
+    #[tracing::instrument(skip_all)]
+    async fn start(mut self) {
+        let mut restarts = Restarts::new("change_reader", RestartPolicy::CONNECTION);
+        while let Err(error) = self.run().await {
+            if !restarts.failed(&error).await {
+                break;
+            }
+        }
+
+        // Dropping the sender lets every session know, and the next one to subscribe starts over
+        self.remove(&mut self.readers.lock());
+    }
+
+    fn remove(&self, entries: &mut HashMap<Uuid, ReaderEntry>) {
+        if entries.get(&self.board_id).map(|entry| entry.id) == Some(self.id) {
+            entries.remove(&self.board_id);
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn run(&mut self) -> Result<()> {
+        loop {
+            // Checked with the lock held so that nobody can subscribe to a reader that's stopping
+            {
+                let mut entries = self.readers.lock();
+                if self.sender.receiver_count() == 0 {
+                    self.remove(&mut entries);
+                    return Ok(());
+                }
+            }
+
+            let changes = self
+                .repo
+                .get_changes_for_board(self.board_id, 100, Some(self.version.clone()))
+                .await?;
+            let last_version = match changes.last() {
+                Some(entry) => entry.version.clone(),
+                None => continue,
+            };
+
+            // Sent with the lock held so that a session subscribing at the same time either gets
+            // this batch or is told to catch up past it, and never neither
+            let mut entries = self.readers.lock();
+            let after = std::mem::replace(&mut self.version, last_version.clone());
+            if let Some(entry) = entries.get_mut(&self.board_id) {
+                entry.version = last_version;
+            }
+            self.sender
+                .send(ChangeBatch {
+                    after,
+                    changes: Arc::new(changes),
+                })
+                .ok();
+        }
+    }
+}
@@ -1,56 +1,308 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use futures::TryStreamExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
 
-use crate::repository::Repository;
+use crate::repository::{Repository, RepositoryResult};
+use crate::restart::{RestartPolicy, Restarts};
 
+/// How long a checkpointer holds a board's lock without renewing it before another server can
+/// take over
+const LOCK_TTL: Duration = Duration::from_secs(30);
+
+/// How long the checkpointer waits between passes over the boards with pending changes unless
+/// told otherwise
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How many pending changes a board can have before it's checkpointed without waiting for the
+/// next pass, unless told otherwise
+const DEFAULT_HOT_BOARD_CHANGES: usize = 500;
+
+/// How often the checkpointer looks for boards that are past the hot board threshold
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the checkpointer goes over every board, in case any has changes that were never
+/// counted as pending, like ones published before the count was kept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// What every checkpointer in this process has done since the server started
+static METRICS: CheckpointMetrics = CheckpointMetrics {
+    checkpointed_boards: AtomicU64::new(0),
+    applied_changes: AtomicU64::new(0),
+    failed_boards: AtomicU64::new(0),
+};
+
+struct CheckpointMetrics {
+    checkpointed_boards: AtomicU64,
+    applied_changes: AtomicU64,
+    failed_boards: AtomicU64,
+}
+
+/// How much checkpointing this server has done since it started
+#[derive(Clone, Copy, Debug)]
+pub struct CheckpointStats {
+    /// Times a board was checkpointed until it was caught up
+    pub checkpointed_boards: u64,
+    pub applied_changes: u64,
+    /// Times a board couldn't be checkpointed, which the checkpointer moved on from
+    pub failed_boards: u64,
+}
+
+/// Applies the changes published to boards to their materialized snapshots. Only boards that the
+/// store says have pending changes are visited, once per interval, or as soon as they pass the hot
+/// board threshold so that busy boards don't build up long streams in between.
 pub struct Checkpointer {
     repo: Repository,
+    /// How long to wait between passes over the boards with pending changes
+    interval: Duration,
+    /// How many pending changes make a board worth checkpointing before the next pass
+    hot_board_changes: usize,
+    /// Which checkpointer holds a board's lock, so that servers don't checkpoint the same board at
+    /// the same time
+    id: Uuid,
+    /// The last board that the pass over every board got through, so that a pass cut short by an
+    /// error picks up after it rather than starting over
+    sweep_position: Mutex<Option<Uuid>>,
 }
 
 impl Checkpointer {
     #[tracing::instrument(skip_all)]
     pub fn new(repo: Repository) -> Self {
-        Self { repo }
+        Self {
+            repo,
+            interval: DEFAULT_INTERVAL,
+            hot_board_changes: DEFAULT_HOT_BOARD_CHANGES,
+            id: Uuid::new_v4(),
+            sweep_position: Mutex::new(None),
+        }
+    }
+
+    pub fn stats() -> CheckpointStats {
+        CheckpointStats {
+            checkpointed_boards: METRICS.checkpointed_boards.load(Ordering::Relaxed),
+            applied_changes: METRICS.applied_changes.load(Ordering::Relaxed),
+            failed_boards: METRICS.failed_boards.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wait the given amount of time between passes over the boards with pending changes
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Checkpoint a board as soon as it has this many pending changes
+    pub fn with_hot_board_changes(self, hot_board_changes: usize) -> Self {
+        Self {
+            hot_board_changes,
+            ..self
+        }
     }
 
     #[tracing::instrument(skip_all)]
     pub async fn start(self) {
+        let mut restarts = Restarts::new("checkpointer", RestartPolicy::BACKGROUND);
         loop {
-            self.run().await.ok();
+            if let Err(error) = self.run().await {
+                restarts.failed(&error).await;
+            }
         }
     }
 
     #[tracing::instrument(skip_all, err)]
     async fn run(&self) -> Result<()> {
-        let repo = self.repo.clone();
+        self.checkpoint_all_boards().await?;
+        let mut last_sweep = Instant::now();
+        let mut last_pass = Instant::now();
         loop {
-            let mut board_ids_stream = repo.stream_all_board_ids().await;
-            while let Some(board_id) = board_ids_stream.try_next().await? {
-                let current_version = repo.get_version_for_board(board_id).await?;
-                let changes = repo
-                    .get_changes_for_board(board_id, 1000, Some(current_version))
+            tokio::time::sleep(POLL_INTERVAL.min(self.interval)).await;
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.checkpoint_all_boards().await?;
+                last_sweep = Instant::now();
+                last_pass = Instant::now();
+            } else if last_pass.elapsed() >= self.interval {
+                self.checkpoint_pending_boards(1).await?;
+                last_pass = Instant::now();
+            } else {
+                self.checkpoint_pending_boards(self.hot_board_changes)
                     .await?;
+            }
+        }
+    }
+
+    /// Apply every pending change to every board, which is also done once more on the way out
+    /// when the server shuts down. Only trouble listing the boards stops the pass, and the next
+    /// one picks up after the last board this one got through.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn checkpoint_all_boards(&self) -> Result<()> {
+        let resume_after = *self.sweep_position();
+        if !self.sweep(resume_after).await? {
+            // The board the last pass stopped at is gone, so there was nowhere to pick up from
+            self.sweep(None).await?;
+        }
+        Ok(())
+    }
+
+    /// Go over every board after `resume_after`, or all of them, returning false if that board
+    /// never came up
+    #[tracing::instrument(skip(self), err)]
+    async fn sweep(&self, resume_after: Option<Uuid>) -> Result<bool> {
+        let pending = self
+            .repo
+            .get_pending_checkpoints()
+            .await?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let mut is_resuming = resume_after.is_some();
+        let mut board_ids_stream = self.repo.stream_all_board_ids().await;
+        while let Some(board_id) = board_ids_stream.try_next().await? {
+            if is_resuming {
+                is_resuming = Some(board_id) != resume_after;
+                continue;
+            }
+
+            let pending_count = pending.get(&board_id).copied().unwrap_or_default();
+            self.checkpoint_or_move_on(board_id, pending_count).await;
+            *self.sweep_position() = Some(board_id);
+        }
+        *self.sweep_position() = None;
+        Ok(!is_resuming)
+    }
+
+    /// Apply every pending change to the boards with at least `min_pending` of them
+    #[tracing::instrument(skip(self), err)]
+    async fn checkpoint_pending_boards(&self, min_pending: usize) -> Result<()> {
+        for (board_id, pending_count) in self.repo.get_pending_checkpoints().await? {
+            if pending_count >= min_pending {
+                self.checkpoint_or_move_on(board_id, pending_count).await;
+            }
+        }
+        Ok(())
+    }
 
-                if changes.is_empty() {
-                    continue;
+    /// One board that can't be checkpointed shouldn't hold up the rest, so its error is logged
+    /// and counted rather than passed on
+    async fn checkpoint_or_move_on(&self, board_id: Uuid, pending_count: usize) {
+        if let Err(error) = self.checkpoint_pending_board(board_id, pending_count).await {
+            METRICS.failed_boards.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(%board_id, %error, "Could not checkpoint board");
+        }
+    }
+
+    fn sweep_position(&self) -> MutexGuard<'_, Option<Uuid>> {
+        self.sweep_position
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Apply batches of changes to a board until it's caught up, then take what was applied off of
+    /// its pending count. Anything counted that wasn't there to apply is taken off too, so that a
+    /// count that's drifted doesn't keep the board pending forever.
+    #[tracing::instrument(skip(self), err)]
+    async fn checkpoint_pending_board(&self, board_id: Uuid, pending_count: usize) -> Result<()> {
+        // Leave boards that another server is already checkpointing to that server
+        let applied_count = self
+            .repo
+            .with_lock(&Self::lock_name(board_id), self.id, LOCK_TTL, async {
+                let mut applied_count = 0;
+                loop {
+                    match self.apply_next_batch(board_id).await? {
+                        0 => return Ok(applied_count),
+                        batch_count => applied_count += batch_count,
+                    }
                 }
+            })
+            .await?;
+
+        if let Some(applied_count) = applied_count {
+            self.repo
+                .clear_pending_checkpoint(board_id, applied_count.max(pending_count))
+                .await?;
+            METRICS.checkpointed_boards.fetch_add(1, Ordering::Relaxed);
+            METRICS
+                .applied_changes
+                .fetch_add(applied_count as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Apply the next batch of pending changes in a board's change stream to its materialized
+    /// snapshot. Returns how many changes were applied, which is zero once the board is caught up.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn checkpoint_board(&self, board_id: Uuid) -> Result<usize> {
+        // Two checkpoints of the same board interleaving can both read the same version and apply
+        // the same changes on top of each other, so wait for any other checkpoint to finish first
+        loop {
+            let applied_count = self
+                .repo
+                .with_lock(
+                    &Self::lock_name(board_id),
+                    self.id,
+                    LOCK_TTL,
+                    self.apply_next_batch(board_id),
+                )
+                .await?;
+            if let Some(applied_count) = applied_count {
+                return Ok(applied_count);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
 
-                let next_version = changes
-                    .last()
-                    .map(|(version, _, _)| version.clone())
-                    .expect("Already checked that changes is not empty");
+    /// Apply batches of changes to a board until it's caught up to the change that was the latest
+    /// one when this was called. Changes published after that are left to the next pass, so that a
+    /// board that's being edited the whole time can't keep this going forever. Returns how many
+    /// changes were applied.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn checkpoint_board_to_latest(&self, board_id: Uuid) -> Result<usize> {
+        let latest_version = self.repo.get_latest_version_for_board(board_id).await?;
+        let latest_version = Repository::parse_stream_id(&latest_version);
+        let mut applied_count = 0;
+        loop {
+            let version = self.repo.get_version_for_board(board_id).await?;
+            if Repository::parse_stream_id(&version) >= latest_version {
+                return Ok(applied_count);
+            }
+            match self.checkpoint_board(board_id).await? {
+                0 => return Ok(applied_count),
+                batch_count => applied_count += batch_count,
+            }
+        }
+    }
 
-                let changes_to_apply = changes
-                    .into_iter()
-                    .map(|(_, _, change)| change)
-                    .collect::<Vec<_>>();
+    fn lock_name(board_id: Uuid) -> String {
+        format!("checkpoint/{board_id}")
+    }
 
-                repo.apply_changes_to_board(board_id, next_version, changes_to_apply)
-                    .await?;
+    #[tracing::instrument(skip(self), err)]
+    async fn apply_next_batch(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        loop {
+            let current_version = self.repo.get_version_for_board(board_id).await?;
+            let changes = self
+                .repo
+                .get_changes_for_board(board_id, 1000, Some(current_version.clone()))
+                .await?;
+
+            if changes.is_empty() {
+                return Ok(0);
+            }
+
+            // If something else checkpointed the board since its version was read, start over
+            // from the new version rather than applying changes that are already applied
+            let applied_count = changes.len();
+            if self
+                .repo
+                .apply_changes_to_board(board_id, &current_version, changes)
+                .await?
+            {
+                // The snapshot kept for the old version would never be used again
+                self.repo.invalidate_snapshot(board_id);
+                return Ok(applied_count);
             }
-            tokio::time::sleep(Duration::from_secs(15)).await;
+            tracing::debug!(%board_id, "Version moved during checkpoint, retrying");
         }
     }
 }
@@ -0,0 +1,99 @@
+use bb8::ErrorSink;
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch::{self, Receiver as WatchReceiver, Sender as WatchSender};
+
+/// Keeps track of whether the store can be reached. After enough failures in a row the breaker
+/// trips and the store is considered degraded, so calls to it fail right away instead of each
+/// waiting out their own timeout. The pool probes the store in the background and closes the
+/// breaker once it answers again.
+pub struct CircuitBreaker {
+    /// How many failures in a row trip the breaker, or zero to never trip it
+    failure_threshold: u32,
+    consecutive_failures: AtomicU32,
+    trips: AtomicU64,
+    degraded_sender: WatchSender<bool>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32) -> Self {
+        Self {
+            failure_threshold,
+            consecutive_failures: AtomicU32::new(0),
+            trips: AtomicU64::new(0),
+            degraded_sender: watch::channel(false).0,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        *self.degraded_sender.borrow()
+    }
+
+    /// Get told whenever the breaker trips or closes, with `true` while the store is degraded
+    pub fn watch(&self) -> WatchReceiver<bool> {
+        self.degraded_sender.subscribe()
+    }
+
+    /// How many times the breaker has tripped since the server started
+    pub fn trips(&self) -> u64 {
+        self.trips.load(Ordering::Relaxed)
+    }
+
+    pub fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.failure_threshold == 0 || failures < self.failure_threshold {
+            return;
+        }
+        let tripped = self
+            .degraded_sender
+            .send_if_modified(|degraded| !std::mem::replace(degraded, true));
+        if tripped {
+            self.trips.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(
+                failures,
+                "The store is unreachable, failing calls to it fast"
+            );
+        }
+    }
+
+    /// Let calls through again after a probe reached the store
+    pub fn close(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        let closed = self
+            .degraded_sender
+            .send_if_modified(|degraded| std::mem::replace(degraded, false));
+        if closed {
+            tracing::info!("The store is reachable again");
+        }
+    }
+}
+
+impl Debug for CircuitBreaker {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("CircuitBreaker")
+            .field("failure_threshold", &self.failure_threshold)
+            .field("is_open", &self.is_open())
+            .finish()
+    }
+}
+
+/// Counts every connection the pool fails to open as a failure, which notices an outage well
+/// before callers waiting for a connection give up
+#[derive(Debug, Clone)]
+pub struct BreakerErrorSink(pub Arc<CircuitBreaker>);
+
+impl<E> ErrorSink<E> for BreakerErrorSink {
+    fn sink(&self, _error: E) {
+        self.0.record_failure();
+    }
+
+    fn boxed_clone(&self) -> Box<dyn ErrorSink<E>> {
+        Box::new(self.clone())
+    }
+}
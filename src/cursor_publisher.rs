@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::watch::Receiver as WatchReceiver;
+use uuid::Uuid;
+
+use crate::message::Cursor;
+use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
+
+/// The most cursor updates a single session will publish per second
+pub const CURSOR_PUBLISHES_PER_SECOND: u64 = 20;
+
+/// Publishes a session's cursor position on its behalf, at most a fixed number of times per
+/// second. Mousemove events arrive far more often than anyone can perceive, so only the latest
+/// position at the time of each publish matters and everything in between is dropped.
+pub struct CursorPublisher {
+    board_id: Uuid,
+    session_id: Uuid,
+    repo: Repository,
+    /// `None` means the cursor has left the board
+    cursor_receiver: WatchReceiver<Option<Cursor>>,
+    /// Whether the store is unreachable, for stores that can tell
+    degraded_receiver: Option<WatchReceiver<bool>>,
+}
+
+impl CursorPublisher {
+    #[tracing::instrument(skip(repo, cursor_receiver))]
+    pub fn new(
+        board_id: Uuid,
+        session_id: Uuid,
+        repo: Repository,
+        cursor_receiver: WatchReceiver<Option<Cursor>>,
+    ) -> Self {
+        Self {
+            board_id,
+            session_id,
+            degraded_receiver: repo.watch_degraded(),
+            repo,
+            cursor_receiver,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(mut self) {
+        // Only errors from Redis are worth retrying, the loop finishes normally once the handler
+        // drops its end of the channel
+        let mut restarts = Restarts::new("cursor_publisher", RestartPolicy::CONNECTION);
+        while let Err(error) = self.run().await {
+            if !restarts.failed(&error).await {
+                return;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn run(&mut self) -> Result<()> {
+        let interval = Duration::from_millis(1000 / CURSOR_PUBLISHES_PER_SECOND);
+
+        while self.cursor_receiver.changed().await.is_ok() {
+            // Nothing is published while the store is unreachable, and the channel only holds on
+            // to the latest position, so that's the one published once it's back
+            if let Some(degraded_receiver) = self.degraded_receiver.as_mut() {
+                degraded_receiver.wait_for(|degraded| !degraded).await?;
+            }
+            let cursor = *self.cursor_receiver.borrow_and_update();
+            match cursor {
+                Some(Cursor { x, y }) => {
+                    self.repo
+                        .update_session_cursor_for_board(self.board_id, self.session_id, x, y)
+                        .await?;
+                }
+                None => {
+                    self.repo
+                        .delete_session_cursor_for_board(self.board_id, self.session_id)
+                        .await?;
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+use anyhow::Result;
+use tokio::sync::watch::Receiver as WatchReceiver;
+
+use crate::message::ServerMessage;
+use crate::socket::SocketSender;
+
+/// Lets a client know whenever the store stops being reachable and when it comes back
+pub struct DegradedNotifier {
+    degraded_receiver: WatchReceiver<bool>,
+    socket_sender: SocketSender,
+}
+
+impl DegradedNotifier {
+    #[tracing::instrument(skip_all)]
+    pub fn new(degraded_receiver: WatchReceiver<bool>, socket_sender: SocketSender) -> Self {
+        Self {
+            degraded_receiver,
+            socket_sender,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(mut self) {
+        self.run().await.ok();
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn run(&mut self) -> Result<()> {
+        // A client that connects during an outage needs to hear about it too
+        let mut was_degraded = false;
+        loop {
+            let degraded = *self.degraded_receiver.borrow_and_update();
+            if degraded != was_degraded {
+                was_degraded = degraded;
+                self.socket_sender
+                    .send(match degraded {
+                        true => ServerMessage::Degraded,
+                        false => ServerMessage::Recovered,
+                    })
+                    .await?;
+            }
+            self.degraded_receiver.changed().await?;
+        }
+    }
+}
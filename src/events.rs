@@ -0,0 +1,62 @@
+use async_stream::try_stream;
+use axum::response::sse::Event;
+use futures::stream::{self, Stream, StreamExt};
+use tokio::sync::oneshot::{self, Sender as OneshotSender};
+use uuid::Uuid;
+
+use crate::message::{AcceptedChange, ServerMessage};
+use crate::repository::Repository;
+
+/// Follow a board's changes and presence as Server-Sent Events, for consumers that can't hold a
+/// websocket. Every accepted change is its own `ChangeAccepted` event with the version as its ID,
+/// so a client that reconnects with `Last-Event-ID` picks up right where it left off. Presence
+/// events are named after the type of the message.
+pub fn board_events(
+    board_id: Uuid,
+    version: String,
+    repo: Repository,
+) -> impl Stream<Item = serde_json::Result<Event>> + Send {
+    // The presence events end once the board is deleted, and the changes end along with them
+    let (deleted_sender, deleted_receiver) = oneshot::channel();
+    stream::select(
+        change_events(board_id, version, repo.clone()).take_until(deleted_receiver),
+        presence_events(board_id, repo, deleted_sender),
+    )
+}
+
+fn change_events(
+    board_id: Uuid,
+    version: String,
+    repo: Repository,
+) -> impl Stream<Item = serde_json::Result<Event>> + Send {
+    repo.stream_changes_for_board(board_id, version)
+        .map(|entry| {
+            Event::default()
+                .event("ChangeAccepted")
+                .id(entry.version.as_str())
+                .json_data(AcceptedChange::from(entry))
+        })
+}
+
+fn presence_events(
+    board_id: Uuid,
+    repo: Repository,
+    deleted_sender: OneshotSender<()>,
+) -> impl Stream<Item = serde_json::Result<Event>> + Send {
+    Box::pin(try_stream! {
+        let mut message_stream = repo.stream_presence_messages_for_board(board_id).await;
+        while let Some(presence_message) = message_stream.next().await {
+            let is_board_deleted =
+                matches!(presence_message.message, ServerMessage::BoardDeleted);
+            let data = serde_json::to_value(presence_message.message)?;
+            let event_name = data["type"].as_str().unwrap_or_default().to_string();
+            yield Event::default().event(event_name).json_data(data)?;
+
+            // There will never be anything else to send
+            if is_board_deleted {
+                deleted_sender.send(()).ok();
+                break;
+            }
+        }
+    })
+}
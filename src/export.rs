@@ -0,0 +1,68 @@
+use anyhow::Result;
+use async_stream::try_stream;
+use futures::stream::{Stream, TryStreamExt};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::board_meta::BoardMeta;
+use crate::message::{AcceptedChange, JsonObject};
+use crate::repository::Repository;
+
+/// One line of a bulk export. Each board starts with a `board` record, followed by its objects a
+/// chunk at a time. The objects can be newer than the version, but replaying the pending changes
+/// on top of them always ends up at the board's state as of the last pending change.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Board {
+        board_id: Uuid,
+        meta: Option<Box<BoardMeta>>,
+        version: String,
+        /// Object ID - index pairs, back to front
+        order: Vec<(Uuid, f64)>,
+        revisions: Vec<(Uuid, u64)>,
+        /// Changes in the change stream that hadn't been checkpointed yet, oldest first
+        pending_changes: Vec<AcceptedChange>,
+    },
+    Objects {
+        board_id: Uuid,
+        entries: Vec<(Uuid, JsonObject)>,
+    },
+}
+
+/// Export every board that isn't archived as newline delimited JSON, for backups and moving
+/// boards between deployments. Boards are read one at a time so that the whole export never has to
+/// fit in memory.
+pub fn export_boards(repo: Repository) -> impl Stream<Item = Result<String>> + Send {
+    Box::pin(try_stream! {
+        let mut board_ids_stream = repo.stream_all_board_ids().await;
+        while let Some(board_id) = board_ids_stream.try_next().await? {
+            let version = repo.get_version_for_board(board_id).await?;
+            let pending_changes = repo
+                .get_pending_changes_for_board(board_id, version.clone())
+                .await?
+                .into_iter()
+                .map(AcceptedChange::from)
+                .collect();
+            yield line(&ExportRecord::Board {
+                board_id,
+                meta: repo.get_meta_for_board(board_id).await?.map(Box::new),
+                version,
+                order: repo.get_order_for_board(board_id).await?,
+                revisions: repo.get_revisions_for_board(board_id).await?,
+                pending_changes,
+            })?;
+
+            let mut chunks_stream = repo.stream_object_chunks_for_board(board_id).await;
+            while let Some(entries) = chunks_stream.try_next().await? {
+                yield line(&ExportRecord::Objects { board_id, entries })?;
+            }
+        }
+    })
+}
+
+fn line(record: &ExportRecord) -> Result<String> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    Ok(line)
+}
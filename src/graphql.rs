@@ -0,0 +1,251 @@
+use async_graphql::{
+    http::{playground_source, GraphQLPlaygroundConfig, ALL_WEBSOCKET_PROTOCOLS},
+    Context, EmptyMutation, Json, Object, Result as GraphQLResult, Schema, SimpleObject,
+    Subscription,
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::{
+    extract::{ws::WebSocketUpgrade, Extension},
+    response::{Html, IntoResponse},
+};
+use chrono::{DateTime, Utc};
+use futures::stream::{Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::auth::CanRead;
+use crate::board_meta::BoardMeta;
+use crate::change::{Change, ChangeEntry};
+use crate::message::JsonObject;
+use crate::repository::Repository;
+use crate::snapshot;
+
+pub type BoardSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// Build the GraphQL schema, which reads everything through the given repo
+pub fn build_schema(repo: Repository) -> BoardSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(repo)
+        .finish()
+}
+
+/// Run a GraphQL query
+#[tracing::instrument(skip_all)]
+pub async fn graphql_handler(
+    _: CanRead,
+    Extension(schema): Extension<BoardSchema>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema.execute(request.into_inner()).await.into()
+}
+
+/// Run GraphQL subscriptions over a websocket
+#[tracing::instrument(skip_all)]
+pub async fn graphql_subscription(
+    _: CanRead,
+    Extension(schema): Extension<BoardSchema>,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> impl IntoResponse {
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| GraphQLWebSocket::new(stream, schema, protocol).serve())
+}
+
+/// Serve GraphQL Playground for exploring the schema
+pub async fn graphql_playground() -> Html<String> {
+    Html(playground_source(
+        GraphQLPlaygroundConfig::new("/api/graphql").subscription_endpoint("/api/graphql/ws"),
+    ))
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A page of boards, most recently active first
+    async fn boards(
+        &self,
+        ctx: &Context<'_>,
+        cursor: Option<usize>,
+        #[graphql(default = 50)] limit: usize,
+    ) -> GraphQLResult<BoardPage> {
+        let repo = ctx.data::<Repository>()?;
+        let (summaries, next_cursor) = repo
+            .get_boards(cursor.unwrap_or_default(), limit.min(200))
+            .await?;
+
+        Ok(BoardPage {
+            boards: summaries
+                .into_iter()
+                .map(|summary| Board {
+                    id: summary.board_id,
+                    meta: summary.meta,
+                    last_activity_at: Some(summary.last_activity_at),
+                })
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    /// Any board, whether or not it was created through the API
+    async fn board(&self, ctx: &Context<'_>, id: Uuid) -> GraphQLResult<Board> {
+        let repo = ctx.data::<Repository>()?;
+
+        Ok(Board {
+            id,
+            meta: repo.get_meta_for_board(id).await?,
+            last_activity_at: None,
+        })
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every change accepted on a board from now on, or from just after the given version
+    async fn changes(
+        &self,
+        ctx: &Context<'_>,
+        board_id: Uuid,
+        after_version: Option<String>,
+        // The macro only recognizes a fallible stream by the name Result
+    ) -> async_graphql::Result<impl Stream<Item = AcceptedChange>> {
+        let repo = ctx.data::<Repository>()?;
+        let version = match after_version {
+            Some(version) => version,
+            None => repo.get_latest_version_for_board(board_id).await?,
+        };
+
+        Ok(repo
+            .stream_changes_for_board(board_id, version)
+            .map(AcceptedChange::from))
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct BoardPage {
+    boards: Vec<Board>,
+    next_cursor: Option<usize>,
+}
+
+pub struct Board {
+    id: Uuid,
+    meta: Option<BoardMeta>,
+    /// Only known when the board came from the listing
+    last_activity_at: Option<DateTime<Utc>>,
+}
+
+#[Object]
+impl Board {
+    async fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Only boards created through the API have metadata
+    async fn meta(&self) -> Option<&BoardMeta> {
+        self.meta.as_ref()
+    }
+
+    async fn last_activity_at(&self) -> Option<DateTime<Utc>> {
+        self.last_activity_at
+    }
+
+    /// The current contents of the board, including changes that haven't been checkpointed yet
+    async fn snapshot(&self, ctx: &Context<'_>) -> GraphQLResult<Snapshot> {
+        let repo = ctx.data::<Repository>()?;
+        let snapshot = snapshot::read_snapshot(self.id, repo).await?;
+
+        Ok(Snapshot {
+            version: snapshot.version,
+            objects: snapshot
+                .objects
+                .into_iter()
+                .map(|(id, data)| BoardObject {
+                    id,
+                    data: Json(data),
+                })
+                .collect(),
+            order: snapshot
+                .order
+                .into_iter()
+                .map(|(id, index)| ObjectIndex { id, index })
+                .collect(),
+        })
+    }
+
+    /// The sessions connected to the board right now
+    async fn sessions(&self, ctx: &Context<'_>) -> GraphQLResult<Vec<Session>> {
+        let repo = ctx.data::<Repository>()?;
+
+        Ok(repo
+            .get_sessions_for_board(self.id)
+            .await?
+            .into_iter()
+            .map(|(id, username)| Session { id, username })
+            .collect())
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Snapshot {
+    version: String,
+    objects: Vec<BoardObject>,
+    /// Back to front, only including objects that have been given an index
+    order: Vec<ObjectIndex>,
+}
+
+#[derive(SimpleObject)]
+pub struct BoardObject {
+    id: Uuid,
+    data: Json<JsonObject>,
+}
+
+#[derive(SimpleObject)]
+pub struct ObjectIndex {
+    id: Uuid,
+    index: f64,
+}
+
+#[derive(SimpleObject)]
+pub struct Session {
+    id: Uuid,
+    username: String,
+}
+
+#[derive(SimpleObject)]
+pub struct ObjectRevision {
+    id: Uuid,
+    revision: u64,
+}
+
+/// A change as it was accepted onto a board. The change itself is left as JSON in the same shape
+/// as the websocket protocol.
+#[derive(SimpleObject)]
+pub struct AcceptedChange {
+    change: Json<Change>,
+    session_id: Uuid,
+    username: Option<String>,
+    user_id: Option<String>,
+    version: String,
+    revisions: Vec<ObjectRevision>,
+    timestamp: u64,
+}
+
+impl From<ChangeEntry> for AcceptedChange {
+    fn from(entry: ChangeEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp(),
+            change: Json(entry.change),
+            session_id: entry.session_id,
+            username: entry.username,
+            user_id: entry.user_id,
+            version: entry.version,
+            revisions: entry
+                .revisions
+                .into_iter()
+                .map(|(id, revision)| ObjectRevision { id, revision })
+                .collect(),
+        }
+    }
+}
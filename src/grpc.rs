@@ -0,0 +1,339 @@
+// tonic handlers return its Status as their error, so it gets passed around unboxed
+#![allow(clippy::result_large_err)]
+
+use std::pin::Pin;
+
+use futures::stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::api::ApiError;
+use crate::auth::{Authenticator, Caller, Scope};
+use crate::change::{Change, ChangeEntry};
+use crate::message::RejectionReason;
+use crate::rate_limit::SessionChangeLimiter;
+use crate::repository::{BanTarget, PublishOutcome, Repository};
+use crate::snapshot;
+
+pub mod proto {
+    tonic::include_proto!("redboard");
+}
+
+use proto::{
+    boards_server::{Boards, BoardsServer},
+    publish_change_response::{self, Outcome},
+};
+
+/// Implements the `Boards` gRPC service on top of the repo. Every call has to carry a token in its
+/// `authorization` metadata the same way as an HTTP request, which needs the read scope to get
+/// snapshots and stream changes, and the write scope to publish them.
+pub struct BoardsService {
+    repo: Repository,
+    authenticator: Authenticator,
+    change_limiter: SessionChangeLimiter,
+}
+
+impl BoardsService {
+    pub fn new(repo: Repository, authenticator: Authenticator) -> Self {
+        Self {
+            repo,
+            authenticator,
+            change_limiter: SessionChangeLimiter::default(),
+        }
+    }
+
+    pub fn into_server(self) -> BoardsServer<Self> {
+        BoardsServer::new(self)
+    }
+
+    /// Work out who made a call from its bearer token and make sure they're allowed to
+    async fn authorize<T>(&self, request: &Request<T>, scope: Scope) -> Result<Caller, Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let caller = self.authenticator.caller(token).await.map_err(api_error)?;
+        caller
+            .require(scope)
+            .map_err(|_| Status::permission_denied("The token doesn't allow this"))?;
+        Ok(caller)
+    }
+
+    /// Make sure a change published on behalf of a session comes from whoever the session belongs
+    /// to, and that the session is on the board and isn't banned from it, the same as for a
+    /// websocket
+    async fn check_session(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        caller: &Caller,
+        ip: Option<&str>,
+    ) -> Result<(), Status> {
+        let on_board = self
+            .repo
+            .get_sessions_for_board(board_id)
+            .await
+            .map_err(internal)?
+            .iter()
+            .any(|(id, _)| *id == session_id);
+        if !on_board
+            || !self
+                .repo
+                .get_session_exists(session_id)
+                .await
+                .map_err(internal)?
+        {
+            return Err(Status::failed_precondition(
+                "The session isn't on the board",
+            ));
+        }
+
+        let user_id = self
+            .repo
+            .get_session_user_for_board(board_id, session_id)
+            .await
+            .map_err(internal)?;
+        if user_id != caller.user_id {
+            return Err(Status::permission_denied(
+                "The session belongs to someone else",
+            ));
+        }
+
+        let ban_targets = BanTarget::for_connection(session_id, user_id.as_deref(), ip);
+        if self
+            .repo
+            .get_ban_for_connection(board_id, &ban_targets)
+            .await
+            .map_err(internal)?
+            .is_some()
+        {
+            return Err(Status::permission_denied("Banned from the board"));
+        }
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Boards for BoardsService {
+    #[tracing::instrument(skip_all)]
+    async fn get_snapshot(
+        &self,
+        request: Request<proto::GetSnapshotRequest>,
+    ) -> Result<Response<proto::Snapshot>, Status> {
+        self.authorize(&request, Scope::Read).await?;
+        let board_id = parse_uuid(&request.get_ref().board_id)?;
+        let snapshot = snapshot::read_snapshot(board_id, &self.repo)
+            .await
+            .map_err(internal)?;
+
+        Ok(Response::new(proto::Snapshot {
+            version: snapshot.version,
+            objects: snapshot
+                .objects
+                .into_iter()
+                .map(|(id, object)| {
+                    Ok(proto::BoardObject {
+                        id: id.to_string(),
+                        json: serde_json::to_string(&object)?,
+                    })
+                })
+                .collect::<serde_json::Result<_>>()
+                .map_err(internal)?,
+            order: snapshot
+                .order
+                .into_iter()
+                .map(|(id, index)| proto::ObjectIndex {
+                    id: id.to_string(),
+                    index,
+                })
+                .collect(),
+        }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn publish_change(
+        &self,
+        request: Request<proto::PublishChangeRequest>,
+    ) -> Result<Response<proto::PublishChangeResponse>, Status> {
+        let caller = self.authorize(&request, Scope::Write).await?;
+        let ip = request
+            .remote_addr()
+            .map(|address| address.ip().to_string());
+        let request = request.into_inner();
+        let board_id = parse_uuid(&request.board_id)?;
+        let session_id = parse_uuid(&request.session_id)?;
+        self.check_session(board_id, session_id, &caller, ip.as_deref())
+            .await?;
+        let mut change = serde_json::from_str::<Change>(&request.change_json)
+            .map_err(|error| Status::invalid_argument(format!("Invalid change: {error}")))?;
+        if let Some(key) = change.invalid_key() {
+            return Err(Status::invalid_argument(format!("Invalid key: {key:?}")));
+        }
+        let idempotency_key = Some(request.idempotency_key).filter(|key| !key.is_empty());
+
+        // Changes past the session's limit are dropped before they get anywhere near the store
+        if let Err(retry_after) = self.change_limiter.check(session_id) {
+            return Err(Status::resource_exhausted(format!(
+                "Too many changes, retry after {}ms",
+                retry_after.as_millis()
+            )));
+        }
+
+        if self
+            .repo
+            .get_board_frozen(board_id)
+            .await
+            .map_err(internal)?
+        {
+            return Err(Status::failed_precondition("The board is frozen"));
+        }
+
+        let conflicting_lock = self
+            .repo
+            .get_conflicting_lock_for_change(board_id, session_id, &change)
+            .await
+            .map_err(internal)?;
+        if let Some(session_id) = conflicting_lock {
+            return Ok(Response::new(proto::PublishChangeResponse {
+                outcome: Some(Outcome::ObjectLocked(
+                    publish_change_response::ObjectLocked {
+                        session_id: session_id.to_string(),
+                    },
+                )),
+            }));
+        }
+
+        // Restored objects are filled in from the trash so that everyone else can show them
+        if let Some(id) = self
+            .repo
+            .fill_restored_objects(board_id, &mut change)
+            .await
+            .map_err(internal)?
+        {
+            return Err(Status::failed_precondition(format!(
+                "Object {id} isn't in the board's trash"
+            )));
+        }
+
+        match self
+            .repo
+            .check_quotas(board_id, &change)
+            .await
+            .map_err(internal)?
+        {
+            Some(RejectionReason::TooManyObjects { max_objects }) => {
+                return Err(Status::resource_exhausted(format!(
+                    "The board already has the maximum of {max_objects} objects"
+                )));
+            }
+            Some(RejectionReason::ObjectTooLarge { id, size, max_size }) => {
+                return Err(Status::resource_exhausted(format!(
+                    "Object {id} would be {size} bytes, more than the maximum of {max_size}"
+                )));
+            }
+            _ => {}
+        }
+
+        let outcome = match self
+            .repo
+            .publish_change_for_board(board_id, session_id, change, idempotency_key)
+            .await
+            .map_err(internal)?
+        {
+            PublishOutcome::Accepted { version, revisions } => {
+                Outcome::Accepted(publish_change_response::Accepted {
+                    version,
+                    revisions: revisions.into_iter().map(object_revision).collect(),
+                })
+            }
+            PublishOutcome::Duplicate { version } => {
+                Outcome::Duplicate(publish_change_response::Duplicate { version })
+            }
+            PublishOutcome::RevisionMismatch {
+                id,
+                current_revision,
+            } => Outcome::RevisionMismatch(publish_change_response::RevisionMismatch {
+                id: id.to_string(),
+                current_revision,
+            }),
+        };
+
+        Ok(Response::new(proto::PublishChangeResponse {
+            outcome: Some(outcome),
+        }))
+    }
+
+    type StreamChangesStream =
+        Pin<Box<dyn Stream<Item = Result<proto::AcceptedChange, Status>> + Send>>;
+
+    #[tracing::instrument(skip_all)]
+    async fn stream_changes(
+        &self,
+        request: Request<proto::StreamChangesRequest>,
+    ) -> Result<Response<Self::StreamChangesStream>, Status> {
+        self.authorize(&request, Scope::Read).await?;
+        let request = request.into_inner();
+        let board_id = parse_uuid(&request.board_id)?;
+        let version = if request.after_version.is_empty() {
+            self.repo
+                .get_latest_version_for_board(board_id)
+                .await
+                .map_err(internal)?
+        } else {
+            request.after_version
+        };
+
+        let changes = self
+            .repo
+            .stream_changes_for_board(board_id, version)
+            .map(accepted_change);
+
+        Ok(Response::new(Box::pin(changes)))
+    }
+}
+
+fn accepted_change(entry: ChangeEntry) -> Result<proto::AcceptedChange, Status> {
+    Ok(proto::AcceptedChange {
+        change_json: serde_json::to_string(&entry.change).map_err(internal)?,
+        session_id: entry.session_id.to_string(),
+        timestamp: entry.timestamp(),
+        username: entry.username.unwrap_or_default(),
+        user_id: entry.user_id.unwrap_or_default(),
+        version: entry.version,
+        revisions: entry.revisions.into_iter().map(object_revision).collect(),
+    })
+}
+
+fn object_revision((id, revision): (Uuid, u64)) -> proto::ObjectRevision {
+    proto::ObjectRevision {
+        id: id.to_string(),
+        revision,
+    }
+}
+
+fn parse_uuid(string: &str) -> Result<Uuid, Status> {
+    string
+        .parse()
+        .map_err(|_| Status::invalid_argument(format!("Invalid UUID {string}")))
+}
+
+/// Turn an error from checking a caller into the closest status
+fn api_error(error: ApiError) -> Status {
+    match error {
+        ApiError::Unauthorized => Status::unauthenticated("Missing or invalid token"),
+        ApiError::Forbidden => Status::permission_denied("Not allowed"),
+        ApiError::BadRequest(message) => Status::invalid_argument(message),
+        ApiError::NotFound => Status::not_found("Not found"),
+        ApiError::Unavailable => Status::unavailable("Unavailable"),
+        ApiError::Internal(error) => internal(error),
+    }
+}
+
+/// Anything unexpected is logged and reported without details
+fn internal(error: impl Into<anyhow::Error>) -> Status {
+    let error = error.into();
+    tracing::error!(%error, "gRPC request failed");
+    Status::internal("Internal server error")
+}
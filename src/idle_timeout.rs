@@ -0,0 +1,31 @@
+use std::env;
+use std::time::Duration;
+
+/// How long a session can go without the user doing anything before it's closed, from the
+/// IDLE_TIMEOUT_MINUTES and IDLE_WARNING_SECONDS environment variables. Heartbeats don't count as
+/// doing anything, so tabs left open in the background eventually give up their spot.
+#[derive(Clone, Copy, Debug)]
+pub struct IdleTimeout {
+    pub timeout: Duration,
+    /// How long before the session is closed that the client is warned, so it can show it
+    pub warning: Duration,
+}
+
+impl IdleTimeout {
+    /// Sessions are never closed for being idle unless IDLE_TIMEOUT_MINUTES is set
+    pub fn from_env() -> Option<Self> {
+        let parse = |var: &str| {
+            env::var(var).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{var} must be a number"))
+            })
+        };
+        let timeout = Duration::from_secs(parse("IDLE_TIMEOUT_MINUTES")? * 60);
+        let warning = Duration::from_secs(parse("IDLE_WARNING_SECONDS").unwrap_or(60));
+        Some(Self {
+            timeout,
+            warning: warning.min(timeout),
+        })
+    }
+}
@@ -1,37 +1,111 @@
+mod admin;
+mod api;
+mod archive;
+mod archiver;
+mod auth;
+mod backup_exporter;
+mod board_access;
+mod board_cache;
 mod board_handler;
+mod board_meta;
 mod broadcaster;
 mod change;
+mod change_reader;
 mod checkpointer;
+mod circuit_breaker;
+mod cursor_publisher;
+mod degraded_notifier;
+mod events;
+mod export;
+mod graphql;
+mod grpc;
+mod idle_timeout;
+mod memory_repository;
 mod message;
+mod oidc;
+mod openapi;
+mod pending_counts;
+mod pool;
+mod postgres_repository;
 mod presence;
+mod presence_channel;
+mod rate_limit;
+mod reaper;
+mod redis_connection;
+mod redis_repository;
+mod render;
 mod repository;
+mod restart;
+mod retry;
+mod search;
 mod session_checker;
+mod share;
+mod shutdown;
+mod snapshot;
+mod snapshot_cache;
 mod socket;
+mod spa;
+mod spatial;
+#[cfg(test)]
+mod store_conformance;
+mod subscription;
+mod username;
+mod webhook;
 
 use axum::{
     extract::{
         ws::{WebSocket, WebSocketUpgrade},
-        Extension, Path, Query,
+        ConnectInfo, Extension, Path, Query, TypedHeader,
     },
-    http::Method,
+    headers::UserAgent,
+    http::{header, Method},
+    middleware,
     response::IntoResponse,
-    routing::get,
+    routing::{delete, get, post, put},
     Router, Server,
 };
-use axum_extra::routing::SpaRouter;
 use futures::stream::StreamExt;
-use redis::Client;
+use redis::IntoConnectionInfo;
 use serde::Deserialize;
 use std::env;
+use std::fs;
 use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
 use tower_http::cors::{self, CorsLayer};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 use uuid::Uuid;
 
+use crate::admin::AdminToken;
+use crate::api::ApiError;
+use crate::archive::ArchiveStore;
+use crate::archiver::Archiver;
+use crate::auth::{Authenticator, Caller, ConfiguredApiKeys, JwtAuth, JwtValidator, Scope};
+use crate::backup_exporter::BackupExporter;
+use crate::board_access::ConnectionGrant;
 use crate::board_handler::BoardHandler;
 use crate::checkpointer::Checkpointer;
-use crate::repository::Repository;
+use crate::grpc::BoardsService;
+use crate::idle_timeout::IdleTimeout;
+use crate::memory_repository::MemoryRepository;
+use crate::oidc::{OidcAuth, OidcClient};
+use crate::openapi::ApiDoc;
+use crate::pool::PoolConfig;
+use crate::postgres_repository::PostgresRepository;
+use crate::presence_channel::DEFAULT_PRESENCE_CHANNEL_CAPACITY;
+use crate::rate_limit::IpRateLimiter;
+use crate::reaper::Reaper;
+use crate::redis_connection::{RedisConnectionManager, RedisTls};
+use crate::redis_repository::RedisRepository;
+use crate::repository::{BoardQuotas, ChangeRetention, CheckpointHistory, ClientInfo, Repository};
+use crate::retry::RetryPolicy;
 use crate::session_checker::SessionChecker;
+use crate::share::Role;
+use crate::shutdown::{Shutdown, ShutdownTrigger};
 use crate::socket::{SocketSender, SocketStream};
+use crate::username::UsernamePolicy;
+use crate::webhook::WebhookDispatcher;
 
 #[tokio::main]
 #[tracing::instrument]
@@ -44,48 +118,643 @@ async fn main() {
     )
     .unwrap();
 
-    tracing::info!("Serving on port 8080");
+    // `--check` makes sure the store is reachable and usable, then exits instead of serving
+    let check_only = env::args().skip(1).any(|arg| arg == "--check");
 
-    let redis_url = env::var("REDIS_URL").expect("REDIS_URL is required");
-    let redis_client = Client::open(redis_url).expect("Could not connect to redis");
+    if !check_only {
+        tracing::info!("Serving on port 8080");
+    }
 
-    // The repo encapsulates all interactions with Redis
-    let repo = Repository::new(redis_client)
-        .await
-        .expect("Could not start repository");
+    // STORE picks where boards are kept. `redis` and `postgres` let any number of servers share
+    // boards, and `memory` keeps everything in this process until it stops. It's `postgres` by
+    // default when DATABASE_URL is set, and `redis` otherwise.
+    let store = env::var("STORE").unwrap_or_else(|_| {
+        match env::var("DATABASE_URL") {
+            Ok(_) => "postgres",
+            Err(_) => "redis",
+        }
+        .to_string()
+    });
 
-    // Run one instance of the checkpointer in the background for the lifetime of the application
-    let checkpointer_handle = tokio::task::spawn(Checkpointer::new(repo.clone()).start());
+    // How many checkpointed changes each board keeps around for the history API
+    let history_length = env::var("HISTORY_LENGTH")
+        .map(|history_length| {
+            history_length
+                .parse()
+                .expect("HISTORY_LENGTH must be a number")
+        })
+        .unwrap_or(10_000);
 
-    // Run one instance of the session checker in the background for the lifetime of the application
-    let session_checker_handle = tokio::task::spawn(SessionChecker::new(repo.clone()).start());
+    // How many checkpoints each board keeps around for rolling it back, configured with
+    // CHECKPOINT_HISTORY_*
+    let checkpoint_history = CheckpointHistory::from_env();
+    let change_retention = ChangeRetention::from_env();
 
-    // Build the application router
+    // Sessions expire after SESSION_TTL_SECONDS without checking in
+    let session_ttl = Duration::from_secs(
+        env::var("SESSION_TTL_SECONDS")
+            .map(|seconds| {
+                seconds
+                    .parse()
+                    .expect("SESSION_TTL_SECONDS must be a number")
+            })
+            .unwrap_or(30),
+    );
+
+    // Inactive boards are archived to the S3-compatible ARCHIVE_BUCKET when it's configured.
+    // ARCHIVE_ENDPOINT is for services other than AWS, and the credentials fall back to the usual
+    // AWS ones.
+    let archive_store = env::var("ARCHIVE_BUCKET").ok().map(|bucket| {
+        ArchiveStore::new(
+            &bucket,
+            env::var("ARCHIVE_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            env::var("ARCHIVE_ENDPOINT").ok(),
+            env::var("ARCHIVE_ACCESS_KEY_ID").ok(),
+            env::var("ARCHIVE_SECRET_ACCESS_KEY").ok(),
+        )
+        .expect("Could not configure archive storage")
+    });
+
+    // The Redis and Postgres stores each keep a pool of connections, configured with POOL_*
+    let pool_config = PoolConfig::from_env();
+
+    // Every board's presence on this server goes through one channel, which can hold
+    // PRESENCE_CHANNEL_CAPACITY messages for whoever is furthest behind
+    let presence_capacity = env::var("PRESENCE_CHANNEL_CAPACITY")
+        .map(|capacity| {
+            capacity
+                .parse()
+                .expect("PRESENCE_CHANNEL_CAPACITY must be a number")
+        })
+        .unwrap_or(DEFAULT_PRESENCE_CHANNEL_CAPACITY);
+
+    // The repo encapsulates all interactions with the store
+    let repo = match store.as_str() {
+        "redis" => {
+            // REDIS_CLUSTER_URLS is a comma-separated list of cluster nodes to discover the rest
+            // of the cluster from, and REDIS_SENTINEL_URLS is one of Sentinels to find the master
+            // named REDIS_SENTINEL_MASTER through. The master's credentials come from REDIS_URL.
+            let manager = match (
+                env::var("REDIS_CLUSTER_URLS"),
+                env::var("REDIS_SENTINEL_URLS"),
+            ) {
+                (Ok(urls), _) => RedisConnectionManager::cluster(
+                    urls.split(',').map(|url| url.trim().to_string()).collect(),
+                )
+                .expect("Could not connect to the redis cluster"),
+                (Err(_), Ok(urls)) => RedisConnectionManager::sentinel(
+                    urls.split(',').map(|url| url.trim().to_string()).collect(),
+                    env::var("REDIS_SENTINEL_MASTER").unwrap_or_else(|_| "mymaster".to_string()),
+                    env::var("REDIS_URL")
+                        .unwrap_or_else(|_| "redis://localhost".to_string())
+                        .into_connection_info()
+                        .expect("REDIS_URL must be a redis URL"),
+                )
+                .expect("Could not configure redis sentinel"),
+                (Err(_), Err(_)) => RedisConnectionManager::single(
+                    env::var("REDIS_URL")
+                        .expect("REDIS_URL is required")
+                        .into_connection_info()
+                        .expect("REDIS_URL must be a redis URL"),
+                ),
+            };
+
+            // rediss:// URLs are checked against the system's trusted roots, plus the PEM CA at
+            // REDIS_TLS_CA_CERT if there is one. REDIS_TLS_CLIENT_CERT and REDIS_TLS_CLIENT_KEY
+            // are a PEM certificate and PKCS #8 key to present to servers that require one.
+            let read_pem = |var: &str| {
+                env::var(var)
+                    .ok()
+                    .map(|path| fs::read(&path).unwrap_or_else(|_| panic!("Could not read {path}")))
+            };
+            let ca_cert = read_pem("REDIS_TLS_CA_CERT");
+            let client_cert = read_pem("REDIS_TLS_CLIENT_CERT");
+            let client_key = read_pem("REDIS_TLS_CLIENT_KEY");
+            let manager = match (ca_cert, client_cert, client_key) {
+                (None, None, None) => manager,
+                (ca_cert, client_cert, client_key) => {
+                    let client_cert_and_key = match (&client_cert, &client_key) {
+                        (Some(cert), Some(key)) => Some((cert.as_slice(), key.as_slice())),
+                        (None, None) => None,
+                        _ => panic!("REDIS_TLS_CLIENT_CERT and REDIS_TLS_CLIENT_KEY go together"),
+                    };
+                    let tls = RedisTls::new(ca_cert.as_deref(), client_cert_and_key)
+                        .expect("Could not load the redis TLS certificates");
+                    manager
+                        .with_tls(tls)
+                        .expect("Could not configure redis TLS")
+                }
+            };
+            if check_only {
+                exit_after_check(RedisRepository::check(manager, &pool_config).await);
+            }
+            Repository::new(exit_on_error(
+                RedisRepository::new(
+                    manager,
+                    &pool_config,
+                    history_length,
+                    checkpoint_history,
+                    change_retention,
+                    session_ttl,
+                    archive_store.clone(),
+                    RetryPolicy::from_env(),
+                    presence_capacity,
+                )
+                .await,
+            ))
+        }
+        "postgres" => {
+            assert!(
+                archive_store.is_none(),
+                "ARCHIVE_BUCKET can't be used with the postgres store"
+            );
+            let database_url = env::var("DATABASE_URL").expect("DATABASE_URL is required");
+            if check_only {
+                exit_after_check(PostgresRepository::check(&database_url, &pool_config).await);
+            }
+            Repository::new(exit_on_error(
+                PostgresRepository::new(
+                    &database_url,
+                    &pool_config,
+                    history_length,
+                    checkpoint_history,
+                    change_retention,
+                    session_ttl,
+                    presence_capacity,
+                )
+                .await,
+            ))
+        }
+        "memory" => {
+            assert!(
+                archive_store.is_none(),
+                "ARCHIVE_BUCKET can't be used with the memory store"
+            );
+            // There's nothing outside of this process to check
+            if check_only {
+                exit_after_check(Ok(()));
+            }
+            Repository::new(MemoryRepository::new(
+                history_length,
+                checkpoint_history,
+                change_retention,
+                session_ttl,
+                presence_capacity,
+            ))
+        }
+        store => panic!("STORE must be redis, postgres, or memory, not {store}"),
+    };
+
+    // Boards are kept from growing past MAX_OBJECTS_PER_BOARD objects or having more than
+    // MAX_OBJECT_BYTES written into one object at a time, when those are set
+    let repo = repo.with_quotas(BoardQuotas::from_env());
+
+    // Boards can be kept in memory for snapshots, for BOARD_CACHE_SECONDS after the last one, so
+    // that a popular board isn't read from the store every time someone joins
+    let repo = match env::var("BOARD_CACHE_SECONDS") {
+        Ok(seconds) => repo.with_board_cache(Duration::from_secs(
+            seconds
+                .parse()
+                .expect("BOARD_CACHE_SECONDS must be a number"),
+        )),
+        Err(_) => repo,
+    };
+
+    // Snapshots are shared between the sessions joining a board within SNAPSHOT_CACHE_SECONDS of
+    // each other, 60 by default, and 0 reads the board from the store for every snapshot
+    let snapshot_cache_seconds = env::var("SNAPSHOT_CACHE_SECONDS")
+        .map(|seconds| {
+            seconds
+                .parse()
+                .expect("SNAPSHOT_CACHE_SECONDS must be a number")
+        })
+        .unwrap_or(60);
+    let repo = match snapshot_cache_seconds {
+        0 => repo,
+        seconds => repo.with_snapshot_cache(Duration::from_secs(seconds)),
+    };
+
+    // Admin routes are only usable when a token is configured
+    let admin_token = AdminToken(env::var("ADMIN_TOKEN").ok());
+
+    // Users can sign in with an OIDC provider when OIDC_ISSUER is configured, which needs the
+    // client's OIDC_CLIENT_ID and OIDC_CLIENT_SECRET, and the OIDC_REDIRECT_URL of /auth/callback
+    // as the provider will see it
+    let oidc_client = match env::var("OIDC_ISSUER") {
+        Ok(issuer) => Some(
+            OidcClient::discover(
+                issuer,
+                env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID is required for OIDC"),
+                env::var("OIDC_CLIENT_SECRET").expect("OIDC_CLIENT_SECRET is required for OIDC"),
+                env::var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL is required for OIDC"),
+            )
+            .await
+            .expect("Could not discover OIDC provider"),
+        ),
+        Err(_) => None,
+    };
+
+    // Boards are only usable with a JWT from JWT_ISSUER when it's configured. The signing keys
+    // are read from JWT_JWKS_URL, which defaults to the issuer's well-known JWKS. When OIDC is
+    // configured, the ID tokens it hands out are accepted by default.
+    let jwt_auth = JwtAuth(match (env::var("JWT_ISSUER"), &oidc_client) {
+        (Ok(issuer), _) => {
+            let jwks_url = env::var("JWT_JWKS_URL").unwrap_or_else(|_| {
+                format!("{}/.well-known/jwks.json", issuer.trim_end_matches('/'))
+            });
+            Some(JwtValidator::new(
+                issuer,
+                env::var("JWT_AUDIENCE").ok(),
+                jwks_url,
+            ))
+        }
+        (Err(_), Some(oidc_client)) => Some(JwtValidator::new(
+            oidc_client.issuer().to_string(),
+            Some(oidc_client.client_id().to_string()),
+            oidc_client.jwks_uri().to_string(),
+        )),
+        (Err(_), None) => None,
+    });
+    let oidc_auth = OidcAuth(oidc_client);
+
+    // API keys for machine clients can be configured up front in addition to the ones created
+    // through the admin routes. API_KEYS is a comma separated list of `name:sha256:scope+scope`.
+    let configured_api_keys = env::var("API_KEYS")
+        .map(|api_keys| ConfiguredApiKeys::parse(&api_keys).expect("API_KEYS is invalid"))
+        .unwrap_or_default();
+
+    // Every API checks the callers' tokens against the same credentials. Once there are any,
+    // callers without a token can only read unless ALLOW_ANONYMOUS_WRITE is true.
+    let allow_anonymous_write = matches!(
+        env::var("ALLOW_ANONYMOUS_WRITE").as_deref(),
+        Ok("true") | Ok("1")
+    );
+    let authenticator =
+        Authenticator::new(repo.clone(), admin_token, jwt_auth, configured_api_keys)
+            .with_anonymous_write(allow_anonymous_write);
+
+    // Run one instance of the checkpointer in the background for the lifetime of the application.
+    // It goes over every board with pending changes every CHECKPOINT_INTERVAL_SECONDS, and over a
+    // board with at least CHECKPOINT_HOT_BOARD_CHANGES of them right away.
+    let checkpoint_interval = env::var("CHECKPOINT_INTERVAL_SECONDS")
+        .map(|seconds| {
+            seconds
+                .parse()
+                .expect("CHECKPOINT_INTERVAL_SECONDS must be a number")
+        })
+        .unwrap_or(15);
+    let checkpoint_hot_board_changes = env::var("CHECKPOINT_HOT_BOARD_CHANGES")
+        .map(|changes| {
+            changes
+                .parse()
+                .expect("CHECKPOINT_HOT_BOARD_CHANGES must be a number")
+        })
+        .unwrap_or(500);
+    let checkpointer_handle = tokio::task::spawn(
+        Checkpointer::new(repo.clone())
+            .with_interval(Duration::from_secs(checkpoint_interval))
+            .with_hot_board_changes(checkpoint_hot_board_changes)
+            .start(),
+    );
+
+    // Run one instance of the session checker in the background for the lifetime of the
+    // application. It removes sessions as soon as the store says they expired, or looks for them
+    // every SESSION_CHECK_INTERVAL_SECONDS if the store can't say.
+    let session_check_interval = env::var("SESSION_CHECK_INTERVAL_SECONDS")
+        .map(|seconds| {
+            seconds
+                .parse()
+                .expect("SESSION_CHECK_INTERVAL_SECONDS must be a number")
+        })
+        .unwrap_or(10);
+    let session_checker_handle = tokio::task::spawn(
+        SessionChecker::new(repo.clone(), Duration::from_secs(session_check_interval)).start(),
+    );
+
+    // Run one instance of the archiver in the background when archiving is turned on. Boards are
+    // archived after ARCHIVE_AFTER_DAYS without any activity.
+    let archiver_handle = archive_store.map(|archive_store| {
+        let archive_after_days = env::var("ARCHIVE_AFTER_DAYS")
+            .map(|days| days.parse().expect("ARCHIVE_AFTER_DAYS must be a number"))
+            .unwrap_or(30);
+        tokio::task::spawn(
+            Archiver::new(
+                repo.clone(),
+                archive_store,
+                chrono::Duration::days(archive_after_days),
+            )
+            .start(),
+        )
+    });
+
+    // Run one instance of the reaper in the background when REAP_AFTER_DAYS is set. Boards with
+    // nobody on them are deleted after that many days without a change.
+    let reaper_handle = env::var("REAP_AFTER_DAYS").ok().map(|days| {
+        let reap_after_days = days.parse().expect("REAP_AFTER_DAYS must be a number");
+        tokio::task::spawn(
+            Reaper::new(repo.clone(), chrono::Duration::days(reap_after_days)).start(),
+        )
+    });
+
+    // Run one instance of the backup exporter in the background when backups are turned on. Every
+    // BACKUP_INTERVAL_MINUTES, each board's latest checkpoint is written to the S3-compatible
+    // BACKUP_BUCKET under backups/. BACKUP_ENDPOINT is for services other than AWS, like GCS with
+    // HMAC keys at https://storage.googleapis.com, and the credentials fall back to the usual AWS
+    // ones.
+    let backup_exporter_handle = env::var("BACKUP_BUCKET").ok().map(|bucket| {
+        let backup_store = ArchiveStore::new(
+            &bucket,
+            env::var("BACKUP_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            env::var("BACKUP_ENDPOINT").ok(),
+            env::var("BACKUP_ACCESS_KEY_ID").ok(),
+            env::var("BACKUP_SECRET_ACCESS_KEY").ok(),
+        )
+        .expect("Could not configure backup storage")
+        .with_prefix("backups");
+        let backup_interval_minutes = env::var("BACKUP_INTERVAL_MINUTES")
+            .map(|minutes| {
+                minutes
+                    .parse()
+                    .expect("BACKUP_INTERVAL_MINUTES must be a number")
+            })
+            .unwrap_or(60);
+        tokio::task::spawn(
+            BackupExporter::new(
+                repo.clone(),
+                backup_store,
+                Duration::from_secs(backup_interval_minutes * 60),
+            )
+            .start(),
+        )
+    });
+
+    // Run one instance of the webhook dispatcher in the background for the lifetime of the
+    // application. WEBHOOK_URLS is a comma separated list of URLs that get every board's events.
+    let webhook_urls = env::var("WEBHOOK_URLS")
+        .map(|urls| {
+            urls.split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let webhook_secret = env::var("WEBHOOK_SECRET").ok();
+    let webhook_dispatcher_handle = tokio::task::spawn(
+        WebhookDispatcher::new(repo.clone(), webhook_urls, webhook_secret).start(),
+    );
+
+    // Serve the gRPC service on its own port for the lifetime of the application
+    let grpc_port = env::var("GRPC_PORT")
+        .map(|grpc_port| grpc_port.parse().expect("GRPC_PORT must be a port number"))
+        .unwrap_or(50051);
+    let grpc_service = BoardsService::new(repo.clone(), authenticator.clone()).into_server();
+    let grpc_server_handle = tokio::task::spawn(async move {
+        let result = tonic::transport::Server::builder()
+            .add_service(grpc_service)
+            .serve(SocketAddr::from(([0, 0, 0, 0], grpc_port)))
+            .await;
+        if let Err(error) = result {
+            tracing::error!(%error, "gRPC server stopped");
+        }
+    });
+
+    // Connections find out through this when the server gets SIGTERM or SIGINT, so that they can
+    // wrap up before it exits
+    let shutdown_trigger = ShutdownTrigger::default();
+
+    // The GraphQL schema reads through its own handle on the repo
+    let graphql_schema = graphql::build_schema(repo.clone());
+
+    // Serve the built client from STATIC_DIR, with STATIC_INDEX_FILE for any other path, unless
+    // SERVE_STATIC is false for API-only deployments
+    let static_router = match env::var("SERVE_STATIC").as_deref() {
+        Ok("false") | Ok("0") => Router::new(),
+        _ => spa::router(
+            &env::var("STATIC_DIR").unwrap_or_else(|_| "static".to_string()),
+            &env::var("STATIC_INDEX_FILE").unwrap_or_else(|_| "index.html".to_string()),
+        ),
+    };
+
+    // Build the application router. Each handler checks what the caller is allowed to do.
     let app = Router::new()
         // Serve the client
-        .merge(SpaRouter::new("/assets", "static/assets").index_file("../index.html"))
-        // Handle websocket connections for boards
-        .route("/api/board/:board_id", get(board_handler))
+        .merge(static_router)
+        // Sign in and out with an OIDC provider
+        .route("/auth/login", get(oidc::login))
+        .route("/auth/callback", get(oidc::callback))
+        .route("/auth/logout", get(oidc::logout))
+        // Create and list boards
+        .route("/api/boards", get(api::list_boards).post(api::create_board))
+        .route("/api/search", get(api::search))
+        // Handle websocket connections for boards, and delete boards
+        .route(
+            "/api/board/:board_id",
+            get(board_handler).delete(api::delete_board),
+        )
+        // Export the current contents of a board
+        .route("/api/board/:board_id/snapshot", get(api::get_snapshot))
+        // Read and change the descriptive information about a board
+        .route(
+            "/api/board/:board_id/meta",
+            get(api::get_board_meta).patch(api::update_board_meta),
+        )
+        // Size up a board for dashboards
+        .route("/api/board/:board_id/stats", get(api::get_board_stats))
+        // Look back through the changes made to a board
+        .route("/api/board/:board_id/changes", get(api::get_change_history))
+        // See what's been deleted from a board and bring it back
+        .route("/api/board/:board_id/trash", get(api::get_trash))
+        .route(
+            "/api/board/:board_id/objects/:object_id/restore",
+            post(api::restore_object),
+        )
+        // Follow a board without a websocket
+        .route("/api/board/:board_id/events", get(api::board_events))
+        // Copy a board into a new one
+        .route("/api/board/:board_id/duplicate", post(api::duplicate_board))
+        // Make a link that opens a board with a given role
+        .route("/api/board/:board_id/share", get(api::share_board))
+        // Render a board as an image for embedding elsewhere
+        .route("/api/board/:board_id/export.svg", get(api::export_svg))
+        .route("/api/board/:board_id/export.png", get(api::export_png))
+        // Query boards with GraphQL, and follow changes with GraphQL subscriptions
+        .route(
+            "/api/graphql",
+            get(graphql::graphql_playground).post(graphql::graphql_handler),
+        )
+        .route("/api/graphql/ws", get(graphql::graphql_subscription))
+        // Inspect and manage the sessions on a board
+        .route(
+            "/api/admin/board/:board_id/sessions",
+            get(admin::list_sessions),
+        )
+        .route(
+            "/api/admin/board/:board_id/sessions/:session_id",
+            delete(admin::kick_session),
+        )
+        // Keep sessions, users, or addresses off of a board
+        .route(
+            "/api/admin/board/:board_id/bans",
+            get(admin::list_bans)
+                .post(admin::add_ban)
+                .delete(admin::remove_ban),
+        )
+        // Stop everyone from changing a board, or let them again
+        .route(
+            "/api/admin/board/:board_id/frozen",
+            put(admin::set_board_frozen),
+        )
+        .route(
+            "/api/admin/board/:board_id/reliable_presence",
+            put(admin::set_board_reliable_presence),
+        )
+        // Manage the webhooks that only get one board's events
+        .route(
+            "/api/admin/board/:board_id/webhooks",
+            get(admin::list_webhooks)
+                .post(admin::add_webhook)
+                .delete(admin::delete_webhook),
+        )
+        // Compact a board's change stream on demand
+        .route(
+            "/api/admin/board/:board_id/checkpoint",
+            post(admin::checkpoint_board),
+        )
+        // List a board's checkpoints and roll it back to one of them
+        .route(
+            "/api/admin/board/:board_id/checkpoints",
+            get(admin::list_checkpoints),
+        )
+        .route(
+            "/api/admin/board/:board_id/checkpoints/:version/restore",
+            post(admin::restore_checkpoint),
+        )
+        // Look for and repair damage left behind by partial failures
+        .route(
+            "/api/admin/board/:board_id/verify",
+            post(admin::verify_board),
+        )
+        // Manage the API keys that machine clients use
+        .route(
+            "/api/admin/api_keys",
+            get(admin::list_api_keys).post(admin::create_api_key),
+        )
+        .route(
+            "/api/admin/api_keys/:api_key_id",
+            delete(admin::delete_api_key),
+        )
+        // Download every board for backups
+        .route("/api/admin/export", get(admin::export_boards))
+        // See how busy the connection pool is
+        .route("/api/admin/pool", get(admin::get_pool_stats))
+        // See how the checkpointer is getting on
+        .route(
+            "/api/admin/checkpointer",
+            get(admin::get_checkpointer_stats),
+        )
+        // See how much presence sessions that fell behind have missed
+        .route("/api/admin/presence", get(admin::get_presence_stats))
+        // Describe the REST routes, and serve Swagger UI for browsing them
+        .merge(SwaggerUi::new("/api/docs/*tail").url("/api/openapi.json", ApiDoc::openapi()))
         // Provide the repo to any listeners
-        .layer(Extension(repo))
+        .layer(Extension(repo.clone()))
+        // Hold usernames to USERNAME_BLOCKLIST
+        .layer(Extension(UsernamePolicy::from_env()))
+        // Close sessions that sit idle for IDLE_TIMEOUT_MINUTES, when it's set
+        .layer(Extension(IdleTimeout::from_env()))
+        // Let websocket connections know when the server is shutting down
+        .layer(Extension(shutdown_trigger.shutdown()))
+        // Provide the GraphQL schema to the GraphQL handler
+        .layer(Extension(graphql_schema))
+        // Provide the credentials that callers are checked against
+        .layer(Extension(authenticator))
+        // Provide the OIDC client to the sign in routes
+        .layer(Extension(oidc_auth))
+        // Turn away IPs that are making too many requests
+        .layer(middleware::from_fn(rate_limit::limit_by_ip))
+        .layer(Extension(IpRateLimiter::default()))
         // Allow CORS connections to make development easier
         .layer(
             CorsLayer::new()
-                .allow_methods([Method::GET])
+                .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+                .allow_headers([
+                    header::AUTHORIZATION,
+                    header::CONTENT_TYPE,
+                    header::IF_NONE_MATCH,
+                ])
+                .expose_headers([header::ETAG])
                 .allow_origin(cors::Any),
         );
 
-    // Start the server
-    Server::bind(&SocketAddr::from(([0, 0, 0, 0], 8080)))
-        .serve(app.into_make_service())
-        .await
-        .expect("Failed to start server");
+    // Start the server, which stops taking new connections as soon as the shutdown starts
+    let mut server_shutdown = shutdown_trigger.shutdown();
+    let mut server_handle = tokio::task::spawn(
+        Server::bind(&SocketAddr::from(([0, 0, 0, 0], 8080)))
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move { server_shutdown.wait().await }),
+    );
+    tokio::select! {
+        result = &mut server_handle => {
+            result
+                .expect("Server task failed")
+                .expect("Failed to start server");
+        }
+        _ = shutdown::signal() => {}
+    }
+
+    // Send every websocket connection on its way and wait for them to wrap up. Event streams
+    // never finish on their own, so the server isn't waited on past that.
+    tracing::info!("Shutting down");
+    shutdown_trigger.trigger().await;
+    server_handle.abort();
+    server_handle.await.ok();
 
-    // If the server shuts down, also shut down background tasks
+    // Checkpoint what the connections left behind before stopping the background tasks
     checkpointer_handle.abort();
     checkpointer_handle.await.ok();
+    Checkpointer::new(repo).checkpoint_all_boards().await.ok();
     session_checker_handle.abort();
     session_checker_handle.await.ok();
+    if let Some(archiver_handle) = archiver_handle {
+        archiver_handle.abort();
+        archiver_handle.await.ok();
+    }
+    if let Some(reaper_handle) = reaper_handle {
+        reaper_handle.abort();
+        reaper_handle.await.ok();
+    }
+    if let Some(backup_exporter_handle) = backup_exporter_handle {
+        backup_exporter_handle.abort();
+        backup_exporter_handle.await.ok();
+    }
+    webhook_dispatcher_handle.abort();
+    webhook_dispatcher_handle.await.ok();
+    grpc_server_handle.abort();
+    grpc_server_handle.await.ok();
+}
+
+/// Report whether the store passed its checks for `--check` and exit with a matching status
+fn exit_after_check(result: anyhow::Result<()>) -> ! {
+    match result {
+        Ok(()) => {
+            tracing::info!("The store is ready");
+            process::exit(0);
+        }
+        Err(error) => {
+            tracing::error!("The store failed its checks: {error:#}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Stop with just the error when the store can't be started, since its message says what to do
+/// about it and a panic would bury that
+fn exit_on_error<T>(result: anyhow::Result<T>) -> T {
+    result.unwrap_or_else(|error| {
+        tracing::error!("Could not start repository: {error:#}");
+        process::exit(1);
+    })
 }
 
 #[derive(Deserialize)]
@@ -96,27 +765,102 @@ struct BoardPath {
 #[derive(Deserialize)]
 struct BoardQuery {
     session_id: Uuid,
+    /// Opens the board with the token's role in place of any other credentials
+    share_token: Option<String>,
+    /// Lets a caller who could edit open the board as a viewer instead, but never the other way
+    /// around
+    role: Option<Role>,
+    /// The version of the client, which is kept with the session for debugging
+    client_version: Option<String>,
 }
 
 /// Accept incoming websocket connections and start a BoardHandler task to drive them
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip_all, fields(path.board_id = %path.board_id, query.session_id = %query.session_id))]
 async fn board_handler(
     Extension(redis_pool): Extension<Repository>,
+    Extension(username_policy): Extension<UsernamePolicy>,
+    Extension(idle_timeout): Extension<Option<IdleTimeout>>,
+    Extension(shutdown): Extension<Shutdown>,
     Path(path): Path<BoardPath>,
     Query(query): Query<BoardQuery>,
+    caller: Option<Caller>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket: WebSocket| async move {
+) -> Result<impl IntoResponse, ApiError> {
+    // Anyone connecting now would be sent away right away
+    if shutdown.is_shutting_down() {
+        return Err(ApiError::Unavailable);
+    }
+
+    // A share link is kept with the connection so that it can't be used to subscribe to any board
+    // besides the one it was made for
+    let (user_id, share_claims, role) = match (query.share_token.as_deref(), caller) {
+        (Some(share_token), caller) => {
+            let secret = redis_pool.get_share_secret().await?;
+            let claims = share::verify(share_token, path.board_id, secret.as_bytes())
+                .map_err(|_| ApiError::Unauthorized)?;
+            let role = claims.role;
+            (caller.and_then(|caller| caller.user_id), Some(claims), role)
+        }
+        // Callers that can only read get to watch
+        (None, Some(caller)) => {
+            let role = if caller.require(Scope::Write).is_ok() {
+                Role::Editor
+            } else {
+                caller.require(Scope::Read)?;
+                Role::Viewer
+            };
+            (caller.user_id, None, role)
+        }
+        (None, None) => return Err(ApiError::Unauthorized),
+    };
+    let role = match query.role {
+        Some(Role::Viewer) => Role::Viewer,
+        Some(Role::Editor) | None => role,
+    };
+    let client = ClientInfo::new(
+        query.client_version,
+        user_agent.map(|TypedHeader(user_agent)| user_agent.to_string()),
+        connect_info.map(|ConnectInfo(address)| address.ip().to_string()),
+    );
+
+    // Banned sessions, users, and addresses can't get back on by reconnecting. Boards the
+    // connection subscribes to later go through the same checks.
+    let grant = ConnectionGrant::new(user_id, share_claims, role);
+    let access = grant
+        .check_board(&redis_pool, path.board_id, query.session_id, &client)
+        .await?
+        .ok_or(ApiError::Forbidden)?;
+
+    // The socket refuses to buffer anything much bigger than the limit, rather than reading a
+    // whole oversized message into memory only to throw it away
+    let max_message_bytes = redis_pool.quotas().max_message_bytes;
+    let ws = match max_message_bytes {
+        Some(max_message_bytes) => ws
+            .max_message_size(max_message_bytes)
+            .max_frame_size(max_message_bytes),
+        None => ws,
+    };
+
+    Ok(ws.on_upgrade(move |socket: WebSocket| async move {
         let (socket_sink, socket_stream) = socket.split();
 
         BoardHandler::new(
             path.board_id,
             query.session_id,
+            grant,
+            access,
+            client,
             redis_pool,
-            SocketSender::new(socket_sink),
-            SocketStream::new(socket_stream),
+            SocketSender::new(path.board_id, socket_sink),
+            SocketStream::new(socket_stream, max_message_bytes),
+            username_policy,
+            idle_timeout,
+            shutdown,
         )
         .start()
         .await;
-    })
+    }))
 }
@@ -0,0 +1,1741 @@
+use async_stream::stream;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use dashmap::DashMap;
+use futures::stream::{self, BoxStream};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{
+    broadcast::{self, error::RecvError, Sender as BroadcastSender},
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    watch::Receiver as WatchReceiver,
+    Mutex,
+};
+use uuid::Uuid;
+
+use crate::auth::ApiKey;
+use crate::board_meta::{BoardMeta, BoardMetaPatch, BoardSummary};
+use crate::change::{Change, ChangeEntry, TrashedObject};
+use crate::message::{AcceptedChange, Cursor, JsonObject, PresenceMessage, ServerMessage};
+use crate::presence_channel::{PresenceChannel, PresenceStats};
+use crate::repository::{
+    Ban, BanTarget, BoardStore, ChangeRetention, CheckpointHistory, ClientInfo, PoolStats,
+    PublishOutcome, Repository, RepositoryError, RepositoryResult, CURSOR_TTL_SECONDS,
+    IDEMPOTENCY_TTL_SECONDS, OBJECT_LOCK_TTL_SECONDS, TRASH_TTL_SECONDS,
+};
+use crate::search::{self, SearchHit};
+use crate::snapshot::BoardSnapshot;
+use crate::spatial::Rect;
+use crate::webhook::WebhookEvent;
+
+/// Everything that belongs to one board, laid out the same way as the board's keys in Redis
+#[derive(Default)]
+struct Board {
+    meta: Option<BoardMeta>,
+    /// The board's last activity in milliseconds, if it's in the registry
+    last_activity: Option<i64>,
+    /// Nothing until the board's first checkpoint, like JSON.SET NX in Redis
+    objects: Option<BTreeMap<Uuid, JsonObject>>,
+    order: HashMap<Uuid, f64>,
+    revisions: HashMap<Uuid, u64>,
+    /// The revision of each object as of the checkpointed version
+    checkpointed_revisions: HashMap<Uuid, u64>,
+    /// Deleted objects, which all expire together like the hash in Redis
+    trash: HashMap<Uuid, TrashedObject>,
+    trash_expires_at: Option<Instant>,
+    version: Option<String>,
+    /// The change stream, which keeps the checkpointed change and any retained ones the way
+    /// XTRIM MINID does
+    changes: VecDeque<ChangeEntry>,
+    /// The ID of the newest change ever published, which new IDs have to come after
+    last_change_id: Option<(u64, u64)>,
+    history: VecDeque<ChangeEntry>,
+    /// Copies of the board at its most recent checkpoints, oldest first
+    checkpoints: VecDeque<BoardSnapshot>,
+    sessions: HashMap<Uuid, String>,
+    session_users: HashMap<Uuid, String>,
+    session_clients: HashMap<Uuid, ClientInfo>,
+    /// Sessions waiting for a spot on the board, in the order they got in line
+    waiting: Vec<Uuid>,
+    /// The last known cursor positions, which all expire together like the hash in Redis
+    cursors: HashMap<Uuid, Cursor>,
+    cursors_expire_at: Option<Instant>,
+    latencies: HashMap<Uuid, f64>,
+    /// Object ID to the session holding the lease and when the lease runs out
+    locks: HashMap<Uuid, (Uuid, Instant)>,
+    /// Idempotency key to the version it was accepted as and when it's forgotten
+    idempotency_keys: HashMap<String, (String, Instant)>,
+    webhooks: BTreeSet<String>,
+    bans: Vec<Ban>,
+    frozen: bool,
+}
+
+impl Board {
+    fn version(&self) -> String {
+        self.version.clone().unwrap_or_else(|| "0".to_string())
+    }
+
+    /// Changes in the change stream after the given version, oldest first
+    fn changes_after(&self, version: &str) -> impl Iterator<Item = &ChangeEntry> {
+        let version = Repository::parse_stream_id(version);
+        self.changes
+            .iter()
+            .filter(move |entry| Repository::parse_stream_id(&entry.version) > version)
+    }
+
+    /// The objects in the trash, unless it's expired
+    fn trash(&self) -> Option<&HashMap<Uuid, TrashedObject>> {
+        match self.trash_expires_at {
+            Some(expires_at) if expires_at > Instant::now() => Some(&self.trash),
+            _ => None,
+        }
+    }
+
+    /// The last known cursor positions, unless they've expired
+    fn cursors(&self) -> Option<&HashMap<Uuid, Cursor>> {
+        match self.cursors_expire_at {
+            Some(expires_at) if expires_at > Instant::now() => Some(&self.cursors),
+            _ => None,
+        }
+    }
+
+    fn lock_holder(&self, object_id: Uuid) -> Option<Uuid> {
+        self.locks
+            .get(&object_id)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(holder, _)| *holder)
+    }
+}
+
+/// Keeps boards in this process's memory, for demos, tests, and deployments small enough for a
+/// single server. Everything is lost when the server stops, and archiving isn't supported.
+#[derive(Clone)]
+pub struct MemoryRepository {
+    /// How many checkpointed changes to keep in each board's history, or zero to keep none
+    history_length: usize,
+    checkpoint_history: CheckpointHistory,
+    change_retention: ChangeRetention,
+    /// How long a session lives without checking in
+    session_ttl: Duration,
+    boards: Arc<DashMap<Uuid, Board>>,
+    /// Session ID to when the session expires unless it checks in again
+    session_checkins: Arc<DashMap<Uuid, Instant>>,
+    api_keys: Arc<DashMap<String, ApiKey>>,
+    /// Lock name to the holder and when the lock runs out
+    task_locks: Arc<DashMap<String, (Uuid, Instant)>>,
+    share_secret: String,
+    webhook_sender: UnboundedSender<WebhookEvent>,
+    webhook_receiver: Arc<Mutex<UnboundedReceiver<WebhookEvent>>>,
+    presence: PresenceChannel,
+    /// Carries the ID of every board that a change is published to, to wake up anyone waiting on
+    /// that board's changes
+    change_sender: BroadcastSender<Uuid>,
+}
+
+impl MemoryRepository {
+    pub fn new(
+        history_length: usize,
+        checkpoint_history: CheckpointHistory,
+        change_retention: ChangeRetention,
+        session_ttl: Duration,
+        presence_capacity: usize,
+    ) -> Self {
+        let (webhook_sender, webhook_receiver) = mpsc::unbounded_channel();
+        let (change_sender, _) = broadcast::channel(1000);
+        Self {
+            history_length,
+            checkpoint_history,
+            change_retention,
+            session_ttl,
+            boards: Arc::new(DashMap::new()),
+            session_checkins: Arc::new(DashMap::new()),
+            api_keys: Arc::new(DashMap::new()),
+            task_locks: Arc::new(DashMap::new()),
+            share_secret: format!(
+                "{}{}",
+                Uuid::new_v4().as_simple(),
+                Uuid::new_v4().as_simple()
+            ),
+            webhook_sender,
+            webhook_receiver: Arc::new(Mutex::new(webhook_receiver)),
+            presence: PresenceChannel::new(presence_capacity),
+            change_sender,
+        }
+    }
+
+    // ---- Private helpers
+
+    /// Add an event to the end of the webhook queue
+    fn push_webhook_event(&self, event: WebhookEvent) {
+        // The receiver lives as long as the repository, so this can't fail
+        let _ = self.webhook_sender.send(event);
+    }
+
+    /// Send a presence message to everyone streaming a board's presence. Nobody listening isn't
+    /// an error.
+    fn publish_presence_message_for_board(&self, board_id: Uuid, message: PresenceMessage) {
+        self.presence.send(board_id, message);
+    }
+
+    /// Make up the ID for the next change published to a board from the current time, the same
+    /// way Redis does for stream entries, so that IDs always increase even if the clock doesn't
+    fn next_change_id(board: &mut Board) -> String {
+        let now = Utc::now().timestamp_millis() as u64;
+        let id = match board.last_change_id {
+            Some((timestamp, sequence)) if timestamp >= now => (timestamp, sequence + 1),
+            _ => (now, 0),
+        };
+        board.last_change_id = Some(id);
+        format!("{}-{}", id.0, id.1)
+    }
+
+    /// Apply a single change to a board's materialized objects and stacking order
+    fn apply_change(board: &mut Board, change: Change) {
+        let objects = board.objects.get_or_insert_with(BTreeMap::new);
+        match change {
+            // Deleted objects go in the trash, which is kept for a while after the last one
+            Change::Delete { id } => {
+                let index = board.order.remove(&id);
+                if let Some(object) = objects.remove(&id) {
+                    let now = Instant::now();
+                    if !matches!(board.trash_expires_at, Some(expires_at) if expires_at > now) {
+                        board.trash.clear();
+                    }
+                    board.trash.insert(id, TrashedObject { object, index });
+                    board.trash_expires_at =
+                        Some(now + Duration::from_secs(TRASH_TTL_SECONDS as u64));
+                }
+            }
+            Change::RestoreObject { id, object, index } => {
+                board.trash.remove(&id);
+                if let Some(object) = object {
+                    objects.insert(id, object);
+                }
+                if let Some(index) = index {
+                    board.order.insert(id, index);
+                }
+            }
+            Change::SetIndex { id, index } => {
+                board.order.insert(id, index);
+            }
+            Change::Transaction { .. } => {
+                unreachable!("Transactions are flattened before being applied")
+            }
+            Change::Insert { id, object } | Change::Replace { id, object, .. } => {
+                objects.insert(id, object);
+            }
+            // Like JSON.SET with a path, an update to an object that doesn't exist does nothing
+            Change::Update { id, key, value } => {
+                if let Some(object) = objects.get_mut(&id) {
+                    object.insert(key, value);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BoardStore for MemoryRepository {
+    #[tracing::instrument(skip(self), err)]
+    async fn create_board(&self, board_id: Uuid, meta: BoardMeta) -> RepositoryResult<()> {
+        {
+            let mut board = self.boards.entry(board_id).or_default();
+
+            // An existing board can never be clobbered, however unlikely a UUID collision is
+            if board.meta.is_some() {
+                return Err(RepositoryError::Conflict(format!(
+                    "Board {board_id} already exists"
+                )));
+            }
+            board.meta = Some(meta.clone());
+
+            // Add the board to the registry, with its creation as its last activity
+            board.last_activity = Some(meta.created_at.timestamp_millis());
+        }
+
+        self.push_webhook_event(WebhookEvent::BoardCreated { board_id, meta });
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_meta_for_board(&self, board_id: Uuid) -> RepositoryResult<Option<BoardMeta>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.meta.clone()))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_meta_for_board(
+        &self,
+        board_id: Uuid,
+        patch: BoardMetaPatch,
+    ) -> RepositoryResult<Option<BoardMeta>> {
+        let mut board = match self.boards.get_mut(&board_id) {
+            Some(board) => board,
+            None => return Ok(None),
+        };
+
+        let meta = board.meta.as_mut().map(|meta| {
+            patch.apply(meta);
+            meta.clone()
+        });
+
+        Ok(meta)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_board_frozen(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map_or(false, |board| board.frozen))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn set_board_frozen(&self, board_id: Uuid, frozen: bool) -> RepositoryResult<()> {
+        self.boards.entry(board_id).or_default().frozen = frozen;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn set_board_reliable_presence(
+        &self,
+        _board_id: Uuid,
+        reliable: bool,
+    ) -> RepositoryResult<()> {
+        if reliable {
+            return Err(RepositoryError::Conflict(
+                "Reliable presence needs the Redis store".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_board(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        if self.boards.remove(&board_id).is_none() {
+            return Ok(false);
+        }
+
+        // Broadcast BoardDeleted notification. The nil session ID means it didn't come from any
+        // session, so nobody will skip it.
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: Uuid::nil(),
+                message: ServerMessage::BoardDeleted,
+            },
+        );
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn duplicate_board(&self, board_id: Uuid, new_board_id: Uuid) -> RepositoryResult<bool> {
+        // Copy everything that makes up the board's document while holding on to it so that the
+        // copy can never land between a change being published and it being checkpointed. The
+        // change stream and version are copied too so that pending changes aren't lost.
+        let copy = match self.boards.get(&board_id) {
+            Some(board)
+                if board.objects.is_some()
+                    || !board.changes.is_empty()
+                    || board.last_activity.is_some() =>
+            {
+                Board {
+                    last_activity: Some(Utc::now().timestamp_millis()),
+                    objects: board.objects.clone(),
+                    order: board.order.clone(),
+                    revisions: board.revisions.clone(),
+                    checkpointed_revisions: board.checkpointed_revisions.clone(),
+                    version: board.version.clone(),
+                    changes: board.changes.clone(),
+                    last_change_id: board.last_change_id,
+                    ..Board::default()
+                }
+            }
+            _ => return Ok(false),
+        };
+        self.boards.insert(new_board_id, copy);
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_boards(
+        &self,
+        cursor: usize,
+        limit: usize,
+    ) -> RepositoryResult<(Vec<BoardSummary>, Option<usize>)> {
+        if limit == 0 {
+            return Ok((vec![], Some(cursor)));
+        }
+
+        // Walk the registry from the most recent activity back, the way ZREVRANGE would
+        let mut registry = self
+            .boards
+            .iter()
+            .filter_map(|board| Some((*board.key(), board.meta.clone(), board.last_activity?)))
+            .collect::<Vec<_>>();
+        registry.sort_by(|(a_id, _, a_activity), (b_id, _, b_activity)| {
+            (b_activity, b_id).cmp(&(a_activity, a_id))
+        });
+
+        let boards = registry
+            .into_iter()
+            .skip(cursor)
+            .take(limit)
+            .map(|(board_id, meta, last_activity)| BoardSummary {
+                board_id,
+                meta,
+                last_activity_at: Utc.timestamp_millis(last_activity),
+            })
+            .collect::<Vec<_>>();
+
+        let next_cursor = if boards.len() == limit {
+            Some(cursor + limit)
+        } else {
+            None
+        };
+
+        Ok((boards, next_cursor))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn create_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        username: String,
+        user_id: Option<String>,
+        client: ClientInfo,
+    ) -> RepositoryResult<()> {
+        {
+            let mut board = self.boards.entry(board_id).or_default();
+            board.sessions.insert(session_id, username.clone());
+            if let Some(user_id) = user_id {
+                board.session_users.insert(session_id, user_id);
+            }
+            board.session_clients.insert(session_id, client);
+        }
+
+        // Start keeping the session alive
+        self.touch_session(session_id).await?;
+
+        // Broadcast UserJoined notification
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserJoined {
+                    session_id,
+                    username: username.clone(),
+                },
+            },
+        );
+
+        self.push_webhook_event(WebhookEvent::UserJoined {
+            board_id,
+            session_id,
+            username,
+        });
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_sessions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, String)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .sessions
+                    .iter()
+                    .map(|(session_id, username)| (*session_id, username.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_user_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<Option<String>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.session_users.get(&session_id).cloned()))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        if let Some(mut board) = self.boards.get_mut(&board_id) {
+            board.sessions.remove(&session_id);
+            board.session_users.remove(&session_id);
+            board.session_clients.remove(&session_id);
+            board.cursors.remove(&session_id);
+            board.latencies.remove(&session_id);
+        }
+        self.session_checkins.remove(&session_id);
+
+        // Broadcast UserLeft notification
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserLeft { session_id },
+            },
+        );
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn kick_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        reason: Option<String>,
+    ) -> RepositoryResult<bool> {
+        let exists = self
+            .boards
+            .get(&board_id)
+            .map_or(false, |board| board.sessions.contains_key(&session_id));
+        if !exists {
+            return Ok(false);
+        }
+
+        // Broadcast SessionKicked notification, which only the kicked session acts on. The nil
+        // session ID means it didn't come from any session.
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: Uuid::nil(),
+                message: ServerMessage::SessionKicked { session_id, reason },
+            },
+        );
+
+        // Clean up right away in case the session's handler is already gone
+        self.delete_session_for_board(board_id, session_id).await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_ban_for_board(&self, board_id: Uuid, ban: Ban) -> RepositoryResult<()> {
+        {
+            let mut board = self.boards.entry(board_id).or_default();
+            board.bans.retain(|existing| existing.target != ban.target);
+            board.bans.push(ban.clone());
+        }
+
+        // Broadcast SessionBanned notification, which only the banned sessions act on
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: Uuid::nil(),
+                message: ServerMessage::SessionBanned {
+                    target: ban.target,
+                    reason: ban.reason,
+                },
+            },
+        );
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn remove_ban_for_board(
+        &self,
+        board_id: Uuid,
+        target: BanTarget,
+    ) -> RepositoryResult<bool> {
+        let mut board = match self.boards.get_mut(&board_id) {
+            Some(board) => board,
+            None => return Ok(false),
+        };
+        let ban_count = board.bans.len();
+        board.bans.retain(|ban| ban.target != target);
+        Ok(board.bans.len() < ban_count)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_bans_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Ban>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.bans.clone())
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn touch_session(&self, session_id: Uuid) -> RepositoryResult<()> {
+        self.session_checkins
+            .insert(session_id, Instant::now() + self.session_ttl);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_exists(&self, session_id: Uuid) -> RepositoryResult<bool> {
+        // Expired checkins are forgotten as soon as anyone notices
+        let exists = self
+            .session_checkins
+            .get(&session_id)
+            .map_or(false, |expires_at| *expires_at > Instant::now());
+        if !exists {
+            self.session_checkins.remove(&session_id);
+        }
+
+        Ok(exists)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn stream_expired_sessions(
+        &self,
+    ) -> RepositoryResult<Option<BoxStream<'static, (Uuid, Vec<Uuid>)>>> {
+        // Expired checkins are only noticed when someone looks for them
+        Ok(None)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_session_cursor_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        x: f64,
+        y: f64,
+    ) -> RepositoryResult<()> {
+        // Remember the latest position so it can be included in presence snapshots and shown to
+        // anyone who joins later
+        {
+            let mut board = self.boards.entry(board_id).or_default();
+            if board.cursors().is_none() {
+                board.cursors.clear();
+            }
+            board.cursors.insert(session_id, Cursor { x, y });
+            board.cursors_expire_at =
+                Some(Instant::now() + Duration::from_secs(CURSOR_TTL_SECONDS as u64));
+        }
+
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserCursorChanged { session_id, x, y },
+            },
+        );
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_session_cursor_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        if let Some(mut board) = self.boards.get_mut(&board_id) {
+            board.cursors.remove(&session_id);
+        }
+
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserCursorLeft { session_id },
+            },
+        );
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_session_latency_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        rtt: f64,
+    ) -> RepositoryResult<()> {
+        self.boards
+            .entry(board_id)
+            .or_default()
+            .latencies
+            .insert(session_id, rtt);
+
+        self.publish_presence_message_for_board(
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserLatencyChanged { session_id, rtt },
+            },
+        );
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_latencies_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, f64)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .latencies
+                    .iter()
+                    .map(|(session_id, rtt)| (*session_id, *rtt))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_cursors_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Cursor)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| {
+                board.cursors().map(|cursors| {
+                    cursors
+                        .iter()
+                        .map(|(session_id, cursor)| (*session_id, *cursor))
+                        .collect()
+                })
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_clients_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, ClientInfo)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .session_clients
+                    .iter()
+                    .map(|(session_id, client)| (*session_id, client.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn lock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Uuid> {
+        let holder = {
+            let mut board = self.boards.entry(board_id).or_default();
+
+            // Take or renew the lease only if nobody else holds it, and report the holder either
+            // way
+            match board.lock_holder(object_id) {
+                Some(holder) if holder != session_id => holder,
+                _ => {
+                    let expires_at =
+                        Instant::now() + Duration::from_secs(OBJECT_LOCK_TTL_SECONDS as u64);
+                    board.locks.insert(object_id, (session_id, expires_at));
+                    session_id
+                }
+            }
+        };
+
+        // Broadcast ObjectLocked notification if the lease belongs to this session
+        if holder == session_id {
+            self.publish_presence_message_for_board(
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::ObjectLocked {
+                        id: object_id,
+                        session_id,
+                    },
+                },
+            );
+        }
+
+        Ok(holder)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn unlock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<bool> {
+        // A session can never release somebody else's lease
+        let released = match self.boards.get_mut(&board_id) {
+            Some(mut board) if board.lock_holder(object_id) == Some(session_id) => {
+                board.locks.remove(&object_id);
+                true
+            }
+            _ => false,
+        };
+
+        // Broadcast ObjectUnlocked notification
+        if released {
+            self.publish_presence_message_for_board(
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::ObjectUnlocked {
+                        id: object_id,
+                        session_id,
+                    },
+                },
+            );
+        }
+
+        Ok(released)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_lock_for_board(
+        &self,
+        board_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Option<Uuid>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.lock_holder(object_id)))
+    }
+
+    #[tracing::instrument(skip(self, change), err)]
+    async fn get_conflicting_lock_for_change(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: &Change,
+    ) -> RepositoryResult<Option<Uuid>> {
+        let edited_ids = change
+            .clone()
+            .flatten()
+            .into_iter()
+            .filter(|edit| !matches!(edit, Change::Insert { .. }))
+            .flat_map(|edit| edit.expected_revisions())
+            .map(|(id, _)| id);
+        for id in edited_ids {
+            let holder = self.get_object_lock_for_board(board_id, id).await?;
+            if let Some(holder) = holder.filter(|holder| *holder != session_id) {
+                return Ok(Some(holder));
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_locks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Uuid)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .locks
+                    .keys()
+                    .filter_map(|object_id| Some((*object_id, board.lock_holder(*object_id)?)))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_all_board_ids(&self) -> BoxStream<'static, RepositoryResult<Uuid>> {
+        // A board exists once anything has been published to it, just like a change stream
+        let board_ids = self
+            .boards
+            .iter()
+            .filter(|board| board.last_change_id.is_some())
+            .map(|board| Ok(*board.key()))
+            .collect::<Vec<_>>();
+        Box::pin(stream::iter(board_ids))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_checkpoints(&self) -> RepositoryResult<Vec<(Uuid, usize)>> {
+        Ok(self
+            .boards
+            .iter()
+            .map(|board| (*board.key(), board.changes_after(&board.version()).count()))
+            .filter(|(_, pending)| *pending > 0)
+            .collect())
+    }
+
+    /// Pending changes are counted from the change stream itself, so there's nothing to clear
+    #[tracing::instrument(skip(self), err)]
+    async fn clear_pending_checkpoint(
+        &self,
+        _board_id: Uuid,
+        _count: usize,
+    ) -> RepositoryResult<()> {
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_changes_for_board(
+        &self,
+        board_id: Uuid,
+        count: usize,
+        version: Option<String>,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        let version = version.unwrap_or_else(|| "0".to_string());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+
+        // Subscribe before looking so that a change published in between isn't missed, then wait
+        // up to a second for one to show up. Returning nothing is fine since callers poll.
+        let mut change_receiver = self.change_sender.subscribe();
+        loop {
+            let changes = self
+                .boards
+                .get(&board_id)
+                .map(|board| {
+                    board
+                        .changes_after(&version)
+                        .take(count)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if !changes.is_empty() {
+                return Ok(changes);
+            }
+
+            loop {
+                match tokio::time::timeout_at(deadline, change_receiver.recv()).await {
+                    Err(_) | Ok(Err(RecvError::Closed)) => return Ok(vec![]),
+                    Ok(Err(RecvError::Lagged(_))) => break,
+                    Ok(Ok(changed_board_id)) if changed_board_id == board_id => break,
+                    Ok(Ok(_)) => continue,
+                }
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_change_history_for_board(
+        &self,
+        board_id: Uuid,
+        since: Option<String>,
+        until: Option<String>,
+        session_id: Option<Uuid>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        let board = match self.boards.get(&board_id) {
+            Some(board) => board,
+            None => return Ok(vec![]),
+        };
+
+        // Bounds are inclusive, and a bare timestamp covers every change in that millisecond
+        let start = since
+            .as_deref()
+            .and_then(Repository::parse_stream_id)
+            .unwrap_or((0, 0));
+        let end = until
+            .as_deref()
+            .and_then(|until| {
+                if until.contains('-') {
+                    Repository::parse_stream_id(until)
+                } else {
+                    Some((until.parse().ok()?, u64::MAX))
+                }
+            })
+            .unwrap_or((u64::MAX, u64::MAX));
+
+        // Read the history first, then whatever is only in the change stream after the point
+        // where the history ends
+        let mut last_id = None;
+        let mut changes = Vec::new();
+        for entry in board.history.iter().chain(board.changes.iter()) {
+            if changes.len() >= limit {
+                break;
+            }
+            let id = Repository::parse_stream_id(&entry.version);
+            if id <= last_id {
+                continue;
+            }
+            last_id = id;
+            let in_range = matches!(id, Some(id) if id >= start && id <= end);
+            if in_range && (session_id.is_none() || session_id == Some(entry.session_id)) {
+                changes.push(entry.clone());
+            }
+        }
+
+        Ok(changes)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.changes_after(&version).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    // Bulk-apply a set of changes to the materialized objects of a board, and persist the version
+    // of the latest change to help future readers know where to pick up the stream after reading
+    // the objects. Holding on to the board the whole time makes this atomic.
+    #[tracing::instrument(skip(self, entries), err)]
+    async fn apply_changes_to_board(
+        &self,
+        board_id: Uuid,
+        since_version: &str,
+        entries: Vec<ChangeEntry>,
+    ) -> RepositoryResult<bool> {
+        let version = match entries.last() {
+            Some(entry) => entry.version.clone(),
+            None => return Ok(true),
+        };
+
+        {
+            let mut board = self.boards.entry(board_id).or_default();
+            if board.version() != since_version {
+                return Ok(false);
+            }
+
+            let changes = entries.iter().map(|entry| entry.change.clone());
+            for change in changes.flat_map(Change::flatten) {
+                Self::apply_change(&mut board, change);
+            }
+            for (id, revision) in entries.iter().flat_map(|entry| &entry.revisions) {
+                let checkpointed_revision = board.checkpointed_revisions.entry(*id).or_default();
+                *checkpointed_revision = (*checkpointed_revision).max(*revision);
+            }
+
+            // Keep the changes in the board's history, capped at the configured length. Anything
+            // at or before the newest entry in the history is skipped in case these changes were
+            // already checkpointed once.
+            if self.history_length > 0 {
+                let archived_version = board
+                    .history
+                    .back()
+                    .and_then(|entry| Repository::parse_stream_id(&entry.version));
+                for entry in &entries {
+                    if Repository::parse_stream_id(&entry.version) > archived_version {
+                        board.history.push_back(entry.clone());
+                    }
+                }
+                while board.history.len() > self.history_length {
+                    board.history.pop_front();
+                }
+            }
+
+            // Drop everything before the new version from the change stream that isn't being
+            // retained, keeping the entry at the version itself
+            if let Some(checkpointed_version) = Repository::parse_stream_id(&version) {
+                let nth_newest = self
+                    .change_retention
+                    .entries
+                    .checked_sub(1)
+                    .and_then(|n| board.changes.iter().rev().nth(n))
+                    .and_then(|entry| Repository::parse_stream_id(&entry.version));
+                let min_id = self
+                    .change_retention
+                    .min_id(checkpointed_version, nth_newest);
+                board
+                    .changes
+                    .retain(|entry| Repository::parse_stream_id(&entry.version) >= Some(min_id));
+            }
+
+            // Keep a copy of the board as it is now in its checkpoint history if it's been long
+            // enough since the last one
+            let newest_version = board
+                .checkpoints
+                .back()
+                .map(|checkpoint| &checkpoint.version);
+            if self
+                .checkpoint_history
+                .keeps(&version, newest_version.map(String::as_str))
+            {
+                let mut order = board
+                    .order
+                    .iter()
+                    .map(|(id, index)| (*id, *index))
+                    .collect::<Vec<_>>();
+                order.sort_by(|(_, left), (_, right)| left.total_cmp(right));
+                let checkpoint = BoardSnapshot {
+                    version: version.clone(),
+                    objects: board.objects.clone().unwrap_or_default(),
+                    order,
+                };
+                board.checkpoints.push_back(checkpoint);
+                while board.checkpoints.len() > self.checkpoint_history.length {
+                    board.checkpoints.pop_front();
+                }
+            }
+            board.version = Some(version);
+        }
+
+        self.push_webhook_event(WebhookEvent::ChangeApplied {
+            board_id,
+            changes: entries.into_iter().map(AcceptedChange::from).collect(),
+        });
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn publish_change_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: Change,
+        idempotency_key: Option<String>,
+    ) -> RepositoryResult<PublishOutcome> {
+        let outcome = {
+            // Check the idempotency key and the expected revisions, bump the revisions, and add
+            // the change to the stream all while holding on to the board so that no other change
+            // can sneak in between
+            let mut board = self.boards.entry(board_id).or_default();
+            let now = Instant::now();
+
+            if let Some(idempotency_key) = &idempotency_key {
+                board
+                    .idempotency_keys
+                    .retain(|_, (_, expires_at)| *expires_at > now);
+                if let Some((version, _)) = board.idempotency_keys.get(idempotency_key) {
+                    return Ok(PublishOutcome::Duplicate {
+                        version: version.clone(),
+                    });
+                }
+            }
+
+            let expected_revisions = change.expected_revisions();
+            for (id, expected_revision) in &expected_revisions {
+                let current_revision = board.revisions.get(id).copied().unwrap_or_default();
+                if matches!(expected_revision, Some(expected) if *expected != current_revision) {
+                    return Ok(PublishOutcome::RevisionMismatch {
+                        id: *id,
+                        current_revision,
+                    });
+                }
+            }
+
+            let revisions = expected_revisions
+                .into_iter()
+                .map(|(id, _)| {
+                    let revision = board.revisions.entry(id).or_default();
+                    *revision += 1;
+                    (id, *revision)
+                })
+                .collect::<Vec<_>>();
+
+            // Record the author's username and user ID from the board's sessions along with the
+            // change, and bump the board's last activity in the registry to the time of the change
+            let version = Self::next_change_id(&mut board);
+            let entry = ChangeEntry {
+                version: version.clone(),
+                session_id,
+                username: board.sessions.get(&session_id).cloned(),
+                user_id: board.session_users.get(&session_id).cloned(),
+                revisions: revisions.clone(),
+                change,
+            };
+            board.last_activity = Some(entry.timestamp() as i64);
+            board.changes.push_back(entry);
+
+            if let Some(idempotency_key) = idempotency_key {
+                let expires_at = now + Duration::from_secs(IDEMPOTENCY_TTL_SECONDS as u64);
+                board
+                    .idempotency_keys
+                    .insert(idempotency_key, (version.clone(), expires_at));
+            }
+
+            PublishOutcome::Accepted { version, revisions }
+        };
+
+        // Wake up anyone waiting on the board's changes. Nobody waiting isn't an error.
+        let _ = self.change_sender.send(board_id);
+
+        Ok(outcome)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_revisions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .revisions
+                    .iter()
+                    .map(|(id, revision)| (*id, *revision))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpointed_revisions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .checkpointed_revisions
+                    .iter()
+                    .map(|(id, revision)| (*id, *revision))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.version())
+            .unwrap_or_else(|| "0".to_string()))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_latest_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.changes.back().map(|entry| entry.version.clone()))
+            .unwrap_or_else(|| "0".to_string()))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_version_available_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<bool> {
+        let requested_version = match Repository::parse_stream_id(version) {
+            Some(requested_version) => requested_version,
+            None => return Ok(false),
+        };
+
+        // Checkpointing only drops entries from the front of the change stream and never past the
+        // checkpointed version, so anything at or after the oldest retained entry or the
+        // checkpointed version can still be read from it
+        let oldest_version = self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.changes.front().map(|entry| entry.version.clone()));
+        let checkpointed_version = self.get_version_for_board(board_id).await?;
+        Ok([oldest_version, Some(checkpointed_version)]
+            .iter()
+            .flatten()
+            .filter_map(|version| Repository::parse_stream_id(version))
+            .any(|available_version| requested_version >= available_version))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.objects.as_ref().map(BTreeMap::len))
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_change_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.changes_after(&board.version()).count())
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.sessions.len())
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn join_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<usize> {
+        let mut board = self.boards.entry(board_id).or_default();
+        let position = match board.waiting.iter().position(|id| *id == session_id) {
+            Some(index) => index + 1,
+            None => {
+                board.waiting.push(session_id);
+                board.waiting.len()
+            }
+        };
+        Ok(position)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn leave_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        if let Some(mut board) = self.boards.get_mut(&board_id) {
+            board.waiting.retain(|id| *id != session_id);
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_waiting_sessions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.waiting.clone())
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_last_activity_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Option<DateTime<Utc>>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.last_activity)
+            .map(|last_activity| Utc.timestamp_millis(last_activity)))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_memory_usage_for_board(&self, board_id: Uuid) -> RepositoryResult<u64> {
+        let board = match self.boards.get(&board_id) {
+            Some(board) => board,
+            None => return Ok(0),
+        };
+
+        // There's no way to ask how much memory a value takes up, so go by how big the objects,
+        // changes, checkpoints, and trash are as JSON, which is close enough to compare boards
+        let mut usage = serde_json::to_vec(&board.objects)?.len();
+        for entry in board.changes.iter().chain(board.history.iter()) {
+            usage += serde_json::to_vec(&entry.change)?.len();
+        }
+        for checkpoint in &board.checkpoints {
+            usage += serde_json::to_vec(&checkpoint.objects)?.len();
+        }
+        usage += serde_json::to_vec(&board.trash)?.len();
+
+        Ok(usage as u64)
+    }
+
+    fn get_pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    fn get_presence_stats(&self) -> PresenceStats {
+        self.presence.stats()
+    }
+
+    fn watch_degraded(&self) -> Option<WatchReceiver<bool>> {
+        None
+    }
+
+    fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpoint_versions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<String>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .checkpoints
+                    .iter()
+                    .rev()
+                    .map(|checkpoint| checkpoint.version.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpoint_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<Option<BoardSnapshot>> {
+        Ok(self.boards.get(&board_id).and_then(|board| {
+            board
+                .checkpoints
+                .iter()
+                .find(|checkpoint| checkpoint.version == version)
+                .cloned()
+        }))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_trash_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, TrashedObject)>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| {
+                board.trash().map(|trash| {
+                    trash
+                        .iter()
+                        .map(|(id, trashed)| (*id, trashed.clone()))
+                        .collect()
+                })
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_order_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, f64)>> {
+        let mut order = self
+            .boards
+            .get(&board_id)
+            .map(|board| {
+                board
+                    .order
+                    .iter()
+                    .map(|(id, index)| (*id, *index))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        // Back to front, the way the sorted set comes out of Redis
+        order.sort_by(|(a_id, a_index), (b_id, b_index)| {
+            a_index.total_cmp(b_index).then(a_id.cmp(b_id))
+        });
+
+        Ok(order)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_objects_in_rect(
+        &self,
+        board_id: Uuid,
+        rect: Rect,
+    ) -> RepositoryResult<Vec<(Uuid, JsonObject)>> {
+        // Boards in memory are small enough that checking every object does the job of an index
+        Ok(self
+            .boards
+            .get(&board_id)
+            .and_then(|board| {
+                let objects = board.objects.as_ref()?;
+                Some(
+                    objects
+                        .iter()
+                        .filter(|(_, object)| {
+                            matches!(Rect::of_object(object), Some(bounds) if bounds.intersects(&rect))
+                        })
+                        .map(|(id, object)| (*id, object.clone()))
+                        .collect(),
+                )
+            })
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn search_objects(
+        &self,
+        terms: &BTreeSet<String>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<SearchHit>> {
+        // Objects in memory don't remember when they changed, so boards that were active most
+        // recently come first instead
+        let mut hits = self
+            .boards
+            .iter()
+            .flat_map(|board| {
+                let board_id = *board.key();
+                let last_activity = board.last_activity.unwrap_or_default();
+                board
+                    .objects
+                    .iter()
+                    .flatten()
+                    .filter(|(_, object)| search::matches(object, terms))
+                    .map(|(object_id, object)| {
+                        let hit = SearchHit {
+                            board_id,
+                            object_id: *object_id,
+                            object: object.clone(),
+                        };
+                        (last_activity, hit)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by_key(|(last_activity, _)| std::cmp::Reverse(*last_activity));
+        Ok(hits.into_iter().take(limit).map(|(_, hit)| hit).collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_object_chunks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, RepositoryResult<Vec<(Uuid, JsonObject)>>> {
+        // Copy the objects out all at once so that the board isn't held while the chunks are sent
+        let entries = self
+            .boards
+            .get(&board_id)
+            .and_then(|board| board.objects.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let chunks = entries
+            .chunks(100)
+            .map(|chunk| Ok(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        Box::pin(stream::iter(chunks))
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn stream_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> BoxStream<'static, ChangeEntry> {
+        let repo = self.clone();
+        Box::pin(stream! {
+            let mut version = version;
+            loop {
+                let changes = match repo
+                    .get_changes_for_board(board_id, 100, Some(version.clone()))
+                    .await
+                {
+                    Ok(changes) => changes,
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                for entry in changes {
+                    version = entry.version.clone();
+                    yield entry;
+                }
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_presence_messages_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, PresenceMessage> {
+        self.presence.stream_for_board(board_id)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn pop_webhook_event(&self) -> RepositoryResult<Option<WebhookEvent>> {
+        let mut webhook_receiver = self.webhook_receiver.lock().await;
+        Ok(
+            tokio::time::timeout(Duration::from_secs(1), webhook_receiver.recv())
+                .await
+                .ok()
+                .flatten(),
+        )
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_webhooks_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<String>> {
+        Ok(self
+            .boards
+            .get(&board_id)
+            .map(|board| board.webhooks.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_webhook_for_board(&self, board_id: Uuid, url: String) -> RepositoryResult<()> {
+        self.boards
+            .entry(board_id)
+            .or_default()
+            .webhooks
+            .insert(url);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_webhook_for_board(
+        &self,
+        board_id: Uuid,
+        url: String,
+    ) -> RepositoryResult<bool> {
+        Ok(self
+            .boards
+            .get_mut(&board_id)
+            .map_or(false, |mut board| board.webhooks.remove(&url)))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_api_key(&self, hash: &str) -> RepositoryResult<Option<ApiKey>> {
+        Ok(self.api_keys.get(hash).map(|api_key| api_key.clone()))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_api_keys(&self) -> RepositoryResult<Vec<(String, ApiKey)>> {
+        Ok(self
+            .api_keys
+            .iter()
+            .map(|api_key| (api_key.key().clone(), api_key.value().clone()))
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_api_key(&self, hash: &str, api_key: ApiKey) -> RepositoryResult<()> {
+        self.api_keys.insert(hash.to_string(), api_key);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_api_key(&self, hash: &str) -> RepositoryResult<bool> {
+        Ok(self.api_keys.remove(hash).is_some())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_share_secret(&self) -> RepositoryResult<String> {
+        // There's only ever one server to agree with, so the secret is made up at startup
+        Ok(self.share_secret.clone())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn acquire_lock(
+        &self,
+        name: &str,
+        holder: Uuid,
+        ttl: Duration,
+    ) -> RepositoryResult<bool> {
+        let now = Instant::now();
+        let mut lock = self
+            .task_locks
+            .entry(name.to_string())
+            .or_insert((holder, now));
+        if lock.0 != holder && lock.1 > now {
+            return Ok(false);
+        }
+        *lock = (holder, now + ttl);
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn release_lock(&self, name: &str, holder: Uuid) -> RepositoryResult<()> {
+        self.task_locks
+            .remove_if(name, |_, (lock_holder, _)| *lock_holder == holder);
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_idle_board_ids(&self, cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>> {
+        Ok(self
+            .boards
+            .iter()
+            .filter(|board| {
+                matches!(board.last_activity, Some(last_activity) if last_activity < cutoff.timestamp_millis())
+            })
+            .map(|board| *board.key())
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_inactive_board_ids(&self, _cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>> {
+        // Nothing is ever archived, so there's never anything to archive
+        Ok(vec![])
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn archive_board(&self, _board_id: Uuid, _version: &str) -> RepositoryResult<bool> {
+        Ok(false)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_board_archived(&self, _board_id: Uuid) -> RepositoryResult<bool> {
+        Ok(false)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn restore_board(&self, _board_id: Uuid) -> RepositoryResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store_conformance;
+
+    fn repo() -> Repository {
+        Repository::new(MemoryRepository::new(
+            0,
+            CheckpointHistory::default(),
+            ChangeRetention::default(),
+            Duration::from_secs(60),
+            100,
+        ))
+    }
+
+    #[tokio::test]
+    async fn publishes_and_checkpoints_changes() {
+        store_conformance::publishes_and_checkpoints_changes(repo()).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_revisions() {
+        store_conformance::keeps_revisions(repo()).await;
+    }
+
+    #[tokio::test]
+    async fn checks_expected_revisions() {
+        store_conformance::checks_expected_revisions(repo()).await;
+    }
+
+    #[tokio::test]
+    async fn skips_duplicate_changes() {
+        store_conformance::skips_duplicate_changes(repo()).await;
+    }
+
+    #[tokio::test]
+    async fn keeps_sessions() {
+        store_conformance::keeps_sessions(repo()).await;
+    }
+
+    #[tokio::test]
+    async fn lists_and_deletes_boards() {
+        store_conformance::lists_and_deletes_boards(repo()).await;
+    }
+}
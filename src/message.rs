@@ -1,33 +1,332 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{Map as JsonMap, Value as JsonValue};
+use std::time::Duration;
 use uuid::Uuid;
 
-use crate::change::Change;
+use crate::change::{Change, ChangeEntry};
+use crate::cursor_publisher::CURSOR_PUBLISHES_PER_SECOND;
+use crate::rate_limit::{CHANGES_PER_SECOND, CURSOR_UPDATES_PER_SECOND};
+use crate::repository::{BanTarget, BoardQuotas, OBJECT_LOCK_TTL_SECONDS};
+use crate::share::Role;
+use crate::spatial::Rect;
 
 pub type JsonObject = JsonMap<String, JsonValue>;
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
-    ClientReady { username: String },
+    /// Guests can leave out the username to have one made up for them, which `ServerReady` says
+    ClientReady {
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        own_changes: OwnChanges,
+    },
+    /// Sent in place of `ClientReady` by a client reconnecting with a session it already had,
+    /// which keeps the session on the board if it hasn't expired yet and picks the change stream
+    /// back up after the last version the client saw
+    ResumeSession {
+        username: String,
+        from_version: String,
+        #[serde(default)]
+        own_changes: OwnChanges,
+    },
     StartSnapshot,
-    ApplyChange { change: Change },
-    CursorChanged { x: f64, y: f64 },
+    /// Start a snapshot with the objects in the part of the board the client is looking at
+    SnapshotViewport {
+        viewport: Rect,
+    },
+    Resync {
+        from_version: String,
+    },
+    SubscribeBoard {
+        board_id: Uuid,
+    },
+    UnsubscribeBoard {
+        board_id: Uuid,
+    },
+    ApplyChange {
+        change: Change,
+        idempotency_key: Option<String>,
+    },
+    CursorChanged {
+        x: f64,
+        y: f64,
+    },
     CursorLeft,
-    Ping,
+    LockObject {
+        id: Uuid,
+    },
+    UnlockObject {
+        id: Uuid,
+    },
+    RequestPresence,
+    /// `client_time` is echoed back in the Pong so the client can measure the round trip, and
+    /// `rtt` is the client's most recent measurement in milliseconds
+    Ping {
+        client_time: Option<f64>,
+        rtt: Option<f64>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
-    ServerReady,
-    SnapshotChunk { entries: Vec<(Uuid, JsonObject)> },
-    SnapshotFinished { version: Option<String> },
-    ChangeAccepted { change: Change, session_id: Uuid },
-    UserJoined { session_id: Uuid, username: String },
-    UserLeft { session_id: Uuid },
-    UserCursorChanged { session_id: Uuid, x: f64, y: f64 },
-    UserCursorLeft { session_id: Uuid },
+    ServerReady {
+        capabilities: Capabilities,
+        /// Viewers can watch the board but their changes and locks are refused
+        role: Role,
+        /// The name the session ended up with, which is trimmed and may be numbered to tell it
+        /// apart from someone else's
+        username: String,
+    },
+    SnapshotChunk {
+        entries: Vec<(Uuid, JsonObject)>,
+    },
+    SnapshotOrder {
+        order: Vec<(Uuid, f64)>,
+    },
+    SnapshotRevisions {
+        revisions: Vec<(Uuid, u64)>,
+    },
+    SnapshotFinished {
+        version: Option<String>,
+        object_count: usize,
+        hash: String,
+    },
+    ResyncStarted {
+        version: String,
+    },
+    ChangesAccepted {
+        changes: Vec<AcceptedChange>,
+    },
+    /// Sent in place of `ChangesAccepted` for the session's own changes when it joined asking for
+    /// `Acknowledge`, in the same order relative to everyone else's
+    ChangesAcknowledged {
+        changes: Vec<ChangeAck>,
+    },
+    UserJoined {
+        session_id: Uuid,
+        username: String,
+    },
+    UserLeft {
+        session_id: Uuid,
+    },
+    UserCursorChanged {
+        session_id: Uuid,
+        x: f64,
+        y: f64,
+    },
+    UserCursorLeft {
+        session_id: Uuid,
+    },
+    ObjectLocked {
+        id: Uuid,
+        session_id: Uuid,
+    },
+    ObjectUnlocked {
+        id: Uuid,
+        session_id: Uuid,
+    },
+    ChangeRejected {
+        change: Change,
+        reason: RejectionReason,
+    },
+    PresenceSnapshot {
+        users: Vec<(Uuid, String)>,
+        cursors: Vec<(Uuid, Cursor)>,
+        locks: Vec<(Uuid, Uuid)>,
+        latencies: Vec<(Uuid, f64)>,
+    },
+    Pong {
+        client_time: Option<f64>,
+        server_time: u64,
+    },
+    BoardDeleted,
+    /// Sent when the session has fallen too far behind the board's changes to be sent the ones it
+    /// missed, or some of them are already gone, after which nothing else is sent from the board
+    /// until the client starts over with `StartSnapshot`. For a board followed with
+    /// `SubscribeBoard`, the client subscribes again instead.
+    ResyncRequired,
+    UserLatencyChanged {
+        session_id: Uuid,
+        rtt: f64,
+    },
+    /// Sent to a session right before it's forcibly disconnected by an admin, or because it was
+    /// banned from the board
+    SessionKicked {
+        session_id: Uuid,
+        reason: Option<String>,
+    },
+    /// Passed between servers when a ban is added, so that any session it matches is disconnected.
+    /// The banned session hears about it as `SessionKicked` and nobody else hears about it at all.
+    SessionBanned {
+        target: BanTarget,
+        reason: Option<String>,
+    },
+    /// Sent when the session is going too fast. `change` is the change that was dropped, or empty
+    /// if cursor updates are being dropped instead.
+    RateLimited {
+        change: Option<Change>,
+        retry_after_ms: u64,
+    },
+    /// Sent in place of `ServerReady` when the board already has as many sessions as it's allowed,
+    /// and again whenever the session moves up in line. `ServerReady` follows once there's a spot.
+    WaitingRoom {
+        position: usize,
+    },
+    /// Sent in place of `ChangesAccepted` when an admin has frozen the board, which refuses every
+    /// change until it's unfrozen
+    BoardReadOnly {
+        change: Change,
+    },
+    /// Sent when the client hasn't sent anything besides heartbeats in a while. Unless it does
+    /// within `closes_in_ms`, the server sends `IdleTimeout` and closes the connection.
+    IdleWarning {
+        closes_in_ms: u64,
+    },
+    /// Sent right before the server closes the connection because the client was idle for too
+    /// long, after which the session is gone from the board
+    IdleTimeout,
+    /// Sent right before the server closes the connection because it's shutting down. Changes
+    /// the server already received have been published, and the client should reconnect after
+    /// `retry_after_ms`, likely to a different server.
+    ServerShutdown {
+        retry_after_ms: u64,
+    },
+    /// Sent when the store can't be reached. Anything the client asks for that needs the store
+    /// fails with an `Unavailable` error until `Recovered` is sent, though cursor movements are
+    /// held onto and published once it is.
+    Degraded,
+    /// Sent when the store can be reached again after `Degraded`
+    Recovered,
+    /// Sent when something the client asked for couldn't be done because of trouble with the
+    /// store, or because it was sent out of order. `retryable` is whether asking again might
+    /// work.
+    Error {
+        code: ErrorCode,
+        retryable: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Cursor {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// A change that has been added to a board's change stream, along with who made it and when
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcceptedChange {
+    pub change: Change,
+    pub session_id: Uuid,
+    pub username: Option<String>,
+    pub user_id: Option<String>,
+    pub version: String,
+    pub revisions: Vec<(Uuid, u64)>,
+    pub timestamp: u64,
+}
+
+impl From<ChangeEntry> for AcceptedChange {
+    fn from(entry: ChangeEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp(),
+            change: entry.change,
+            session_id: entry.session_id,
+            username: entry.username,
+            user_id: entry.user_id,
+            version: entry.version,
+            revisions: entry.revisions,
+        }
+    }
+}
+
+/// What a session wants to hear about the changes it made itself once they're accepted, which it
+/// already knows the contents of
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OwnChanges {
+    /// Send them back in full like everyone else's
+    #[default]
+    Echo,
+    /// Send just their versions and revisions in `ChangesAcknowledged`
+    Acknowledge,
+    /// Don't send them at all. The session's last version only moves past them once someone
+    /// else's change arrives.
+    Skip,
+}
+
+/// The parts of an accepted change that its own session doesn't already know
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeAck {
+    pub version: String,
+    pub revisions: Vec<(Uuid, u64)>,
+    pub timestamp: u64,
+}
+
+impl From<ChangeEntry> for ChangeAck {
+    fn from(entry: ChangeEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp(),
+            version: entry.version,
+            revisions: entry.revisions,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum RejectionReason {
+    ObjectLocked {
+        session_id: Uuid,
+    },
+    RevisionMismatch {
+        id: Uuid,
+        current_revision: u64,
+    },
+    ReadOnly,
+    /// A restored object isn't in the board's trash, because it was never deleted, its delete
+    /// hasn't been checkpointed yet, or the trash expired
+    NotInTrash {
+        id: Uuid,
+    },
+    /// An update sets a key that isn't allowed, see `Change::is_valid_key`
+    InvalidKey {
+        key: String,
+    },
+    /// The board already has as many objects as it's allowed
+    TooManyObjects {
+        max_objects: usize,
+    },
+    /// The change would write more JSON into one object than it's allowed
+    ObjectTooLarge {
+        id: Uuid,
+        size: usize,
+        max_size: usize,
+    },
+}
+
+/// What kind of trouble the server ran into, without the details of the store behind it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// The board, or something on it, doesn't exist
+    NotFound,
+    /// Something else changed the board first
+    Conflict,
+    /// The store is too busy or can't be reached right now
+    Unavailable,
+    Internal,
+    /// The session isn't at the point where it can send that yet, or already sent it once, like a
+    /// change before the snapshot or a second `ClientReady`
+    OutOfOrder,
+    /// The username is empty, too long, or not allowed, so the client has to join with another
+    InvalidUsername,
+    /// The message was bigger than `max_message_size`, so the connection is closed
+    MessageTooLarge,
+    /// `ResumeSession` named a session that belongs to another user, so the client has to connect
+    /// again with a session ID of its own
+    SessionTaken,
+    /// The connection isn't allowed on the board it subscribed to
+    Forbidden,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,3 +334,40 @@ pub struct PresenceMessage {
     pub source_session: Uuid,
     pub message: ServerMessage,
 }
+
+/// The limits and features of this server, so that clients don't have to hardcode them. Limits
+/// that aren't enforced are left empty.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    pub max_message_size: Option<usize>,
+    pub max_objects_per_board: Option<usize>,
+    pub max_sessions_per_board: Option<usize>,
+    pub max_object_bytes: Option<usize>,
+    pub supported_change_types: Vec<String>,
+    /// How often the client should send something to keep its session alive
+    pub heartbeat_interval_seconds: usize,
+    pub object_lock_ttl_seconds: usize,
+    pub cursor_publishes_per_second: u64,
+    /// How many changes and cursor updates a session can send per second on average before the
+    /// server starts dropping them
+    pub max_changes_per_second: f64,
+    pub max_cursor_updates_per_second: f64,
+}
+
+impl Capabilities {
+    pub fn current(quotas: &BoardQuotas, session_ttl: Duration) -> Self {
+        Self {
+            max_message_size: quotas.max_message_bytes,
+            max_objects_per_board: quotas.max_objects,
+            max_sessions_per_board: quotas.max_sessions,
+            max_object_bytes: quotas.max_object_bytes,
+            supported_change_types: Change::TYPES.iter().map(ToString::to_string).collect(),
+            // Leave plenty of room before the session would expire
+            heartbeat_interval_seconds: (session_ttl.as_secs() * 2 / 3) as usize,
+            object_lock_ttl_seconds: OBJECT_LOCK_TTL_SECONDS,
+            cursor_publishes_per_second: CURSOR_PUBLISHES_PER_SECOND,
+            max_changes_per_second: CHANGES_PER_SECOND,
+            max_cursor_updates_per_second: CURSOR_UPDATES_PER_SECOND,
+        }
+    }
+}
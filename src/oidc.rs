@@ -0,0 +1,228 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Extension, Query},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
+};
+use reqwest::Url;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::api::ApiError;
+use crate::auth::{self, TOKEN_COOKIE};
+
+/// Remembers the state sent to the provider, and where to go afterward, until the callback
+const STATE_COOKIE: &str = "redboard_oidc_state";
+
+/// The parts of the provider's discovery document that the login flow needs
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProviderMetadata {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The OIDC client for the login routes, which is empty when OIDC login is turned off
+#[derive(Clone)]
+pub struct OidcAuth(pub Option<OidcClient>);
+
+/// Signs users in with an OIDC provider using the authorization code flow
+#[derive(Clone)]
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: String,
+    metadata: ProviderMetadata,
+    http_client: reqwest::Client,
+}
+
+impl OidcClient {
+    /// Look up the provider's endpoints from its discovery document
+    #[tracing::instrument(skip(client_secret), err)]
+    pub async fn discover(
+        issuer: String,
+        client_id: String,
+        client_secret: String,
+        redirect_url: String,
+    ) -> Result<Self> {
+        let http_client = reqwest::Client::new();
+        let body = http_client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(Self {
+            issuer,
+            client_id,
+            client_secret,
+            redirect_url,
+            metadata: serde_json::from_slice(&body)?,
+            http_client,
+        })
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn jwks_uri(&self) -> &str {
+        &self.metadata.jwks_uri
+    }
+
+    fn authorization_url(&self, state: &str) -> Result<Url> {
+        Ok(Url::parse_with_params(
+            &self.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", "openid profile"),
+                ("state", state),
+            ],
+        )?)
+    }
+
+    /// Trade the code from the callback for the user's ID token
+    #[tracing::instrument(skip_all, err)]
+    async fn exchange_code(&self, code: &str) -> Result<String> {
+        let body = self
+            .http_client
+            .post(&self.metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(serde_json::from_slice::<TokenResponse>(&body)?.id_token)
+    }
+
+    /// Cookies only get the Secure flag when the service is served over HTTPS, so that logging in
+    /// still works locally
+    fn cookie(&self, name: &str, value: &str, max_age: Option<u64>) -> String {
+        let mut cookie = format!("{name}={value}; Path=/; HttpOnly; SameSite=Lax");
+        if let Some(max_age) = max_age {
+            cookie.push_str(&format!("; Max-Age={max_age}"));
+        }
+        if self.redirect_url.starts_with("https://") {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    return_to: Option<String>,
+}
+
+/// Send the user off to the provider to sign in
+#[tracing::instrument(skip_all)]
+pub async fn login(
+    Extension(OidcAuth(oidc)): Extension<OidcAuth>,
+    Query(query): Query<LoginQuery>,
+) -> Result<Response, ApiError> {
+    let oidc = oidc.ok_or(ApiError::NotFound)?;
+
+    // Only come back to paths on this server, and only ones that are safe to put in a cookie
+    let return_to = query
+        .return_to
+        .filter(|return_to| {
+            return_to.starts_with('/')
+                && !return_to.starts_with("//")
+                && !return_to
+                    .chars()
+                    .any(|c| c.is_whitespace() || matches!(c, ';' | ',' | '"' | '\\'))
+        })
+        .unwrap_or_else(|| "/".to_string());
+
+    let state = Uuid::new_v4().as_simple().to_string();
+    let authorization_url = oidc.authorization_url(&state)?;
+    let state_cookie = oidc.cookie(STATE_COOKIE, &format!("{state}:{return_to}"), Some(600));
+
+    Ok((
+        [(header::SET_COOKIE, state_cookie)],
+        Redirect::to(authorization_url.as_str()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: String,
+    state: String,
+}
+
+/// Finish signing in by trading the code for an ID token and keeping it in a cookie, which the
+/// websocket and everything else accept in place of an `Authorization` header
+#[tracing::instrument(skip_all)]
+pub async fn callback(
+    Extension(OidcAuth(oidc)): Extension<OidcAuth>,
+    Query(query): Query<CallbackQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let oidc = oidc.ok_or(ApiError::NotFound)?;
+
+    // The state has to match the one this browser was sent off with, so that nobody can sign
+    // someone else in as themselves
+    let (state, return_to) = auth::cookie(&headers, STATE_COOKIE)
+        .and_then(|value| value.split_once(':'))
+        .ok_or(ApiError::Unauthorized)?;
+    if state != query.state {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let id_token = oidc
+        .exchange_code(&query.code)
+        .await
+        .map_err(|error| ApiError::Internal(anyhow!("Could not finish signing in: {error}")))?;
+
+    // Both cookies have to be appended since the array form of headers would overwrite one with
+    // the other
+    let mut cookies = HeaderMap::new();
+    cookies.append(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&oidc.cookie(TOKEN_COOKIE, &id_token, None))?,
+    );
+    cookies.append(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&oidc.cookie(STATE_COOKIE, "", Some(0)))?,
+    );
+
+    Ok((cookies, Redirect::to(return_to)).into_response())
+}
+
+/// Forget the user's token. They stay signed in with the provider itself.
+#[tracing::instrument(skip_all)]
+pub async fn logout(Extension(OidcAuth(oidc)): Extension<OidcAuth>) -> Result<Response, ApiError> {
+    let oidc = oidc.ok_or(ApiError::NotFound)?;
+
+    Ok((
+        [(header::SET_COOKIE, oidc.cookie(TOKEN_COOKIE, "", Some(0)))],
+        Redirect::to("/"),
+    )
+        .into_response())
+}
@@ -0,0 +1,122 @@
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::admin;
+use crate::api;
+use crate::auth::Scope;
+use crate::board_meta::{BoardMeta, BoardMetaPatch, BoardSummary};
+use crate::repository::{Ban, BanTarget, BoardInconsistency};
+use crate::search::SearchHit;
+use crate::share::Role;
+use crate::snapshot::BoardSnapshot;
+
+/// The OpenAPI description of the REST routes, served at `/api/openapi.json`. The websocket,
+/// GraphQL and gRPC APIs describe themselves elsewhere.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        api::create_board,
+        api::list_boards,
+        api::search,
+        api::delete_board,
+        api::get_snapshot,
+        api::export_svg,
+        api::export_png,
+        api::duplicate_board,
+        api::get_board_meta,
+        api::update_board_meta,
+        api::get_board_stats,
+        api::board_events,
+        api::get_change_history,
+        api::get_trash,
+        api::restore_object,
+        api::share_board,
+        admin::list_sessions,
+        admin::kick_session,
+        admin::list_bans,
+        admin::add_ban,
+        admin::remove_ban,
+        admin::set_board_frozen,
+        admin::set_board_reliable_presence,
+        admin::checkpoint_board,
+        admin::list_checkpoints,
+        admin::restore_checkpoint,
+        admin::verify_board,
+        admin::list_webhooks,
+        admin::add_webhook,
+        admin::delete_webhook,
+        admin::list_api_keys,
+        admin::create_api_key,
+        admin::delete_api_key,
+        admin::export_boards,
+        admin::get_pool_stats,
+        admin::get_checkpointer_stats,
+        admin::get_presence_stats,
+    ),
+    components(schemas(
+        api::CreateBoardRequest,
+        api::CreateBoardResponse,
+        api::ListBoardsResponse,
+        api::SearchResponse,
+        SearchHit,
+        api::DuplicateBoardResponse,
+        api::BoardStatsResponse,
+        api::ChangeHistoryResponse,
+        api::TrashedObjectSummary,
+        api::TrashResponse,
+        api::RestoreObjectResponse,
+        api::ShareResponse,
+        Role,
+        admin::SessionSummary,
+        admin::ListSessionsResponse,
+        admin::ListBansResponse,
+        admin::RemoveBanRequest,
+        admin::FreezeBoardRequest,
+        admin::ReliablePresenceRequest,
+        admin::CheckpointResponse,
+        admin::ListCheckpointsResponse,
+        admin::RestoreCheckpointResponse,
+        admin::VerifyBoardResponse,
+        BoardInconsistency,
+        Ban,
+        BanTarget,
+        admin::WebhookRequest,
+        admin::ListWebhooksResponse,
+        admin::CreateApiKeyRequest,
+        admin::CreateApiKeyResponse,
+        admin::ApiKeySummary,
+        admin::ListApiKeysResponse,
+        admin::PoolStatsResponse,
+        admin::CheckpointerStatsResponse,
+        admin::PresenceStatsResponse,
+        admin::BoardPresenceStats,
+        Scope,
+        BoardMeta,
+        BoardMetaPatch,
+        BoardSummary,
+        BoardSnapshot,
+    )),
+    modifiers(&SecurityAddon),
+    tags(
+        (name = "boards", description = "Create, read and export boards"),
+        (name = "admin", description = "Manage boards, requires the admin token"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Describes the bearer token that the admin routes expect, which is either the admin token or an
+/// API key with the admin scope
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
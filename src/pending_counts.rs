@@ -0,0 +1,179 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::change::ChangeEntry;
+use crate::repository::Repository;
+
+/// How many boards' pending changes are kept track of at once, after which the one checked least
+/// recently is dropped to make room
+const MAX_BOARDS: usize = 1024;
+
+/// How many objects the changes waiting to be checkpointed add to each board, so that checking a
+/// change against the board's object quota only reads the changes published since the last check
+/// instead of every pending change. A change is forgotten once the board's checkpointed version
+/// moves past it, since from then on it's part of the board's object count.
+#[derive(Clone, Default)]
+pub struct PendingCounts {
+    boards: Arc<Mutex<HashMap<Uuid, PendingChanges>>>,
+}
+
+struct PendingChanges {
+    /// The checkpointed version the board was last checked at
+    version: String,
+    /// The version of the latest pending change that's been counted
+    counted: String,
+    /// The version and object count delta of every counted change that adds or removes objects
+    deltas: VecDeque<(String, i64)>,
+    total: i64,
+    checked_at: Instant,
+}
+
+impl PendingCounts {
+    /// Get how many objects the board's pending changes add up to so far, along with the version
+    /// that changes haven't been counted after, given the board's checkpointed version
+    pub fn get(&self, board_id: Uuid, version: &str) -> (String, i64) {
+        let mut boards = self.lock();
+        match boards.get_mut(&board_id) {
+            Some(pending) => {
+                pending.checkpointed_at(version);
+                (pending.counted.clone(), pending.total)
+            }
+            None => (version.to_owned(), 0),
+        }
+    }
+
+    /// Count pending changes that were read after the version returned by `get`. Any that were
+    /// already counted by a check that ran at the same time are skipped.
+    pub fn add(&self, board_id: Uuid, version: &str, entries: &[ChangeEntry]) {
+        let mut boards = self.lock();
+        if boards.len() >= MAX_BOARDS && !boards.contains_key(&board_id) {
+            let oldest = boards
+                .iter()
+                .min_by_key(|(_, pending)| pending.checked_at)
+                .map(|(board_id, _)| *board_id);
+            if let Some(oldest) = oldest {
+                boards.remove(&oldest);
+            }
+        }
+
+        let pending = boards
+            .entry(board_id)
+            .or_insert_with(|| PendingChanges::new(version));
+        pending.checkpointed_at(version);
+        for entry in entries {
+            if !is_after(&entry.version, &pending.counted) {
+                continue;
+            }
+            let delta = entry.change.object_count_delta();
+            if delta != 0 {
+                pending.deltas.push_back((entry.version.clone(), delta));
+                pending.total += delta;
+            }
+            pending.counted = entry.version.clone();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, PendingChanges>> {
+        self.boards
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl PendingChanges {
+    fn new(version: &str) -> Self {
+        Self {
+            version: version.to_owned(),
+            counted: version.to_owned(),
+            deltas: VecDeque::new(),
+            total: 0,
+            checked_at: Instant::now(),
+        }
+    }
+
+    /// Forget the changes that have been checkpointed since the last check. A version earlier than
+    /// the last one means the board's changes started over, so everything counted is forgotten.
+    fn checkpointed_at(&mut self, version: &str) {
+        self.checked_at = Instant::now();
+        if is_after(&self.version, version) {
+            *self = Self::new(version);
+            return;
+        }
+
+        self.version = version.to_owned();
+        while let Some((delta_version, delta)) = self.deltas.front() {
+            if is_after(delta_version, version) {
+                break;
+            }
+            self.total -= delta;
+            self.deltas.pop_front();
+        }
+        if is_after(version, &self.counted) {
+            self.counted = version.to_owned();
+        }
+    }
+}
+
+fn is_after(version: &str, other: &str) -> bool {
+    Repository::parse_stream_id(version) > Repository::parse_stream_id(other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change::Change;
+
+    fn entry(version: &str, change: Change) -> ChangeEntry {
+        ChangeEntry {
+            version: version.to_owned(),
+            session_id: Uuid::nil(),
+            username: None,
+            user_id: None,
+            revisions: vec![],
+            change,
+        }
+    }
+
+    fn insert() -> Change {
+        Change::Insert {
+            id: Uuid::new_v4(),
+            object: Default::default(),
+        }
+    }
+
+    #[test]
+    fn forgets_changes_once_checkpointed() {
+        let counts = PendingCounts::default();
+        let board_id = Uuid::new_v4();
+        assert_eq!(counts.get(board_id, "0"), ("0".to_owned(), 0));
+
+        counts.add(
+            board_id,
+            "0",
+            &[
+                entry("1-0", insert()),
+                entry("2-0", insert()),
+                entry("3-0", Change::Delete { id: Uuid::new_v4() }),
+            ],
+        );
+        assert_eq!(counts.get(board_id, "0"), ("3-0".to_owned(), 1));
+
+        // Counting the same changes again doesn't count them twice
+        counts.add(board_id, "0", &[entry("3-0", insert())]);
+        assert_eq!(counts.get(board_id, "0"), ("3-0".to_owned(), 1));
+
+        assert_eq!(counts.get(board_id, "2-0"), ("3-0".to_owned(), -1));
+        assert_eq!(counts.get(board_id, "4-0"), ("4-0".to_owned(), 0));
+    }
+
+    #[test]
+    fn starts_over_when_the_version_goes_backwards() {
+        let counts = PendingCounts::default();
+        let board_id = Uuid::new_v4();
+        counts.add(board_id, "5-0", &[entry("6-0", insert())]);
+
+        assert_eq!(counts.get(board_id, "0"), ("0".to_owned(), 0));
+    }
+}
@@ -0,0 +1,195 @@
+use bb8::{ManageConnection, Pool, PooledConnection, RunError};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch::Receiver as WatchReceiver;
+
+use crate::circuit_breaker::{BreakerErrorSink, CircuitBreaker};
+use crate::repository::PoolStats;
+
+/// How big a store's connection pool gets and how long it waits, from the POOL_MAX_SIZE,
+/// POOL_MIN_IDLE, POOL_CONNECT_TIMEOUT_MS, POOL_WAIT_TIMEOUT_MS, CIRCUIT_BREAKER_THRESHOLD, and
+/// CIRCUIT_BREAKER_PROBE_MS environment variables
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    /// How many idle connections to keep around, or none to only open connections when needed
+    pub min_idle: Option<u32>,
+    /// How long to wait for a new connection to be established
+    pub connect_timeout: Duration,
+    /// How long to wait for a connection to be handed out before giving up
+    pub wait_timeout: Duration,
+    /// How many failures in a row mark the store as degraded, or zero to never mark it
+    pub breaker_threshold: u32,
+    /// How often to try reaching the store while it's degraded
+    pub probe_interval: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 5,
+            min_idle: None,
+            connect_timeout: Duration::from_secs(10),
+            wait_timeout: Duration::from_secs(30),
+            breaker_threshold: 5,
+            probe_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Self {
+        let parse = |var: &str| {
+            env::var(var).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{var} must be a number"))
+            })
+        };
+        let default = Self::default();
+        Self {
+            max_size: parse("POOL_MAX_SIZE").map_or(default.max_size, |max_size| max_size as u32),
+            min_idle: parse("POOL_MIN_IDLE").map(|min_idle| min_idle as u32),
+            connect_timeout: parse("POOL_CONNECT_TIMEOUT_MS")
+                .map_or(default.connect_timeout, Duration::from_millis),
+            wait_timeout: parse("POOL_WAIT_TIMEOUT_MS")
+                .map_or(default.wait_timeout, Duration::from_millis),
+            breaker_threshold: parse("CIRCUIT_BREAKER_THRESHOLD")
+                .map_or(default.breaker_threshold, |threshold| threshold as u32),
+            probe_interval: parse("CIRCUIT_BREAKER_PROBE_MS")
+                .map_or(default.probe_interval, Duration::from_millis),
+        }
+    }
+}
+
+/// A bb8 pool that keeps track of how long callers wait for connections, and stops handing them
+/// out while the store can't be reached
+pub struct MeteredPool<M: ManageConnection> {
+    pool: Pool<M>,
+    max_size: u32,
+    metrics: Arc<PoolMetrics>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+#[derive(Default)]
+struct PoolMetrics {
+    checkouts: AtomicU64,
+    timed_out_checkouts: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+impl<M: ManageConnection> Clone for MeteredPool<M> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            max_size: self.max_size,
+            metrics: self.metrics.clone(),
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+impl<M: ManageConnection> MeteredPool<M> {
+    pub async fn new(manager: M, config: &PoolConfig) -> Result<Self, M::Error> {
+        let breaker = Arc::new(CircuitBreaker::new(config.breaker_threshold));
+        let pool = Pool::builder()
+            .max_size(config.max_size)
+            .min_idle(config.min_idle)
+            .connection_timeout(config.wait_timeout)
+            .error_sink(Box::new(BreakerErrorSink(breaker.clone())))
+            .build(manager)
+            .await?;
+        tokio::task::spawn(Self::probe(
+            pool.clone(),
+            breaker.clone(),
+            config.probe_interval,
+        ));
+        Ok(Self {
+            pool,
+            max_size: config.max_size,
+            metrics: Arc::default(),
+            breaker,
+        })
+    }
+
+    /// Try to open a connection every so often while the breaker is open, and close it as soon as
+    /// one opens
+    async fn probe(pool: Pool<M>, breaker: Arc<CircuitBreaker>, interval: Duration) {
+        let mut degraded_receiver = breaker.watch();
+        loop {
+            if degraded_receiver
+                .wait_for(|degraded| *degraded)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            tokio::time::sleep(interval).await;
+            if pool.dedicated_connection().await.is_ok() {
+                breaker.close();
+            }
+        }
+    }
+
+    /// Get a connection from the pool, waiting for one to free up if they're all in use. Fails
+    /// right away while the store is degraded.
+    pub async fn get(&self) -> Result<PooledConnection<'_, M>, RunError<M::Error>> {
+        if self.breaker.is_open() {
+            return Err(RunError::TimedOut);
+        }
+
+        let started_at = Instant::now();
+        let connection = self.pool.get().await;
+        let wait_micros = started_at.elapsed().as_micros() as u64;
+
+        self.metrics.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .total_wait_micros
+            .fetch_add(wait_micros, Ordering::Relaxed);
+        self.metrics
+            .max_wait_micros
+            .fetch_max(wait_micros, Ordering::Relaxed);
+        if let Err(RunError::TimedOut) = connection {
+            self.metrics
+                .timed_out_checkouts
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        match connection {
+            Ok(_) => self.breaker.record_success(),
+            Err(_) => self.breaker.record_failure(),
+        }
+
+        connection
+    }
+
+    /// Get told whenever the store becomes degraded or recovers, with `true` while it's degraded
+    pub fn watch_degraded(&self) -> WatchReceiver<bool> {
+        self.breaker.watch()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.pool.state();
+        let checkouts = self.metrics.checkouts.load(Ordering::Relaxed);
+        let total_wait_micros = self.metrics.total_wait_micros.load(Ordering::Relaxed);
+        PoolStats {
+            max_size: self.max_size,
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            checkouts,
+            timed_out_checkouts: self.metrics.timed_out_checkouts.load(Ordering::Relaxed),
+            average_wait_ms: match checkouts {
+                0 => 0.0,
+                _ => total_wait_micros as f64 / checkouts as f64 / 1000.0,
+            },
+            max_wait_ms: self.metrics.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            retries: 0,
+            exhausted_retries: 0,
+            degraded: self.breaker.is_open(),
+            breaker_trips: self.breaker.trips(),
+        }
+    }
+}
@@ -0,0 +1,2957 @@
+use anyhow::{anyhow, Result};
+use async_stream::{stream, try_stream};
+use async_trait::async_trait;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use itertools::Itertools;
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError, Sender as BroadcastSender},
+        mpsc,
+        watch::Receiver as WatchReceiver,
+        Notify,
+    },
+    task::JoinHandle,
+};
+use tokio_postgres::{error::SqlState, types::Json, AsyncMessage, Config, GenericClient, Row};
+use uuid::Uuid;
+
+use crate::auth::ApiKey;
+use crate::board_meta::{BoardMeta, BoardMetaPatch, BoardSummary};
+use crate::change::{Change, ChangeEntry, TrashedObject};
+use crate::message::{AcceptedChange, Cursor, JsonObject, PresenceMessage, ServerMessage};
+use crate::pool::{MeteredPool, PoolConfig};
+use crate::presence_channel::{PresenceChannel, PresenceStats};
+use crate::repository::{
+    Ban, BanTarget, BoardStore, ChangeRetention, CheckpointHistory, ClientInfo, PoolStats,
+    PublishOutcome, Repository, RepositoryError, RepositoryResult, IDEMPOTENCY_TTL_SECONDS,
+    OBJECT_LOCK_TTL_SECONDS, TRASH_TTL_SECONDS,
+};
+use crate::search::{self, SearchHit};
+use crate::snapshot::BoardSnapshot;
+use crate::spatial::Rect;
+use crate::webhook::WebhookEvent;
+
+/// The tables are created when the server starts if they don't exist yet. Versions are stored as
+/// the millisecond and sequence halves of `{milliseconds}-{sequence}` so that they can be
+/// compared as row values, and the `id` of each change keeps them in the order they were added.
+const SCHEMA: &str = r"
+CREATE TABLE IF NOT EXISTS boards (
+    board_id UUID PRIMARY KEY,
+    meta JSONB,
+    -- Milliseconds since the Unix epoch, or null if the board isn't in the registry
+    last_activity_at BIGINT,
+    version TEXT NOT NULL DEFAULT '0',
+    last_change_ms BIGINT,
+    last_change_seq BIGINT,
+    frozen BOOLEAN NOT NULL DEFAULT false
+);
+CREATE INDEX IF NOT EXISTS boards_last_activity_at ON boards (last_activity_at);
+
+CREATE TABLE IF NOT EXISTS objects (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    object JSONB NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+
+-- The spatial index, with each object's bounds kept up to date as changes are checkpointed
+CREATE TABLE IF NOT EXISTS object_bounds (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    min_x DOUBLE PRECISION NOT NULL,
+    min_y DOUBLE PRECISION NOT NULL,
+    max_x DOUBLE PRECISION NOT NULL,
+    max_y DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+CREATE INDEX IF NOT EXISTS object_bounds_x ON object_bounds (board_id, min_x, max_x);
+
+-- The search index, with a row for every term in each object's text. indexed_at is the timestamp
+-- of the version the term was added to the object in.
+CREATE TABLE IF NOT EXISTS object_terms (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    term TEXT NOT NULL,
+    indexed_at BIGINT NOT NULL,
+    PRIMARY KEY (board_id, object_id, term)
+);
+CREATE INDEX IF NOT EXISTS object_terms_term ON object_terms (term);
+
+CREATE TABLE IF NOT EXISTS object_order (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    position DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+
+-- Deleted objects, which all expire together once the board hasn't had anything deleted for a
+-- while
+CREATE TABLE IF NOT EXISTS trash (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    object JSONB NOT NULL,
+    position DOUBLE PRECISION,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+
+CREATE TABLE IF NOT EXISTS revisions (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    revision BIGINT NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+
+-- The revision of each object as of the board's checkpointed version
+CREATE TABLE IF NOT EXISTS checkpointed_revisions (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    revision BIGINT NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+
+CREATE TABLE IF NOT EXISTS changes (
+    id BIGSERIAL PRIMARY KEY,
+    board_id UUID NOT NULL,
+    timestamp_ms BIGINT NOT NULL,
+    sequence BIGINT NOT NULL,
+    session_id UUID NOT NULL,
+    username TEXT,
+    user_id TEXT,
+    revisions JSONB NOT NULL,
+    change JSONB NOT NULL,
+    UNIQUE (board_id, timestamp_ms, sequence)
+);
+
+CREATE TABLE IF NOT EXISTS change_history (
+    id BIGSERIAL PRIMARY KEY,
+    board_id UUID NOT NULL,
+    timestamp_ms BIGINT NOT NULL,
+    sequence BIGINT NOT NULL,
+    session_id UUID NOT NULL,
+    username TEXT,
+    user_id TEXT,
+    revisions JSONB NOT NULL,
+    change JSONB NOT NULL,
+    UNIQUE (board_id, timestamp_ms, sequence)
+);
+
+-- Copies of each board's objects and order at its most recent checkpoints, with the order as an
+-- array of object ID - position pairs
+CREATE TABLE IF NOT EXISTS checkpoints (
+    board_id UUID NOT NULL,
+    timestamp_ms BIGINT NOT NULL,
+    sequence BIGINT NOT NULL,
+    objects JSONB NOT NULL,
+    object_order JSONB NOT NULL,
+    PRIMARY KEY (board_id, timestamp_ms, sequence)
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    board_id UUID NOT NULL,
+    session_id UUID NOT NULL,
+    username TEXT NOT NULL,
+    user_id TEXT,
+    cursor JSONB,
+    latency DOUBLE PRECISION,
+    client JSONB,
+    PRIMARY KEY (board_id, session_id)
+);
+
+CREATE TABLE IF NOT EXISTS waiting_sessions (
+    board_id UUID NOT NULL,
+    session_id UUID NOT NULL,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (board_id, session_id)
+);
+
+CREATE TABLE IF NOT EXISTS session_checkins (
+    session_id UUID PRIMARY KEY,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS object_locks (
+    board_id UUID NOT NULL,
+    object_id UUID NOT NULL,
+    session_id UUID NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (board_id, object_id)
+);
+
+-- Locks that keep servers from doing the same background work at the same time
+CREATE TABLE IF NOT EXISTS task_locks (
+    name TEXT PRIMARY KEY,
+    holder UUID NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+    board_id UUID NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    version TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (board_id, idempotency_key)
+);
+
+CREATE TABLE IF NOT EXISTS board_webhooks (
+    board_id UUID NOT NULL,
+    url TEXT NOT NULL,
+    PRIMARY KEY (board_id, url)
+);
+
+CREATE TABLE IF NOT EXISTS board_bans (
+    board_id UUID NOT NULL,
+    target JSONB NOT NULL,
+    reason TEXT,
+    PRIMARY KEY (board_id, target)
+);
+
+CREATE TABLE IF NOT EXISTS webhook_queue (
+    id BIGSERIAL PRIMARY KEY,
+    event JSONB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    hash TEXT PRIMARY KEY,
+    api_key JSONB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS settings (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+/// Every table with rows that belong to a single board
+const BOARD_TABLES: &[&str] = &[
+    "boards",
+    "objects",
+    "object_bounds",
+    "object_terms",
+    "object_order",
+    "trash",
+    "revisions",
+    "checkpointed_revisions",
+    "changes",
+    "change_history",
+    "checkpoints",
+    "sessions",
+    "waiting_sessions",
+    "object_locks",
+    "idempotency_keys",
+    "board_webhooks",
+    "board_bans",
+];
+
+/// The columns of `changes` and `change_history` that make up a `ChangeEntry`
+const CHANGE_COLUMNS: &str =
+    "timestamp_ms, sequence, session_id, username, user_id, revisions, change";
+
+/// NOTIFY channel for presence messages, with a `PresenceNotification` as the payload
+const PRESENCE_CHANNEL: &str = "board_presence";
+
+/// NOTIFY channel for published changes, with the board ID as the payload
+const CHANGES_CHANNEL: &str = "board_changes";
+
+/// NOTIFY channel for events added to the webhook queue, with no payload
+const WEBHOOK_QUEUE_CHANNEL: &str = "webhook_queue";
+
+#[derive(Serialize, Deserialize)]
+struct PresenceNotification {
+    board_id: Uuid,
+    message: PresenceMessage,
+}
+
+type PostgresPool = MeteredPool<PostgresConnectionManager<MakeTlsConnector>>;
+
+/// Keeps boards in Postgres, with objects as JSONB rows, changes in a table ordered by version,
+/// and presence over LISTEN/NOTIFY. Archiving isn't supported since nothing has to fit in memory.
+#[derive(Clone)]
+pub struct PostgresRepository {
+    pool: PostgresPool,
+    /// How many checkpointed changes to keep in each board's history, or zero to keep none
+    history_length: usize,
+    checkpoint_history: CheckpointHistory,
+    change_retention: ChangeRetention,
+    /// How long a session lives without checking in
+    session_ttl: Duration,
+    presence: PresenceChannel,
+    /// Carries the ID of every board that a change is published to, to wake up anyone waiting on
+    /// that board's changes
+    change_sender: BroadcastSender<Uuid>,
+    webhook_notify: Arc<Notify>,
+    _listen_handle: Arc<JoinHandle<()>>,
+}
+
+impl PostgresRepository {
+    #[tracing::instrument(skip_all, err)]
+    pub async fn new(
+        database_url: &str,
+        pool_config: &PoolConfig,
+        history_length: usize,
+        checkpoint_history: CheckpointHistory,
+        change_retention: ChangeRetention,
+        session_ttl: Duration,
+        presence_capacity: usize,
+    ) -> Result<Self> {
+        // TLS is used whenever the server offers it, or always with `sslmode=require`
+        let tls = MakeTlsConnector::new(TlsConnector::new()?);
+        let mut config = database_url.parse::<Config>()?;
+        config.connect_timeout(pool_config.connect_timeout);
+        Self::check_environment(&config, tls.clone()).await?;
+        let manager = PostgresConnectionManager::new(config, tls.clone());
+        let pool = MeteredPool::new(manager, pool_config).await?;
+
+        pool.get().await?.batch_execute(SCHEMA).await?;
+
+        let presence = PresenceChannel::new(presence_capacity);
+        let (change_sender, _) = broadcast::channel(1000);
+        let webhook_notify = Arc::new(Notify::new());
+        let listen_handle = tokio::task::spawn(Self::start_listen(
+            database_url.to_string(),
+            tls,
+            presence.clone(),
+            change_sender.clone(),
+            webhook_notify.clone(),
+        ));
+        Ok(Self {
+            pool,
+            history_length,
+            checkpoint_history,
+            change_retention,
+            session_ttl,
+            presence,
+            change_sender,
+            webhook_notify,
+            _listen_handle: Arc::new(listen_handle),
+        })
+    }
+
+    /// Make sure that Postgres can be reached before anything else touches it, without creating
+    /// the tables, for the --check mode
+    pub async fn check(database_url: &str, pool_config: &PoolConfig) -> Result<()> {
+        let tls = MakeTlsConnector::new(TlsConnector::new()?);
+        let mut config = database_url.parse::<Config>()?;
+        config.connect_timeout(pool_config.connect_timeout);
+        Self::check_environment(&config, tls).await?;
+        tracing::info!("Postgres is ready");
+        Ok(())
+    }
+
+    /// Check that Postgres answers, so that a misconfigured deployment stops right away with
+    /// something to do about it instead of waiting out the pool
+    #[tracing::instrument(skip_all, err)]
+    async fn check_environment(config: &Config, tls: MakeTlsConnector) -> Result<()> {
+        let (client, connection) = config.connect(tls).await.map_err(|error| {
+            anyhow!(
+                "Could not connect to Postgres: {error}. Check that DATABASE_URL points at a \
+                running database that this server can reach, with the right user and password."
+            )
+        })?;
+        let connection_handle = tokio::task::spawn(connection);
+        let result = client.simple_query("SELECT 1").await;
+        connection_handle.abort();
+        result.map_err(|error| anyhow!("Postgres refused a query: {error}"))?;
+        Ok(())
+    }
+
+    // ---- Private helpers
+
+    /// Add an event to the end of the webhook queue and wake up the dispatcher
+    #[tracing::instrument(skip(connection), err)]
+    async fn push_webhook_event<C>(connection: &C, event: &WebhookEvent) -> Result<()>
+    where
+        C: GenericClient + Sync,
+    {
+        connection
+            .execute(
+                "INSERT INTO webhook_queue (event) VALUES ($1)",
+                &[&Json(event)],
+            )
+            .await?;
+        connection
+            .execute("SELECT pg_notify($1, '')", &[&WEBHOOK_QUEUE_CHANNEL])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Bring the bounds of the given objects in object_bounds up to date with the objects table, or
+    /// of every object on the board if none are given. Objects without bounds are left out.
+    #[tracing::instrument(skip(connection, object_ids), err)]
+    async fn index_objects<C>(
+        connection: &C,
+        board_id: Uuid,
+        object_ids: Option<Vec<Uuid>>,
+    ) -> Result<()>
+    where
+        C: GenericClient + Sync,
+    {
+        let rows = match &object_ids {
+            Some(object_ids) => {
+                connection
+                    .execute(
+                        "DELETE FROM object_bounds WHERE board_id = $1 AND object_id = ANY($2)",
+                        &[&board_id, object_ids],
+                    )
+                    .await?;
+                connection
+                    .query(
+                        "SELECT object_id, object FROM objects
+                        WHERE board_id = $1 AND object_id = ANY($2)",
+                        &[&board_id, object_ids],
+                    )
+                    .await?
+            }
+            None => {
+                connection
+                    .execute(
+                        "DELETE FROM object_bounds WHERE board_id = $1",
+                        &[&board_id],
+                    )
+                    .await?;
+                connection
+                    .query(
+                        "SELECT object_id, object FROM objects WHERE board_id = $1",
+                        &[&board_id],
+                    )
+                    .await?
+            }
+        };
+
+        for row in rows {
+            let Json(object) = row.get::<_, Json<JsonObject>>("object");
+            if let Some(bounds) = Rect::of_object(&object) {
+                connection
+                    .execute(
+                        "INSERT INTO object_bounds (board_id, object_id, min_x, min_y, max_x, max_y)
+                        VALUES ($1, $2, $3, $4, $5, $6)",
+                        &[
+                            &board_id,
+                            &row.get::<_, Uuid>("object_id"),
+                            &bounds.min_x,
+                            &bounds.min_y,
+                            &bounds.max_x,
+                            &bounds.max_y,
+                        ],
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bring the terms of the given objects in object_terms up to date with the objects table.
+    /// Terms an object already had keep the time they were first indexed at.
+    #[tracing::instrument(skip(connection, object_ids), err)]
+    async fn index_object_terms<C>(
+        connection: &C,
+        board_id: Uuid,
+        object_ids: &[Uuid],
+        indexed_at: i64,
+    ) -> Result<()>
+    where
+        C: GenericClient + Sync,
+    {
+        let objects = connection
+            .query(
+                "SELECT object_id, object FROM objects WHERE board_id = $1 AND object_id = ANY($2)",
+                &[&board_id, &object_ids],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(object) = row.get::<_, Json<JsonObject>>("object");
+                (row.get::<_, Uuid>("object_id"), object)
+            })
+            .collect::<HashMap<_, _>>();
+
+        for object_id in object_ids {
+            // Objects that are gone have no terms, so all of theirs are removed
+            let terms = objects
+                .get(object_id)
+                .map(search::object_terms)
+                .unwrap_or_default()
+                .into_iter()
+                .collect::<Vec<_>>();
+            connection
+                .execute(
+                    "DELETE FROM object_terms
+                    WHERE board_id = $1 AND object_id = $2 AND NOT (term = ANY($3))",
+                    &[&board_id, object_id, &terms],
+                )
+                .await?;
+            connection
+                .execute(
+                    "INSERT INTO object_terms (board_id, object_id, term, indexed_at)
+                    SELECT $1, $2, unnest($3::TEXT[]), $4
+                    ON CONFLICT (board_id, object_id, term) DO NOTHING",
+                    &[&board_id, object_id, &terms, &indexed_at],
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish a presence message for a board using NOTIFY. Inside a transaction, it's only sent
+    /// if the transaction commits.
+    #[tracing::instrument(skip(connection), err)]
+    async fn publish_presence_message_for_board<C>(
+        connection: &C,
+        board_id: Uuid,
+        message: PresenceMessage,
+    ) -> Result<()>
+    where
+        C: GenericClient + Sync,
+    {
+        let payload = serde_json::to_string(&PresenceNotification { board_id, message })?;
+        connection
+            .execute("SELECT pg_notify($1, $2)", &[&PRESENCE_CHANNEL, &payload])
+            .await?;
+
+        Ok(())
+    }
+
+    /// Parse a row of `changes` or `change_history` selected with `CHANGE_COLUMNS`
+    fn parse_change_row(row: &Row) -> Option<ChangeEntry> {
+        Some(ChangeEntry {
+            version: format!(
+                "{}-{}",
+                row.get::<_, i64>("timestamp_ms"),
+                row.get::<_, i64>("sequence")
+            ),
+            session_id: row.get("session_id"),
+            username: row.get("username"),
+            user_id: row.get("user_id"),
+            revisions: serde_json::from_value(row.get("revisions")).ok()?,
+            change: serde_json::from_value(row.get("change")).ok()?,
+        })
+    }
+
+    /// Split a version into the millisecond and sequence halves it's stored as
+    fn split_version(version: &str) -> Result<(i64, i64)> {
+        let (timestamp, sequence) = Repository::parse_stream_id(version)
+            .ok_or_else(|| anyhow!("{version} is not a valid version"))?;
+        Ok((timestamp as i64, sequence as i64))
+    }
+
+    /// Start the notification loop. LISTEN needs a connection of its own, so it runs in a
+    /// background task and forwards notifications to in-memory channels that each session can
+    /// stream from.
+    #[tracing::instrument(skip_all)]
+    async fn start_listen(
+        database_url: String,
+        tls: MakeTlsConnector,
+        presence: PresenceChannel,
+        change_sender: BroadcastSender<Uuid>,
+        webhook_notify: Arc<Notify>,
+    ) {
+        loop {
+            let _ = Self::run_listen(
+                &database_url,
+                tls.clone(),
+                presence.clone(),
+                change_sender.clone(),
+                webhook_notify.clone(),
+            )
+            .await;
+
+            // Don't hammer the database while it's unreachable
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// LISTEN on every notification channel and forward each notification to the matching
+    /// in-memory channel. Returns when the connection is lost.
+    #[tracing::instrument(skip_all, err)]
+    async fn run_listen(
+        database_url: &str,
+        tls: MakeTlsConnector,
+        presence: PresenceChannel,
+        change_sender: BroadcastSender<Uuid>,
+        webhook_notify: Arc<Notify>,
+    ) -> Result<()> {
+        let (client, mut connection) = tokio_postgres::connect(database_url, tls).await?;
+
+        // Notifications only arrive while the connection is being polled, which has to happen in
+        // its own task so that the LISTEN itself can go through
+        let (notification_sender, mut notifications) = mpsc::unbounded_channel();
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        tokio::task::spawn(async move {
+            while let Some(Ok(message)) = messages.next().await {
+                if let AsyncMessage::Notification(notification) = message {
+                    if notification_sender.send(notification).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        client
+            .batch_execute(&format!(
+                "LISTEN {PRESENCE_CHANNEL}; LISTEN {CHANGES_CHANNEL}; \
+                LISTEN {WEBHOOK_QUEUE_CHANNEL};"
+            ))
+            .await?;
+
+        while let Some(notification) = notifications.recv().await {
+            match notification.channel() {
+                PRESENCE_CHANNEL => {
+                    let presence_notification =
+                        serde_json::from_str::<PresenceNotification>(notification.payload())?;
+                    presence.send(
+                        presence_notification.board_id,
+                        presence_notification.message,
+                    );
+                }
+                CHANGES_CHANNEL => {
+                    let _ = change_sender.send(notification.payload().parse()?);
+                }
+                WEBHOOK_QUEUE_CHANNEL => webhook_notify.notify_one(),
+                _ => {}
+            }
+        }
+
+        Err(anyhow!("Lost the connection listening for notifications"))
+    }
+}
+
+impl From<tokio_postgres::Error> for RepositoryError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        // A closed connection is replaced by the pool, and a statement that was cancelled ran
+        // into statement_timeout
+        if error.is_closed() || error.code() == Some(&SqlState::QUERY_CANCELED) {
+            return Self::Timeout(error.into());
+        }
+
+        // Serialization failures and deadlocks are how Postgres asks for a transaction to be
+        // tried again
+        let transient = error.code() == Some(&SqlState::T_R_SERIALIZATION_FAILURE)
+            || error.code() == Some(&SqlState::T_R_DEADLOCK_DETECTED);
+        Self::Backend {
+            error: error.into(),
+            transient,
+        }
+    }
+}
+
+#[async_trait]
+impl BoardStore for PostgresRepository {
+    #[tracing::instrument(skip(self), err)]
+    async fn create_board(&self, board_id: Uuid, meta: BoardMeta) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        // Add the board to the registry, with its creation as its last activity. A board that
+        // already has metadata can never be clobbered, however unlikely a UUID collision is.
+        let created = connection
+            .execute(
+                "INSERT INTO boards (board_id, meta, last_activity_at) VALUES ($1, $2, $3)
+                ON CONFLICT (board_id) DO UPDATE
+                SET meta = EXCLUDED.meta, last_activity_at = EXCLUDED.last_activity_at
+                WHERE boards.meta IS NULL",
+                &[&board_id, &Json(&meta), &meta.created_at.timestamp_millis()],
+            )
+            .await?;
+
+        if created == 0 {
+            return Err(RepositoryError::Conflict(format!(
+                "Board {board_id} already exists"
+            )));
+        }
+
+        Self::push_webhook_event(
+            &*connection,
+            &WebhookEvent::BoardCreated {
+                board_id,
+                meta: meta.clone(),
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_meta_for_board(&self, board_id: Uuid) -> RepositoryResult<Option<BoardMeta>> {
+        let connection = self.pool.get().await?;
+
+        let meta = connection
+            .query_opt("SELECT meta FROM boards WHERE board_id = $1", &[&board_id])
+            .await?
+            .and_then(|row| row.get::<_, Option<Json<BoardMeta>>>("meta"))
+            .map(|Json(meta)| meta);
+
+        Ok(meta)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_meta_for_board(
+        &self,
+        board_id: Uuid,
+        patch: BoardMetaPatch,
+    ) -> RepositoryResult<Option<BoardMeta>> {
+        let mut connection = self.pool.get().await?;
+        let transaction = connection.transaction().await?;
+
+        // Lock the row so that nobody else can change the metadata in between
+        let mut meta = match transaction
+            .query_opt(
+                "SELECT meta FROM boards WHERE board_id = $1 FOR UPDATE",
+                &[&board_id],
+            )
+            .await?
+            .and_then(|row| row.get::<_, Option<Json<BoardMeta>>>("meta"))
+        {
+            Some(Json(meta)) => meta,
+            None => return Ok(None),
+        };
+        patch.apply(&mut meta);
+
+        transaction
+            .execute(
+                "UPDATE boards SET meta = $2 WHERE board_id = $1",
+                &[&board_id, &Json(&meta)],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        Ok(Some(meta))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_board_frozen(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        let frozen = connection
+            .query_opt(
+                "SELECT frozen FROM boards WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .map(|row| row.get("frozen"))
+            .unwrap_or(false);
+
+        Ok(frozen)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn set_board_frozen(&self, board_id: Uuid, frozen: bool) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        // Boards that haven't been written to yet don't have a row to update
+        connection
+            .execute(
+                "INSERT INTO boards (board_id, frozen) VALUES ($1, $2)
+                ON CONFLICT (board_id) DO UPDATE SET frozen = EXCLUDED.frozen",
+                &[&board_id, &frozen],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn set_board_reliable_presence(
+        &self,
+        _board_id: Uuid,
+        reliable: bool,
+    ) -> RepositoryResult<()> {
+        // Presence goes through LISTEN/NOTIFY, which doesn't keep anything for later
+        if reliable {
+            return Err(RepositoryError::Conflict(
+                "Reliable presence needs the Redis store".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_board(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        let mut connection = self.pool.get().await?;
+        let transaction = connection.transaction().await?;
+
+        // Delete every row that belongs to the board, which covers the change stream, objects,
+        // version, sessions, and everything else
+        let mut deleted = 0;
+        for table in BOARD_TABLES {
+            deleted += transaction
+                .execute(
+                    format!("DELETE FROM {table} WHERE board_id = $1").as_str(),
+                    &[&board_id],
+                )
+                .await?;
+        }
+
+        if deleted == 0 {
+            return Ok(false);
+        }
+
+        // Broadcast BoardDeleted notification. The nil session ID means it didn't come from any
+        // session, so nobody will skip it.
+        Self::publish_presence_message_for_board(
+            &transaction,
+            board_id,
+            PresenceMessage {
+                source_session: Uuid::nil(),
+                message: ServerMessage::BoardDeleted,
+            },
+        )
+        .await?;
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn duplicate_board(&self, board_id: Uuid, new_board_id: Uuid) -> RepositoryResult<bool> {
+        let mut connection = self.pool.get().await?;
+        let transaction = connection.transaction().await?;
+
+        // Lock the source board so that the copy can never land between a change being published
+        // and it being checkpointed
+        let board_row = transaction
+            .query_opt(
+                "SELECT version, last_change_ms, last_change_seq, last_activity_at,
+                    EXISTS (SELECT 1 FROM objects WHERE board_id = $1) AS has_objects
+                FROM boards WHERE board_id = $1 FOR UPDATE",
+                &[&board_id],
+            )
+            .await?;
+        let board_row = match board_row {
+            Some(row)
+                if row.get::<_, bool>("has_objects")
+                    || row.get::<_, Option<i64>>("last_change_ms").is_some()
+                    || row.get::<_, Option<i64>>("last_activity_at").is_some() =>
+            {
+                row
+            }
+            _ => return Ok(false),
+        };
+
+        // Copy the objects, order, revisions, version, and change stream so that pending changes
+        // aren't lost, and add the new board to the registry
+        transaction
+            .execute(
+                "INSERT INTO boards
+                    (board_id, last_activity_at, version, last_change_ms, last_change_seq)
+                VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &new_board_id,
+                    &Utc::now().timestamp_millis(),
+                    &board_row.get::<_, String>("version"),
+                    &board_row.get::<_, Option<i64>>("last_change_ms"),
+                    &board_row.get::<_, Option<i64>>("last_change_seq"),
+                ],
+            )
+            .await?;
+        for statement in [
+            "INSERT INTO objects (board_id, object_id, object)
+            SELECT $2, object_id, object FROM objects WHERE board_id = $1",
+            "INSERT INTO object_bounds (board_id, object_id, min_x, min_y, max_x, max_y)
+            SELECT $2, object_id, min_x, min_y, max_x, max_y FROM object_bounds
+            WHERE board_id = $1",
+            "INSERT INTO object_terms (board_id, object_id, term, indexed_at)
+            SELECT $2, object_id, term, indexed_at FROM object_terms WHERE board_id = $1",
+            "INSERT INTO object_order (board_id, object_id, position)
+            SELECT $2, object_id, position FROM object_order WHERE board_id = $1",
+            "INSERT INTO revisions (board_id, object_id, revision)
+            SELECT $2, object_id, revision FROM revisions WHERE board_id = $1",
+            "INSERT INTO checkpointed_revisions (board_id, object_id, revision)
+            SELECT $2, object_id, revision FROM checkpointed_revisions WHERE board_id = $1",
+        ] {
+            transaction
+                .execute(statement, &[&board_id, &new_board_id])
+                .await?;
+        }
+        transaction
+            .execute(
+                format!(
+                    "INSERT INTO changes (board_id, {CHANGE_COLUMNS})
+                    SELECT $2, {CHANGE_COLUMNS} FROM changes WHERE board_id = $1 ORDER BY id"
+                )
+                .as_str(),
+                &[&board_id, &new_board_id],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_boards(
+        &self,
+        cursor: usize,
+        limit: usize,
+    ) -> RepositoryResult<(Vec<BoardSummary>, Option<usize>)> {
+        if limit == 0 {
+            return Ok((vec![], Some(cursor)));
+        }
+
+        let connection = self.pool.get().await?;
+
+        // Page through the registry from the most recent activity back
+        let boards = connection
+            .query(
+                "SELECT board_id, meta, last_activity_at FROM boards
+                WHERE last_activity_at IS NOT NULL
+                ORDER BY last_activity_at DESC, board_id DESC
+                OFFSET $1 LIMIT $2",
+                &[&(cursor as i64), &(limit as i64)],
+            )
+            .await?
+            .into_iter()
+            .map(|row| BoardSummary {
+                board_id: row.get("board_id"),
+                meta: row
+                    .get::<_, Option<Json<BoardMeta>>>("meta")
+                    .map(|Json(meta)| meta),
+                last_activity_at: Utc.timestamp_millis(row.get("last_activity_at")),
+            })
+            .collect::<Vec<_>>();
+
+        let next_cursor = if boards.len() == limit {
+            Some(cursor + limit)
+        } else {
+            None
+        };
+
+        Ok((boards, next_cursor))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn create_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        username: String,
+        user_id: Option<String>,
+        client: ClientInfo,
+    ) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "INSERT INTO sessions (board_id, session_id, username, user_id, client)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (board_id, session_id) DO UPDATE
+                SET username = EXCLUDED.username, user_id = EXCLUDED.user_id,
+                    client = EXCLUDED.client",
+                &[&board_id, &session_id, &username, &user_id, &Json(client)],
+            )
+            .await?;
+
+        // Start keeping the session alive
+        self.touch_session(session_id).await?;
+
+        // Broadcast UserJoined notification
+        Self::publish_presence_message_for_board(
+            &*connection,
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserJoined {
+                    session_id,
+                    username: username.clone(),
+                },
+            },
+        )
+        .await?;
+
+        Self::push_webhook_event(
+            &*connection,
+            &WebhookEvent::UserJoined {
+                board_id,
+                session_id,
+                username,
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_sessions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, String)>> {
+        let connection = self.pool.get().await?;
+
+        let sessions = connection
+            .query(
+                "SELECT session_id, username FROM sessions WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get("session_id"), row.get("username")))
+            .collect();
+
+        Ok(sessions)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_user_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<Option<String>> {
+        let connection = self.pool.get().await?;
+
+        let user_id = connection
+            .query_opt(
+                "SELECT user_id FROM sessions WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id],
+            )
+            .await?
+            .and_then(|row| row.get("user_id"));
+
+        Ok(user_id)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        // The session's row holds its username, user ID, cursor, latency, and client info
+        connection
+            .execute(
+                "DELETE FROM sessions WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id],
+            )
+            .await?;
+        connection
+            .execute(
+                "DELETE FROM session_checkins WHERE session_id = $1",
+                &[&session_id],
+            )
+            .await?;
+
+        // Broadcast UserLeft notification
+        Self::publish_presence_message_for_board(
+            &*connection,
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserLeft { session_id },
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn kick_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        reason: Option<String>,
+    ) -> RepositoryResult<bool> {
+        {
+            let connection = self.pool.get().await?;
+
+            let exists = connection
+                .query_opt(
+                    "SELECT 1 FROM sessions WHERE board_id = $1 AND session_id = $2",
+                    &[&board_id, &session_id],
+                )
+                .await?
+                .is_some();
+            if !exists {
+                return Ok(false);
+            }
+
+            // Broadcast SessionKicked notification, which only the kicked session acts on. The nil
+            // session ID means it didn't come from any session.
+            Self::publish_presence_message_for_board(
+                &*connection,
+                board_id,
+                PresenceMessage {
+                    source_session: Uuid::nil(),
+                    message: ServerMessage::SessionKicked { session_id, reason },
+                },
+            )
+            .await?;
+        }
+
+        // Clean up right away in case the session's handler is already gone
+        self.delete_session_for_board(board_id, session_id).await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_ban_for_board(&self, board_id: Uuid, ban: Ban) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "INSERT INTO board_bans (board_id, target, reason) VALUES ($1, $2, $3)
+                ON CONFLICT (board_id, target) DO UPDATE SET reason = EXCLUDED.reason",
+                &[&board_id, &Json(&ban.target), &ban.reason],
+            )
+            .await?;
+
+        // Broadcast SessionBanned notification, which only the banned sessions act on
+        Self::publish_presence_message_for_board(
+            &*connection,
+            board_id,
+            PresenceMessage {
+                source_session: Uuid::nil(),
+                message: ServerMessage::SessionBanned {
+                    target: ban.target,
+                    reason: ban.reason,
+                },
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn remove_ban_for_board(
+        &self,
+        board_id: Uuid,
+        target: BanTarget,
+    ) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        let removed = connection
+            .execute(
+                "DELETE FROM board_bans WHERE board_id = $1 AND target = $2",
+                &[&board_id, &Json(&target)],
+            )
+            .await?;
+
+        Ok(removed > 0)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_bans_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Ban>> {
+        let connection = self.pool.get().await?;
+
+        let bans = connection
+            .query(
+                "SELECT target, reason FROM board_bans WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(target) = row.get::<_, Json<BanTarget>>("target");
+                Ban {
+                    target,
+                    reason: row.get("reason"),
+                }
+            })
+            .collect();
+
+        Ok(bans)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn touch_session(&self, session_id: Uuid) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+        connection
+            .execute(
+                "INSERT INTO session_checkins (session_id, expires_at)
+                VALUES ($1, now() + make_interval(secs => $2))
+                ON CONFLICT (session_id) DO UPDATE SET expires_at = EXCLUDED.expires_at",
+                &[&session_id, &self.session_ttl.as_secs_f64()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_exists(&self, session_id: Uuid) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        let exists = connection
+            .query_opt(
+                "SELECT 1 FROM session_checkins WHERE session_id = $1 AND expires_at > now()",
+                &[&session_id],
+            )
+            .await?
+            .is_some();
+
+        Ok(exists)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn stream_expired_sessions(
+        &self,
+    ) -> RepositoryResult<Option<BoxStream<'static, (Uuid, Vec<Uuid>)>>> {
+        // Checkins are rows with an expiration time, so nothing happens when they expire
+        Ok(None)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_session_cursor_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        x: f64,
+        y: f64,
+    ) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        // Remember the latest position so it can be included in presence snapshots
+        connection
+            .execute(
+                "UPDATE sessions SET cursor = $3 WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id, &Json(Cursor { x, y })],
+            )
+            .await?;
+
+        Self::publish_presence_message_for_board(
+            &*connection,
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserCursorChanged { session_id, x, y },
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_session_cursor_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "UPDATE sessions SET cursor = NULL WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id],
+            )
+            .await?;
+
+        Self::publish_presence_message_for_board(
+            &*connection,
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserCursorLeft { session_id },
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_session_latency_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        rtt: f64,
+    ) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "UPDATE sessions SET latency = $3 WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id, &rtt],
+            )
+            .await?;
+
+        Self::publish_presence_message_for_board(
+            &*connection,
+            board_id,
+            PresenceMessage {
+                source_session: session_id,
+                message: ServerMessage::UserLatencyChanged { session_id, rtt },
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_latencies_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, f64)>> {
+        let connection = self.pool.get().await?;
+
+        let latencies = connection
+            .query(
+                "SELECT session_id, latency FROM sessions
+                WHERE board_id = $1 AND latency IS NOT NULL",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get("session_id"), row.get("latency")))
+            .collect();
+
+        Ok(latencies)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_cursors_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Cursor)>> {
+        let connection = self.pool.get().await?;
+
+        let cursors = connection
+            .query(
+                "SELECT session_id, cursor FROM sessions
+                WHERE board_id = $1 AND cursor IS NOT NULL",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(cursor) = row.get::<_, Json<Cursor>>("cursor");
+                (row.get("session_id"), cursor)
+            })
+            .collect();
+
+        Ok(cursors)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_clients_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, ClientInfo)>> {
+        let connection = self.pool.get().await?;
+
+        let clients = connection
+            .query(
+                "SELECT session_id, client FROM sessions
+                WHERE board_id = $1 AND client IS NOT NULL",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(client) = row.get::<_, Json<ClientInfo>>("client");
+                (row.get("session_id"), client)
+            })
+            .collect();
+
+        Ok(clients)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn lock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Uuid> {
+        let connection = self.pool.get().await?;
+
+        // Take or renew the lease only if nobody else holds it, and report the holder either way.
+        // The outer SELECT sees the lease as it was before the INSERT, which is the holder if the
+        // INSERT didn't take it.
+        let holder = connection
+            .query_one(
+                "WITH taken AS (
+                    INSERT INTO object_locks (board_id, object_id, session_id, expires_at)
+                    VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+                    ON CONFLICT (board_id, object_id) DO UPDATE
+                    SET session_id = EXCLUDED.session_id, expires_at = EXCLUDED.expires_at
+                    WHERE object_locks.session_id = EXCLUDED.session_id
+                        OR object_locks.expires_at <= now()
+                    RETURNING session_id
+                )
+                SELECT session_id FROM taken
+                UNION ALL
+                SELECT session_id FROM object_locks
+                WHERE board_id = $1 AND object_id = $2 AND NOT EXISTS (SELECT 1 FROM taken)",
+                &[
+                    &board_id,
+                    &object_id,
+                    &session_id,
+                    &(OBJECT_LOCK_TTL_SECONDS as f64),
+                ],
+            )
+            .await?
+            .get::<_, Uuid>("session_id");
+
+        // Broadcast ObjectLocked notification if the lease belongs to this session
+        if holder == session_id {
+            Self::publish_presence_message_for_board(
+                &*connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::ObjectLocked {
+                        id: object_id,
+                        session_id,
+                    },
+                },
+            )
+            .await?;
+        }
+
+        Ok(holder)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn unlock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        // Compare-and-delete so that a session can never release somebody else's lease
+        let released = connection
+            .execute(
+                "DELETE FROM object_locks
+                WHERE board_id = $1 AND object_id = $2 AND session_id = $3 AND expires_at > now()",
+                &[&board_id, &object_id, &session_id],
+            )
+            .await?
+            > 0;
+
+        // Broadcast ObjectUnlocked notification
+        if released {
+            Self::publish_presence_message_for_board(
+                &*connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::ObjectUnlocked {
+                        id: object_id,
+                        session_id,
+                    },
+                },
+            )
+            .await?;
+        }
+
+        Ok(released)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_lock_for_board(
+        &self,
+        board_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Option<Uuid>> {
+        let connection = self.pool.get().await?;
+
+        let holder = connection
+            .query_opt(
+                "SELECT session_id FROM object_locks
+                WHERE board_id = $1 AND object_id = $2 AND expires_at > now()",
+                &[&board_id, &object_id],
+            )
+            .await?
+            .map(|row| row.get("session_id"));
+
+        Ok(holder)
+    }
+
+    #[tracing::instrument(skip(self, change), err)]
+    async fn get_conflicting_lock_for_change(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: &Change,
+    ) -> RepositoryResult<Option<Uuid>> {
+        let edited_ids = change
+            .clone()
+            .flatten()
+            .into_iter()
+            .filter(|edit| !matches!(edit, Change::Insert { .. }))
+            .flat_map(|edit| edit.expected_revisions())
+            .map(|(id, _)| id);
+        for id in edited_ids {
+            let holder = self.get_object_lock_for_board(board_id, id).await?;
+            if let Some(holder) = holder.filter(|holder| *holder != session_id) {
+                return Ok(Some(holder));
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_locks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Uuid)>> {
+        let connection = self.pool.get().await?;
+
+        let locks = connection
+            .query(
+                "SELECT object_id, session_id FROM object_locks
+                WHERE board_id = $1 AND expires_at > now()",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get("object_id"), row.get("session_id")))
+            .collect();
+
+        Ok(locks)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_all_board_ids(&self) -> BoxStream<'static, RepositoryResult<Uuid>> {
+        let pool = self.pool.clone();
+        Box::pin(try_stream! {
+            let connection = pool.get().await?;
+
+            // A board exists once anything has been published to it, just like a change stream
+            let rows = connection
+                .query(
+                    "SELECT board_id FROM boards WHERE last_change_ms IS NOT NULL",
+                    &[],
+                )
+                .await?;
+            for row in rows {
+                yield row.get::<_, Uuid>("board_id");
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_checkpoints(&self) -> RepositoryResult<Vec<(Uuid, usize)>> {
+        let connection = self.pool.get().await?;
+
+        // Count the changes after each board's version, which is only ever `0` or
+        // `{milliseconds}-{sequence}`
+        let rows = connection
+            .query(
+                "SELECT boards.board_id, count(*) AS pending FROM boards
+                JOIN changes ON changes.board_id = boards.board_id
+                    AND (changes.timestamp_ms, changes.sequence) > (
+                        split_part(boards.version, '-', 1)::BIGINT,
+                        COALESCE(NULLIF(split_part(boards.version, '-', 2), ''), '0')::BIGINT
+                    )
+                GROUP BY boards.board_id",
+                &[],
+            )
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get::<_, Uuid>("board_id"),
+                    row.get::<_, i64>("pending") as usize,
+                )
+            })
+            .collect())
+    }
+
+    /// Pending changes are counted from the changes table itself, so there's nothing to clear
+    #[tracing::instrument(skip(self), err)]
+    async fn clear_pending_checkpoint(
+        &self,
+        _board_id: Uuid,
+        _count: usize,
+    ) -> RepositoryResult<()> {
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_changes_for_board(
+        &self,
+        board_id: Uuid,
+        count: usize,
+        version: Option<String>,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        let (timestamp, sequence) = Self::split_version(version.as_deref().unwrap_or("0"))?;
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+
+        // Subscribe before looking so that a change published in between isn't missed, then wait
+        // up to a second for one to show up. Returning nothing is fine since callers poll.
+        let mut change_receiver = self.change_sender.subscribe();
+        loop {
+            let changes = self
+                .pool
+                .get()
+                .await?
+                .query(
+                    format!(
+                        "SELECT {CHANGE_COLUMNS} FROM changes
+                        WHERE board_id = $1 AND (timestamp_ms, sequence) > ($2, $3)
+                        ORDER BY timestamp_ms, sequence LIMIT $4"
+                    )
+                    .as_str(),
+                    &[&board_id, &timestamp, &sequence, &(count as i64)],
+                )
+                .await?
+                .iter()
+                .filter_map(Self::parse_change_row)
+                .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                return Ok(changes);
+            }
+
+            loop {
+                match tokio::time::timeout_at(deadline, change_receiver.recv()).await {
+                    Err(_) | Ok(Err(RecvError::Closed)) => return Ok(vec![]),
+                    Ok(Err(RecvError::Lagged(_))) => break,
+                    Ok(Ok(changed_board_id)) if changed_board_id == board_id => break,
+                    Ok(Ok(_)) => continue,
+                }
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_change_history_for_board(
+        &self,
+        board_id: Uuid,
+        since: Option<String>,
+        until: Option<String>,
+        session_id: Option<Uuid>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        let connection = self.pool.get().await?;
+
+        // Bounds are inclusive, and a bare timestamp covers every change in that millisecond
+        let start = match &since {
+            Some(since) => Self::split_version(since)?,
+            None => (0, 0),
+        };
+        let end = match &until {
+            Some(until) if until.contains('-') => Self::split_version(until)?,
+            Some(until) => (
+                until
+                    .parse()
+                    .map_err(|_| anyhow!("{until} is not a valid timestamp"))?,
+                i64::MAX,
+            ),
+            None => (i64::MAX, i64::MAX),
+        };
+
+        // Read from the history along with whatever is only in the change stream after the point
+        // where the history ends. UNION drops the changes that are in both.
+        let changes = connection
+            .query(
+                format!(
+                    "SELECT {CHANGE_COLUMNS} FROM (
+                        SELECT {CHANGE_COLUMNS} FROM change_history WHERE board_id = $1
+                        UNION
+                        SELECT {CHANGE_COLUMNS} FROM changes WHERE board_id = $1
+                    ) AS all_changes
+                    WHERE (timestamp_ms, sequence) >= ($2, $3)
+                        AND (timestamp_ms, sequence) <= ($4, $5)
+                        AND ($6::UUID IS NULL OR session_id = $6)
+                    ORDER BY timestamp_ms, sequence LIMIT $7"
+                )
+                .as_str(),
+                &[
+                    &board_id,
+                    &start.0,
+                    &start.1,
+                    &end.0,
+                    &end.1,
+                    &session_id,
+                    &(limit as i64),
+                ],
+            )
+            .await?
+            .iter()
+            .filter_map(Self::parse_change_row)
+            .collect();
+
+        Ok(changes)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        let connection = self.pool.get().await?;
+        let (timestamp, sequence) = Self::split_version(&version)?;
+
+        let changes = connection
+            .query(
+                format!(
+                    "SELECT {CHANGE_COLUMNS} FROM changes
+                    WHERE board_id = $1 AND (timestamp_ms, sequence) > ($2, $3)
+                    ORDER BY timestamp_ms, sequence"
+                )
+                .as_str(),
+                &[&board_id, &timestamp, &sequence],
+            )
+            .await?
+            .iter()
+            .filter_map(Self::parse_change_row)
+            .collect();
+
+        Ok(changes)
+    }
+
+    // Bulk-apply a set of changes to the materialized objects of a board, and persist the version
+    // of the latest change to help future readers know where to pick up the change stream after
+    // reading the objects. It all happens in one transaction, so it either all happens or none of
+    // it does.
+    #[tracing::instrument(skip(self, entries), err)]
+    async fn apply_changes_to_board(
+        &self,
+        board_id: Uuid,
+        since_version: &str,
+        entries: Vec<ChangeEntry>,
+    ) -> RepositoryResult<bool> {
+        let version = match entries.last() {
+            Some(entry) => entry.version.clone(),
+            None => return Ok(true),
+        };
+        let (timestamp, sequence) = Self::split_version(&version)?;
+
+        let mut connection = self.pool.get().await?;
+        let transaction = connection.transaction().await?;
+
+        // Move the version along first, but only from the version the changes were read after.
+        // This locks the board's row until the transaction is done, so another checkpoint of the
+        // same board waits for this one and then finds that the version has moved on.
+        let version_updated = transaction
+            .execute(
+                "INSERT INTO boards (board_id, version) VALUES ($1, $2)
+                ON CONFLICT (board_id) DO UPDATE SET version = EXCLUDED.version
+                WHERE boards.version = $3",
+                &[&board_id, &version, &since_version],
+            )
+            .await?
+            == 1;
+        if !version_updated {
+            return Ok(false);
+        }
+
+        // Translate each change into a statement. Transactions are unpacked into their individual
+        // changes, which is all it takes to apply them atomically since this is all one
+        // transaction and a transaction is a single change that can never be split across two
+        // checkpoints. Changes that later ones make redundant are merged away first.
+        let changes = entries.iter().map(|entry| entry.change.clone());
+        let mut trashed_any = false;
+        for change in Change::coalesce(changes.flat_map(Change::flatten).collect()) {
+            match change {
+                // Deleted objects are moved to the trash along with their position
+                Change::Delete { id } => {
+                    trashed_any |= transaction
+                        .execute(
+                            "INSERT INTO trash (board_id, object_id, object, position, expires_at)
+                            SELECT objects.board_id, objects.object_id, object, position,
+                                now() + make_interval(secs => $3)
+                            FROM objects LEFT JOIN object_order USING (board_id, object_id)
+                            WHERE objects.board_id = $1 AND objects.object_id = $2
+                            ON CONFLICT (board_id, object_id) DO UPDATE
+                            SET object = EXCLUDED.object, position = EXCLUDED.position",
+                            &[&board_id, &id, &(TRASH_TTL_SECONDS as f64)],
+                        )
+                        .await?
+                        > 0;
+                    transaction
+                        .execute(
+                            "DELETE FROM objects WHERE board_id = $1 AND object_id = $2",
+                            &[&board_id, &id],
+                        )
+                        .await?;
+                    transaction
+                        .execute(
+                            "DELETE FROM object_order WHERE board_id = $1 AND object_id = $2",
+                            &[&board_id, &id],
+                        )
+                        .await?;
+                }
+                Change::SetIndex { id, index } => {
+                    transaction
+                        .execute(
+                            "INSERT INTO object_order (board_id, object_id, position)
+                            VALUES ($1, $2, $3)
+                            ON CONFLICT (board_id, object_id) DO UPDATE
+                            SET position = EXCLUDED.position",
+                            &[&board_id, &id, &index],
+                        )
+                        .await?;
+                }
+                Change::Transaction { .. } => {
+                    unreachable!("Transactions are flattened before being applied")
+                }
+                Change::Insert { id, object } | Change::Replace { id, object, .. } => {
+                    transaction
+                        .execute(
+                            "INSERT INTO objects (board_id, object_id, object) VALUES ($1, $2, $3)
+                            ON CONFLICT (board_id, object_id) DO UPDATE
+                            SET object = EXCLUDED.object",
+                            &[&board_id, &id, &Json(&object)],
+                        )
+                        .await?;
+                }
+                Change::RestoreObject { id, object, index } => {
+                    transaction
+                        .execute(
+                            "DELETE FROM trash WHERE board_id = $1 AND object_id = $2",
+                            &[&board_id, &id],
+                        )
+                        .await?;
+                    if let Some(object) = object {
+                        transaction
+                            .execute(
+                                "INSERT INTO objects (board_id, object_id, object)
+                                VALUES ($1, $2, $3)
+                                ON CONFLICT (board_id, object_id) DO UPDATE
+                                SET object = EXCLUDED.object",
+                                &[&board_id, &id, &Json(&object)],
+                            )
+                            .await?;
+                    }
+                    if let Some(index) = index {
+                        transaction
+                            .execute(
+                                "INSERT INTO object_order (board_id, object_id, position)
+                                VALUES ($1, $2, $3)
+                                ON CONFLICT (board_id, object_id) DO UPDATE
+                                SET position = EXCLUDED.position",
+                                &[&board_id, &id, &index],
+                            )
+                            .await?;
+                    }
+                }
+                // An update to an object that doesn't exist does nothing
+                Change::Update { id, key, value } => {
+                    transaction
+                        .execute(
+                            "UPDATE objects SET object = jsonb_set(object, ARRAY[$3], $4)
+                            WHERE board_id = $1 AND object_id = $2",
+                            &[&board_id, &id, &key, &Json(&value)],
+                        )
+                        .await?;
+                }
+            }
+        }
+
+        // Each changed object is now at the revision it was given by the last of these changes
+        let checkpointed_revisions = entries
+            .iter()
+            .flat_map(|entry| &entry.revisions)
+            .map(|(id, revision)| (*id, *revision as i64))
+            .collect::<HashMap<_, _>>();
+        for (id, revision) in checkpointed_revisions {
+            transaction
+                .execute(
+                    "INSERT INTO checkpointed_revisions (board_id, object_id, revision)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (board_id, object_id) DO UPDATE
+                    SET revision = GREATEST(checkpointed_revisions.revision, EXCLUDED.revision)",
+                    &[&board_id, &id, &revision],
+                )
+                .await?;
+        }
+
+        // Like the hash in Redis, the whole trash expires together, so throw out whatever already
+        // expired and keep the rest around as long as what was just put in it
+        if trashed_any {
+            transaction
+                .execute(
+                    "DELETE FROM trash WHERE board_id = $1 AND expires_at <= now()",
+                    &[&board_id],
+                )
+                .await?;
+            transaction
+                .execute(
+                    "UPDATE trash SET expires_at = now() + make_interval(secs => $2)
+                    WHERE board_id = $1",
+                    &[&board_id, &(TRASH_TTL_SECONDS as f64)],
+                )
+                .await?;
+        }
+
+        // Keep the changes in the board's history, capped at the configured length. A change can
+        // only be kept once, so anything that was already checkpointed once is skipped.
+        if self.history_length > 0 {
+            for entry in &entries {
+                let (entry_timestamp, entry_sequence) = Self::split_version(&entry.version)?;
+                transaction
+                    .execute(
+                        format!(
+                            "INSERT INTO change_history (board_id, {CHANGE_COLUMNS})
+                            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                            ON CONFLICT (board_id, timestamp_ms, sequence) DO NOTHING"
+                        )
+                        .as_str(),
+                        &[
+                            &board_id,
+                            &entry_timestamp,
+                            &entry_sequence,
+                            &entry.session_id,
+                            &entry.username,
+                            &entry.user_id,
+                            &Json(&entry.revisions),
+                            &Json(&entry.change),
+                        ],
+                    )
+                    .await?;
+            }
+            transaction
+                .execute(
+                    "DELETE FROM change_history WHERE board_id = $1 AND id NOT IN (
+                        SELECT id FROM change_history WHERE board_id = $1
+                        ORDER BY timestamp_ms DESC, sequence DESC LIMIT $2
+                    )",
+                    &[&board_id, &(self.history_length as i64)],
+                )
+                .await?;
+        }
+
+        // Copy the board as it is now into its checkpoint history if it's been long enough since
+        // the last one, keeping only the configured number of checkpoints
+        let newest_version = transaction
+            .query_opt(
+                "SELECT timestamp_ms, sequence FROM checkpoints WHERE board_id = $1
+                ORDER BY timestamp_ms DESC, sequence DESC LIMIT 1",
+                &[&board_id],
+            )
+            .await?
+            .map(|row| format!("{}-{}", row.get::<_, i64>(0), row.get::<_, i64>(1)));
+        if self
+            .checkpoint_history
+            .keeps(&version, newest_version.as_deref())
+        {
+            transaction
+                .execute(
+                    "INSERT INTO checkpoints
+                        (board_id, timestamp_ms, sequence, objects, object_order)
+                    VALUES (
+                        $1, $2, $3,
+                        (SELECT COALESCE(jsonb_object_agg(object_id, object), '{}')
+                            FROM objects WHERE board_id = $1),
+                        (SELECT COALESCE(
+                            jsonb_agg(jsonb_build_array(object_id, position) ORDER BY position),
+                            '[]'
+                        ) FROM object_order WHERE board_id = $1)
+                    )
+                    ON CONFLICT (board_id, timestamp_ms, sequence) DO NOTHING",
+                    &[&board_id, &timestamp, &sequence],
+                )
+                .await?;
+            transaction
+                .execute(
+                    "DELETE FROM checkpoints WHERE board_id = $1
+                    AND (timestamp_ms, sequence) NOT IN (
+                        SELECT timestamp_ms, sequence FROM checkpoints WHERE board_id = $1
+                        ORDER BY timestamp_ms DESC, sequence DESC LIMIT $2
+                    )",
+                    &[&board_id, &(self.checkpoint_history.length as i64)],
+                )
+                .await?;
+        }
+
+        // Update the spatial and search indexes for every object the changes touched
+        let touched_ids = entries
+            .iter()
+            .flat_map(|entry| entry.change.expected_revisions())
+            .map(|(id, _)| id)
+            .unique()
+            .collect::<Vec<_>>();
+        Self::index_object_terms(&transaction, board_id, &touched_ids, timestamp).await?;
+        Self::index_objects(&transaction, board_id, Some(touched_ids)).await?;
+
+        Self::push_webhook_event(
+            &transaction,
+            &WebhookEvent::ChangeApplied {
+                board_id,
+                changes: entries.iter().cloned().map(AcceptedChange::from).collect(),
+            },
+        )
+        .await?;
+
+        // Finally, drop everything before the new version from the change stream that isn't
+        // being retained. The change at the version itself is kept, the same as trimming a Redis
+        // stream with MINID.
+        let nth_newest = match self.change_retention.entries {
+            0 => None,
+            entries => transaction
+                .query_opt(
+                    "SELECT timestamp_ms, sequence FROM changes WHERE board_id = $1
+                    ORDER BY timestamp_ms DESC, sequence DESC OFFSET $2 LIMIT 1",
+                    &[&board_id, &(entries as i64 - 1)],
+                )
+                .await?
+                .map(|row| (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64)),
+        };
+        let (min_timestamp, min_sequence) = self
+            .change_retention
+            .min_id((timestamp as u64, sequence as u64), nth_newest);
+        transaction
+            .execute(
+                "DELETE FROM changes WHERE board_id = $1 AND (timestamp_ms, sequence) < ($2, $3)",
+                &[&board_id, &(min_timestamp as i64), &(min_sequence as i64)],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn publish_change_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: Change,
+        idempotency_key: Option<String>,
+    ) -> RepositoryResult<PublishOutcome> {
+        let mut connection = self.pool.get().await?;
+        let transaction = connection.transaction().await?;
+
+        // Lock the board's row for the rest of the transaction so that no other change can sneak
+        // in between checking the revisions and adding this change
+        transaction
+            .execute(
+                "INSERT INTO boards (board_id) VALUES ($1) ON CONFLICT (board_id) DO NOTHING",
+                &[&board_id],
+            )
+            .await?;
+        let board_row = transaction
+            .query_one(
+                "SELECT last_change_ms, last_change_seq FROM boards
+                WHERE board_id = $1 FOR UPDATE",
+                &[&board_id],
+            )
+            .await?;
+
+        if let Some(idempotency_key) = &idempotency_key {
+            transaction
+                .execute(
+                    "DELETE FROM idempotency_keys WHERE board_id = $1 AND expires_at <= now()",
+                    &[&board_id],
+                )
+                .await?;
+            let existing_version = transaction
+                .query_opt(
+                    "SELECT version FROM idempotency_keys
+                    WHERE board_id = $1 AND idempotency_key = $2",
+                    &[&board_id, idempotency_key],
+                )
+                .await?;
+            if let Some(row) = existing_version {
+                return Ok(PublishOutcome::Duplicate {
+                    version: row.get("version"),
+                });
+            }
+        }
+
+        let expected_revisions = change.expected_revisions();
+        for (id, expected_revision) in &expected_revisions {
+            let current_revision = transaction
+                .query_opt(
+                    "SELECT revision FROM revisions WHERE board_id = $1 AND object_id = $2",
+                    &[&board_id, id],
+                )
+                .await?
+                .map(|row| row.get::<_, i64>("revision") as u64)
+                .unwrap_or_default();
+            if matches!(expected_revision, Some(expected) if *expected != current_revision) {
+                return Ok(PublishOutcome::RevisionMismatch {
+                    id: *id,
+                    current_revision,
+                });
+            }
+        }
+
+        let mut revisions = Vec::new();
+        for (id, _) in expected_revisions {
+            let revision = transaction
+                .query_one(
+                    "INSERT INTO revisions (board_id, object_id, revision) VALUES ($1, $2, 1)
+                    ON CONFLICT (board_id, object_id) DO UPDATE
+                    SET revision = revisions.revision + 1
+                    RETURNING revision",
+                    &[&board_id, &id],
+                )
+                .await?
+                .get::<_, i64>("revision");
+            revisions.push((id, revision as u64));
+        }
+
+        // Make up the version from the current time, the same way Redis does for stream entries,
+        // so that versions always increase even if the clock doesn't
+        let now = Utc::now().timestamp_millis();
+        let (timestamp, sequence) = match (
+            board_row.get::<_, Option<i64>>("last_change_ms"),
+            board_row.get::<_, Option<i64>>("last_change_seq"),
+        ) {
+            (Some(timestamp), Some(sequence)) if timestamp >= now => (timestamp, sequence + 1),
+            _ => (now, 0),
+        };
+        let version = format!("{timestamp}-{sequence}");
+
+        // Record the author's username and user ID from the board's sessions along with the
+        // change, and bump the board's last activity in the registry to the time of the change
+        let session_row = transaction
+            .query_opt(
+                "SELECT username, user_id FROM sessions WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id],
+            )
+            .await?;
+        let username = session_row
+            .as_ref()
+            .map(|row| row.get::<_, String>("username"));
+        let user_id = session_row.and_then(|row| row.get::<_, Option<String>>("user_id"));
+        transaction
+            .execute(
+                format!(
+                    "INSERT INTO changes (board_id, {CHANGE_COLUMNS})
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)"
+                )
+                .as_str(),
+                &[
+                    &board_id,
+                    &timestamp,
+                    &sequence,
+                    &session_id,
+                    &username,
+                    &user_id,
+                    &Json(&revisions),
+                    &Json(&change),
+                ],
+            )
+            .await?;
+        transaction
+            .execute(
+                "UPDATE boards SET last_change_ms = $2, last_change_seq = $3, last_activity_at = $2
+                WHERE board_id = $1",
+                &[&board_id, &timestamp, &sequence],
+            )
+            .await?;
+
+        if let Some(idempotency_key) = &idempotency_key {
+            transaction
+                .execute(
+                    "INSERT INTO idempotency_keys (board_id, idempotency_key, version, expires_at)
+                    VALUES ($1, $2, $3, now() + make_interval(secs => $4))",
+                    &[
+                        &board_id,
+                        idempotency_key,
+                        &version,
+                        &(IDEMPOTENCY_TTL_SECONDS as f64),
+                    ],
+                )
+                .await?;
+        }
+
+        // Wake up anyone waiting on the board's changes once the transaction commits
+        transaction
+            .execute(
+                "SELECT pg_notify($1, $2)",
+                &[&CHANGES_CHANNEL, &board_id.to_string()],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        Ok(PublishOutcome::Accepted { version, revisions })
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_revisions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        let connection = self.pool.get().await?;
+
+        let revisions = connection
+            .query(
+                "SELECT object_id, revision FROM revisions WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get("object_id"), row.get::<_, i64>("revision") as u64))
+            .collect();
+
+        Ok(revisions)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpointed_revisions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        let connection = self.pool.get().await?;
+
+        let revisions = connection
+            .query(
+                "SELECT object_id, revision FROM checkpointed_revisions WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get("object_id"), row.get::<_, i64>("revision") as u64))
+            .collect();
+
+        Ok(revisions)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String> {
+        let connection = self.pool.get().await?;
+
+        let version = connection
+            .query_opt(
+                "SELECT version FROM boards WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .map(|row| row.get("version"))
+            .unwrap_or_else(|| "0".to_string());
+
+        Ok(version)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_latest_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String> {
+        let connection = self.pool.get().await?;
+
+        let version = connection
+            .query_opt(
+                "SELECT timestamp_ms, sequence FROM changes WHERE board_id = $1
+                ORDER BY timestamp_ms DESC, sequence DESC LIMIT 1",
+                &[&board_id],
+            )
+            .await?
+            .map(|row| {
+                format!(
+                    "{}-{}",
+                    row.get::<_, i64>("timestamp_ms"),
+                    row.get::<_, i64>("sequence")
+                )
+            })
+            .unwrap_or_else(|| "0".to_string());
+
+        Ok(version)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_version_available_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<bool> {
+        let requested_version = match Repository::parse_stream_id(version) {
+            Some(requested_version) => requested_version,
+            None => return Ok(false),
+        };
+
+        // Checkpointing only drops the oldest changes and never past the checkpointed version, so
+        // anything at or after the oldest retained change or the checkpointed version can still
+        // be read from the change stream
+        let checkpointed_version = self.get_version_for_board(board_id).await?;
+        let connection = self.pool.get().await?;
+        let oldest_version = connection
+            .query_opt(
+                "SELECT timestamp_ms, sequence FROM changes WHERE board_id = $1
+                ORDER BY timestamp_ms, sequence LIMIT 1",
+                &[&board_id],
+            )
+            .await?
+            .map(|row| (row.get::<_, i64>(0) as u64, row.get::<_, i64>(1) as u64));
+        Ok([
+            oldest_version,
+            Repository::parse_stream_id(checkpointed_version.as_str()),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|available_version| requested_version >= available_version))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        let connection = self.pool.get().await?;
+
+        let object_count = connection
+            .query_one(
+                "SELECT COUNT(*) FROM objects WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .get::<_, i64>(0);
+
+        Ok(object_count as usize)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_change_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        let version = self.get_version_for_board(board_id).await?;
+        let (timestamp, sequence) = Self::split_version(&version)?;
+        let connection = self.pool.get().await?;
+
+        let pending_change_count = connection
+            .query_one(
+                "SELECT COUNT(*) FROM changes
+                WHERE board_id = $1 AND (timestamp_ms, sequence) > ($2, $3)",
+                &[&board_id, &timestamp, &sequence],
+            )
+            .await?
+            .get::<_, i64>(0);
+
+        Ok(pending_change_count as usize)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        let connection = self.pool.get().await?;
+
+        let session_count = connection
+            .query_one(
+                "SELECT COUNT(*) FROM sessions WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .get::<_, i64>(0);
+
+        Ok(session_count as usize)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn join_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<usize> {
+        let connection = self.pool.get().await?;
+
+        // Joining again keeps the session's place
+        connection
+            .execute(
+                "INSERT INTO waiting_sessions (board_id, session_id) VALUES ($1, $2)
+                ON CONFLICT (board_id, session_id) DO NOTHING",
+                &[&board_id, &session_id],
+            )
+            .await?;
+        let position = connection
+            .query_one(
+                "SELECT COUNT(*) FROM waiting_sessions
+                WHERE board_id = $1 AND (joined_at, session_id) <= (
+                    SELECT joined_at, session_id FROM waiting_sessions
+                    WHERE board_id = $1 AND session_id = $2
+                )",
+                &[&board_id, &session_id],
+            )
+            .await?
+            .get::<_, i64>(0);
+
+        Ok(position.max(1) as usize)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn leave_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+        connection
+            .execute(
+                "DELETE FROM waiting_sessions WHERE board_id = $1 AND session_id = $2",
+                &[&board_id, &session_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_waiting_sessions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let connection = self.pool.get().await?;
+
+        let session_ids = connection
+            .query(
+                "SELECT session_id FROM waiting_sessions WHERE board_id = $1
+                ORDER BY joined_at, session_id",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| row.get("session_id"))
+            .collect();
+
+        Ok(session_ids)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_last_activity_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Option<DateTime<Utc>>> {
+        let connection = self.pool.get().await?;
+
+        let last_activity = connection
+            .query_opt(
+                "SELECT last_activity_at FROM boards WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .and_then(|row| row.get::<_, Option<i64>>("last_activity_at"))
+            .map(|last_activity| Utc.timestamp_millis(last_activity));
+
+        Ok(last_activity)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_memory_usage_for_board(&self, board_id: Uuid) -> RepositoryResult<u64> {
+        let connection = self.pool.get().await?;
+
+        // Add up the stored size of the board's objects, changes, checkpoints, and trash, which are
+        // nearly all of it
+        let usage = connection
+            .query_one(
+                "SELECT (
+                    (SELECT COALESCE(SUM(pg_column_size(object)), 0) FROM objects
+                        WHERE board_id = $1)
+                    + (SELECT COALESCE(SUM(pg_column_size(change)), 0) FROM changes
+                        WHERE board_id = $1)
+                    + (SELECT COALESCE(SUM(pg_column_size(change)), 0) FROM change_history
+                        WHERE board_id = $1)
+                    + (SELECT COALESCE(SUM(pg_column_size(objects)), 0) FROM checkpoints
+                        WHERE board_id = $1)
+                    + (SELECT COALESCE(SUM(pg_column_size(object)), 0) FROM trash
+                        WHERE board_id = $1)
+                )::BIGINT",
+                &[&board_id],
+            )
+            .await?
+            .get::<_, i64>(0);
+
+        Ok(usage as u64)
+    }
+
+    fn get_pool_stats(&self) -> Option<PoolStats> {
+        Some(self.pool.stats())
+    }
+
+    fn get_presence_stats(&self) -> PresenceStats {
+        self.presence.stats()
+    }
+
+    fn watch_degraded(&self) -> Option<WatchReceiver<bool>> {
+        Some(self.pool.watch_degraded())
+    }
+
+    fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpoint_versions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<String>> {
+        let connection = self.pool.get().await?;
+
+        let versions = connection
+            .query(
+                "SELECT timestamp_ms, sequence FROM checkpoints WHERE board_id = $1
+                ORDER BY timestamp_ms DESC, sequence DESC",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| format!("{}-{}", row.get::<_, i64>(0), row.get::<_, i64>(1)))
+            .collect();
+
+        Ok(versions)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpoint_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<Option<BoardSnapshot>> {
+        let (timestamp, sequence) = match Self::split_version(version) {
+            Ok(split_version) => split_version,
+            Err(_) => return Ok(None),
+        };
+        let connection = self.pool.get().await?;
+
+        let checkpoint = connection
+            .query_opt(
+                "SELECT objects, object_order FROM checkpoints
+                WHERE board_id = $1 AND timestamp_ms = $2 AND sequence = $3",
+                &[&board_id, &timestamp, &sequence],
+            )
+            .await?
+            .map(|row| {
+                let Json(objects) = row.get::<_, Json<BTreeMap<Uuid, JsonObject>>>("objects");
+                let Json(order) = row.get::<_, Json<Vec<(Uuid, f64)>>>("object_order");
+                BoardSnapshot {
+                    version: version.to_string(),
+                    objects,
+                    order,
+                }
+            });
+
+        Ok(checkpoint)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_trash_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, TrashedObject)>> {
+        let connection = self.pool.get().await?;
+
+        let trash = connection
+            .query(
+                "SELECT object_id, object, position FROM trash
+                WHERE board_id = $1 AND expires_at > now()",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(object) = row.get::<_, Json<JsonObject>>("object");
+                let trashed = TrashedObject {
+                    object,
+                    index: row.get("position"),
+                };
+                (row.get("object_id"), trashed)
+            })
+            .collect();
+
+        Ok(trash)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_order_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, f64)>> {
+        let connection = self.pool.get().await?;
+
+        let order = connection
+            .query(
+                "SELECT object_id, position FROM object_order WHERE board_id = $1
+                ORDER BY position, object_id",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| (row.get("object_id"), row.get("position")))
+            .collect();
+
+        Ok(order)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_objects_in_rect(
+        &self,
+        board_id: Uuid,
+        rect: Rect,
+    ) -> RepositoryResult<Vec<(Uuid, JsonObject)>> {
+        let connection = self.pool.get().await?;
+
+        // Boards from before there was a spatial index get indexed the first time they're asked
+        let indexed = connection
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM object_bounds WHERE board_id = $1)
+                OR NOT EXISTS (SELECT 1 FROM objects WHERE board_id = $1)",
+                &[&board_id],
+            )
+            .await?
+            .get::<_, bool>(0);
+        if !indexed {
+            Self::index_objects(&*connection, board_id, None).await?;
+        }
+
+        let objects = connection
+            .query(
+                "SELECT object_id, object
+                FROM object_bounds JOIN objects USING (board_id, object_id)
+                WHERE board_id = $1
+                AND min_x <= $4 AND max_x >= $2 AND min_y <= $5 AND max_y >= $3",
+                &[
+                    &board_id,
+                    &rect.min_x,
+                    &rect.min_y,
+                    &rect.max_x,
+                    &rect.max_y,
+                ],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(object) = row.get::<_, Json<JsonObject>>("object");
+                (row.get("object_id"), object)
+            })
+            .collect();
+
+        Ok(objects)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn search_objects(
+        &self,
+        terms: &BTreeSet<String>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<SearchHit>> {
+        let connection = self.pool.get().await?;
+        let terms = terms.iter().cloned().collect::<Vec<_>>();
+
+        // An object matches when it has a row for every term, and it changed when the newest of
+        // those rows was added
+        let hits = connection
+            .query(
+                "SELECT board_id, object_id, object FROM objects JOIN (
+                    SELECT board_id, object_id, max(indexed_at) AS indexed_at FROM object_terms
+                    WHERE term = ANY($1)
+                    GROUP BY board_id, object_id
+                    HAVING count(*) = $2
+                ) AS matches USING (board_id, object_id)
+                ORDER BY matches.indexed_at DESC
+                LIMIT $3",
+                &[&terms, &(terms.len() as i64), &(limit as i64)],
+            )
+            .await?
+            .into_iter()
+            .map(|row| {
+                let Json(object) = row.get::<_, Json<JsonObject>>("object");
+                SearchHit {
+                    board_id: row.get("board_id"),
+                    object_id: row.get("object_id"),
+                    object,
+                }
+            })
+            .collect();
+
+        Ok(hits)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_object_chunks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, RepositoryResult<Vec<(Uuid, JsonObject)>>> {
+        let pool = self.pool.clone();
+        Box::pin(try_stream! {
+            // Page through the objects 100 at a time in order of their IDs, starting each page
+            // after the last ID of the one before
+            let mut last_object_id: Option<Uuid> = None;
+            loop {
+                let rows = pool
+                    .get()
+                    .await?
+                    .query(
+                        "SELECT object_id, object FROM objects
+                        WHERE board_id = $1 AND ($2::UUID IS NULL OR object_id > $2)
+                        ORDER BY object_id LIMIT 100",
+                        &[&board_id, &last_object_id],
+                    )
+                    .await?;
+                let entries = rows
+                    .into_iter()
+                    .map(|row| {
+                        let Json(object) = row.get::<_, Json<JsonObject>>("object");
+                        (row.get::<_, Uuid>("object_id"), object)
+                    })
+                    .collect::<Vec<_>>();
+
+                match entries.last() {
+                    Some((object_id, _)) => last_object_id = Some(*object_id),
+                    None => break,
+                }
+                yield entries;
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn stream_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> BoxStream<'static, ChangeEntry> {
+        let repo = self.clone();
+        Box::pin(stream! {
+            let mut version = version;
+            loop {
+                let changes = match repo
+                    .get_changes_for_board(board_id, 100, Some(version.clone()))
+                    .await
+                {
+                    Ok(changes) => changes,
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                for entry in changes {
+                    version = entry.version.clone();
+                    yield entry;
+                }
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_presence_messages_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, PresenceMessage> {
+        self.presence.stream_for_board(board_id)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn pop_webhook_event(&self) -> RepositoryResult<Option<WebhookEvent>> {
+        // SKIP LOCKED lets several servers take events off of the queue at once without ever
+        // getting the same one
+        let pop = || async {
+            let connection = self.pool.get().await?;
+            let event = connection
+                .query_opt(
+                    "DELETE FROM webhook_queue WHERE id = (
+                        SELECT id FROM webhook_queue ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING event",
+                    &[],
+                )
+                .await?
+                .map(|row| row.get::<_, Json<WebhookEvent>>("event").0);
+            Ok::<_, RepositoryError>(event)
+        };
+
+        if let Some(event) = pop().await? {
+            return Ok(Some(event));
+        }
+
+        // Wait up to a second for the next event to be queued before trying again
+        let notified = tokio::time::timeout(Duration::from_secs(1), self.webhook_notify.notified());
+        if notified.await.is_err() {
+            return Ok(None);
+        }
+        pop().await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_webhooks_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<String>> {
+        let connection = self.pool.get().await?;
+
+        let urls = connection
+            .query(
+                "SELECT url FROM board_webhooks WHERE board_id = $1",
+                &[&board_id],
+            )
+            .await?
+            .into_iter()
+            .map(|row| row.get("url"))
+            .collect();
+
+        Ok(urls)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_webhook_for_board(&self, board_id: Uuid, url: String) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "INSERT INTO board_webhooks (board_id, url) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&board_id, &url],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_webhook_for_board(
+        &self,
+        board_id: Uuid,
+        url: String,
+    ) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        let removed = connection
+            .execute(
+                "DELETE FROM board_webhooks WHERE board_id = $1 AND url = $2",
+                &[&board_id, &url],
+            )
+            .await?
+            > 0;
+
+        Ok(removed)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_api_key(&self, hash: &str) -> RepositoryResult<Option<ApiKey>> {
+        let connection = self.pool.get().await?;
+
+        let api_key = connection
+            .query_opt("SELECT api_key FROM api_keys WHERE hash = $1", &[&hash])
+            .await?
+            .map(|row| row.get::<_, Json<ApiKey>>("api_key").0);
+
+        Ok(api_key)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_api_keys(&self) -> RepositoryResult<Vec<(String, ApiKey)>> {
+        let connection = self.pool.get().await?;
+
+        let api_keys = connection
+            .query("SELECT hash, api_key FROM api_keys", &[])
+            .await?
+            .into_iter()
+            .map(|row| (row.get("hash"), row.get::<_, Json<ApiKey>>("api_key").0))
+            .collect();
+
+        Ok(api_keys)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_api_key(&self, hash: &str, api_key: ApiKey) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "INSERT INTO api_keys (hash, api_key) VALUES ($1, $2)
+                ON CONFLICT (hash) DO UPDATE SET api_key = EXCLUDED.api_key",
+                &[&hash, &Json(&api_key)],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_api_key(&self, hash: &str) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        let removed = connection
+            .execute("DELETE FROM api_keys WHERE hash = $1", &[&hash])
+            .await?
+            > 0;
+
+        Ok(removed)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_share_secret(&self) -> RepositoryResult<String> {
+        let connection = self.pool.get().await?;
+
+        // Store a random secret unless another server already has, then read back whichever one
+        // won
+        let secret = format!(
+            "{}{}",
+            Uuid::new_v4().as_simple(),
+            Uuid::new_v4().as_simple()
+        );
+        connection
+            .execute(
+                "INSERT INTO settings (name, value) VALUES ('share_secret', $1)
+                ON CONFLICT (name) DO NOTHING",
+                &[&secret],
+            )
+            .await?;
+        let secret = connection
+            .query_one(
+                "SELECT value FROM settings WHERE name = 'share_secret'",
+                &[],
+            )
+            .await?
+            .get("value");
+
+        Ok(secret)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn acquire_lock(
+        &self,
+        name: &str,
+        holder: Uuid,
+        ttl: Duration,
+    ) -> RepositoryResult<bool> {
+        let connection = self.pool.get().await?;
+
+        // Take or renew the lock only if nobody else holds it, the same way as object locks
+        let acquired = connection
+            .execute(
+                "INSERT INTO task_locks (name, holder, expires_at)
+                VALUES ($1, $2, now() + make_interval(secs => $3))
+                ON CONFLICT (name) DO UPDATE
+                SET holder = EXCLUDED.holder, expires_at = EXCLUDED.expires_at
+                WHERE task_locks.holder = EXCLUDED.holder OR task_locks.expires_at <= now()",
+                &[&name, &holder, &ttl.as_secs_f64()],
+            )
+            .await?;
+
+        Ok(acquired == 1)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn release_lock(&self, name: &str, holder: Uuid) -> RepositoryResult<()> {
+        let connection = self.pool.get().await?;
+
+        connection
+            .execute(
+                "DELETE FROM task_locks WHERE name = $1 AND holder = $2",
+                &[&name, &holder],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_idle_board_ids(&self, cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>> {
+        let connection = self.pool.get().await?;
+
+        let board_ids = connection
+            .query(
+                "SELECT board_id FROM boards WHERE last_activity_at < $1",
+                &[&cutoff.timestamp_millis()],
+            )
+            .await?
+            .into_iter()
+            .map(|row| row.get("board_id"))
+            .collect();
+
+        Ok(board_ids)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_inactive_board_ids(&self, _cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>> {
+        // Boards stay in Postgres no matter how long they sit, so nothing is ever archived
+        Ok(vec![])
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn archive_board(&self, _board_id: Uuid, _version: &str) -> RepositoryResult<bool> {
+        Ok(false)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_board_archived(&self, _board_id: Uuid) -> RepositoryResult<bool> {
+        Ok(false)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn restore_board(&self, _board_id: Uuid) -> RepositoryResult<()> {
+        Ok(())
+    }
+}
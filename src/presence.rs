@@ -1,8 +1,10 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use futures::stream::StreamExt;
 use uuid::Uuid;
 
-use crate::repository::Repository;
+use crate::message::ServerMessage;
+use crate::repository::{BanTarget, Repository};
+use crate::restart::{RestartPolicy, Restarts};
 use crate::socket::SocketSender;
 
 pub struct Presence {
@@ -10,6 +12,12 @@ pub struct Presence {
     session_id: Uuid,
     repo: Repository,
     socket_sender: SocketSender,
+    /// Whether this is the board the connection was opened for, in which case the whole
+    /// connection is closed if the board is deleted or the session is kicked off of it. On a board
+    /// the connection subscribed to, a kick or ban only ends the subscription.
+    is_connection_board: bool,
+    /// Every way the connection can be banned from the board
+    ban_targets: Vec<BanTarget>,
 }
 
 impl Presence {
@@ -19,19 +27,32 @@ impl Presence {
         session_id: Uuid,
         repo: Repository,
         socket_sender: SocketSender,
+        is_connection_board: bool,
+        ban_targets: Vec<BanTarget>,
     ) -> Self {
         Self {
             board_id,
             session_id,
             repo,
             socket_sender,
+            is_connection_board,
+            ban_targets,
         }
     }
 
     #[tracing::instrument(skip_all)]
     pub async fn start(self) {
+        let mut restarts = Restarts::new("presence", RestartPolicy::CONNECTION);
         loop {
-            self.run().await.ok();
+            let error = match self.run().await {
+                // The session was kicked or banned from a board it subscribed to
+                Ok(()) => return,
+                Err(error) => error,
+            };
+            if !restarts.failed(&error).await {
+                self.socket_sender.disconnect().await.ok();
+                return;
+            }
         }
     }
 
@@ -42,10 +63,49 @@ impl Presence {
             .stream_presence_messages_for_board(self.board_id)
             .await;
         while let Some(message) = message_stream.next().await {
-            if message.source_session != self.session_id {
-                self.socket_sender.send(message.message).await?;
+            if message.source_session == self.session_id {
+                continue;
+            }
+
+            match message.message {
+                ServerMessage::BoardDeleted => {
+                    self.socket_sender.send(message.message).await?;
+                    if self.is_connection_board {
+                        self.socket_sender.disconnect().await?;
+                    }
+                }
+                // Nobody else needs to know, they'll see the session leave
+                ServerMessage::SessionKicked { session_id, reason } => {
+                    if session_id == self.session_id {
+                        return self.kick(reason).await;
+                    }
+                }
+                ServerMessage::SessionBanned { target, reason } => {
+                    if self.ban_targets.contains(&target) {
+                        return self.kick(reason).await;
+                    }
+                }
+                message => self.socket_sender.send(message).await?,
             }
         }
+
+        // The stream only ends when the connection to the store behind it is lost
+        Err(anyhow!("Presence stream ended"))
+    }
+
+    /// Tell the client why it's being kicked off of the board, then close the connection with the
+    /// reason if it's the connection's own board
+    #[tracing::instrument(skip(self), err)]
+    async fn kick(&self, reason: Option<String>) -> Result<()> {
+        self.socket_sender
+            .send(ServerMessage::SessionKicked {
+                session_id: self.session_id,
+                reason: reason.clone(),
+            })
+            .await?;
+        if self.is_connection_board {
+            self.socket_sender.disconnect_kicked(reason).await?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,95 @@
+use async_stream::stream;
+use futures::stream::BoxStream;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError, Sender as BroadcastSender};
+use uuid::Uuid;
+
+use crate::message::PresenceMessage;
+
+/// How many presence messages can wait for the slowest board on this server when
+/// PRESENCE_CHANNEL_CAPACITY isn't set
+pub const DEFAULT_PRESENCE_CHANNEL_CAPACITY: usize = 1000;
+
+/// Hands the presence messages for every board on this server out to whoever is watching each
+/// board. Every store gets them from one place, like a single Pub/Sub connection, so they all go
+/// through one channel, and anyone who falls more than its capacity behind misses the oldest of
+/// them. Some lossiness in presence is fine, but how much was lost is counted for each board so
+/// that a channel too small for a busy server can be told apart from a quiet one.
+#[derive(Clone)]
+pub struct PresenceChannel {
+    sender: BroadcastSender<(Uuid, PresenceMessage)>,
+    capacity: usize,
+    /// How many messages were missed on each board since the server started
+    dropped: Arc<Mutex<HashMap<Uuid, u64>>>,
+}
+
+/// How much presence this server has missed, see `PresenceChannel`
+#[derive(Clone, Debug)]
+pub struct PresenceStats {
+    pub capacity: usize,
+    pub dropped_messages: u64,
+    /// Every board that missed any messages and how many, most first
+    pub boards: Vec<(Uuid, u64)>,
+}
+
+impl PresenceChannel {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            capacity,
+            dropped: Arc::default(),
+        }
+    }
+
+    /// Hand a message out to everyone watching the board, if anyone is
+    pub fn send(&self, board_id: Uuid, message: PresenceMessage) {
+        let _ = self.sender.send((board_id, message));
+    }
+
+    /// Get a stream of the messages sent for a board from now on, which ends once nothing can be
+    /// sent anymore
+    pub fn stream_for_board(&self, board_id: Uuid) -> BoxStream<'static, PresenceMessage> {
+        let mut receiver = self.sender.subscribe();
+        let dropped = self.dropped.clone();
+        Box::pin(stream! {
+            loop {
+                // A receiver that falls behind picks up with the oldest message still in the
+                // channel, after the ones it missed are counted against its board
+                let (next_board_id, next_message) = match receiver.recv().await {
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(count)) => {
+                        tracing::warn!(%board_id, count, "Presence messages were dropped");
+                        *dropped
+                            .lock()
+                            .unwrap_or_else(|error| error.into_inner())
+                            .entry(board_id)
+                            .or_default() += count;
+                        continue;
+                    }
+                    Ok(message) => message,
+                };
+                if next_board_id == board_id {
+                    yield next_message;
+                }
+            }
+        })
+    }
+
+    pub fn stats(&self) -> PresenceStats {
+        let mut boards = self
+            .dropped
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .iter()
+            .map(|(board_id, count)| (*board_id, *count))
+            .collect::<Vec<_>>();
+        boards.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        PresenceStats {
+            capacity: self.capacity,
+            dropped_messages: boards.iter().map(|(_, count)| count).sum(),
+            boards,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use axum::{
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How many HTTP requests, including websocket upgrades, a single IP can make per second on
+/// average, and how many it can make at once after being idle
+pub const REQUESTS_PER_SECOND: f64 = 20.0;
+pub const REQUEST_BURST: f64 = 100.0;
+
+/// How many changes a single session can submit per second on average, and in a burst
+pub const CHANGES_PER_SECOND: f64 = 30.0;
+pub const CHANGE_BURST: f64 = 60.0;
+
+/// How many cursor updates a single session can send per second on average, and in a burst. This
+/// is well above what the cursor publisher sends on to Redis, since it only has to stop clients
+/// that are flooding the socket.
+pub const CURSOR_UPDATES_PER_SECOND: f64 = 60.0;
+pub const CURSOR_UPDATE_BURST: f64 = 120.0;
+
+/// Past this many tracked IPs or sessions, the buckets that have refilled completely are dropped
+/// since they'd behave the same as a new bucket
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// A token bucket that starts full, refills continuously, and spends one token per request
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_second: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Spend a token if there is one. Returns false if the caller should be limited.
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the next token is available
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.refill_per_second).max(0.0))
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Token buckets for every IP that has made a request to this server. Limits are per server
+/// process, so a client spreading its requests across several servers gets several allowances.
+#[derive(Clone, Default)]
+pub struct IpRateLimiter {
+    buckets: Arc<Mutex<HashMap<IpAddr, TokenBucket>>>,
+}
+
+impl IpRateLimiter {
+    /// Spend a token for the IP, returning how long it has to wait if it has none left
+    fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        take(&self.buckets, ip, || {
+            TokenBucket::new(REQUESTS_PER_SECOND, REQUEST_BURST)
+        })
+    }
+}
+
+/// Token buckets for the changes every session has published through this server without a
+/// websocket, which get the same allowance as a websocket session's
+#[derive(Clone, Default)]
+pub struct SessionChangeLimiter {
+    buckets: Arc<Mutex<HashMap<Uuid, TokenBucket>>>,
+}
+
+impl SessionChangeLimiter {
+    /// Spend a token for the session, returning how long it has to wait if it has none left
+    pub fn check(&self, session_id: Uuid) -> Result<(), Duration> {
+        take(&self.buckets, session_id, || {
+            TokenBucket::new(CHANGES_PER_SECOND, CHANGE_BURST)
+        })
+    }
+}
+
+/// Spend a token from the key's bucket, starting it off full if it's new
+fn take<K: Eq + Hash>(
+    buckets: &Mutex<HashMap<K, TokenBucket>>,
+    key: K,
+    new_bucket: impl FnOnce() -> TokenBucket,
+) -> Result<(), Duration> {
+    let mut buckets = buckets.lock().unwrap_or_else(|error| error.into_inner());
+    if buckets.len() > MAX_TRACKED_KEYS {
+        buckets.retain(|_, bucket| !bucket.is_full());
+    }
+
+    let bucket = buckets.entry(key).or_insert_with(new_bucket);
+    if bucket.try_take() {
+        Ok(())
+    } else {
+        Err(bucket.retry_after())
+    }
+}
+
+/// Reject requests from IPs that are over their limit with a 429. Requests without a peer address
+/// are let through.
+pub async fn limit_by_ip<B>(request: Request<B>, next: Next<B>) -> Response {
+    let limiter = request.extensions().get::<IpRateLimiter>().cloned();
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+
+    if let (Some(limiter), Some(ip)) = (limiter, ip) {
+        if let Err(retry_after) = limiter.check(ip) {
+            tracing::warn!(%ip, "Rate limited request");
+            let retry_after = retry_after.as_secs().max(1).to_string();
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after)],
+                "Too many requests",
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Utc;
+use uuid::Uuid;
+
+use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
+
+/// Deletes boards that nobody has changed or joined in a long time, so that throwaway boards don't
+/// stay in the store forever. Archived boards are deleted from the archive too.
+pub struct Reaper {
+    repo: Repository,
+    idle_after: chrono::Duration,
+}
+
+impl Reaper {
+    #[tracing::instrument(skip_all)]
+    pub fn new(repo: Repository, idle_after: chrono::Duration) -> Self {
+        Self { repo, idle_after }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(self) {
+        let mut restarts = Restarts::new("reaper", RestartPolicy::BACKGROUND);
+        loop {
+            if let Err(error) = self.run().await {
+                restarts.failed(&error).await;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn run(&self) -> Result<()> {
+        loop {
+            let cutoff = Utc::now() - self.idle_after;
+            for board_id in self.repo.get_idle_board_ids(cutoff).await? {
+                // One board that can't be deleted shouldn't hold up the rest
+                if let Err(error) = self.reap_board(board_id, cutoff).await {
+                    tracing::warn!(%board_id, %error, "Could not delete idle board");
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(60 * 60)).await;
+        }
+    }
+
+    /// Delete the board unless someone is on it or has changed it since it was found to be idle.
+    /// Returns whether the board was deleted.
+    #[tracing::instrument(skip(self), err)]
+    async fn reap_board(&self, board_id: Uuid, cutoff: chrono::DateTime<Utc>) -> Result<bool> {
+        if self.repo.get_session_count_for_board(board_id).await? > 0 {
+            return Ok(false);
+        }
+        let last_activity = self.repo.get_last_activity_for_board(board_id).await?;
+        if !matches!(last_activity, Some(last_activity) if last_activity < cutoff) {
+            return Ok(false);
+        }
+
+        let deleted = self.repo.delete_board(board_id).await?;
+        if deleted {
+            tracing::info!(%board_id, "Deleted idle board");
+        }
+
+        Ok(deleted)
+    }
+}
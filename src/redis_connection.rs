@@ -0,0 +1,400 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use itertools::Itertools;
+use native_tls::{Certificate, Identity};
+use redis::{
+    aio::{AsyncStream, Connection, ConnectionLike, PubSub},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    AsyncCommands, Client, Cmd, ConnectionAddr, ConnectionInfo, FromRedisValue, IntoConnectionInfo,
+    Pipeline, RedisError, RedisFuture, RedisResult, Value,
+};
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Hands out connections to a single Redis server, a Redis Cluster, or a master found through
+/// Sentinel for the bb8 pool
+#[derive(Clone)]
+pub struct RedisConnectionManager {
+    topology: Topology,
+    tls: Option<RedisTls>,
+    /// How long to wait for a new connection before giving up, if at all
+    connect_timeout: Option<Duration>,
+}
+
+#[derive(Clone)]
+enum Topology {
+    Single(ConnectionInfo),
+    Cluster {
+        client: ClusterClient,
+        /// The first node given, for anything that needs a plain connection to one node
+        node_info: ConnectionInfo,
+    },
+    /// A master found through Sentinel, which is looked up again for every new connection so
+    /// that a failover is picked up instead of connecting to the old master forever
+    Sentinel {
+        sentinels: Vec<ConnectionInfo>,
+        master_name: String,
+        /// How to connect to the master, with the address swapped for whatever Sentinel says
+        master_info: ConnectionInfo,
+    },
+}
+
+/// Certificates for rediss:// connections beyond the system's trusted roots
+#[derive(Clone)]
+pub struct RedisTls {
+    /// A CA to trust for the server's certificate, for providers with their own CA
+    root_certificate: Option<Certificate>,
+    /// A client certificate and key to present, for servers that require them
+    identity: Option<Identity>,
+}
+
+impl RedisTls {
+    /// Both the CA and the client certificate and key are PEM
+    pub fn new(
+        ca_cert: Option<&[u8]>,
+        client_cert_and_key: Option<(&[u8], &[u8])>,
+    ) -> Result<Self> {
+        Ok(Self {
+            root_certificate: ca_cert.map(Certificate::from_pem).transpose()?,
+            identity: client_cert_and_key
+                .map(|(cert, key)| Identity::from_pkcs8(cert, key))
+                .transpose()?,
+        })
+    }
+}
+
+impl RedisConnectionManager {
+    pub fn single(info: ConnectionInfo) -> Self {
+        Self {
+            topology: Topology::Single(info),
+            tls: None,
+            connect_timeout: None,
+        }
+    }
+
+    /// Connect to a cluster through any of its nodes. The rest are discovered from there.
+    pub fn cluster(urls: Vec<String>) -> Result<Self> {
+        let node_info = urls
+            .first()
+            .ok_or_else(|| anyhow!("A cluster needs at least one node"))?
+            .as_str()
+            .into_connection_info()?;
+        let client = ClusterClient::new(urls)?;
+        Ok(Self {
+            topology: Topology::Cluster { client, node_info },
+            tls: None,
+            connect_timeout: None,
+        })
+    }
+
+    /// Find the master called `master_name` through any of the Sentinels at `urls`
+    pub fn sentinel(
+        urls: Vec<String>,
+        master_name: String,
+        master_info: ConnectionInfo,
+    ) -> Result<Self> {
+        if urls.is_empty() {
+            return Err(anyhow!("Sentinel needs at least one Sentinel"));
+        }
+        let sentinels = urls
+            .into_iter()
+            .map(|url| url.into_connection_info())
+            .collect::<RedisResult<Vec<_>>>()?;
+        Ok(Self {
+            topology: Topology::Sentinel {
+                sentinels,
+                master_name,
+                master_info,
+            },
+            tls: None,
+            connect_timeout: None,
+        })
+    }
+
+    /// Use extra certificates for every rediss:// connection. The cluster client makes its own
+    /// connections to each node, so it can only use the system's trusted roots.
+    pub fn with_tls(self, tls: RedisTls) -> Result<Self> {
+        if self.is_cluster() {
+            return Err(anyhow!(
+                "Redis Cluster can't use a custom CA or client certificate"
+            ));
+        }
+        Ok(Self {
+            tls: Some(tls),
+            ..self
+        })
+    }
+
+    pub fn with_connect_timeout(self, connect_timeout: Duration) -> Self {
+        Self {
+            connect_timeout: Some(connect_timeout),
+            ..self
+        }
+    }
+
+    pub fn is_cluster(&self) -> bool {
+        matches!(self.topology, Topology::Cluster { .. })
+    }
+
+    /// Open a connection for Pub/Sub outside of the pool. Messages published anywhere in a
+    /// cluster are forwarded to every node, so subscribing to one of them is enough.
+    pub async fn pubsub(&self) -> Result<PubSub> {
+        let info = match &self.topology {
+            Topology::Single(info) => info.clone(),
+            Topology::Cluster { node_info, .. } => node_info.clone(),
+            Topology::Sentinel { .. } => self.master_info().await?,
+        };
+        Ok(open_connection(&info, self.tls.as_ref())
+            .await?
+            .into_pubsub())
+    }
+
+    /// Open a new connection for the pool, without a timeout
+    async fn open(&self) -> RedisResult<RedisConnection> {
+        match &self.topology {
+            Topology::Single(info) => Ok(RedisConnection::Single(
+                open_connection(info, self.tls.as_ref()).await?,
+            )),
+            Topology::Cluster { client, node_info } => Ok(RedisConnection::Cluster {
+                connection: client.get_async_connection().await?,
+                node_info: node_info.clone(),
+            }),
+            Topology::Sentinel { .. } => Ok(RedisConnection::Single(
+                open_connection(&self.master_info().await?, self.tls.as_ref()).await?,
+            )),
+        }
+    }
+
+    /// Ask each Sentinel in turn where the master is
+    #[tracing::instrument(skip(self), err)]
+    async fn master_info(&self) -> RedisResult<ConnectionInfo> {
+        let (sentinels, master_name, master_info) = match &self.topology {
+            Topology::Sentinel {
+                sentinels,
+                master_name,
+                master_info,
+            } => (sentinels, master_name, master_info),
+            _ => return Err((redis::ErrorKind::ClientError, "Not using Sentinel").into()),
+        };
+
+        for sentinel in sentinels {
+            let address = async {
+                let mut connection = open_connection(sentinel, self.tls.as_ref()).await?;
+                redis::cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(master_name)
+                    .query_async::<_, Option<(String, u16)>>(&mut connection)
+                    .await
+            }
+            .await;
+
+            match address {
+                Ok(Some((host, port))) => return Ok(with_address(master_info, host, port)),
+                Ok(None) => tracing::warn!(?sentinel.addr, "Sentinel doesn't know the master"),
+                Err(error) => tracing::warn!(?sentinel.addr, %error, "Couldn't reach Sentinel"),
+            }
+        }
+
+        Err((
+            redis::ErrorKind::IoError,
+            "No Sentinel could say where the master is",
+        )
+            .into())
+    }
+}
+
+/// Open a plain connection to one server. redis-rs can only check rediss:// connections against
+/// the system's trusted roots, so with extra certificates the TLS stream is set up here instead.
+async fn open_connection(info: &ConnectionInfo, tls: Option<&RedisTls>) -> RedisResult<Connection> {
+    match (&info.addr, tls) {
+        (
+            ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure,
+                ..
+            },
+            Some(tls),
+        ) => {
+            let mut builder = native_tls::TlsConnector::builder();
+            if let Some(root_certificate) = &tls.root_certificate {
+                builder.add_root_certificate(root_certificate.clone());
+            }
+            if let Some(identity) = &tls.identity {
+                builder.identity(identity.clone());
+            }
+            if *insecure {
+                builder.danger_accept_invalid_certs(true);
+            }
+            let connector =
+                tokio_native_tls::TlsConnector::from(builder.build().map_err(|error| {
+                    RedisError::from((
+                        redis::ErrorKind::IoError,
+                        "Couldn't set up TLS",
+                        error.to_string(),
+                    ))
+                })?);
+
+            let stream = TcpStream::connect((host.as_str(), *port)).await?;
+            let stream = connector.connect(host, stream).await.map_err(|error| {
+                RedisError::from((
+                    redis::ErrorKind::IoError,
+                    "TLS handshake failed",
+                    error.to_string(),
+                ))
+            })?;
+            let stream: Pin<Box<dyn AsyncStream + Send + Sync>> = Box::pin(stream);
+            Connection::new(&info.redis, stream).await
+        }
+        _ => Client::open(info.clone())?.get_async_connection().await,
+    }
+}
+
+/// Copy connection info with the host and port replaced, keeping the credentials, database, and
+/// whether to use TLS
+fn with_address(info: &ConnectionInfo, host: String, port: u16) -> ConnectionInfo {
+    let mut info = info.clone();
+    info.addr = match info.addr {
+        ConnectionAddr::TcpTls {
+            insecure,
+            tls_params,
+            ..
+        } => ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure,
+            tls_params,
+        },
+        _ => ConnectionAddr::Tcp(host, port),
+    };
+    info
+}
+
+#[async_trait]
+impl bb8::ManageConnection for RedisConnectionManager {
+    type Connection = RedisConnection;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<RedisConnection, RedisError> {
+        match self.connect_timeout {
+            Some(connect_timeout) => tokio::time::timeout(connect_timeout, self.open())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out connecting"))?,
+            None => self.open().await,
+        }
+    }
+
+    async fn is_valid(&self, connection: &mut RedisConnection) -> Result<(), RedisError> {
+        match self.topology {
+            // A master that failed over comes back as a replica, so its connections have to be
+            // replaced with ones to the new master
+            Topology::Sentinel { .. } => {
+                let role = redis::cmd("ROLE")
+                    .query_async::<_, Vec<Value>>(connection)
+                    .await?;
+                match role.first().map(String::from_redis_value) {
+                    Some(Ok(role)) if role == "master" => Ok(()),
+                    _ => Err((redis::ErrorKind::ReadOnly, "No longer the master").into()),
+                }
+            }
+            _ => redis::cmd("PING").query_async(connection).await,
+        }
+    }
+
+    fn has_broken(&self, _connection: &mut RedisConnection) -> bool {
+        false
+    }
+}
+
+/// A pooled connection to a single server or to a whole cluster, which routes each command to
+/// the node holding its keys
+pub enum RedisConnection {
+    Single(Connection),
+    Cluster {
+        connection: ClusterConnection,
+        node_info: ConnectionInfo,
+    },
+}
+
+impl RedisConnection {
+    /// SCAN for every key that matches a pattern. SCAN only covers the node it runs on, so in a
+    /// cluster each primary is scanned over a connection of its own.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn scan_match_all(&mut self, pattern: &str) -> Result<Vec<String>> {
+        match self {
+            Self::Single(connection) => Ok(connection
+                .scan_match::<_, String>(pattern)
+                .await?
+                .collect::<Vec<_>>()
+                .await),
+            Self::Cluster {
+                connection,
+                node_info,
+            } => {
+                // CLUSTER SLOTS lists every range of slots with its primary's host and port first
+                let slot_ranges = redis::cmd("CLUSTER")
+                    .arg("SLOTS")
+                    .query_async::<_, Vec<Vec<Value>>>(connection)
+                    .await?;
+                let primaries = slot_ranges
+                    .into_iter()
+                    .filter_map(|slot_range| {
+                        let primary = Vec::<Value>::from_redis_value(slot_range.get(2)?).ok()?;
+                        Some((
+                            String::from_redis_value(primary.first()?).ok()?,
+                            u16::from_redis_value(primary.get(1)?).ok()?,
+                        ))
+                    })
+                    .unique()
+                    .collect::<Vec<_>>();
+
+                let mut keys = Vec::new();
+                for (host, port) in primaries {
+                    let mut primary_connection =
+                        open_connection(&with_address(node_info, host, port), None).await?;
+                    keys.extend(
+                        primary_connection
+                            .scan_match::<_, String>(pattern)
+                            .await?
+                            .collect::<Vec<_>>()
+                            .await,
+                    );
+                }
+
+                Ok(keys)
+            }
+        }
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Single(connection) => connection.req_packed_command(cmd),
+            Self::Cluster { connection, .. } => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Single(connection) => connection.req_packed_commands(cmd, offset, count),
+            Self::Cluster { connection, .. } => connection.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Single(connection) => connection.get_db(),
+            Self::Cluster { connection, .. } => connection.get_db(),
+        }
+    }
+}
@@ -0,0 +1,4532 @@
+use anyhow::{anyhow, Result};
+use async_stream::{stream, try_stream};
+use async_trait::async_trait;
+use bb8::ManageConnection;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{
+    future,
+    stream::{self, BoxStream},
+    Future, StreamExt, TryStreamExt,
+};
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use redis::{
+    streams::{StreamId, StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply},
+    AsyncCommands, FromRedisValue, RedisError, Script,
+};
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{sync::watch::Receiver as WatchReceiver, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::archive::{ArchiveStore, ArchivedBoard};
+use crate::auth::ApiKey;
+use crate::board_meta::{BoardMeta, BoardMetaPatch, BoardSummary};
+use crate::change::{Change, ChangeEntry, TrashedObject};
+use crate::message::{AcceptedChange, Cursor, JsonObject, PresenceMessage, ServerMessage};
+use crate::pool::{MeteredPool, PoolConfig};
+use crate::presence_channel::{PresenceChannel, PresenceStats};
+use crate::redis_connection::{RedisConnection, RedisConnectionManager};
+use crate::repository::{
+    Ban, BanTarget, BoardStore, ChangeRetention, CheckpointHistory, ClientInfo, PoolStats,
+    PublishOutcome, Repository, RepositoryError, RepositoryResult, CURSOR_TTL_SECONDS,
+    IDEMPOTENCY_TTL_SECONDS, OBJECT_LOCK_TTL_SECONDS, TRASH_TTL_SECONDS,
+};
+use crate::retry::RetryPolicy;
+use crate::search::{self, SearchHit, MAX_CANDIDATES};
+use crate::snapshot::BoardSnapshot;
+use crate::spatial::{Rect, MAX_QUERY_CELLS, OVERSIZED_CELL};
+use crate::webhook::WebhookEvent;
+
+type RedisPool = MeteredPool<RedisConnectionManager>;
+
+/// The version of the key layout this server reads and writes, which is stored at schema_version.
+/// Bump it along with a new step in `run_migrations` whenever existing data has to be moved
+/// around to fit a new layout.
+const SCHEMA_VERSION: u64 = 1;
+
+/// How long one server can hold on to running migrations before another may take over, in case
+/// it dies partway through
+const MIGRATION_LOCK_TTL: Duration = Duration::from_secs(600);
+
+/// About how many presence messages a board with reliable presence keeps in its stream, which is
+/// how far behind a session can fall before it misses some anyway
+const PRESENCE_STREAM_LENGTH: usize = 1000;
+
+/// How many board IDs are read from the registry at boards at a time when walking every board
+const BOARD_IDS_PAGE_SIZE: isize = 1000;
+
+/// How the objects at board/{board_id}/objects are stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ObjectStorage {
+    /// A RedisJSON document with a property for each object
+    Json,
+    /// A plain hash from each object ID to the object as a JSON string, for servers that can't
+    /// load modules
+    Hash,
+}
+
+/// Keeps boards in Redis or a Redis Cluster, with objects in RedisJSON or plain hashes, changes in
+/// streams, and presence over Pub/Sub. Every key that belongs to a board is hash-tagged with its
+/// ID so that a cluster keeps them together, which lets scripts and transactions work on one
+/// board at a time. Anything that also touches a global key like boards does so separately.
+#[derive(Clone)]
+pub struct RedisRepository {
+    /// Kept for the connections that subscribe to something, which don't come from the pool
+    manager: RedisConnectionManager,
+    pool: RedisPool,
+    object_storage: ObjectStorage,
+    /// How many checkpointed changes to keep in each board's history, or zero to keep none
+    history_length: usize,
+    checkpoint_history: CheckpointHistory,
+    change_retention: ChangeRetention,
+    /// How long a session lives without checking in
+    session_ttl: Duration,
+    /// Where inactive boards are moved to, if archiving is turned on
+    archive_store: Option<ArchiveStore>,
+    retry_policy: RetryPolicy,
+    presence: PresenceChannel,
+    _presence_handle: Arc<JoinHandle<()>>,
+}
+
+impl RedisRepository {
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip_all, err)]
+    pub async fn new(
+        manager: RedisConnectionManager,
+        pool_config: &PoolConfig,
+        history_length: usize,
+        checkpoint_history: CheckpointHistory,
+        change_retention: ChangeRetention,
+        session_ttl: Duration,
+        archive_store: Option<ArchiveStore>,
+        retry_policy: RetryPolicy,
+        presence_capacity: usize,
+    ) -> Result<Self> {
+        let manager = manager.with_connect_timeout(pool_config.connect_timeout);
+        let object_storage = Self::check_environment(&manager).await?;
+        tracing::info!(?object_storage, "Storing objects");
+        let pool = MeteredPool::new(manager.clone(), pool_config).await?;
+        Self::run_migrations(&pool, &retry_policy, manager.is_cluster()).await?;
+        let presence = PresenceChannel::new(presence_capacity);
+        let presence_handle =
+            tokio::task::spawn(Self::start_presence(manager.clone(), presence.clone()));
+        Ok(Self {
+            manager,
+            pool,
+            object_storage,
+            history_length,
+            checkpoint_history,
+            change_retention,
+            session_ttl,
+            archive_store,
+            retry_policy,
+            presence,
+            _presence_handle: Arc::new(presence_handle),
+        })
+    }
+
+    /// Make sure that Redis can be reached and can do everything the server needs before anything
+    /// else touches it, without writing anything, for the --check mode
+    pub async fn check(manager: RedisConnectionManager, pool_config: &PoolConfig) -> Result<()> {
+        let manager = manager.with_connect_timeout(pool_config.connect_timeout);
+        let object_storage = Self::check_environment(&manager).await?;
+        tracing::info!(?object_storage, "Redis is ready");
+        Ok(())
+    }
+
+    // ---- Private helpers
+
+    /// The hash version of stream_object_chunks_for_board
+    fn stream_hash_object_chunks(
+        pool: RedisPool,
+        retry_policy: RetryPolicy,
+        board_id: Uuid,
+    ) -> BoxStream<'static, RepositoryResult<Vec<(Uuid, JsonObject)>>> {
+        Box::pin(try_stream! {
+            let board_objects_key = Self::board_objects_key(board_id);
+
+            // First get all of the object IDs in the hash at board/{board_id}/objects
+            let object_ids = Self::with_redis_retry(&retry_policy, || async {
+                let mut connection = pool.get().await?;
+                let object_ids = connection
+                    .hkeys::<_, Vec<String>>(board_objects_key.as_str())
+                    .await?;
+                Ok(object_ids)
+            }).await?;
+
+            // Then HMGET them in groups of 100, skipping any deleted in between
+            for ids in object_ids.chunks(100) {
+                let entries = Self::with_redis_retry(&retry_policy, || async {
+                    let mut connection = pool.get().await?;
+                    let objects = redis::cmd("HMGET")
+                        .arg(board_objects_key.as_str())
+                        .arg(ids)
+                        .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                        .await?;
+
+                    Ok(ids
+                        .iter()
+                        .zip(objects)
+                        .filter_map(|(id, object)| {
+                            Some((id.parse::<Uuid>().ok()?, serde_json::from_str(&object?).ok()?))
+                        })
+                        .collect::<Vec<_>>())
+                }).await?;
+
+                yield entries;
+            }
+        })
+    }
+
+    /// Check that Redis answers, that it's new enough for the change streams, and that boards
+    /// already in it are stored the way they'll be read, so that a misconfigured deployment stops
+    /// right away with something to do about it instead of failing on the first change. Figures
+    /// out how objects are stored along the way: in RedisJSON when the server has it, and in
+    /// plain hashes when it doesn't.
+    #[tracing::instrument(skip_all, err)]
+    async fn check_environment(manager: &RedisConnectionManager) -> Result<ObjectStorage> {
+        let mut connection = manager.connect().await.map_err(|error| {
+            anyhow!(
+                "Could not connect to Redis: {error}. Check that REDIS_URL, REDIS_CLUSTER_URLS, or \
+                REDIS_SENTINEL_URLS points at a running Redis that this server can reach."
+            )
+        })?;
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut connection)
+            .await
+            .map_err(|error| {
+                anyhow!(
+                    "Redis refused PING: {error}. If it needs a password, put it in the URL, like \
+                    redis://:password@host."
+                )
+            })?;
+
+        // Change streams are trimmed with XTRIM MINID, which came in 6.2
+        let server_info = redis::cmd("INFO")
+            .arg("server")
+            .query_async::<_, String>(&mut connection)
+            .await?;
+        let version = server_info
+            .lines()
+            .find_map(|line| line.strip_prefix("redis_version:"))
+            .map(str::trim);
+        if let Some(version) = version {
+            let mut parts = version
+                .split('.')
+                .map(|part| part.parse::<u32>().unwrap_or(0));
+            let major_minor = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+            if major_minor < (6, 2) {
+                return Err(anyhow!(
+                    "Redis {version} can't trim streams by ID, which the checkpointer needs. \
+                    Upgrade to Redis 6.2 or newer."
+                ));
+            }
+        }
+
+        // COMMAND INFO comes back with nil for a command the server doesn't know
+        let info = redis::cmd("COMMAND")
+            .arg("INFO")
+            .arg("JSON.SET")
+            .query_async::<_, Vec<redis::Value>>(&mut connection)
+            .await?;
+        let object_storage = match info.first() {
+            None | Some(redis::Value::Nil) => ObjectStorage::Hash,
+            Some(_) => ObjectStorage::Json,
+        };
+
+        // Boards stored one way can't be read the other way, which happens when the module is
+        // added to or taken away from an existing deployment. The most recently active board is
+        // as good a sample as any.
+        let newest_board_id = connection
+            .zrevrange::<_, Vec<String>>(Self::boards_key(), 0, 0)
+            .await?
+            .into_iter()
+            .find_map(|board_id| board_id.parse::<Uuid>().ok());
+        if let Some(board_id) = newest_board_id {
+            let key_type = redis::cmd("TYPE")
+                .arg(Self::board_objects_key(board_id))
+                .query_async::<_, String>(&mut connection)
+                .await?;
+            match (key_type.as_str(), object_storage) {
+                ("hash", ObjectStorage::Json) => {
+                    return Err(anyhow!(
+                        "Boards in this Redis are stored in plain hashes, but RedisJSON has been \
+                        loaded since they were written. Unload the module, or start with an \
+                        empty Redis."
+                    ))
+                }
+                ("ReJSON-RL", ObjectStorage::Hash) => {
+                    return Err(anyhow!(
+                        "Boards in this Redis are stored with RedisJSON, but the module isn't \
+                        loaded. Load RedisJSON, for example by using redis-stack."
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        // A server that doesn't know the layout its data is in can't safely touch it
+        let schema_version = connection
+            .get::<_, Option<u64>>(Self::schema_version_key())
+            .await?
+            .unwrap_or(0);
+        if schema_version > SCHEMA_VERSION {
+            return Err(anyhow!(
+                "Redis has been migrated to schema version {schema_version}, but this server only \
+                knows up to {SCHEMA_VERSION}. Upgrade the server before pointing it at this Redis."
+            ));
+        }
+
+        Ok(object_storage)
+    }
+
+    /// Bring the data in Redis up to `SCHEMA_VERSION` one step at a time, recording each step as
+    /// it finishes so that a migration that's interrupted picks up where it left off. Only one
+    /// server migrates at a time, and the rest wait for it to finish before they start.
+    #[tracing::instrument(skip_all, err)]
+    async fn run_migrations(
+        pool: &RedisPool,
+        retry_policy: &RetryPolicy,
+        is_cluster: bool,
+    ) -> RepositoryResult<()> {
+        let holder = Uuid::new_v4().to_string();
+        loop {
+            let (schema_version, acquired) = Self::with_redis_retry(retry_policy, || async {
+                let mut connection = pool.get().await?;
+                let schema_version = connection
+                    .get::<_, Option<u64>>(Self::schema_version_key())
+                    .await?
+                    .unwrap_or(0);
+                if schema_version >= SCHEMA_VERSION {
+                    return Ok((schema_version, false));
+                }
+
+                // SET locks/migrations NX PX so that only one server migrates at a time
+                let acquired = redis::cmd("SET")
+                    .arg(Self::task_lock_key("migrations"))
+                    .arg(&holder)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(MIGRATION_LOCK_TTL.as_millis() as u64)
+                    .query_async::<_, Option<String>>(&mut *connection)
+                    .await?
+                    .is_some();
+                Ok((schema_version, acquired))
+            })
+            .await?;
+
+            if schema_version >= SCHEMA_VERSION {
+                return Ok(());
+            }
+            if !acquired {
+                tracing::info!("Waiting for another server to migrate");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+
+            for version in (schema_version + 1)..=SCHEMA_VERSION {
+                tracing::info!(version, "Migrating keys");
+                match version {
+                    // A cluster can only ever have been filled with hash-tagged keys
+                    1 if is_cluster => {}
+                    1 => Self::migrate_keys(pool, retry_policy).await?,
+                    _ => unreachable!("There's no migration to schema version {version}"),
+                }
+                Self::with_redis_retry(retry_policy, || async {
+                    let mut connection = pool.get().await?;
+                    connection
+                        .set::<_, _, ()>(Self::schema_version_key(), version)
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+            }
+
+            // The lock is ours for long enough that nobody else can have taken it in between
+            Self::with_redis_retry(retry_policy, || async {
+                let mut connection = pool.get().await?;
+                connection
+                    .del::<_, ()>(Self::task_lock_key("migrations"))
+                    .await?;
+                Ok(())
+            })
+            .await?;
+            return Ok(());
+        }
+    }
+
+    /// Move the keys of boards from before keys were hash-tagged, at board/<board_id>/..., over to
+    /// board/{board_id}/.... Boards that were already archived get marked as such, and boards
+    /// with change streams are registered at change_streams, since neither used to be tracked
+    /// per board.
+    #[tracing::instrument(skip_all, err)]
+    async fn migrate_keys(pool: &RedisPool, retry_policy: &RetryPolicy) -> RepositoryResult<()> {
+        Self::with_redis_retry(retry_policy, || async {
+            let mut connection = pool.get().await?;
+
+            // SCAN over every key that matches board/* and skip the ones that are already tagged
+            let old_keys = connection
+                .scan_match_all("board/*")
+                .await?
+                .into_iter()
+                .filter(|key| !key.starts_with("board/{"))
+                .collect::<Vec<_>>();
+            if old_keys.is_empty() {
+                return Ok(());
+            }
+            tracing::info!(count = old_keys.len(), "Migrating board keys");
+
+            // RENAMENX each of them, which keeps any expiration
+            for old_key in &old_keys {
+                let (board_id, rest) = match old_key["board/".len()..].split_once('/') {
+                    Some(parts) => parts,
+                    None => continue,
+                };
+                redis::cmd("RENAMENX")
+                    .arg(old_key)
+                    .arg(format!("board/{{{board_id}}}/{rest}"))
+                    .query_async::<_, ()>(&mut *connection)
+                    .await?;
+                if rest == "changes" {
+                    connection
+                        .sadd::<_, _, ()>(Self::change_streams_key(), board_id)
+                        .await?;
+                }
+            }
+
+            // SET board/{board_id}/archived for every board in archived_boards
+            let archived_board_ids = connection
+                .smembers::<_, Vec<String>>(Self::archived_boards_key())
+                .await?;
+            for board_id in archived_board_ids {
+                if let Ok(board_id) = board_id.parse::<Uuid>() {
+                    connection
+                        .set_nx::<_, _, ()>(Self::board_archived_key(board_id), 1)
+                        .await?;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Publish a change unless the board is archived, in which case nothing is returned
+    async fn try_publish_change_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: Change,
+        idempotency_key: Option<String>,
+    ) -> Result<Option<PublishOutcome>> {
+        lazy_static! {
+            // Check the idempotency key and the expected revisions, bump the revisions, and add the
+            // change to the stream all in one step so that no other change can sneak in between.
+            // The author's username and user ID are looked up from the board's sessions and
+            // recorded with the change. Archived boards have to be restored before anything can be
+            // added to them. KEYS[6] is the idempotency key, if there is one. ARGV holds the
+            // change, session ID, and idempotency expiration followed by pairs of object ID and
+            // expected revision, where an empty expected revision means anything goes.
+            static ref PUBLISH_SCRIPT: Script = Script::new(
+                r"
+                if redis.call('EXISTS', KEYS[5]) == 1 then
+                    return {3, '', ''}
+                end
+                if KEYS[6] then
+                    local existing_version = redis.call('GET', KEYS[6])
+                    if existing_version then
+                        return {2, existing_version, ''}
+                    end
+                end
+                for i = 4, #ARGV, 2 do
+                    local revision = tonumber(redis.call('HGET', KEYS[1], ARGV[i]) or '0')
+                    if ARGV[i + 1] ~= '' and tonumber(ARGV[i + 1]) ~= revision then
+                        return {0, ARGV[i], tostring(revision)}
+                    end
+                end
+                local revisions = {}
+                for i = 4, #ARGV, 2 do
+                    table.insert(revisions, {ARGV[i], redis.call('HINCRBY', KEYS[1], ARGV[i], 1)})
+                end
+                local encoded_revisions = cjson.encode(revisions)
+                local username = redis.call('HGET', KEYS[3], ARGV[2]) or ''
+                local user_id = redis.call('HGET', KEYS[4], ARGV[2]) or ''
+                local version = redis.call(
+                    'XADD', KEYS[2], '*',
+                    'change', ARGV[1],
+                    'session_id', ARGV[2],
+                    'username', username,
+                    'user_id', user_id,
+                    'revisions', encoded_revisions
+                )
+                if KEYS[6] then
+                    redis.call('SET', KEYS[6], version, 'EX', ARGV[3])
+                end
+                return {1, version, encoded_revisions}
+                "
+            );
+        }
+
+        let outcome = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            let mut invocation = PUBLISH_SCRIPT.prepare_invoke();
+            invocation
+                .key(Self::board_revisions_key(board_id))
+                .key(Self::board_changes_key(board_id))
+                .key(Self::board_sessions_key(board_id))
+                .key(Self::board_session_users_key(board_id))
+                .key(Self::board_archived_key(board_id))
+                .arg(serde_json::to_string(&change.clone())?)
+                .arg(session_id.to_string())
+                .arg(IDEMPOTENCY_TTL_SECONDS);
+            if let Some(idempotency_key) = &idempotency_key {
+                invocation.key(Self::board_idempotency_key(board_id, idempotency_key));
+            }
+            for (id, expected_revision) in change.expected_revisions() {
+                invocation.arg(id.to_string()).arg(
+                    expected_revision
+                        .map(|revision| revision.to_string())
+                        .unwrap_or_default(),
+                );
+            }
+
+            // XADD the change, session_id, username, user_id, and new revisions to the stream.
+            // Passing `*` as the entry ID is perhaps the most important detail of this design, as
+            // it allows Redis to fully determine the global ordering of changes to a board.
+            // Clients are responsible for rearranging any optimistic updates to match the order
+            // that the Redis stream decides.
+            let (status, first, second) = invocation
+                .invoke_async::<_, (u8, String, String)>(&mut *connection)
+                .await?;
+
+            match status {
+                0 => Ok(Some(PublishOutcome::RevisionMismatch {
+                    id: first.parse()?,
+                    current_revision: second.parse()?,
+                })),
+                1 => Ok(Some(PublishOutcome::Accepted {
+                    version: first,
+                    revisions: Self::parse_revisions(second.as_str()),
+                })),
+                2 => Ok(Some(PublishOutcome::Duplicate { version: first })),
+                _ => Ok(None),
+            }
+        })
+        .await?;
+
+        // Bump the board's last activity in the registry at boards to the time of the change, make
+        // sure its stream is registered at change_streams, and count the change as waiting to be
+        // checkpointed at pending_checkpoints. A cluster keeps those apart from the board's own
+        // keys, so the script can't do it.
+        if let Some(PublishOutcome::Accepted { version, .. }) = &outcome {
+            let last_activity = Repository::parse_stream_id(version)
+                .map(|(timestamp, _)| timestamp)
+                .unwrap_or_default();
+            Self::with_redis_retry(&self.retry_policy, || async {
+                let mut connection = self.pool.get().await?;
+                connection
+                    .zadd::<_, _, _, ()>(Self::boards_key(), board_id.to_string(), last_activity)
+                    .await?;
+                connection
+                    .sadd::<_, _, ()>(Self::change_streams_key(), board_id.to_string())
+                    .await?;
+                connection
+                    .zincr::<_, _, _, ()>(Self::pending_checkpoints_key(), board_id.to_string(), 1)
+                    .await?;
+                Ok(())
+            })
+            .await?;
+        }
+
+        Ok(outcome)
+    }
+
+    /// Read the given objects from board/{board_id}/objects, leaving out any that don't exist
+    #[tracing::instrument(skip(self, connection, object_ids), err)]
+    async fn read_objects(
+        &self,
+        connection: &mut RedisConnection,
+        board_id: Uuid,
+        object_ids: &[Uuid],
+    ) -> Result<Vec<(Uuid, JsonObject)>> {
+        if object_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let board_objects_key = Self::board_objects_key(board_id);
+
+        let objects = match self.object_storage {
+            // HMGET them all at once
+            ObjectStorage::Hash => redis::cmd("HMGET")
+                .arg(&board_objects_key)
+                .arg(object_ids.iter().map(Uuid::to_string).collect::<Vec<_>>())
+                .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                .await?
+                .into_iter()
+                .map(|object| object.and_then(|object| serde_json::from_str(&object).ok()))
+                .collect::<Vec<Option<JsonObject>>>(),
+            // JSON.GET each of them in one round trip. Each comes back inside an array, which is
+            // empty if the object doesn't exist.
+            ObjectStorage::Json => {
+                let mut pipeline = redis::pipe();
+                for id in object_ids {
+                    pipeline
+                        .cmd("JSON.GET")
+                        .arg(&board_objects_key)
+                        .arg(format!("$.{id}"));
+                }
+                pipeline
+                    .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                    .await?
+                    .into_iter()
+                    .map(|values| {
+                        serde_json::from_str::<Vec<JsonObject>>(&values?)
+                            .ok()?
+                            .pop()
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(object_ids
+            .iter()
+            .zip(objects)
+            .filter_map(|(id, object)| Some((*id, object?)))
+            .collect())
+    }
+
+    /// Bring the spatial index up to date with the given objects as they are now, where an object
+    /// that's gone is given as None. The bounds each object was indexed with are kept in the hash
+    /// at board/{board_id}/bounds, and the grid cells it touches are members named
+    /// `{cell}/{object_id}` of the sorted set at board/{board_id}/cells, all with a score of 0 so
+    /// that each cell can be read with ZRANGEBYLEX.
+    #[tracing::instrument(skip(connection, objects), err)]
+    async fn index_objects(
+        connection: &mut RedisConnection,
+        board_id: Uuid,
+        objects: Vec<(Uuid, Option<JsonObject>)>,
+    ) -> Result<()> {
+        if objects.is_empty() {
+            return Ok(());
+        }
+        let bounds_key = Self::board_bounds_key(board_id);
+        let cells_key = Self::board_cells_key(board_id);
+
+        // HMGET the bounds they were indexed with before so that their old cells can be cleared
+        let old_bounds = redis::cmd("HMGET")
+            .arg(&bounds_key)
+            .arg(
+                objects
+                    .iter()
+                    .map(|(id, _)| id.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<_, Vec<Option<String>>>(&mut *connection)
+            .await?;
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        for ((id, object), old_bounds) in objects.into_iter().zip(old_bounds) {
+            let old_bounds =
+                old_bounds.and_then(|bounds| serde_json::from_str::<Rect>(&bounds).ok());
+            let new_bounds = object.as_ref().and_then(Rect::of_object);
+            if old_bounds == new_bounds {
+                continue;
+            }
+            if let Some(old_bounds) = old_bounds {
+                for cell in old_bounds.object_cells() {
+                    pipeline.zrem(&cells_key, format!("{cell}/{id}")).ignore();
+                }
+            }
+            match new_bounds {
+                Some(new_bounds) => {
+                    pipeline
+                        .hset(
+                            &bounds_key,
+                            id.to_string(),
+                            serde_json::to_string(&new_bounds)?,
+                        )
+                        .ignore();
+                    for cell in new_bounds.object_cells() {
+                        pipeline
+                            .zadd(&cells_key, format!("{cell}/{id}"), 0)
+                            .ignore();
+                    }
+                }
+                None => {
+                    pipeline.hdel(&bounds_key, id.to_string()).ignore();
+                }
+            }
+        }
+        pipeline.query_async::<_, ()>(&mut *connection).await?;
+
+        Ok(())
+    }
+
+    /// Bring the search index up to date with the given objects as they are now, where an object
+    /// that's gone is given as None. Each term has a sorted set at search/{term} with a member
+    /// named `{board_id}/{object_id}` for every object that has the term, scored by when the term
+    /// was added to it, and the terms each object was indexed with are kept in the hash at
+    /// board/{board_id}/terms. The sorted sets are spread across a cluster, so each is written on
+    /// its own instead of in a MULTI/EXEC.
+    #[tracing::instrument(skip(connection, objects), err)]
+    async fn index_object_terms(
+        connection: &mut RedisConnection,
+        board_id: Uuid,
+        objects: &[(Uuid, Option<JsonObject>)],
+        indexed_at: u64,
+    ) -> Result<()> {
+        if objects.is_empty() {
+            return Ok(());
+        }
+        let terms_key = Self::board_terms_key(board_id);
+
+        // HMGET the terms they were indexed with before so that only the difference is written
+        let old_terms = redis::cmd("HMGET")
+            .arg(&terms_key)
+            .arg(
+                objects
+                    .iter()
+                    .map(|(id, _)| id.to_string())
+                    .collect::<Vec<_>>(),
+            )
+            .query_async::<_, Vec<Option<String>>>(&mut *connection)
+            .await?;
+
+        for ((id, object), old_terms) in objects.iter().zip(old_terms) {
+            let old_terms = old_terms
+                .map(|terms| {
+                    terms
+                        .split(' ')
+                        .map(ToString::to_string)
+                        .collect::<BTreeSet<_>>()
+                })
+                .unwrap_or_default();
+            let new_terms = object
+                .as_ref()
+                .map(search::object_terms)
+                .unwrap_or_default();
+            if old_terms == new_terms {
+                continue;
+            }
+
+            let member = format!("{board_id}/{id}");
+            for term in old_terms.difference(&new_terms) {
+                connection
+                    .zrem::<_, _, ()>(Self::search_term_key(term), &member)
+                    .await?;
+            }
+            for term in new_terms.difference(&old_terms) {
+                connection
+                    .zadd::<_, _, _, ()>(Self::search_term_key(term), &member, indexed_at)
+                    .await?;
+            }
+            if new_terms.is_empty() {
+                connection
+                    .hdel::<_, _, ()>(&terms_key, id.to_string())
+                    .await?;
+            } else {
+                connection
+                    .hset::<_, _, _, ()>(&terms_key, id.to_string(), new_terms.iter().join(" "))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Index the text of every object on a board that isn't in the search index yet
+    #[tracing::instrument(skip(self), err)]
+    async fn index_board_terms(&self, board_id: Uuid) -> Result<()> {
+        let indexed_at = Utc::now().timestamp_millis() as u64;
+        let mut chunks_stream = self.stream_object_chunks_for_board(board_id).await;
+        while let Some(entries) = chunks_stream.try_next().await? {
+            let objects = entries
+                .into_iter()
+                .map(|(id, object)| (id, Some(object)))
+                .collect::<Vec<_>>();
+            Self::with_redis_retry(&self.retry_policy, || async {
+                let mut connection = self.pool.get().await?;
+                Self::index_object_terms(&mut connection, board_id, &objects, indexed_at).await
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Throw away a board's spatial index and index all of its objects from scratch
+    #[tracing::instrument(skip(self), err)]
+    async fn reindex_board(&self, board_id: Uuid) -> Result<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            connection
+                .del::<_, ()>(vec![
+                    Self::board_bounds_key(board_id),
+                    Self::board_cells_key(board_id),
+                ])
+                .await?;
+            Ok(())
+        })
+        .await?;
+
+        let mut chunks_stream = self.stream_object_chunks_for_board(board_id).await;
+        while let Some(entries) = chunks_stream.try_next().await? {
+            let objects = entries
+                .into_iter()
+                .map(|(id, object)| (id, Some(object)))
+                .collect::<Vec<_>>();
+            Self::with_redis_retry(&self.retry_policy, || async {
+                let mut connection = self.pool.get().await?;
+                Self::index_objects(&mut connection, board_id, objects.clone()).await
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Add an event to the end of the webhook queue
+    #[tracing::instrument(skip(connection), err)]
+    async fn push_webhook_event(
+        connection: &mut RedisConnection,
+        event: &WebhookEvent,
+    ) -> Result<()> {
+        // Convert the event to a JSON string and push it onto the list at webhooks/queue
+        connection
+            .lpush::<_, _, ()>(Self::webhook_queue_key(), serde_json::to_string(event)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Publish a presence message for a board using Pub/Sub
+    #[tracing::instrument(skip(connection), err)]
+    async fn publish_presence_message_for_board(
+        connection: &mut RedisConnection,
+        board_id: Uuid,
+        message: PresenceMessage,
+    ) -> Result<()> {
+        let message_string = serde_json::to_string(&message)?;
+
+        // Add the message to the stream at board/{board_id}/presence_stream too if the board has
+        // reliable presence. It's still published for sessions that started listening before
+        // reliable presence was turned on.
+        if !Self::is_unreliable_presence(&message.message)
+            && connection
+                .exists::<_, bool>(Self::board_reliable_presence_key(board_id))
+                .await?
+        {
+            connection
+                .xadd_maxlen::<_, _, _, _, ()>(
+                    Self::board_presence_stream_key(board_id),
+                    StreamMaxlen::Approx(PRESENCE_STREAM_LENGTH),
+                    "*",
+                    &[("message", &message_string)],
+                )
+                .await?;
+        }
+
+        // Publish the JSON string to board/{board_id}/presence
+        connection
+            .publish::<String, String, ()>(Self::board_presence_key(board_id), message_string)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Whether a presence message only ever goes out over Pub/Sub, even on a board with reliable
+    /// presence. Cursor and latency updates only matter until the next one, and a deleted board's
+    /// presence stream is deleted along with it.
+    fn is_unreliable_presence(message: &ServerMessage) -> bool {
+        matches!(
+            message,
+            ServerMessage::UserCursorChanged { .. }
+                | ServerMessage::UserCursorLeft { .. }
+                | ServerMessage::UserLatencyChanged { .. }
+                | ServerMessage::BoardDeleted
+        )
+    }
+
+    /// Read a board's presence stream after `position` until reliable presence is turned off,
+    /// waiting out any trouble with Redis so that nothing is missed
+    fn stream_reliable_presence(
+        &self,
+        board_id: Uuid,
+        position: String,
+        reliable: Arc<AtomicBool>,
+    ) -> BoxStream<'static, PresenceMessage> {
+        let repo = self.clone();
+        Box::pin(stream! {
+            let mut position = position;
+            loop {
+                let read = Self::with_redis_retry(&repo.retry_policy, || async {
+                    let mut connection = repo.pool.get().await?;
+
+                    // XREAD whatever comes after the position, blocking for 1 second
+                    let read_reply = connection
+                        .xread_options::<_, _, StreamReadReply>(
+                            &[Self::board_presence_stream_key(board_id)],
+                            &[&position],
+                            &StreamReadOptions::default().block(1000).count(100),
+                        )
+                        .await?;
+                    let entries = read_reply
+                        .keys
+                        .into_iter()
+                        .next()
+                        .into_iter()
+                        .flat_map(|key| key.ids)
+                        .collect::<Vec<_>>();
+
+                    // Only look for whether it's been turned off while nothing is happening
+                    let still_reliable = !entries.is_empty()
+                        || connection
+                            .exists::<_, bool>(Self::board_reliable_presence_key(board_id))
+                            .await?;
+                    Ok((entries, still_reliable))
+                })
+                .await;
+                let (entries, still_reliable) = match read {
+                    Ok(read) => read,
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                if !still_reliable {
+                    reliable.store(false, Ordering::SeqCst);
+                    break;
+                }
+
+                for entry in entries {
+                    position = entry.id.clone();
+                    let message = entry
+                        .map
+                        .get("message")
+                        .and_then(|value| String::from_redis_value(value).ok())
+                        .and_then(|string| serde_json::from_str::<PresenceMessage>(&string).ok());
+                    if let Some(message) = message {
+                        yield message;
+                    }
+                }
+            }
+        })
+    }
+
+    #[tracing::instrument(err)]
+    fn parse_board_id_from_key(stream_key: &str) -> Result<Uuid> {
+        lazy_static! {
+            static ref BOARD_ID_REGEX: Regex = Regex::new(r"^board/\{([^/]+)\}/.*$").unwrap();
+        }
+
+        Ok(BOARD_ID_REGEX
+            .captures(stream_key)
+            .ok_or_else(|| anyhow!("No UUID found in stream key"))?
+            .get(1)
+            .ok_or_else(|| anyhow!("No UUID found in stream key"))?
+            .as_str()
+            .parse::<Uuid>()?)
+    }
+
+    /// Parse the contents of a change stream entry into a session ID, username, user ID, object
+    /// revisions, and a change. Entries written before usernames, user IDs, and revisions were
+    /// tracked have none of them.
+    fn parse_change_entry(id: StreamId) -> Option<ChangeEntry> {
+        Some(ChangeEntry {
+            session_id: id
+                .map
+                .get("session_id")
+                .and_then(|value| String::from_redis_value(value).ok())
+                .and_then(|string| string.parse::<Uuid>().ok())?,
+            username: id
+                .map
+                .get("username")
+                .and_then(|value| String::from_redis_value(value).ok())
+                .filter(|username| !username.is_empty()),
+            user_id: id
+                .map
+                .get("user_id")
+                .and_then(|value| String::from_redis_value(value).ok())
+                .filter(|user_id| !user_id.is_empty()),
+            revisions: id
+                .map
+                .get("revisions")
+                .and_then(|value| String::from_redis_value(value).ok())
+                .map(|string| Self::parse_revisions(string.as_str()))
+                .unwrap_or_default(),
+            change: id
+                .map
+                .get("change")
+                .and_then(|value| String::from_redis_value(value).ok())
+                .and_then(|string| serde_json::from_str::<Change>(&string).ok())?,
+            version: id.id,
+        })
+    }
+
+    /// Parse the object revisions recorded with a change, which the publish script encodes as a
+    /// JSON array of `[object_id, revision]` pairs. Lua's JSON encoder can't tell an empty array
+    /// from an empty object, so anything unexpected is treated as no revisions.
+    fn parse_revisions(encoded_revisions: &str) -> Vec<(Uuid, u64)> {
+        serde_json::from_str(encoded_revisions).unwrap_or_default()
+    }
+
+    fn boards_key() -> String {
+        "boards".to_string()
+    }
+
+    fn board_key_pattern(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/*")
+    }
+
+    fn board_meta_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/meta")
+    }
+
+    fn board_frozen_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/frozen")
+    }
+
+    fn board_reliable_presence_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/reliable_presence")
+    }
+
+    fn board_presence_stream_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/presence_stream")
+    }
+
+    fn board_objects_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/objects")
+    }
+
+    fn board_version_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/version")
+    }
+
+    fn board_order_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/order")
+    }
+
+    fn board_presence_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/presence")
+    }
+
+    fn board_changes_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/changes")
+    }
+
+    fn board_history_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/history")
+    }
+
+    fn board_checkpoints_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/checkpoints")
+    }
+
+    fn board_trash_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/trash")
+    }
+
+    fn board_bounds_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/bounds")
+    }
+
+    fn board_cells_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/cells")
+    }
+
+    fn board_terms_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/terms")
+    }
+
+    fn search_term_key(term: &str) -> String {
+        format!("search/{term}")
+    }
+
+    fn board_revisions_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/revisions")
+    }
+
+    fn board_checkpointed_revisions_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/checkpointed_revisions")
+    }
+
+    fn board_sessions_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/sessions")
+    }
+
+    fn board_session_users_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/session_users")
+    }
+
+    fn board_session_clients_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/session_clients")
+    }
+
+    fn board_waiting_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/waiting")
+    }
+
+    fn board_object_lock_key(board_id: Uuid, object_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/locks/{object_id}")
+    }
+
+    fn board_idempotency_key(board_id: Uuid, idempotency_key: &str) -> String {
+        format!("board/{{{board_id}}}/idempotency/{idempotency_key}")
+    }
+
+    fn board_object_lock_key_pattern(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/locks/*")
+    }
+
+    fn board_cursors_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/cursors")
+    }
+
+    fn board_latencies_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/latencies")
+    }
+
+    fn board_bans_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/bans")
+    }
+
+    fn board_webhooks_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/webhooks")
+    }
+
+    fn board_archived_key(board_id: Uuid) -> String {
+        format!("board/{{{board_id}}}/archived")
+    }
+
+    fn change_streams_key() -> String {
+        "change_streams".to_string()
+    }
+
+    fn pending_checkpoints_key() -> String {
+        "pending_checkpoints".to_string()
+    }
+
+    fn archived_boards_key() -> String {
+        "archived_boards".to_string()
+    }
+
+    fn api_keys_key() -> String {
+        "api_keys".to_string()
+    }
+
+    fn share_secret_key() -> String {
+        "share_secret".to_string()
+    }
+
+    fn schema_version_key() -> String {
+        "schema_version".to_string()
+    }
+
+    fn task_lock_key(name: &str) -> String {
+        format!("locks/{name}")
+    }
+
+    fn webhook_queue_key() -> String {
+        "webhooks/queue".to_string()
+    }
+
+    fn session_checkin_key(session_id: Uuid) -> String {
+        format!("session/{session_id}/checkin")
+    }
+
+    fn session_boards_key(session_id: Uuid) -> String {
+        format!("session/{session_id}/boards")
+    }
+
+    fn parse_session_id_from_checkin_key(key: &str) -> Option<Uuid> {
+        key.strip_prefix("session/")?
+            .strip_suffix("/checkin")?
+            .parse()
+            .ok()
+    }
+
+    /// Remember that a session is on or waiting for a board, so that the board can be cleaned up
+    /// once the session expires. The set outlives the checkin so that it's still there by then.
+    async fn add_board_for_session(
+        &self,
+        connection: &mut RedisConnection,
+        session_id: Uuid,
+        board_id: Uuid,
+    ) -> Result<()> {
+        let session_boards_key = Self::session_boards_key(session_id);
+        connection
+            .sadd::<_, _, ()>(&session_boards_key, board_id.to_string())
+            .await?;
+        connection
+            .expire::<_, ()>(&session_boards_key, 2 * self.session_ttl.as_secs() as usize)
+            .await?;
+        Ok(())
+    }
+
+    /// Turn on the notifications Redis sends when a key expires, unless they're on already.
+    /// Returns false if they're off and can't be turned on, which hosted Redis services that
+    /// don't allow CONFIG do.
+    async fn enable_expired_notifications(&self) -> Result<bool> {
+        let mut connection = self.pool.get().await?;
+        let (_, mut flags) = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("notify-keyspace-events")
+            .query_async::<_, (String, String)>(&mut *connection)
+            .await?;
+
+        // E is for keyevent notifications, and x is for expired keys, which A includes
+        let expired = flags.contains('x') || flags.contains('A');
+        if flags.contains('E') && expired {
+            return Ok(true);
+        }
+        if !flags.contains('E') {
+            flags.push('E');
+        }
+        if !expired {
+            flags.push('x');
+        }
+        let enabled = redis::cmd("CONFIG")
+            .arg("SET")
+            .arg("notify-keyspace-events")
+            .arg(&flags)
+            .query_async::<_, ()>(&mut *connection)
+            .await;
+        if let Err(error) = enabled {
+            tracing::warn!(%error, "Could not turn on expired key notifications");
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Get the boards an expired session was on or waiting for, and forget them so that no other
+    /// server gets them too
+    async fn take_boards_for_session(&self, session_id: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let session_boards_key = Self::session_boards_key(session_id);
+
+            // SMEMBERS and DEL session/{session_id}/boards in one transaction
+            let (board_ids,) = redis::pipe()
+                .atomic()
+                .smembers(&session_boards_key)
+                .del(&session_boards_key)
+                .ignore()
+                .query_async::<_, (Vec<String>,)>(&mut *connection)
+                .await?;
+
+            Ok(board_ids
+                .iter()
+                .filter_map(|board_id| board_id.parse().ok())
+                .collect())
+        })
+        .await
+    }
+
+    /// The redis-rs client doesn't handle retries particularly well. Wrapping a Redis call with
+    /// this method retries it with backoff in cases where I observed errors that seemed to be
+    /// transient, which are sorted out when the error is converted into a `RepositoryError`. It
+    /// takes the policy rather than self so that streams and startup can use it too.
+    async fn with_redis_retry<F, T, O>(retry_policy: &RetryPolicy, action: F) -> RepositoryResult<T>
+    where
+        F: FnMut() -> O,
+        O: Future<Output = Result<T>>,
+    {
+        retry_policy.run(action).await
+    }
+
+    /// Start the presence subscription loop. The presence Pub/Sub subscription runs in a background
+    /// task and forwards messages to an in-memory channel that can be more efficiently streamed by
+    /// each connected session.
+    #[tracing::instrument(skip_all)]
+    async fn start_presence(manager: RedisConnectionManager, presence: PresenceChannel) {
+        loop {
+            let _ = Self::run_presence(manager.clone(), presence.clone()).await;
+
+            // Give a failover a moment to finish before connecting again
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Listen to messages on all presence channels and forward them into a tokio broadcast channel.
+    /// This approach allows each instance of the server to use only one dedicated connection for
+    /// subscribing to presence, reducing the number of connections used overall. The alternatives
+    /// are either much more complicated than is warranted for a hackathon, or else to open a new
+    /// subscription for every sesssion - potentially overloading the database with connections.
+    #[tracing::instrument(skip_all, err)]
+    async fn run_presence(
+        manager: RedisConnectionManager,
+        presence: PresenceChannel,
+    ) -> Result<()> {
+        let mut pubsub = manager.pubsub().await?;
+        pubsub.psubscribe("board/*/presence").await?;
+        let mut stream = pubsub.into_on_message();
+        while let Some(msg) = stream.next().await {
+            let channel_name = msg.get_channel::<String>()?;
+            let board_id = Self::parse_board_id_from_key(channel_name.as_str())?;
+            let message = serde_json::from_slice::<PresenceMessage>(msg.get_payload_bytes())?;
+            presence.send(board_id, message);
+        }
+        Ok(())
+    }
+}
+
+impl From<RedisError> for RepositoryError {
+    fn from(error: RedisError) -> Self {
+        // Timeouts and dropped connections are typically transient
+        if error.is_timeout() || error.is_connection_dropped() {
+            return Self::Timeout(error.into());
+        }
+
+        let transient = match error.kind() {
+            // For some reason, sometimes a slow connection or the database getting overloaded
+            // with too many connections would result in Redis returning the wrong type for a key
+            // that should have correct data. Rust's types help to prevent incorrect formats from
+            // being stored so my best conclusion is sometimes Redis is unable to send the correct
+            // data, or the client is unable to deal with that data correctly. When that happened
+            // a retry would resolve the issue. If we ever had to start being more flexible about
+            // stored formats this might be untenable but for now it works.
+            redis::ErrorKind::TypeError => true,
+
+            // Sometimes Redis or the client just tells us directly to try again.
+            redis::ErrorKind::TryAgain => true,
+
+            // A cluster can be briefly unavailable while it fails over, and a master can turn
+            // into a replica while Sentinel fails it over. The pool replaces connections to
+            // replicas before handing them out.
+            redis::ErrorKind::ClusterDown | redis::ErrorKind::ReadOnly => true,
+
+            redis::ErrorKind::ResponseError => true,
+
+            // Otherwise, the error is real
+            _ => false,
+        };
+        Self::Backend {
+            error: error.into(),
+            transient,
+        }
+    }
+}
+
+#[async_trait]
+impl BoardStore for RedisRepository {
+    #[tracing::instrument(skip(self), err)]
+    async fn create_board(&self, board_id: Uuid, meta: BoardMeta) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Store the metadata as a JSON string at board/{board_id}/meta. NX makes sure an
+            // existing board can never be clobbered, however unlikely a UUID collision is.
+            let created = connection
+                .set_nx::<_, _, bool>(
+                    Self::board_meta_key(board_id),
+                    serde_json::to_string(&meta)?,
+                )
+                .await?;
+
+            if !created {
+                return Err(
+                    RepositoryError::Conflict(format!("Board {board_id} already exists")).into(),
+                );
+            }
+
+            // Add the board to the registry at boards, with its creation as its last activity
+            connection
+                .zadd::<_, _, _, ()>(
+                    Self::boards_key(),
+                    board_id.to_string(),
+                    meta.created_at.timestamp_millis(),
+                )
+                .await?;
+
+            // Queue up a board.created webhook
+            Self::push_webhook_event(
+                &mut connection,
+                &WebhookEvent::BoardCreated {
+                    board_id,
+                    meta: meta.clone(),
+                },
+            )
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_meta_for_board(&self, board_id: Uuid) -> RepositoryResult<Option<BoardMeta>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read the JSON string at board/{board_id}/meta
+            let meta = connection
+                .get::<_, Option<String>>(Self::board_meta_key(board_id))
+                .await?
+                .map(|string| serde_json::from_str::<BoardMeta>(&string))
+                .transpose()?;
+
+            Ok(meta)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_meta_for_board(
+        &self,
+        board_id: Uuid,
+        patch: BoardMetaPatch,
+    ) -> RepositoryResult<Option<BoardMeta>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let board_meta_key = Self::board_meta_key(board_id);
+
+            loop {
+                // WATCH board/{board_id}/meta so that the write fails and gets retried if somebody
+                // else changes it in between
+                redis::cmd("WATCH")
+                    .arg(&board_meta_key)
+                    .query_async::<_, ()>(&mut *connection)
+                    .await?;
+
+                let mut meta = match connection.get::<_, Option<String>>(&board_meta_key).await? {
+                    Some(string) => serde_json::from_str::<BoardMeta>(&string)?,
+                    None => {
+                        redis::cmd("UNWATCH")
+                            .query_async::<_, ()>(&mut *connection)
+                            .await?;
+                        return Ok(None);
+                    }
+                };
+                patch.clone().apply(&mut meta);
+
+                // Write the updated JSON string back, which comes back empty if the WATCH tripped
+                let written = redis::pipe()
+                    .atomic()
+                    .set(&board_meta_key, serde_json::to_string(&meta)?)
+                    .ignore()
+                    .query_async::<_, Option<()>>(&mut *connection)
+                    .await?;
+
+                if written.is_some() {
+                    return Ok(Some(meta));
+                }
+            }
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_board_frozen(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // EXISTS board/{board_id}/frozen
+            let frozen = connection
+                .exists::<_, bool>(Self::board_frozen_key(board_id))
+                .await?;
+
+            Ok(frozen)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn set_board_frozen(&self, board_id: Uuid, frozen: bool) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Set or delete board/{board_id}/frozen
+            if frozen {
+                connection
+                    .set::<_, _, ()>(Self::board_frozen_key(board_id), 1)
+                    .await?;
+            } else {
+                connection
+                    .del::<_, ()>(Self::board_frozen_key(board_id))
+                    .await?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn set_board_reliable_presence(
+        &self,
+        board_id: Uuid,
+        reliable: bool,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Set or delete board/{board_id}/reliable_presence, and the stream along with it
+            if reliable {
+                connection
+                    .set::<_, _, ()>(Self::board_reliable_presence_key(board_id), 1)
+                    .await?;
+            } else {
+                connection
+                    .del::<_, ()>(&[
+                        Self::board_reliable_presence_key(board_id),
+                        Self::board_presence_stream_key(board_id),
+                    ])
+                    .await?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_board(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        let deleted = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // SCAN over every key that matches board/{board_id}/*, which covers the change stream,
+            // objects, version, sessions, and everything else that belongs to the board
+            let board_keys = connection
+                .scan_match_all(&Self::board_key_pattern(board_id))
+                .await?;
+
+            // Take the board's objects out of the search index with the terms they were indexed
+            // with in board/{board_id}/terms, since the search/{term} sets live elsewhere
+            let indexed_terms = connection
+                .hgetall::<_, Vec<(String, String)>>(Self::board_terms_key(board_id))
+                .await?;
+            for (id, terms) in indexed_terms {
+                for term in terms.split(' ') {
+                    connection
+                        .zrem::<_, _, ()>(Self::search_term_key(term), format!("{board_id}/{id}"))
+                        .await?;
+                }
+            }
+
+            // Remove the board from the registry at boards, from archived_boards, from
+            // change_streams, and from pending_checkpoints before deleting all of its keys, so
+            // that a change published in between can only leave the board registered without a
+            // stream and never the reverse
+            let unregistered = connection
+                .zrem::<_, _, bool>(Self::boards_key(), board_id.to_string())
+                .await?;
+            connection
+                .srem::<_, _, ()>(Self::archived_boards_key(), board_id.to_string())
+                .await?;
+            connection
+                .srem::<_, _, ()>(Self::change_streams_key(), board_id.to_string())
+                .await?;
+            connection
+                .zrem::<_, _, ()>(Self::pending_checkpoints_key(), board_id.to_string())
+                .await?;
+            if !board_keys.is_empty() {
+                connection.del::<_, ()>(&board_keys).await?;
+            }
+
+            if !unregistered && board_keys.is_empty() {
+                return Ok(false);
+            }
+
+            // Broadcast BoardDeleted notification. The nil session ID means it didn't come from
+            // any session, so nobody will skip it.
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: Uuid::nil(),
+                    message: ServerMessage::BoardDeleted,
+                },
+            )
+            .await?;
+
+            Ok(true)
+        })
+        .await?;
+
+        // Also remove the board's archive, in case it was ever archived
+        if let (true, Some(archive_store)) = (deleted, &self.archive_store) {
+            archive_store.delete(board_id).await?;
+        }
+
+        Ok(deleted)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn duplicate_board(&self, board_id: Uuid, new_board_id: Uuid) -> RepositoryResult<bool> {
+        // An archived board has nothing in Redis to copy until it's restored
+        self.restore_board(board_id).await?;
+
+        let duplicated = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let key_sets = [board_id, new_board_id].map(|id| {
+                [
+                    Self::board_objects_key(id),
+                    Self::board_order_key(id),
+                    Self::board_revisions_key(id),
+                    Self::board_checkpointed_revisions_key(id),
+                    Self::board_version_key(id),
+                    Self::board_changes_key(id),
+                    Self::board_bounds_key(id),
+                    Self::board_cells_key(id),
+                ]
+            });
+
+            // DUMP everything that makes up the board's document in one MULTI/EXEC so that the
+            // copy can never land between a change being published and it being checkpointed.
+            // The change stream and version are copied too so that pending changes aren't lost,
+            // along with the spatial index.
+            let mut pipeline = redis::pipe();
+            pipeline.atomic();
+            for key in &key_sets[0] {
+                pipeline.cmd("DUMP").arg(key);
+            }
+            let dumps = pipeline
+                .query_async::<_, Vec<Option<Vec<u8>>>>(&mut *connection)
+                .await?;
+
+            if dumps.iter().any(Option::is_some) {
+                // RESTORE the dumps to board/{new_board_id}/*, which is somewhere else entirely in
+                // a cluster. REPLACE makes it safe to try again.
+                let mut pipeline = redis::pipe();
+                pipeline.atomic();
+                for (key, dump) in key_sets[1].iter().zip(&dumps) {
+                    if let Some(dump) = dump {
+                        pipeline
+                            .cmd("RESTORE")
+                            .arg(key)
+                            .arg(0)
+                            .arg(dump.as_slice())
+                            .arg("REPLACE")
+                            .ignore();
+                    }
+                }
+                pipeline.query_async::<_, ()>(&mut *connection).await?;
+            } else {
+                // A board that was created but never changed only shows up in the registry at
+                // boards
+                let registered = connection
+                    .zscore::<_, _, Option<i64>>(Self::boards_key(), board_id.to_string())
+                    .await?
+                    .is_some();
+                if !registered {
+                    return Ok(false);
+                }
+            }
+
+            // Add the new board to the registry at boards, and to change_streams if it got a copy
+            // of the change stream
+            connection
+                .zadd::<_, _, _, ()>(
+                    Self::boards_key(),
+                    new_board_id.to_string(),
+                    Utc::now().timestamp_millis(),
+                )
+                .await?;
+            if dumps[4].is_some() {
+                connection
+                    .sadd::<_, _, ()>(Self::change_streams_key(), new_board_id.to_string())
+                    .await?;
+            }
+
+            Ok(true)
+        })
+        .await?;
+
+        // The copy's objects go in the search index under its own ID
+        if duplicated {
+            self.index_board_terms(new_board_id).await?;
+        }
+
+        Ok(duplicated)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_boards(
+        &self,
+        cursor: usize,
+        limit: usize,
+    ) -> RepositoryResult<(Vec<BoardSummary>, Option<usize>)> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            if limit == 0 {
+                return Ok((vec![], Some(cursor)));
+            }
+
+            // ZREVRANGE over the registry at boards to get the page along with each board's last
+            // activity in milliseconds
+            let page = connection
+                .zrevrange_withscores::<_, Vec<(String, i64)>>(
+                    Self::boards_key(),
+                    cursor as isize,
+                    (cursor + limit - 1) as isize,
+                )
+                .await?
+                .into_iter()
+                .filter_map(|(board_id_string, last_activity)| {
+                    Some((board_id_string.parse::<Uuid>().ok()?, last_activity))
+                })
+                .collect::<Vec<_>>();
+
+            if page.is_empty() {
+                return Ok((vec![], None));
+            }
+
+            // MGET all of the metadata at once. Boards that came into existence implicitly don't
+            // have any.
+            let metas = redis::cmd("MGET")
+                .arg(
+                    page.iter()
+                        .map(|(board_id, _)| Self::board_meta_key(*board_id))
+                        .collect::<Vec<_>>(),
+                )
+                .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                .await?;
+
+            let next_cursor = if page.len() == limit {
+                Some(cursor + limit)
+            } else {
+                None
+            };
+
+            let boards = page
+                .into_iter()
+                .zip(metas)
+                .map(|((board_id, last_activity), meta)| BoardSummary {
+                    board_id,
+                    meta: meta.and_then(|string| serde_json::from_str(&string).ok()),
+                    last_activity_at: Utc.timestamp_millis(last_activity),
+                })
+                .collect::<Vec<_>>();
+
+            Ok((boards, next_cursor))
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn create_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        username: String,
+        user_id: Option<String>,
+        client: ClientInfo,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let sessions_key = Self::board_sessions_key(board_id);
+
+            // Add the session ID and username as a key-value pair to the hash at
+            // board/{board_id}/sessions
+            connection
+                .hset::<_, _, _, ()>(&sessions_key, session_id.to_string(), username.clone())
+                .await?;
+
+            // Add the board ID to the set at session/{session_id}/boards
+            self.add_board_for_session(&mut connection, session_id, board_id)
+                .await?;
+
+            // Add the session ID and user ID as a key-value pair to the hash at
+            // board/{board_id}/session_users
+            if let Some(user_id) = &user_id {
+                connection
+                    .hset::<_, _, _, ()>(
+                        Self::board_session_users_key(board_id),
+                        session_id.to_string(),
+                        user_id,
+                    )
+                    .await?;
+            }
+
+            // Add the session ID and what the session connected with as a key-value pair to the
+            // hash at board/{board_id}/session_clients
+            connection
+                .hset::<_, _, _, ()>(
+                    Self::board_session_clients_key(board_id),
+                    session_id.to_string(),
+                    serde_json::to_string(&client)?,
+                )
+                .await?;
+
+            // Start keeping the session alive by bumping the expiration at
+            // sessions/{session_id}/checkin
+            self.touch_session(session_id).await?;
+
+            // Broadcast UserJoined notification
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::UserJoined {
+                        session_id,
+                        username: username.clone(),
+                    },
+                },
+            )
+            .await?;
+
+            // Queue up a user.joined webhook
+            Self::push_webhook_event(
+                &mut connection,
+                &WebhookEvent::UserJoined {
+                    board_id,
+                    session_id,
+                    username: username.clone(),
+                },
+            )
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_sessions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, String)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let sessions_key = Self::board_sessions_key(board_id);
+
+            // Read all of the session ID - username pairs from the hash at
+            // board/{board_id}/sessions
+            let sessions = connection
+                .hgetall::<_, HashMap<String, String>>(&sessions_key)
+                .await?
+                .into_iter()
+                .filter_map(|(session_id_string, username)| {
+                    session_id_string
+                        .parse::<Uuid>()
+                        .ok()
+                        .map(|session_id| (session_id, username))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(sessions)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_user_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<Option<String>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read the session's user ID from the hash at board/{board_id}/session_users
+            let user_id = connection
+                .hget::<_, _, Option<String>>(
+                    Self::board_session_users_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            Ok(user_id)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Delete the session ID from the hash at board/{board_id}/sessions
+            connection
+                .hdel::<String, String, ()>(
+                    Self::board_sessions_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            // Delete the user ID from the hash at board/{board_id}/session_users
+            connection
+                .hdel::<String, String, ()>(
+                    Self::board_session_users_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            // Delete the client info from the hash at board/{board_id}/session_clients
+            connection
+                .hdel::<String, String, ()>(
+                    Self::board_session_clients_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            // Delete the checkin state at sessions/{session_id}/checkin
+            connection
+                .del::<_, ()>(Self::session_checkin_key(session_id))
+                .await?;
+
+            // Delete the board ID from the set at session/{session_id}/boards
+            connection
+                .srem::<_, _, ()>(Self::session_boards_key(session_id), board_id.to_string())
+                .await?;
+
+            // Delete the last known cursor position from the hash at board/{board_id}/cursors
+            connection
+                .hdel::<String, String, ()>(
+                    Self::board_cursors_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            // Delete the last reported latency from the hash at board/{board_id}/latencies
+            connection
+                .hdel::<String, String, ()>(
+                    Self::board_latencies_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            // Broadcast UserLeft notification
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::UserLeft { session_id },
+                },
+            )
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn kick_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        reason: Option<String>,
+    ) -> RepositoryResult<bool> {
+        let kicked = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Check for the session ID in the hash at board/{board_id}/sessions
+            let exists = connection
+                .hexists::<_, _, bool>(Self::board_sessions_key(board_id), session_id.to_string())
+                .await?;
+            if !exists {
+                return Ok(false);
+            }
+
+            // Broadcast SessionKicked notification, which only the kicked session acts on. The nil
+            // session ID means it didn't come from any session.
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: Uuid::nil(),
+                    message: ServerMessage::SessionKicked {
+                        session_id,
+                        reason: reason.clone(),
+                    },
+                },
+            )
+            .await?;
+
+            Ok(true)
+        })
+        .await?;
+
+        // Clean up right away in case the session's handler is already gone
+        if kicked {
+            self.delete_session_for_board(board_id, session_id).await?;
+        }
+
+        Ok(kicked)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_ban_for_board(&self, board_id: Uuid, ban: Ban) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Add the target and the ban as a key-value pair to the hash at board/{board_id}/bans
+            connection
+                .hset::<_, _, _, ()>(
+                    Self::board_bans_key(board_id),
+                    serde_json::to_string(&ban.target)?,
+                    serde_json::to_string(&ban)?,
+                )
+                .await?;
+
+            // Broadcast SessionBanned notification, which only the banned sessions act on
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: Uuid::nil(),
+                    message: ServerMessage::SessionBanned {
+                        target: ban.target.clone(),
+                        reason: ban.reason.clone(),
+                    },
+                },
+            )
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn remove_ban_for_board(
+        &self,
+        board_id: Uuid,
+        target: BanTarget,
+    ) -> RepositoryResult<bool> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Delete the target from the hash at board/{board_id}/bans
+            let removed = connection
+                .hdel::<_, _, bool>(
+                    Self::board_bans_key(board_id),
+                    serde_json::to_string(&target)?,
+                )
+                .await?;
+
+            Ok(removed)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_bans_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Ban>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the bans from the hash at board/{board_id}/bans
+            let bans = connection
+                .hvals::<_, Vec<String>>(Self::board_bans_key(board_id))
+                .await?
+                .into_iter()
+                .filter_map(|ban_string| serde_json::from_str::<Ban>(&ban_string).ok())
+                .collect::<Vec<_>>();
+
+            Ok(bans)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn touch_session(&self, session_id: Uuid) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            connection
+                .set_ex::<_, _, ()>(
+                    Self::session_checkin_key(session_id),
+                    1,
+                    self.session_ttl.as_secs() as usize,
+                )
+                .await?;
+
+            // Keep session/{session_id}/boards around for a while after the checkin
+            connection
+                .expire::<_, ()>(
+                    Self::session_boards_key(session_id),
+                    2 * self.session_ttl.as_secs() as usize,
+                )
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_exists(&self, session_id: Uuid) -> RepositoryResult<bool> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Simply check EXISTS at sessions/{session_id}/checkin and let the expiration handle
+            let exists = connection
+                .exists::<_, bool>(Self::session_checkin_key(session_id))
+                .await?;
+
+            Ok(exists)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn stream_expired_sessions(
+        &self,
+    ) -> RepositoryResult<Option<BoxStream<'static, (Uuid, Vec<Uuid>)>>> {
+        let enabled =
+            Self::with_redis_retry(&self.retry_policy, || self.enable_expired_notifications())
+                .await?;
+        if !enabled {
+            return Ok(None);
+        }
+
+        // Redis tells every subscriber about every key that expires, and only checkins matter
+        let pubsub = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut pubsub = self.manager.pubsub().await?;
+            pubsub.psubscribe("__keyevent@*__:expired").await?;
+            Ok(pubsub)
+        })
+        .await?;
+        let repo = self.clone();
+        Ok(Some(Box::pin(
+            pubsub
+                .into_on_message()
+                .filter_map(|msg| async move {
+                    Self::parse_session_id_from_checkin_key(&msg.get_payload::<String>().ok()?)
+                })
+                .filter_map(move |session_id| {
+                    let repo = repo.clone();
+                    async move {
+                        match repo.take_boards_for_session(session_id).await {
+                            Ok(board_ids) if board_ids.is_empty() => None,
+                            Ok(board_ids) => Some((session_id, board_ids)),
+                            // The sweep finds it later
+                            Err(error) => {
+                                tracing::warn!(
+                                    %session_id,
+                                    %error,
+                                    "Could not get the boards of an expired session"
+                                );
+                                None
+                            }
+                        }
+                    }
+                }),
+        )))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_session_cursor_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        x: f64,
+        y: f64,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Remember the latest position in the hash at board/{board_id}/cursors so it can be
+            // included in presence snapshots and shown to anyone who joins later, and push back
+            // the hash's expiration
+            let cursors_key = Self::board_cursors_key(board_id);
+            redis::pipe()
+                .atomic()
+                .hset(
+                    &cursors_key,
+                    session_id.to_string(),
+                    serde_json::to_string(&Cursor { x, y })?,
+                )
+                .ignore()
+                .expire(&cursors_key, CURSOR_TTL_SECONDS)
+                .ignore()
+                .query_async::<_, ()>(&mut *connection)
+                .await?;
+
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::UserCursorChanged { session_id, x, y },
+                },
+            )
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_session_cursor_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Forget the last known position in the hash at board/{board_id}/cursors
+            connection
+                .hdel::<String, String, ()>(
+                    Self::board_cursors_key(board_id),
+                    session_id.to_string(),
+                )
+                .await?;
+
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::UserCursorLeft { session_id },
+                },
+            )
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn update_session_latency_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        rtt: f64,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Keep the latest measurement in the hash at board/{board_id}/latencies
+            connection
+                .hset::<_, _, _, ()>(
+                    Self::board_latencies_key(board_id),
+                    session_id.to_string(),
+                    rtt,
+                )
+                .await?;
+
+            // Broadcast UserLatencyChanged notification
+            Self::publish_presence_message_for_board(
+                &mut connection,
+                board_id,
+                PresenceMessage {
+                    source_session: session_id,
+                    message: ServerMessage::UserLatencyChanged { session_id, rtt },
+                },
+            )
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_latencies_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, f64)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the session ID - latency pairs from the hash at
+            // board/{board_id}/latencies
+            let latencies = connection
+                .hgetall::<_, HashMap<String, f64>>(Self::board_latencies_key(board_id))
+                .await?
+                .into_iter()
+                .filter_map(|(session_id_string, rtt)| {
+                    Some((session_id_string.parse::<Uuid>().ok()?, rtt))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(latencies)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_cursors_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Cursor)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the session ID - cursor pairs from the hash at board/{board_id}/cursors
+            let cursors = connection
+                .hgetall::<_, HashMap<String, String>>(Self::board_cursors_key(board_id))
+                .await?
+                .into_iter()
+                .filter_map(|(session_id_string, cursor_string)| {
+                    Some((
+                        session_id_string.parse::<Uuid>().ok()?,
+                        serde_json::from_str::<Cursor>(&cursor_string).ok()?,
+                    ))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(cursors)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_clients_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, ClientInfo)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the session ID - client info pairs from the hash at
+            // board/{board_id}/session_clients
+            let clients = connection
+                .hgetall::<_, HashMap<String, String>>(Self::board_session_clients_key(board_id))
+                .await?
+                .into_iter()
+                .filter_map(|(session_id_string, client_string)| {
+                    Some((
+                        session_id_string.parse::<Uuid>().ok()?,
+                        serde_json::from_str::<ClientInfo>(&client_string).ok()?,
+                    ))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(clients)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn lock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Uuid> {
+        lazy_static! {
+            // Take or renew the lease only if nobody else holds it, and report the holder either
+            // way. This has to happen in a script because SET NX can't tell us whether the
+            // existing holder is ourselves.
+            static ref LOCK_SCRIPT: Script = Script::new(
+                r"
+                local holder = redis.call('GET', KEYS[1])
+                if holder == false or holder == ARGV[1] then
+                    redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+                    return ARGV[1]
+                end
+                return holder
+                "
+            );
+        }
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Run the lock script against board/{board_id}/locks/{object_id}
+            let holder = LOCK_SCRIPT
+                .key(Self::board_object_lock_key(board_id, object_id))
+                .arg(session_id.to_string())
+                .arg(OBJECT_LOCK_TTL_SECONDS)
+                .invoke_async::<_, String>(&mut *connection)
+                .await?
+                .parse::<Uuid>()?;
+
+            // Broadcast ObjectLocked notification if the lease belongs to this session
+            if holder == session_id {
+                Self::publish_presence_message_for_board(
+                    &mut connection,
+                    board_id,
+                    PresenceMessage {
+                        source_session: session_id,
+                        message: ServerMessage::ObjectLocked {
+                            id: object_id,
+                            session_id,
+                        },
+                    },
+                )
+                .await?;
+            }
+
+            Ok(holder)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn unlock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<bool> {
+        lazy_static! {
+            // Compare-and-delete so that a session can never release somebody else's lease
+            static ref UNLOCK_SCRIPT: Script = Script::new(
+                r"
+                if redis.call('GET', KEYS[1]) == ARGV[1] then
+                    return redis.call('DEL', KEYS[1])
+                end
+                return 0
+                "
+            );
+        }
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            let released = UNLOCK_SCRIPT
+                .key(Self::board_object_lock_key(board_id, object_id))
+                .arg(session_id.to_string())
+                .invoke_async::<_, bool>(&mut *connection)
+                .await?;
+
+            // Broadcast ObjectUnlocked notification
+            if released {
+                Self::publish_presence_message_for_board(
+                    &mut connection,
+                    board_id,
+                    PresenceMessage {
+                        source_session: session_id,
+                        message: ServerMessage::ObjectUnlocked {
+                            id: object_id,
+                            session_id,
+                        },
+                    },
+                )
+                .await?;
+            }
+
+            Ok(released)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_lock_for_board(
+        &self,
+        board_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Option<Uuid>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Simple GET at board/{board_id}/locks/{object_id}, expiration takes care of stale
+            // leases
+            let holder = connection
+                .get::<_, Option<String>>(Self::board_object_lock_key(board_id, object_id))
+                .await?
+                .and_then(|string| string.parse::<Uuid>().ok());
+
+            Ok(holder)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self, change), err)]
+    async fn get_conflicting_lock_for_change(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: &Change,
+    ) -> RepositoryResult<Option<Uuid>> {
+        let edited_ids = change
+            .clone()
+            .flatten()
+            .into_iter()
+            .filter(|edit| !matches!(edit, Change::Insert { .. }))
+            .flat_map(|edit| edit.expected_revisions())
+            .map(|(id, _)| id);
+        for id in edited_ids {
+            let holder = self.get_object_lock_for_board(board_id, id).await?;
+            if let Some(holder) = holder.filter(|holder| *holder != session_id) {
+                return Ok(Some(holder));
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_locks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Uuid)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // SCAN over keys that match board/{board_id}/locks/*
+            let lock_keys = connection
+                .scan_match_all(&Self::board_object_lock_key_pattern(board_id))
+                .await?;
+
+            if lock_keys.is_empty() {
+                return Ok(vec![]);
+            }
+
+            // MGET the holders all at once. Some leases may have expired since the SCAN, and those
+            // will just come back empty.
+            let holders = redis::cmd("MGET")
+                .arg(&lock_keys)
+                .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                .await?;
+
+            let locks = lock_keys
+                .into_iter()
+                .zip(holders)
+                .filter_map(|(lock_key, holder)| {
+                    Some((
+                        lock_key.rsplit('/').next()?.parse::<Uuid>().ok()?,
+                        holder?.parse::<Uuid>().ok()?,
+                    ))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(locks)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_all_board_ids(&self) -> BoxStream<'static, RepositoryResult<Uuid>> {
+        let pool = self.pool.clone();
+        Box::pin(try_stream! {
+            // ZRANGE over the registry at boards a page at a time, most recently active first so
+            // that the boards in use are visited soonest. A board that becomes active while this
+            // runs moves to the front and may be skipped until the next pass, which is fine for
+            // the periodic sweeps that use this.
+            let mut start = 0;
+            loop {
+                let mut connection = pool.get().await?;
+                let board_ids = connection
+                    .zrevrange::<_, Vec<String>>(
+                        Self::boards_key(),
+                        start,
+                        start + BOARD_IDS_PAGE_SIZE - 1,
+                    )
+                    .await?;
+                if board_ids.is_empty() {
+                    break;
+                }
+                start += BOARD_IDS_PAGE_SIZE;
+
+                // Archived boards keep their place in the registry but have nothing left to sweep
+                let mut pipeline = redis::pipe();
+                for board_id in &board_ids {
+                    pipeline.sismember(Self::archived_boards_key(), board_id);
+                }
+                let archived = pipeline
+                    .query_async::<_, Vec<bool>>(&mut *connection)
+                    .await?;
+                drop(connection);
+
+                for (board_id, archived) in board_ids.iter().zip(archived) {
+                    if archived {
+                        continue;
+                    }
+                    if let Ok(board_id) = board_id.parse::<Uuid>() {
+                        yield board_id;
+                    }
+                }
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_checkpoints(&self) -> RepositoryResult<Vec<(Uuid, usize)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZRANGE over pending_checkpoints, where each board's score is how many changes have
+            // been published to it since it was last checkpointed
+            let pending = connection
+                .zrange_withscores::<_, Vec<(String, f64)>>(Self::pending_checkpoints_key(), 0, -1)
+                .await?
+                .into_iter()
+                .filter_map(|(board_id, count)| Some((board_id.parse().ok()?, count as usize)))
+                .collect();
+
+            Ok(pending)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn clear_pending_checkpoint(&self, board_id: Uuid, count: usize) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Count down rather than removing the board outright, so that changes published while
+            // it was being checkpointed keep it pending
+            connection
+                .zincr::<_, _, _, ()>(
+                    Self::pending_checkpoints_key(),
+                    board_id.to_string(),
+                    -(count as f64),
+                )
+                .await?;
+            connection
+                .zrembyscore::<_, _, _, ()>(Self::pending_checkpoints_key(), "-inf", 0)
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_changes_for_board(
+        &self,
+        board_id: Uuid,
+        count: usize,
+        version: Option<String>,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let actual_version = version.clone().unwrap_or_else(|| "0".to_string());
+
+            // XREAD the next `count` items, blocking for 1 second. This means that no messages may
+            // be returned even though some may be added immediately after calling this method and
+            // therefore it is up to the caller to poll in an appropriate loop.
+            let read_reply = connection
+                .xread_options::<_, _, StreamReadReply>(
+                    &[Self::board_changes_key(board_id)],
+                    &[actual_version],
+                    &StreamReadOptions::default().block(1000).count(count),
+                )
+                .await?;
+
+            let changes = read_reply
+                .keys
+                .into_iter()
+                // next() gets the very next item in the iterator and returns Some if there is one
+                // or None if the iterator is empty. We only expect one set of stream entries to be
+                // returned because we only requested one key.
+                .next()
+                .into_iter()
+                .flat_map(|key| key.ids)
+                // Parse the contents of each entry into a change
+                .filter_map(Self::parse_change_entry)
+                .collect::<Vec<_>>();
+
+            Ok(changes)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_change_history_for_board(
+        &self,
+        board_id: Uuid,
+        since: Option<String>,
+        until: Option<String>,
+        session_id: Option<Uuid>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let mut changes = Vec::new();
+            let mut start = since.clone().unwrap_or_else(|| "-".to_string());
+            let end = until.clone().unwrap_or_else(|| "+".to_string());
+
+            // Page through the archive at board/{board_id}/history first, then through whatever is
+            // only in board/{board_id}/changes after the point where the archive ends
+            for key in [
+                Self::board_history_key(board_id),
+                Self::board_changes_key(board_id),
+            ] {
+                while changes.len() < limit {
+                    let range_reply = connection
+                        .xrange_count::<_, _, _, _, StreamRangeReply>(&key, &start, &end, 1000)
+                        .await?;
+                    let last_version = match range_reply.ids.last() {
+                        Some(id) => id.id.clone(),
+                        None => break,
+                    };
+                    changes.extend(
+                        range_reply
+                            .ids
+                            .into_iter()
+                            .filter_map(Self::parse_change_entry)
+                            .filter(|entry| {
+                                session_id.is_none() || session_id == Some(entry.session_id)
+                            }),
+                    );
+                    // XRANGE is inclusive, so start the next page just after this one
+                    start = format!("({last_version}");
+                }
+            }
+            changes.truncate(limit);
+
+            Ok(changes)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> RepositoryResult<Vec<ChangeEntry>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let mut changes = Vec::new();
+            let mut start = version.clone();
+
+            loop {
+                // XRANGE is inclusive of the start ID, so the first entry of each page is skipped
+                // if it's the one we already have
+                let range_reply = connection
+                    .xrange_count::<_, _, _, _, StreamRangeReply>(
+                        Self::board_changes_key(board_id),
+                        &start,
+                        "+",
+                        1000,
+                    )
+                    .await?;
+                let page = range_reply
+                    .ids
+                    .into_iter()
+                    .filter(|id| id.id != start)
+                    .collect::<Vec<_>>();
+
+                match page.last() {
+                    Some(id) => start = id.id.clone(),
+                    None => return Ok(changes),
+                }
+                changes.extend(page.into_iter().filter_map(Self::parse_change_entry));
+            }
+        })
+        .await
+    }
+
+    // Bulk-apply a set of changes to the materialized objects of a board, and persist the stream ID
+    // of the latest change to help future readers know where to pick up the stream after reading
+    // the objects.
+    #[tracing::instrument(skip(self, entries), err)]
+    async fn apply_changes_to_board(
+        &self,
+        board_id: Uuid,
+        since_version: &str,
+        entries: Vec<ChangeEntry>,
+    ) -> RepositoryResult<bool> {
+        lazy_static! {
+            // Apply a batch of changes to a board and move its version along in one round trip.
+            // KEYS are the board's objects, order, trash, history, checkpoints, version, changes,
+            // and checkpointed revisions. ARGV starts with how objects are stored, the new version,
+            // the history length, the checkpoint history length and interval in milliseconds, how
+            // many changes to retain, the oldest change ID to retain by age, the trash TTL, and the
+            // version the changes were read after. The rest of ARGV is a list of operations, each
+            // a name followed by a fixed number of arguments. The script gives up and returns -1
+            // if the version has moved on. Every `expect` is checked before anything is written,
+            // and it returns 0 if an object in a hash isn't what it was when it was read.
+            // Object writes use pcall so that one that fails doesn't stop the rest, the same as
+            // in a MULTI/EXEC, since a script that errors halfway would leave its writes behind.
+            static ref APPLY_SCRIPT: Script = Script::new(
+                r#"
+                local storage, version = ARGV[1], ARGV[2]
+                local history_length = tonumber(ARGV[3])
+                local checkpoint_length = tonumber(ARGV[4])
+                local checkpoint_interval = tonumber(ARGV[5])
+                local retained_entries = tonumber(ARGV[6])
+                local min_id = ARGV[7]
+                local trash_ttl = ARGV[8]
+                if (redis.call('GET', KEYS[6]) or '0') ~= ARGV[9] then
+                    return -1
+                end
+
+                local function parse_id(id)
+                    local ms, seq = string.match(id, '^(%d+)-(%d+)$')
+                    if not ms then
+                        return tonumber(id) or 0, 0
+                    end
+                    return tonumber(ms), tonumber(seq)
+                end
+                local function is_before(left, right)
+                    local left_ms, left_seq = parse_id(left)
+                    local right_ms, right_seq = parse_id(right)
+                    return left_ms < right_ms or (left_ms == right_ms and left_seq < right_seq)
+                end
+
+                local arities = {
+                    expect = 2, trash = 2, jset = 2, jdel = 1, hset = 2, hdel = 1,
+                    zadd = 2, zrem = 1, untrash = 1, history = 6, revision = 2,
+                }
+                local ops = {}
+                local at = 10
+                while at <= #ARGV do
+                    table.insert(ops, {ARGV[at], at + 1})
+                    at = at + 1 + arities[ARGV[at]]
+                end
+
+                for _, op in ipairs(ops) do
+                    if op[1] == 'expect' then
+                        local current = redis.call('HGET', KEYS[1], ARGV[op[2]]) or ''
+                        if current ~= ARGV[op[2] + 1] then
+                            return 0
+                        end
+                    end
+                end
+
+                if storage == 'json' then
+                    redis.call('JSON.SET', KEYS[1], '.', '{}', 'NX')
+                end
+
+                for _, op in ipairs(ops) do
+                    local name, at = op[1], op[2]
+                    local a, b = ARGV[at], ARGV[at + 1]
+                    if name == 'trash' then
+                        local object = b
+                        if object == '' then
+                            local found = redis.call('JSON.GET', KEYS[1], '$.' .. a)
+                            if found then
+                                object = string.sub(found, 2, -2)
+                            end
+                        end
+                        if object ~= '' then
+                            local index = redis.call('ZSCORE', KEYS[2], a) or 'null'
+                            redis.call(
+                                'HSET', KEYS[3], a,
+                                '{"object":' .. object .. ',"index":' .. index .. '}'
+                            )
+                            redis.call('EXPIRE', KEYS[3], trash_ttl)
+                        end
+                    elseif name == 'jset' then
+                        redis.pcall('JSON.SET', KEYS[1], a, b)
+                    elseif name == 'jdel' then
+                        redis.pcall('JSON.DEL', KEYS[1], a)
+                    elseif name == 'hset' then
+                        redis.call('HSET', KEYS[1], a, b)
+                    elseif name == 'hdel' then
+                        redis.call('HDEL', KEYS[1], a)
+                    elseif name == 'zadd' then
+                        redis.call('ZADD', KEYS[2], b, a)
+                    elseif name == 'zrem' then
+                        redis.call('ZREM', KEYS[2], a)
+                    elseif name == 'untrash' then
+                        redis.call('HDEL', KEYS[3], a)
+                    elseif name == 'revision' then
+                        redis.call('HSET', KEYS[8], a, b)
+                    elseif name == 'history' then
+                        redis.pcall(
+                            'XADD', KEYS[4], 'MAXLEN', '~', history_length, a,
+                            'change', b,
+                            'session_id', ARGV[at + 2],
+                            'username', ARGV[at + 3],
+                            'user_id', ARGV[at + 4],
+                            'revisions', ARGV[at + 5]
+                        )
+                    end
+                end
+
+                if checkpoint_length > 0 then
+                    local newest = redis.call('XREVRANGE', KEYS[5], '+', '-', 'COUNT', 1)[1]
+                    local keeps = true
+                    if newest then
+                        local version_ms = parse_id(version)
+                        local newest_ms = parse_id(newest[1])
+                        keeps = is_before(newest[1], version)
+                            and version_ms >= newest_ms + checkpoint_interval
+                    end
+                    if keeps then
+                        local objects
+                        if storage == 'json' then
+                            objects = redis.call('JSON.GET', KEYS[1], '.') or '{}'
+                        else
+                            local fields = redis.call('HGETALL', KEYS[1])
+                            local entries = {}
+                            for i = 1, #fields, 2 do
+                                table.insert(
+                                    entries,
+                                    cjson.encode(fields[i]) .. ':' .. fields[i + 1]
+                                )
+                            end
+                            objects = '{' .. table.concat(entries, ',') .. '}'
+                        end
+                        local order = redis.call('ZRANGE', KEYS[2], 0, -1, 'WITHSCORES')
+                        local encoded_order = '[]'
+                        if #order > 0 then
+                            encoded_order = cjson.encode(order)
+                        end
+                        redis.call(
+                            'XADD', KEYS[5], 'MAXLEN', checkpoint_length, version,
+                            'objects', objects,
+                            'order', encoded_order
+                        )
+                    end
+                end
+
+                if retained_entries > 0 then
+                    local retained = redis.call(
+                        'XREVRANGE', KEYS[7], '+', '-', 'COUNT', retained_entries
+                    )
+                    local nth_newest = '0-0'
+                    if #retained == retained_entries then
+                        nth_newest = retained[#retained][1]
+                    end
+                    if is_before(nth_newest, min_id) then
+                        min_id = nth_newest
+                    end
+                end
+
+                redis.call('SET', KEYS[6], version)
+                redis.call('XTRIM', KEYS[7], 'MINID', min_id)
+                return 1
+                "#
+            );
+        }
+
+        let version = match entries.last() {
+            Some(entry) => entry.version.clone(),
+            None => return Ok(true),
+        };
+
+        let webhook_event = WebhookEvent::ChangeApplied {
+            board_id,
+            changes: entries.iter().cloned().map(AcceptedChange::from).collect(),
+        };
+
+        // Everything before the version that isn't retained by age is trimmed from the change
+        // stream, and the script works out which entries are retained by count
+        let (min_timestamp, min_sequence) = ChangeRetention {
+            entries: 0,
+            ..self.change_retention
+        }
+        .min_id(
+            Repository::parse_stream_id(&version).unwrap_or_default(),
+            None,
+        );
+
+        let applied = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            let board_objects_key = Self::board_objects_key(board_id);
+
+            // Changes that later ones in the batch make redundant are merged away first, which
+            // matters most for the stream of updates from an object being dragged around
+            let changes = Change::coalesce(
+                entries
+                    .iter()
+                    .flat_map(|entry| entry.change.clone().flatten())
+                    .collect(),
+            );
+
+            let mut invocation = APPLY_SCRIPT.prepare_invoke();
+            invocation
+                .key(&board_objects_key)
+                .key(Self::board_order_key(board_id))
+                .key(Self::board_trash_key(board_id))
+                .key(Self::board_history_key(board_id))
+                .key(Self::board_checkpoints_key(board_id))
+                .key(Self::board_version_key(board_id))
+                .key(Self::board_changes_key(board_id))
+                .key(Self::board_checkpointed_revisions_key(board_id))
+                .arg(match self.object_storage {
+                    ObjectStorage::Json => "json",
+                    ObjectStorage::Hash => "hash",
+                })
+                .arg(&version)
+                .arg(self.history_length)
+                .arg(self.checkpoint_history.length)
+                .arg(self.checkpoint_history.interval_ms)
+                .arg(self.change_retention.entries)
+                .arg(format!("{min_timestamp}-{min_sequence}"))
+                .arg(TRASH_TTL_SECONDS)
+                .arg(since_version);
+
+            // Objects in a hash are written whole, so the updated and deleted ones are read first
+            // and the final version of every changed object is worked out here. The script checks
+            // that they're still the same so that the checkpoint gets retried if somebody else
+            // changed them in between.
+            let mut hash_objects = HashMap::<Uuid, Option<JsonObject>>::new();
+            if self.object_storage == ObjectStorage::Hash {
+                let read_ids = changes
+                    .iter()
+                    .filter_map(|change| match change {
+                        Change::Update { id, .. } | Change::Delete { id } => Some(*id),
+                        _ => None,
+                    })
+                    .unique()
+                    .collect::<Vec<_>>();
+                if !read_ids.is_empty() {
+                    let objects = redis::cmd("HMGET")
+                        .arg(&board_objects_key)
+                        .arg(read_ids.iter().map(Uuid::to_string).collect::<Vec<_>>())
+                        .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                        .await?;
+                    for (id, object) in read_ids.into_iter().zip(objects) {
+                        invocation
+                            .arg("expect")
+                            .arg(id.to_string())
+                            .arg(object.as_deref().unwrap_or_default());
+                        let object = object.and_then(|object| serde_json::from_str(&object).ok());
+                        hash_objects.insert(id, object);
+                    }
+                }
+            }
+
+            // Translate each change in to operations for the script. Deletes are translated into
+            // a JSON.DEL for the given object ID, along with removing it from the stacking order,
+            // once the object has been copied to the trash. Restores reverse that. Inserts are
+            // translated into a JSON.SET for the entire object ID, passing the new object as the
+            // value. Updates are translated into a JSON.SET for the key nested under the object
+            // ID. Index changes are a ZADD into the sorted set at board/{board_id}/order.
+            // Transactions are unpacked into their individual changes, which is all it takes to
+            // apply them atomically since the whole script is atomic and a transaction is a
+            // single stream entry that can never be split across two checkpoints. With hashes, the
+            // object changes are collected and written once at the end instead.
+            for change in changes {
+                match change {
+                    Change::Delete { id } => {
+                        // Move the object into the hash at board/{board_id}/trash first
+                        let object = match self.object_storage {
+                            ObjectStorage::Json => String::new(),
+                            ObjectStorage::Hash => match hash_objects.get(&id) {
+                                Some(Some(object)) => serde_json::to_string(object)?,
+                                _ => String::new(),
+                            },
+                        };
+                        if self.object_storage == ObjectStorage::Json || !object.is_empty() {
+                            invocation.arg("trash").arg(id.to_string()).arg(object);
+                        }
+                        match self.object_storage {
+                            ObjectStorage::Json => {
+                                invocation.arg("jdel").arg(format!("$.{id}"));
+                            }
+                            ObjectStorage::Hash => {
+                                hash_objects.insert(id, None);
+                            }
+                        }
+                        invocation.arg("zrem").arg(id.to_string());
+                    }
+                    Change::SetIndex { id, index } => {
+                        invocation.arg("zadd").arg(id.to_string()).arg(index);
+                    }
+                    Change::Transaction { .. } => {
+                        unreachable!("Transactions are flattened before being applied")
+                    }
+                    // Take the object back out of the trash, then treat it like an insert and a
+                    // SetIndex
+                    Change::RestoreObject { id, object, index } => {
+                        invocation.arg("untrash").arg(id.to_string());
+                        if let Some(object) = object {
+                            match self.object_storage {
+                                ObjectStorage::Json => {
+                                    invocation
+                                        .arg("jset")
+                                        .arg(format!("$.{id}"))
+                                        .arg(serde_json::to_string(&object)?);
+                                }
+                                ObjectStorage::Hash => {
+                                    hash_objects.insert(id, Some(object));
+                                }
+                            }
+                        }
+                        if let Some(index) = index {
+                            invocation.arg("zadd").arg(id.to_string()).arg(index);
+                        }
+                    }
+                    Change::Insert { id, object } | Change::Replace { id, object, .. } => {
+                        match self.object_storage {
+                            ObjectStorage::Json => {
+                                invocation
+                                    .arg("jset")
+                                    .arg(format!("$.{id}"))
+                                    .arg(serde_json::to_string(&object)?);
+                            }
+                            ObjectStorage::Hash => {
+                                hash_objects.insert(id, Some(object));
+                            }
+                        }
+                    }
+                    // Keys are checked when changes are published, but anything that got into the
+                    // stream before that is skipped rather than trusted
+                    Change::Update { key, .. } if !Change::is_valid_key(&key) => {
+                        tracing::warn!(%board_id, %key, "Skipping update with an invalid key");
+                    }
+                    Change::Update { id, key, value } => match self.object_storage {
+                        // The key is quoted in bracket notation on top of being checked, so that
+                        // it can only ever name a property of the object
+                        ObjectStorage::Json => {
+                            invocation
+                                .arg("jset")
+                                .arg(format!("$.{id}[{}]", serde_json::to_string(&key)?))
+                                .arg(serde_json::to_string(&value)?);
+                        }
+                        // Like JSON.SET with a path, an update to an object that doesn't exist
+                        // does nothing
+                        ObjectStorage::Hash => {
+                            if let Some(Some(object)) = hash_objects.get_mut(&id) {
+                                object.insert(key, value);
+                            }
+                        }
+                    },
+                }
+            }
+
+            // Write the final version of every object changed in the hash at
+            // board/{board_id}/objects, or delete it if it ended up deleted
+            for (id, object) in hash_objects {
+                match object {
+                    Some(object) => invocation
+                        .arg("hset")
+                        .arg(id.to_string())
+                        .arg(serde_json::to_string(&object)?),
+                    None => invocation.arg("hdel").arg(id.to_string()),
+                };
+            }
+
+            // Each changed object is now at the revision it was given by the last of these
+            // changes, which goes in the hash at board/{board_id}/checkpointed_revisions
+            let checkpointed_revisions = entries
+                .iter()
+                .flat_map(|entry| entry.revisions.iter().copied())
+                .collect::<HashMap<_, _>>();
+            for (id, revision) in checkpointed_revisions {
+                invocation.arg("revision").arg(id.to_string()).arg(revision);
+            }
+
+            // Archive the changes in the stream at board/{board_id}/history under the same entry
+            // IDs, capped at roughly the configured length. A change can only be archived once
+            // because IDs have to increase, so the script lets XADD fail for anything at or before
+            // the newest archived entry in case these changes were already checkpointed once.
+            if self.history_length > 0 {
+                for entry in &entries {
+                    invocation
+                        .arg("history")
+                        .arg(&entry.version)
+                        .arg(serde_json::to_string(&entry.change)?)
+                        .arg(entry.session_id.to_string())
+                        .arg(entry.username.clone().unwrap_or_default())
+                        .arg(entry.user_id.clone().unwrap_or_default())
+                        .arg(serde_json::to_string(&entry.revisions)?);
+                }
+            }
+
+            // After the changes, the script copies the board into the stream at
+            // board/{board_id}/checkpoints if it's been long enough since the newest checkpoint
+            // there, the same way `CheckpointHistory::keeps` decides. Finally, it sets the version
+            // and drops the changes from the change stream prior to it, other than the ones being
+            // retained for clients catching up. The script runs atomically, so we know that if it
+            // succeeds then we have no need for the changes in the stream anymore. Future reads
+            // will start with the new version of board/{board_id}/objects and then start
+            // streaming changes that have been added since this operation was performed and
+            // everything remains fast and consistent.
+            match invocation.invoke_async::<_, i64>(&mut *connection).await? {
+                -1 => Ok(false),
+                0 => Err(RedisError::from((
+                    redis::ErrorKind::TryAgain,
+                    "Objects changed during checkpoint",
+                ))
+                .into()),
+                _ => Ok(true),
+            }
+        })
+        .await?;
+        if !applied {
+            return Ok(false);
+        }
+
+        // Bring the spatial and search indexes up to date with every object the changes touched.
+        // They're derived entirely from the objects, so it's done after the script rather than in
+        // it. If this fails, the objects are reindexed whenever they're next checkpointed.
+        let touched_ids = entries
+            .iter()
+            .flat_map(|entry| entry.change.expected_revisions())
+            .map(|(id, _)| id)
+            .unique()
+            .collect::<Vec<_>>();
+        let (indexed_at, _) = Repository::parse_stream_id(&version).unwrap_or_default();
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let mut objects = self
+                .read_objects(&mut connection, board_id, &touched_ids)
+                .await?
+                .into_iter()
+                .collect::<HashMap<_, _>>();
+            let objects = touched_ids
+                .iter()
+                .map(|id| (*id, objects.remove(id)))
+                .collect::<Vec<_>>();
+            Self::index_object_terms(&mut connection, board_id, &objects, indexed_at).await?;
+            Self::index_objects(&mut connection, board_id, objects).await
+        })
+        .await?;
+
+        // Queue up a change.applied webhook for the whole batch in the list at webhooks/queue,
+        // which only happens if the checkpoint does. A cluster keeps the queue apart from the
+        // board's keys, so it can't go in the same script.
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            Self::push_webhook_event(&mut connection, &webhook_event).await
+        })
+        .await?;
+
+        Ok(true)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn publish_change_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: Change,
+        idempotency_key: Option<String>,
+    ) -> RepositoryResult<PublishOutcome> {
+        loop {
+            let outcome = self
+                .try_publish_change_for_board(
+                    board_id,
+                    session_id,
+                    change.clone(),
+                    idempotency_key.clone(),
+                )
+                .await?;
+
+            // Nothing was published if the board was archived, so bring it back and try again
+            match outcome {
+                Some(outcome) => return Ok(outcome),
+                None => self.restore_board(board_id).await?,
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_revisions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the object ID - revision pairs from the hash at
+            // board/{board_id}/revisions
+            let revisions = connection
+                .hgetall::<_, HashMap<String, u64>>(Self::board_revisions_key(board_id))
+                .await?
+                .into_iter()
+                .filter_map(|(id_string, revision)| {
+                    Some((id_string.parse::<Uuid>().ok()?, revision))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(revisions)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpointed_revisions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the object ID - revision pairs from the hash at
+            // board/{board_id}/checkpointed_revisions
+            let revisions = connection
+                .hgetall::<_, HashMap<String, u64>>(Self::board_checkpointed_revisions_key(
+                    board_id,
+                ))
+                .await?
+                .into_iter()
+                .filter_map(|(id_string, revision)| {
+                    Some((id_string.parse::<Uuid>().ok()?, revision))
+                })
+                .collect::<Vec<_>>();
+
+            Ok(revisions)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            let board_version_key = Self::board_version_key(board_id);
+
+            // Simple GET, with a default value of 0 if it does not exist
+            let version = connection
+                .get::<_, Option<String>>(board_version_key.as_str())
+                .await?
+                .unwrap_or_else(|| "0".to_string());
+
+            Ok(version)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_latest_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // XREVRANGE the single newest entry in board/{board_id}/changes
+            let range_reply = connection
+                .xrevrange_count::<_, _, _, _, StreamRangeReply>(
+                    Self::board_changes_key(board_id),
+                    "+",
+                    "-",
+                    1,
+                )
+                .await?;
+
+            Ok(range_reply
+                .ids
+                .into_iter()
+                .next()
+                .map(|id| id.id)
+                .unwrap_or_else(|| "0".to_string()))
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_version_available_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<bool> {
+        let requested_version = match Repository::parse_stream_id(version) {
+            Some(requested_version) => requested_version,
+            None => return Ok(false),
+        };
+
+        // The checkpointer only trims the front of board/{board_id}/changes and never past the
+        // checkpointed version, so anything at or after the oldest retained entry or the
+        // checkpointed version can still be read from it
+        let checkpointed_version = self.get_version_for_board(board_id).await?;
+        let oldest_version = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let oldest = connection
+                .xrange_count::<_, _, _, _, StreamRangeReply>(
+                    Self::board_changes_key(board_id),
+                    "-",
+                    "+",
+                    1,
+                )
+                .await?;
+            Ok(oldest.ids.into_iter().next().map(|id| id.id))
+        })
+        .await?;
+        Ok([oldest_version, Some(checkpointed_version)]
+            .iter()
+            .flatten()
+            .filter_map(|version| Repository::parse_stream_id(version))
+            .any(|available_version| requested_version >= available_version))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_object_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // JSON.OBJLEN or HLEN board/{board_id}/objects, which is nil or zero if nothing was
+            // ever checkpointed
+            let object_count = match self.object_storage {
+                ObjectStorage::Json => redis::cmd("JSON.OBJLEN")
+                    .arg(Self::board_objects_key(board_id))
+                    .query_async::<_, Option<usize>>(&mut *connection)
+                    .await?
+                    .unwrap_or_default(),
+                ObjectStorage::Hash => {
+                    connection
+                        .hlen::<_, usize>(Self::board_objects_key(board_id))
+                        .await?
+                }
+            };
+
+            Ok(object_count)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_pending_change_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        let version = self.get_version_for_board(board_id).await?;
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read the entries in board/{board_id}/changes after board/{board_id}/version, since
+            // the checkpointed entry and any retained ones before it are still in the stream
+            let pending = connection
+                .xrange::<_, _, _, StreamRangeReply>(
+                    Self::board_changes_key(board_id),
+                    format!("({version}"),
+                    "+",
+                )
+                .await?;
+
+            Ok(pending.ids.len())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_session_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // HLEN board/{board_id}/sessions
+            let session_count = connection
+                .hlen::<_, usize>(Self::board_sessions_key(board_id))
+                .await?;
+
+            Ok(session_count)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn join_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<usize> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let waiting_key = Self::board_waiting_key(board_id);
+
+            // Add the session to the sorted set at board/{board_id}/waiting with ZADD NX, scored by
+            // when it got in line, so that joining again keeps its place
+            redis::cmd("ZADD")
+                .arg(&waiting_key)
+                .arg("NX")
+                .arg(Utc::now().timestamp_millis())
+                .arg(session_id.to_string())
+                .query_async::<_, ()>(&mut *connection)
+                .await?;
+
+            // Add the board ID to the set at session/{session_id}/boards
+            self.add_board_for_session(&mut connection, session_id, board_id)
+                .await?;
+
+            // ZRANK counts from 0 at the front of the line
+            let rank = connection
+                .zrank::<_, _, Option<usize>>(&waiting_key, session_id.to_string())
+                .await?;
+
+            Ok(rank.map_or(1, |rank| rank + 1))
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn leave_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZREM the session from board/{board_id}/waiting
+            connection
+                .zrem::<_, _, ()>(Self::board_waiting_key(board_id), session_id.to_string())
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_waiting_sessions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZRANGE over all of board/{board_id}/waiting, which is already in order
+            let session_ids = connection
+                .zrange::<_, Vec<String>>(Self::board_waiting_key(board_id), 0, -1)
+                .await?
+                .into_iter()
+                .filter_map(|session_id| session_id.parse::<Uuid>().ok())
+                .collect();
+
+            Ok(session_ids)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_last_activity_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Option<DateTime<Utc>>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZSCORE the board in the registry at boards, which is in milliseconds
+            let last_activity = connection
+                .zscore::<_, _, Option<i64>>(Self::boards_key(), board_id.to_string())
+                .await?
+                .map(|last_activity| Utc.timestamp_millis(last_activity));
+
+            Ok(last_activity)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_memory_usage_for_board(&self, board_id: Uuid) -> RepositoryResult<u64> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // SCAN over every key that matches board/{board_id}/*
+            let board_keys = connection
+                .scan_match_all(&Self::board_key_pattern(board_id))
+                .await?;
+            if board_keys.is_empty() {
+                return Ok(0);
+            }
+
+            // MEMORY USAGE each of them in one round trip. Keys that disappeared since the SCAN
+            // come back as nil.
+            let mut pipeline = redis::pipe();
+            for board_key in &board_keys {
+                pipeline.cmd("MEMORY").arg("USAGE").arg(board_key);
+            }
+            let usages = pipeline
+                .query_async::<_, Vec<Option<u64>>>(&mut *connection)
+                .await?;
+
+            Ok(usages.into_iter().flatten().sum())
+        })
+        .await
+    }
+
+    fn get_pool_stats(&self) -> Option<PoolStats> {
+        let (retries, exhausted_retries) = self.retry_policy.stats();
+        Some(PoolStats {
+            retries,
+            exhausted_retries,
+            ..self.pool.stats()
+        })
+    }
+
+    fn get_presence_stats(&self) -> PresenceStats {
+        self.presence.stats()
+    }
+
+    fn watch_degraded(&self) -> Option<WatchReceiver<bool>> {
+        Some(self.pool.watch_degraded())
+    }
+
+    fn session_ttl(&self) -> Duration {
+        self.session_ttl
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpoint_versions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<String>> {
+        lazy_static! {
+            // Every checkpoint carries a whole copy of the board, so only send back the entry IDs
+            static ref CHECKPOINT_VERSIONS_SCRIPT: Script = Script::new(
+                r"
+                local versions = {}
+                for _, entry in ipairs(redis.call('XREVRANGE', KEYS[1], '+', '-')) do
+                    table.insert(versions, entry[1])
+                end
+                return versions
+                "
+            );
+        }
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // List the entry IDs in board/{board_id}/checkpoints, newest first
+            let versions = CHECKPOINT_VERSIONS_SCRIPT
+                .key(Self::board_checkpoints_key(board_id))
+                .invoke_async::<_, Vec<String>>(&mut *connection)
+                .await?;
+
+            Ok(versions)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_checkpoint_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<Option<BoardSnapshot>> {
+        if Repository::parse_stream_id(version).is_none() {
+            return Ok(None);
+        }
+
+        let entry = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // XRANGE board/{board_id}/checkpoints from the version to itself to get just the one
+            let entry = connection
+                .xrange::<_, _, _, StreamRangeReply>(
+                    Self::board_checkpoints_key(board_id),
+                    version,
+                    version,
+                )
+                .await?
+                .ids
+                .into_iter()
+                .next();
+
+            Ok(entry)
+        })
+        .await?;
+
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let objects = entry
+            .get::<String>("objects")
+            .ok_or_else(|| anyhow!("Checkpoint {version} of board {board_id} has no objects"))?;
+        let order = entry
+            .get::<String>("order")
+            .ok_or_else(|| anyhow!("Checkpoint {version} of board {board_id} has no order"))?;
+        let order = serde_json::from_str::<Vec<String>>(&order)?
+            .chunks(2)
+            .filter_map(|pair| match pair {
+                [id, index] => Some((id.parse().ok()?, index.parse().ok()?)),
+                _ => None,
+            })
+            .collect();
+
+        Ok(Some(BoardSnapshot {
+            version: version.to_string(),
+            objects: serde_json::from_str(&objects)?,
+            order,
+        }))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_trash_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, TrashedObject)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // HGETALL board/{board_id}/trash, which is gone entirely once it expires
+            let trash = connection
+                .hgetall::<_, HashMap<String, String>>(Self::board_trash_key(board_id))
+                .await?
+                .into_iter()
+                .filter_map(|(id, trashed)| {
+                    Some((id.parse().ok()?, serde_json::from_str(&trashed).ok()?))
+                })
+                .collect();
+
+            Ok(trash)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_order_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, f64)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read the whole sorted set at board/{board_id}/order along with the scores
+            let order = connection
+                .zrange_withscores::<_, Vec<(String, f64)>>(Self::board_order_key(board_id), 0, -1)
+                .await?
+                .into_iter()
+                .filter_map(|(id_string, index)| Some((id_string.parse::<Uuid>().ok()?, index)))
+                .collect::<Vec<_>>();
+
+            Ok(order)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_objects_in_rect(
+        &self,
+        board_id: Uuid,
+        rect: Rect,
+    ) -> RepositoryResult<Vec<(Uuid, JsonObject)>> {
+        // Boards from before there was a spatial index get indexed the first time they're asked
+        let indexed = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let indexed = connection
+                .exists::<_, bool>(Self::board_bounds_key(board_id))
+                .await?;
+            Ok(indexed)
+        })
+        .await?;
+        if !indexed && self.get_object_count_for_board(board_id).await? > 0 {
+            self.reindex_board(board_id).await?;
+        }
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let bounds_key = Self::board_bounds_key(board_id);
+
+            let bounds = match rect.cells(MAX_QUERY_CELLS) {
+                // ZRANGEBYLEX each cell the rectangle covers in board/{board_id}/cells, plus the
+                // one for oversized objects, in one round trip. Then HMGET the bounds of whatever
+                // was found to check that it's actually in the rectangle.
+                Some(mut cells) => {
+                    cells.push(OVERSIZED_CELL.to_string());
+                    let cells_key = Self::board_cells_key(board_id);
+                    let mut pipeline = redis::pipe();
+                    for cell in &cells {
+                        pipeline
+                            .cmd("ZRANGEBYLEX")
+                            .arg(&cells_key)
+                            .arg(format!("[{cell}/"))
+                            .arg(format!("({cell}0"));
+                    }
+                    let ids = pipeline
+                        .query_async::<_, Vec<Vec<String>>>(&mut *connection)
+                        .await?
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|member| Some(member.split_once('/')?.1.to_string()))
+                        .unique()
+                        .collect::<Vec<_>>();
+                    if ids.is_empty() {
+                        return Ok(vec![]);
+                    }
+                    let bounds = redis::cmd("HMGET")
+                        .arg(&bounds_key)
+                        .arg(&ids)
+                        .query_async::<_, Vec<Option<String>>>(&mut *connection)
+                        .await?;
+                    ids.into_iter()
+                        .zip(bounds)
+                        .filter_map(|(id, bounds)| Some((id, bounds?)))
+                        .collect::<Vec<_>>()
+                }
+                // A rectangle that covers too many cells is quicker to check against everything
+                // in board/{board_id}/bounds
+                None => {
+                    connection
+                        .hgetall::<_, Vec<(String, String)>>(&bounds_key)
+                        .await?
+                }
+            };
+
+            let object_ids = bounds
+                .into_iter()
+                .filter_map(|(id, bounds)| {
+                    let bounds = serde_json::from_str::<Rect>(&bounds).ok()?;
+                    if !bounds.intersects(&rect) {
+                        return None;
+                    }
+                    id.parse::<Uuid>().ok()
+                })
+                .collect::<Vec<_>>();
+
+            self.read_objects(&mut connection, board_id, &object_ids)
+                .await
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn search_objects(
+        &self,
+        terms: &BTreeSet<String>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<SearchHit>> {
+        let candidates = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZCARD every term's sorted set at search/{term} to start from the rarest one
+            let mut counts = Vec::with_capacity(terms.len());
+            for term in terms {
+                let count = connection
+                    .zcard::<_, usize>(Self::search_term_key(term))
+                    .await?;
+                counts.push((term, count));
+            }
+            let rarest = match counts.into_iter().min_by_key(|(_, count)| *count) {
+                Some((term, count)) if count > 0 => term,
+                _ => return Ok(vec![]),
+            };
+
+            // ZREVRANGE the objects that got the rarest term most recently, then ZMSCORE them in
+            // each of the other terms' sets to keep the ones that have every term
+            let mut candidates = connection
+                .zrevrange::<_, Vec<String>>(
+                    Self::search_term_key(rarest),
+                    0,
+                    MAX_CANDIDATES as isize - 1,
+                )
+                .await?;
+            for term in terms.iter().filter(|term| *term != rarest) {
+                if candidates.is_empty() {
+                    break;
+                }
+                let scores = redis::cmd("ZMSCORE")
+                    .arg(Self::search_term_key(term))
+                    .arg(&candidates)
+                    .query_async::<_, Vec<Option<f64>>>(&mut *connection)
+                    .await?;
+                candidates = candidates
+                    .into_iter()
+                    .zip(scores)
+                    .filter_map(|(candidate, score)| score.map(|_| candidate))
+                    .collect();
+            }
+
+            Ok(candidates)
+        })
+        .await?
+        .into_iter()
+        .filter_map(|candidate| {
+            let (board_id, object_id) = candidate.split_once('/')?;
+            Some((
+                board_id.parse::<Uuid>().ok()?,
+                object_id.parse::<Uuid>().ok()?,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+        // Read the candidates from each of their boards, leaving out any that are gone or whose
+        // text no longer matches because they changed after they were indexed
+        let mut objects = HashMap::new();
+        for (board_id, object_ids) in candidates.iter().copied().into_group_map() {
+            let board_objects = Self::with_redis_retry(&self.retry_policy, || async {
+                let mut connection = self.pool.get().await?;
+                self.read_objects(&mut connection, board_id, &object_ids)
+                    .await
+            })
+            .await?;
+            for (object_id, object) in board_objects {
+                objects.insert((board_id, object_id), object);
+            }
+        }
+
+        Ok(candidates
+            .into_iter()
+            .filter_map(|(board_id, object_id)| {
+                let object = objects.remove(&(board_id, object_id))?;
+                search::matches(&object, terms).then_some(SearchHit {
+                    board_id,
+                    object_id,
+                    object,
+                })
+            })
+            .take(limit)
+            .collect())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_object_chunks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, RepositoryResult<Vec<(Uuid, JsonObject)>>> {
+        if self.object_storage == ObjectStorage::Hash {
+            return Self::stream_hash_object_chunks(
+                self.pool.clone(),
+                self.retry_policy.clone(),
+                board_id,
+            );
+        }
+
+        let pool = self.pool.clone();
+        let retry_policy = self.retry_policy.clone();
+        Box::pin(try_stream! {
+            let board_objects_key = Self::board_objects_key(board_id);
+
+            let object_key_chunks = Self::with_redis_retry(&retry_policy, || async {
+                let mut connection = pool.get().await?;
+
+                // First get all of the object IDs in the board and split them into groups of 100
+                let object_key_chunks = redis::cmd("JSON.OBJKEYS")
+                    .arg(board_objects_key.as_str())
+                    .arg(".")
+                    .query_async::<_, Option<Vec<String>>>(&mut *connection)
+                    .await?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|key| format!("$.{key}"))
+                    .chunks(100);
+
+                let object_key_chunks = object_key_chunks
+                    .into_iter()
+                    .map(|chunk| chunk.collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+
+                Ok(object_key_chunks)
+            }).await?;
+
+            for keys in object_key_chunks {
+                let keys_length = keys.len();
+
+                if keys_length == 0 {
+                    continue;
+                }
+
+                let entries = Self::with_redis_retry(&retry_policy, || async {
+                    let mut connection = pool.get().await?;
+
+                    // Retrieve the values of each object ID at once by passing them as variadic
+                    // args to JSON.GET. JSON.GET returns a different JSON data structure depending
+                    // on whether there is a single key or many keys the returned. A single key will
+                    // come back as a single object inside of an array. Multiple keys will come back
+                    // as a JSON object that maps keys onto a similar one-value array.
+                    let entries_string = redis::cmd("JSON.GET")
+                        .arg(board_objects_key.clone())
+                        .arg(&keys)
+                        .query_async::<_, Option<String>>(&mut *connection)
+                        .await?;
+
+                    if keys_length == 1 {
+                        Ok(entries_string
+                            // Single-key input case: the return value is just whatever JSON data is
+                            // at that key, but inside of an array. It will look like
+                            // [ { "property1": "hello", "property2": "world" } ]
+                            .and_then(|string| serde_json::from_str::<Vec<JsonObject>>(&string).ok())
+                            .and_then(|mut values| {
+                                Some(vec![(
+                                    keys[0].trim_start_matches("$.").parse::<Uuid>().ok()?,
+                                    values.pop()?,
+                                )])
+                            })
+                            .unwrap_or_default())
+                    } else {
+                        Ok(entries_string
+                            // Multiple-key input case: the return value is a mapping of input keys
+                            // to values inside arrays
+                            // {
+                            //   "$.<UUID>": [ { "property1": "hello", "property2": "world" } ],
+                            //   "$.<UUID>": [ { "propety1": "foo", "property2": "bar" } ]
+                            // }
+                            .and_then(|string| {
+                                serde_json::from_str::<HashMap<String, Vec<JsonObject>>>(&string).ok()
+                            })
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter_map(|(key, mut values)| {
+                                Some((
+                                    key.trim_start_matches("$.").parse::<Uuid>().ok()?,
+                                    values.pop()?,
+                                ))
+                            })
+                            .collect::<Vec<_>>())
+                    }
+                }).await?;
+
+                yield entries;
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn stream_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> BoxStream<'static, ChangeEntry> {
+        let repo = self.clone();
+        Box::pin(stream! {
+            let mut version = version;
+            loop {
+                let changes = match repo
+                    .get_changes_for_board(board_id, 100, Some(version.clone()))
+                    .await
+                {
+                    Ok(changes) => changes,
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                for entry in changes {
+                    version = entry.version.clone();
+                    yield entry;
+                }
+            }
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn stream_presence_messages_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, PresenceMessage> {
+        // Start reading the presence stream after whatever is in it now, or stick to Pub/Sub if
+        // the board doesn't have reliable presence
+        let position = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            if !connection
+                .exists::<_, bool>(Self::board_reliable_presence_key(board_id))
+                .await?
+            {
+                return Ok(None);
+            }
+
+            // XREVRANGE the single newest entry in board/{board_id}/presence_stream
+            let range_reply = connection
+                .xrevrange_count::<_, _, _, _, StreamRangeReply>(
+                    Self::board_presence_stream_key(board_id),
+                    "+",
+                    "-",
+                    1,
+                )
+                .await?;
+            Ok(Some(
+                range_reply
+                    .ids
+                    .into_iter()
+                    .next()
+                    .map_or_else(|| "0-0".to_string(), |entry| entry.id),
+            ))
+        })
+        .await;
+        let position = match position {
+            Ok(Some(position)) => position,
+            Ok(None) | Err(_) => return self.presence.stream_for_board(board_id),
+        };
+
+        // Everything that goes into the stream is published too, so it's left out here until
+        // reliable presence is turned off
+        let reliable = Arc::new(AtomicBool::new(true));
+        let published = {
+            let reliable = reliable.clone();
+            self.presence
+                .stream_for_board(board_id)
+                .filter(move |message| {
+                    future::ready(
+                        Self::is_unreliable_presence(&message.message)
+                            || !reliable.load(Ordering::SeqCst),
+                    )
+                })
+        };
+        Box::pin(stream::select(
+            published,
+            self.stream_reliable_presence(board_id, position, reliable),
+        ))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn pop_webhook_event(&self) -> RepositoryResult<Option<WebhookEvent>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // BRPOP from the list at webhooks/queue, which comes back empty if the wait runs out
+            let event = connection
+                .brpop::<_, Option<(String, String)>>(Self::webhook_queue_key(), 1.0)
+                .await?
+                .map(|(_, event)| serde_json::from_str::<WebhookEvent>(&event))
+                .transpose()?;
+
+            Ok(event)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_webhooks_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<String>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read the set at board/{board_id}/webhooks
+            let urls = connection
+                .smembers::<_, Vec<String>>(Self::board_webhooks_key(board_id))
+                .await?;
+
+            Ok(urls)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_webhook_for_board(&self, board_id: Uuid, url: String) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Add the URL to the set at board/{board_id}/webhooks
+            connection
+                .sadd::<_, _, ()>(Self::board_webhooks_key(board_id), &url)
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_webhook_for_board(
+        &self,
+        board_id: Uuid,
+        url: String,
+    ) -> RepositoryResult<bool> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Remove the URL from the set at board/{board_id}/webhooks
+            let removed = connection
+                .srem::<_, _, bool>(Self::board_webhooks_key(board_id), &url)
+                .await?;
+
+            Ok(removed)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_api_key(&self, hash: &str) -> RepositoryResult<Option<ApiKey>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read the key's name and scopes from the hash at api_keys
+            let api_key = connection
+                .hget::<_, _, Option<String>>(Self::api_keys_key(), hash)
+                .await?
+                .map(|api_key| serde_json::from_str::<ApiKey>(&api_key))
+                .transpose()?;
+
+            Ok(api_key)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_api_keys(&self) -> RepositoryResult<Vec<(String, ApiKey)>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Read all of the hash - API key pairs from the hash at api_keys
+            let api_keys = connection
+                .hgetall::<_, HashMap<String, String>>(Self::api_keys_key())
+                .await?
+                .into_iter()
+                .filter_map(|(hash, api_key)| {
+                    serde_json::from_str::<ApiKey>(&api_key)
+                        .ok()
+                        .map(|api_key| (hash, api_key))
+                })
+                .collect();
+
+            Ok(api_keys)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn add_api_key(&self, hash: &str, api_key: ApiKey) -> RepositoryResult<()> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Add the hash and API key as a key-value pair to the hash at api_keys
+            connection
+                .hset::<_, _, _, ()>(Self::api_keys_key(), hash, serde_json::to_string(&api_key)?)
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn delete_api_key(&self, hash: &str) -> RepositoryResult<bool> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Delete the hash from the hash at api_keys
+            let removed = connection
+                .hdel::<_, _, bool>(Self::api_keys_key(), hash)
+                .await?;
+
+            Ok(removed)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_share_secret(&self) -> RepositoryResult<String> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // SET a random secret at share_secret unless another server already has, then read
+            // back whichever one won
+            let secret = format!(
+                "{}{}",
+                Uuid::new_v4().as_simple(),
+                Uuid::new_v4().as_simple()
+            );
+            connection
+                .set_nx::<_, _, ()>(Self::share_secret_key(), secret)
+                .await?;
+            let secret = connection
+                .get::<_, String>(Self::share_secret_key())
+                .await?;
+
+            Ok(secret)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn acquire_lock(
+        &self,
+        name: &str,
+        holder: Uuid,
+        ttl: Duration,
+    ) -> RepositoryResult<bool> {
+        lazy_static! {
+            // Extend the lock only if it's still ours, which SET can't check on its own
+            static ref RENEW_LOCK_SCRIPT: Script = Script::new(
+                r"
+                if redis.call('GET', KEYS[1]) == ARGV[1] then
+                    return redis.call('PEXPIRE', KEYS[1], ARGV[2])
+                end
+                return 0
+                "
+            );
+        }
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            let lock_key = Self::task_lock_key(name);
+            let ttl_ms = ttl.as_millis() as u64;
+
+            // SET locks/{name} NX PX to take the lock if nobody has it, and otherwise renew it if
+            // it's already ours
+            let acquired = redis::cmd("SET")
+                .arg(&lock_key)
+                .arg(holder.to_string())
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl_ms)
+                .query_async::<_, Option<String>>(&mut *connection)
+                .await?
+                .is_some();
+            if acquired {
+                return Ok(true);
+            }
+            let renewed = RENEW_LOCK_SCRIPT
+                .key(&lock_key)
+                .arg(holder.to_string())
+                .arg(ttl_ms)
+                .invoke_async::<_, bool>(&mut *connection)
+                .await?;
+
+            Ok(renewed)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn release_lock(&self, name: &str, holder: Uuid) -> RepositoryResult<()> {
+        lazy_static! {
+            // Only delete the lock if it's still ours, in case it ran out and somebody else took it
+            static ref RELEASE_LOCK_SCRIPT: Script = Script::new(
+                r"
+                if redis.call('GET', KEYS[1]) == ARGV[1] then
+                    return redis.call('DEL', KEYS[1])
+                end
+                return 0
+                "
+            );
+        }
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            RELEASE_LOCK_SCRIPT
+                .key(Self::task_lock_key(name))
+                .arg(holder.to_string())
+                .invoke_async::<_, ()>(&mut *connection)
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_idle_board_ids(&self, cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZRANGEBYSCORE the registry at boards up to the cutoff in milliseconds
+            let board_ids = connection
+                .zrangebyscore::<_, _, _, Vec<String>>(
+                    Self::boards_key(),
+                    "-inf",
+                    format!("({}", cutoff.timestamp_millis()),
+                )
+                .await?;
+
+            Ok(board_ids
+                .into_iter()
+                .filter_map(|board_id| board_id.parse().ok())
+                .collect())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_inactive_board_ids(&self, cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // ZRANGEBYSCORE the registry at boards up to the cutoff in milliseconds
+            let board_ids = connection
+                .zrangebyscore::<_, _, _, Vec<String>>(
+                    Self::boards_key(),
+                    "-inf",
+                    cutoff.timestamp_millis(),
+                )
+                .await?;
+            if board_ids.is_empty() {
+                return Ok(vec![]);
+            }
+
+            // SISMEMBER each of them in archived_boards in one round trip
+            let mut pipeline = redis::pipe();
+            for board_id in &board_ids {
+                pipeline.sismember(Self::archived_boards_key(), board_id);
+            }
+            let archived = pipeline
+                .query_async::<_, Vec<bool>>(&mut *connection)
+                .await?;
+
+            Ok(board_ids
+                .into_iter()
+                .zip(archived)
+                .filter(|(_, archived)| !archived)
+                .filter_map(|(board_id, _)| board_id.parse().ok())
+                .collect())
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn archive_board(&self, board_id: Uuid, version: &str) -> RepositoryResult<bool> {
+        lazy_static! {
+            // Check that the checkpointed version is still the one that was uploaded, that there's
+            // nothing in the change stream after it, and that there are no sessions, and only
+            // then delete the board's document, history, checkpoints, trash, and spatial index. The
+            // metadata and the board's place in the registry are kept so that the board still
+            // shows up in listings.
+            static ref ARCHIVE_SCRIPT: Script = Script::new(
+                r"
+                if redis.call('EXISTS', KEYS[1]) == 1 then
+                    return 0
+                end
+                if (redis.call('GET', KEYS[2]) or '0') ~= ARGV[1] then
+                    return 0
+                end
+                local latest = redis.call('XREVRANGE', KEYS[3], '+', '-', 'COUNT', 1)[1]
+                if latest and latest[1] ~= ARGV[1] then
+                    return 0
+                end
+                if redis.call('HLEN', KEYS[4]) > 0 then
+                    return 0
+                end
+                redis.call(
+                    'DEL', KEYS[2], KEYS[3], KEYS[5], KEYS[6], KEYS[7], KEYS[8], KEYS[9], KEYS[10],
+                    KEYS[11], KEYS[12], KEYS[13]
+                )
+                redis.call('SET', KEYS[1], 1)
+                return 1
+                "
+            );
+        }
+
+        // Take the board out of change_streams first so that a change published while it's being
+        // archived puts it right back
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+            connection
+                .srem::<_, _, ()>(Self::change_streams_key(), board_id.to_string())
+                .await?;
+            Ok(())
+        })
+        .await?;
+
+        let archived = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Run the archive script over board/{board_id}/version, changes, sessions, objects,
+            // order, revisions, history, checkpoints, trash, spatial index, and checkpointed
+            // revisions, and SET board/{board_id}/archived
+            let archived = ARCHIVE_SCRIPT
+                .key(Self::board_archived_key(board_id))
+                .key(Self::board_version_key(board_id))
+                .key(Self::board_changes_key(board_id))
+                .key(Self::board_sessions_key(board_id))
+                .key(Self::board_objects_key(board_id))
+                .key(Self::board_order_key(board_id))
+                .key(Self::board_revisions_key(board_id))
+                .key(Self::board_history_key(board_id))
+                .key(Self::board_checkpoints_key(board_id))
+                .key(Self::board_trash_key(board_id))
+                .key(Self::board_bounds_key(board_id))
+                .key(Self::board_cells_key(board_id))
+                .key(Self::board_checkpointed_revisions_key(board_id))
+                .arg(version)
+                .invoke_async::<_, bool>(&mut *connection)
+                .await?;
+
+            Ok(archived)
+        })
+        .await?;
+
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Add the board to archived_boards, or put it back in change_streams if it stayed
+            let registry_key = if archived {
+                Self::archived_boards_key()
+            } else {
+                Self::change_streams_key()
+            };
+            connection
+                .sadd::<_, _, ()>(registry_key, board_id.to_string())
+                .await?;
+
+            Ok(())
+        })
+        .await?;
+
+        Ok(archived)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn get_board_archived(&self, board_id: Uuid) -> RepositoryResult<bool> {
+        Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // EXISTS board/{board_id}/archived, which is what publishing checks too
+            let archived = connection
+                .exists::<_, bool>(Self::board_archived_key(board_id))
+                .await?;
+
+            Ok(archived)
+        })
+        .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn restore_board(&self, board_id: Uuid) -> RepositoryResult<()> {
+        lazy_static! {
+            // Only restore the board if it's still archived, so that two servers restoring the
+            // same board at once can't clobber changes made after the first one finished. ARGV
+            // holds the objects, version, order, revisions, and how objects are stored, with the
+            // order and revisions as JSON arrays of pairs. When objects are stored in a hash, they
+            // follow as pairs of object ID and JSON instead.
+            static ref RESTORE_SCRIPT: Script = Script::new(
+                r"
+                if redis.call('DEL', KEYS[1]) == 0 then
+                    return 0
+                end
+                if ARGV[5] == 'json' then
+                    redis.call('JSON.SET', KEYS[2], '.', ARGV[1])
+                else
+                    for i = 6, #ARGV, 2 do
+                        redis.call('HSET', KEYS[2], ARGV[i], ARGV[i + 1])
+                    end
+                end
+                if ARGV[2] ~= '0' then
+                    redis.call('SET', KEYS[3], ARGV[2])
+                end
+                for _, entry in ipairs(cjson.decode(ARGV[3])) do
+                    redis.call('ZADD', KEYS[4], entry[2], entry[1])
+                end
+                for _, entry in ipairs(cjson.decode(ARGV[4])) do
+                    redis.call('HSET', KEYS[5], entry[1], entry[2])
+                end
+                return 1
+                "
+            );
+        }
+
+        // This is all that happens for nearly every board
+        if !self.get_board_archived(board_id).await? {
+            return Ok(());
+        }
+
+        let archive_store = self.archive_store.as_ref().ok_or_else(|| {
+            anyhow!("Board {board_id} is archived but archive storage isn't configured")
+        })?;
+        let archived_board: ArchivedBoard =
+            archive_store.get(board_id).await?.ok_or_else(|| {
+                RepositoryError::NotFound(format!("The archive of board {board_id} is missing"))
+            })?;
+
+        let restored = Self::with_redis_retry(&self.retry_policy, || async {
+            let mut connection = self.pool.get().await?;
+
+            // Run the restore script into board/{board_id}/objects, version, order, and revisions,
+            // deleting board/{board_id}/archived
+            let mut invocation = RESTORE_SCRIPT.prepare_invoke();
+            invocation
+                .key(Self::board_archived_key(board_id))
+                .key(Self::board_objects_key(board_id))
+                .key(Self::board_version_key(board_id))
+                .key(Self::board_order_key(board_id))
+                .key(Self::board_revisions_key(board_id));
+            match self.object_storage {
+                ObjectStorage::Json => {
+                    invocation.arg(serde_json::to_string(&archived_board.objects)?);
+                }
+                ObjectStorage::Hash => {
+                    invocation.arg("");
+                }
+            }
+            invocation
+                .arg(&archived_board.version)
+                .arg(serde_json::to_string(&archived_board.order)?)
+                .arg(serde_json::to_string(&archived_board.revisions)?);
+            match self.object_storage {
+                ObjectStorage::Json => {
+                    invocation.arg("json");
+                }
+                ObjectStorage::Hash => {
+                    invocation.arg("hash");
+                    for (id, object) in &archived_board.objects {
+                        invocation
+                            .arg(id.to_string())
+                            .arg(serde_json::to_string(object)?);
+                    }
+                }
+            }
+            let restored = invocation.invoke_async::<_, bool>(&mut *connection).await?;
+
+            Ok(restored)
+        })
+        .await?;
+
+        if restored {
+            tracing::info!(%board_id, "Restored board from the archive");
+
+            // Remove the board from archived_boards and bump its last activity in the registry at
+            // boards so it isn't archived again right away
+            Self::with_redis_retry(&self.retry_policy, || async {
+                let mut connection = self.pool.get().await?;
+                connection
+                    .srem::<_, _, ()>(Self::archived_boards_key(), board_id.to_string())
+                    .await?;
+                redis::cmd("ZADD")
+                    .arg(Self::boards_key())
+                    .arg("XX")
+                    .arg(Utc::now().timestamp_millis())
+                    .arg(board_id.to_string())
+                    .query_async::<_, ()>(&mut *connection)
+                    .await?;
+                Ok(())
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+}
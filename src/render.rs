@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+use serde_json::Value as JsonValue;
+
+use crate::message::JsonObject;
+use crate::snapshot::BoardSnapshot;
+use crate::spatial::Rect;
+
+/// Empty space left around the objects in an export
+const PADDING: f64 = 20.0;
+
+/// Textboxes are drawn with 8px of padding and the browser's default line height
+const TEXTBOX_PADDING: f64 = 8.0;
+const TEXTBOX_LINE_HEIGHT: f64 = 1.2;
+
+/// Render the objects on a board to a standalone SVG document. This mirrors the components the
+/// client uses to draw each object type closely enough for embedding, and skips anything it
+/// doesn't recognize.
+pub fn render_svg(snapshot: &BoardSnapshot) -> String {
+    let mut objects = snapshot
+        .objects
+        .iter()
+        .filter_map(|(id, object)| Shape::from_object(object).map(|shape| (id, shape)))
+        .collect::<Vec<_>>();
+
+    // The client stacks objects by layer, anything within the same layer is ordered by index
+    let order = snapshot.order.iter().copied().collect::<HashMap<_, _>>();
+    objects.sort_by(|(left_id, left), (right_id, right)| {
+        left.layer.total_cmp(&right.layer).then_with(|| {
+            let left_index = order.get(left_id).copied().unwrap_or_default();
+            let right_index = order.get(right_id).copied().unwrap_or_default();
+            left_index.total_cmp(&right_index)
+        })
+    });
+
+    let (min_x, min_y, max_x, max_y) = objects.iter().fold(
+        (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+        |(min_x, min_y, max_x, max_y), (_, shape)| {
+            (
+                min_x.min(shape.x),
+                min_y.min(shape.y),
+                max_x.max(shape.x + shape.width),
+                max_y.max(shape.y + shape.height),
+            )
+        },
+    );
+    let (min_x, min_y, width, height) = if objects.is_empty() {
+        (0.0, 0.0, PADDING * 2.0, PADDING * 2.0)
+    } else {
+        (
+            min_x - PADDING,
+            min_y - PADDING,
+            max_x - min_x + PADDING * 2.0,
+            max_y - min_y + PADDING * 2.0,
+        )
+    };
+
+    let mut svg = String::new();
+    write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" "#
+    )
+    .ok();
+    write!(svg, r#"viewBox="{min_x} {min_y} {width} {height}">"#).ok();
+    write!(
+        svg,
+        r#"<rect x="{min_x}" y="{min_y}" width="{width}" height="{height}" fill="white"/>"#
+    )
+    .ok();
+    for (_, shape) in objects {
+        shape.write_svg(&mut svg);
+    }
+    svg.push_str("</svg>");
+
+    svg
+}
+
+/// Rasterize an SVG produced by `render_svg` into a PNG
+pub fn render_png(svg: &str) -> Result<Vec<u8>> {
+    let mut options = usvg::Options::default();
+    options.fontdb.load_system_fonts();
+
+    let tree = usvg::Tree::from_str(svg, &options.to_ref())?;
+    let size = tree.svg_node().size.to_screen_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| anyhow!("Board is too large to render"))?;
+    resvg::render(
+        &tree,
+        usvg::FitTo::Original,
+        tiny_skia::Transform::default(),
+        pixmap.as_mut(),
+    )
+    .ok_or_else(|| anyhow!("Failed to render board"))?;
+
+    Ok(pixmap.encode_png()?)
+}
+
+/// The parts of an object needed to draw it, with its bounding box in board coordinates
+struct Shape {
+    kind: ShapeKind,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    layer: f64,
+}
+
+enum ShapeKind {
+    Square {
+        fill: String,
+    },
+    Circle {
+        fill: String,
+    },
+    Star {
+        fill: String,
+    },
+    Triangle {
+        fill: String,
+    },
+    Textbox {
+        color: String,
+        font_size: f64,
+        content: String,
+    },
+}
+
+impl Shape {
+    fn from_object(object: &JsonObject) -> Option<Self> {
+        let bounds = Rect::of_object(object)?;
+        let layer = number(object, "layer").unwrap_or_default();
+        let fill = || string(object, "fill");
+
+        let kind = match object.get("type")?.as_str()? {
+            "Square" => ShapeKind::Square { fill: fill()? },
+            "Circle" => ShapeKind::Circle { fill: fill()? },
+            "Star" => ShapeKind::Star { fill: fill()? },
+            "Triangle" => ShapeKind::Triangle { fill: fill()? },
+            "Textbox" => ShapeKind::Textbox {
+                color: string(object, "color")?,
+                font_size: number(object, "fontSize")?,
+                content: string(object, "content")?,
+            },
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            x: bounds.min_x,
+            y: bounds.min_y,
+            width: bounds.max_x - bounds.min_x,
+            height: bounds.max_y - bounds.min_y,
+            layer,
+        })
+    }
+
+    fn write_svg(&self, svg: &mut String) {
+        let Self {
+            x,
+            y,
+            width,
+            height,
+            ..
+        } = *self;
+
+        match &self.kind {
+            ShapeKind::Square { fill } => {
+                write!(
+                    svg,
+                    r#"<rect x="{x}" y="{y}" width="{width}" height="{height}" rx="6" "#
+                )
+                .ok();
+                write!(svg, r#"fill="{}"/>"#, escape(fill)).ok();
+            }
+            ShapeKind::Circle { fill } => {
+                let radius = width / 2.0;
+                let (cx, cy) = (x + radius, y + radius);
+                write!(
+                    svg,
+                    r#"<circle cx="{cx}" cy="{cy}" r="{radius}" fill="{}"/>"#,
+                    escape(fill)
+                )
+                .ok();
+            }
+            // The star and triangle paths are the same ones the client draws in their own
+            // viewBoxes, scaled to fit the object
+            ShapeKind::Star { fill } => {
+                let (scale_x, scale_y) = (width / 51.0, height / 48.0);
+                write!(
+                    svg,
+                    r#"<path transform="translate({x} {y}) scale({scale_x} {scale_y})" "#
+                )
+                .ok();
+                write!(
+                    svg,
+                    r#"d="m25,1 6,17h18l-14,11 5,17-15-10-15,10 5-17-14-11h18z" fill="{}"/>"#,
+                    escape(fill)
+                )
+                .ok();
+            }
+            ShapeKind::Triangle { fill } => {
+                let (scale_x, scale_y) = (width / 10.0, height / 10.0);
+                write!(
+                    svg,
+                    r#"<polygon transform="translate({x} {y}) scale({scale_x} {scale_y})" "#
+                )
+                .ok();
+                write!(svg, r#"points="0.5,9 5,1 9.5,9" fill="{}"/>"#, escape(fill)).ok();
+            }
+            ShapeKind::Textbox {
+                color,
+                font_size,
+                content,
+            } => {
+                let text_x = x + TEXTBOX_PADDING;
+                write!(
+                    svg,
+                    r#"<text font-family="sans-serif" font-size="{font_size}" fill="{}">"#,
+                    escape(color)
+                )
+                .ok();
+                for (line_number, line) in content.lines().enumerate() {
+                    let line_y = y
+                        + TEXTBOX_PADDING
+                        + font_size * TEXTBOX_LINE_HEIGHT * (line_number as f64 + 1.0);
+                    write!(
+                        svg,
+                        r#"<tspan x="{text_x}" y="{line_y}">{}</tspan>"#,
+                        escape(line)
+                    )
+                    .ok();
+                }
+                svg.push_str("</text>");
+            }
+        }
+    }
+}
+
+fn number(object: &JsonObject, key: &str) -> Option<f64> {
+    object.get(key).and_then(JsonValue::as_f64)
+}
+
+fn string(object: &JsonObject, key: &str) -> Option<String> {
+    object
+        .get(key)
+        .and_then(JsonValue::as_str)
+        .map(ToString::to_string)
+}
+
+/// Escape text for use in both XML attributes and content
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
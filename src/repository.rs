@@ -1,703 +1,1291 @@
-use anyhow::{anyhow, Result};
-use async_stream::{stream, try_stream};
-use bb8_redis::{bb8::Pool, RedisConnectionManager};
-use futures::{stream::Stream, Future, StreamExt};
-use itertools::Itertools;
-use lazy_static::lazy_static;
-use redis::{
-    aio::Connection,
-    streams::{StreamReadOptions, StreamReadReply},
-    AsyncCommands, Client, FromRedisValue, RedisError,
-};
-use regex::Regex;
-use std::collections::HashMap;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bb8::RunError;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fmt::{self, Debug, Display};
+use std::ops::Deref;
 use std::sync::Arc;
-use tokio::{
-    sync::broadcast::{self, error::RecvError, Sender as BroadcastSender},
-    task::JoinHandle,
-};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::Receiver as BroadcastReceiver;
+use tokio::sync::watch::Receiver as WatchReceiver;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::change::Change;
-use crate::message::{JsonObject, PresenceMessage, ServerMessage};
+use crate::auth::ApiKey;
+use crate::board_cache::{BoardCaches, CachedBoard};
+use crate::board_meta::{BoardMeta, BoardMetaPatch, BoardSummary};
+use crate::change::{Change, ChangeEntry, TrashedObject};
+use crate::change_reader::{ChangeBatch, ChangeReaders};
+use crate::message::{Cursor, JsonObject, PresenceMessage, RejectionReason};
+use crate::pending_counts::PendingCounts;
+use crate::presence_channel::PresenceStats;
+use crate::search::SearchHit;
+use crate::snapshot::{self, BoardSnapshot};
+use crate::snapshot_cache::SnapshotCache;
+use crate::spatial::Rect;
+use crate::webhook::WebhookEvent;
+
+/// What went wrong in the store, sorted into what callers care about: whether to tell the client
+/// something specific, and whether trying again might work. Each backend sorts its own errors
+/// into these when they're converted.
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// The board, or something on it, doesn't exist
+    NotFound(String),
+    /// What was asked for clashes with what's already in the store
+    Conflict(String),
+    /// The store didn't answer in time, or the connection to it dropped
+    Timeout(anyhow::Error),
+    /// Something couldn't be converted to or from how it's stored
+    Serialization(serde_json::Error),
+    /// Anything else that went wrong in the store. Transient errors may go away on their own.
+    Backend {
+        error: anyhow::Error,
+        transient: bool,
+    },
+}
 
-#[derive(Clone)]
-pub struct Repository {
-    pool: Pool<RedisConnectionManager>,
-    presence_sender: BroadcastSender<(Uuid, PresenceMessage)>,
-    _presence_handle: Arc<JoinHandle<()>>,
+pub type RepositoryResult<T> = Result<T, RepositoryError>;
+
+impl RepositoryError {
+    /// An error from the store that isn't expected to go away by trying again
+    pub fn backend(error: impl Into<anyhow::Error>) -> Self {
+        Self::Backend {
+            error: error.into(),
+            transient: false,
+        }
+    }
+
+    /// Whether the same call might succeed if it's made again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Timeout(_) => true,
+            Self::Backend { transient, .. } => *transient,
+            Self::NotFound(_) | Self::Conflict(_) | Self::Serialization(_) => false,
+        }
+    }
 }
 
-impl Repository {
-    #[tracing::instrument(skip_all, err)]
-    pub async fn new(client: Client) -> Result<Self> {
-        let manager = RedisConnectionManager::new(client.get_connection_info().clone())?;
-        let pool = Pool::builder().max_size(5).build(manager).await?;
-        let (presence_sender, _) = broadcast::channel(1000);
-        let presence_handle =
-            tokio::task::spawn(Self::start_presence(pool.clone(), presence_sender.clone()));
-        Ok(Self {
-            pool,
-            presence_sender,
-            _presence_handle: Arc::new(presence_handle),
-        })
+impl Display for RepositoryError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(message) | Self::Conflict(message) => formatter.write_str(message),
+            Self::Timeout(error) => write!(formatter, "Timed out talking to the store: {error}"),
+            Self::Serialization(error) => write!(formatter, "Invalid data for the store: {error}"),
+            Self::Backend { error, .. } => write!(formatter, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::NotFound(_) | Self::Conflict(_) => None,
+            Self::Timeout(error) | Self::Backend { error, .. } => Some(error.as_ref()),
+            Self::Serialization(error) => Some(error),
+        }
+    }
+}
+
+impl From<serde_json::Error> for RepositoryError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Serialization(error)
+    }
+}
+
+impl<E: Into<RepositoryError>> From<RunError<E>> for RepositoryError {
+    fn from(error: RunError<E>) -> Self {
+        match error {
+            RunError::User(error) => error.into(),
+            RunError::TimedOut => Self::Timeout(anyhow!("Timed out waiting for a connection")),
+        }
+    }
+}
+
+/// Errors from helpers that return `anyhow::Result` are sorted by whichever backend error they
+/// started out as
+impl From<anyhow::Error> for RepositoryError {
+    fn from(error: anyhow::Error) -> Self {
+        fn downcast<E>(error: anyhow::Error) -> Result<RepositoryError, anyhow::Error>
+        where
+            E: Into<RepositoryError> + Display + Debug + Send + Sync + 'static,
+        {
+            error.downcast::<E>().map(Into::into)
+        }
+
+        downcast::<RepositoryError>(error)
+            .or_else(downcast::<serde_json::Error>)
+            .or_else(downcast::<redis::RedisError>)
+            .or_else(downcast::<RunError<redis::RedisError>>)
+            .or_else(downcast::<tokio_postgres::Error>)
+            .or_else(downcast::<RunError<tokio_postgres::Error>>)
+            .unwrap_or_else(Self::backend)
+    }
+}
+
+/// What happened to a change submitted with `Repository::publish_change_for_board`
+#[derive(Debug)]
+pub enum PublishOutcome {
+    Accepted {
+        version: String,
+        revisions: Vec<(Uuid, u64)>,
+    },
+    RevisionMismatch {
+        id: Uuid,
+        current_revision: u64,
+    },
+    Duplicate {
+        version: String,
+    },
+}
+
+/// Something about a board's stored state that doesn't add up, usually left behind by a write to
+/// the store that only partly went through
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum BoardInconsistency {
+    /// The board's version isn't a stream entry ID, so nothing can be checkpointed
+    InvalidVersion { version: String },
+    /// The board's version is past the newest change in its change stream, so changes published
+    /// with earlier IDs would be skipped by the checkpointer. This is only reported, since there's
+    /// no telling which changes were skipped.
+    VersionAheadOfChanges {
+        version: String,
+        latest_version: String,
+    },
+    /// An object has a place in the stacking order but doesn't exist
+    OrderedWithoutObject { id: Uuid },
+}
+
+/// The outcome of `Repository::verify_board`
+#[derive(Clone, Debug)]
+pub struct BoardVerification {
+    pub inconsistencies: Vec<BoardInconsistency>,
+    /// The version of the change that repaired the board, if one was published
+    pub repaired_version: Option<String>,
+}
+
+/// How busy a store's connection pool is and how long callers have waited for connections
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    /// Connections handed out since the server started
+    pub checkouts: u64,
+    /// Checkouts that gave up waiting for a connection
+    pub timed_out_checkouts: u64,
+    pub average_wait_ms: f64,
+    pub max_wait_ms: f64,
+    /// Calls that were tried again after a transient error, which stays at zero for stores that
+    /// don't retry
+    pub retries: u64,
+    /// Calls that were given up on after running out of attempts
+    pub exhausted_retries: u64,
+    /// Whether the store can't be reached right now, so calls to it are failing fast
+    pub degraded: bool,
+    /// How many times the store has become degraded since the server started
+    pub breaker_trips: u64,
+}
+
+/// How many of each board's checkpoints are kept around so that the board can be rolled back to
+/// one of them, from the CHECKPOINT_HISTORY_LENGTH and CHECKPOINT_HISTORY_INTERVAL_SECONDS
+/// environment variables
+#[derive(Clone, Copy, Debug)]
+pub struct CheckpointHistory {
+    /// How many checkpoints to keep, or zero to keep none
+    pub length: usize,
+    /// How much newer a checkpoint has to be than the last one kept for it to be kept too, so that
+    /// a busy board's history doesn't only cover the last few minutes
+    pub interval_ms: u64,
+}
+
+impl Default for CheckpointHistory {
+    fn default() -> Self {
+        Self {
+            length: 10,
+            interval_ms: 5 * 60 * 1000,
+        }
     }
+}
+
+impl CheckpointHistory {
+    pub fn from_env() -> Self {
+        let parse = |var: &str| {
+            env::var(var).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{var} must be a number"))
+            })
+        };
+        let default = Self::default();
+        Self {
+            length: parse("CHECKPOINT_HISTORY_LENGTH")
+                .map_or(default.length, |length| length as usize),
+            interval_ms: parse("CHECKPOINT_HISTORY_INTERVAL_SECONDS")
+                .map_or(default.interval_ms, |interval| interval * 1000),
+        }
+    }
+
+    /// Determine whether a checkpoint at the given version should be added to a board's history,
+    /// given the version of the newest checkpoint already in it
+    pub fn keeps(&self, version: &str, newest_version: Option<&str>) -> bool {
+        if self.length == 0 {
+            return false;
+        }
+        let newest_version = match newest_version {
+            Some(newest_version) => newest_version,
+            None => return true,
+        };
+        match (
+            Repository::parse_stream_id(version),
+            Repository::parse_stream_id(newest_version),
+        ) {
+            (Some(version), Some(newest_version)) => {
+                version > newest_version && version.0 >= newest_version.0 + self.interval_ms
+            }
+            _ => false,
+        }
+    }
+}
+
+/// How much of each board's change stream outlives the checkpoint that applies it, so that
+/// reconnecting clients can catch up on what they missed instead of starting over, from the
+/// CHANGE_RETENTION_ENTRIES and CHANGE_RETENTION_MINUTES environment variables. An entry is kept
+/// if either setting keeps it, and changes that haven't been checkpointed are always kept.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChangeRetention {
+    /// How many of the newest entries to keep, or zero to not keep any by count
+    pub entries: usize,
+    /// How long to keep entries for, or zero to not keep any by age
+    pub duration_ms: u64,
+}
+
+impl ChangeRetention {
+    pub fn from_env() -> Self {
+        let parse = |var: &str| {
+            env::var(var).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{var} must be a number"))
+            })
+        };
+        Self {
+            entries: parse("CHANGE_RETENTION_ENTRIES").map_or(0, |entries| entries as usize),
+            duration_ms: parse("CHANGE_RETENTION_MINUTES").map_or(0, |minutes| minutes * 60 * 1000),
+        }
+    }
+
+    /// Work out the oldest entry ID a board's change stream has to keep once it's checkpointed at
+    /// the given version, given the ID of the entry `entries` back from the newest one if the
+    /// stream is that long
+    pub fn min_id(&self, version: (u64, u64), nth_newest: Option<(u64, u64)>) -> (u64, u64) {
+        let mut min_id = version;
+        if self.entries > 0 {
+            min_id = min_id.min(nth_newest.unwrap_or((0, 0)));
+        }
+        if self.duration_ms > 0 {
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64);
+            min_id = min_id.min((now_ms.saturating_sub(self.duration_ms), 0));
+        }
+        min_id
+    }
+}
+
+/// What a session connected with, which is kept with the session so that trouble only some
+/// clients run into can be traced back to them
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClientInfo {
+    /// The version the client reported when it connected, if it did
+    pub client_version: Option<String>,
+    pub user_agent: Option<String>,
+    /// The peer address of the connection, which is a proxy's if there's one in front
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// When the websocket was opened, in milliseconds since the epoch
+    pub connected_at: u64,
+}
+
+impl ClientInfo {
+    pub fn new(
+        client_version: Option<String>,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Self {
+        Self {
+            client_version,
+            user_agent,
+            ip,
+            connected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+        }
+    }
+}
+
+/// Something about a connection that a ban can match it by
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    Session(Uuid),
+    /// The authenticated user behind the session
+    User(String),
+    /// The peer address of the connection
+    Ip(String),
+}
+
+impl BanTarget {
+    /// Every way a connection can be banned, given what's known about it
+    pub fn for_connection(session_id: Uuid, user_id: Option<&str>, ip: Option<&str>) -> Vec<Self> {
+        let mut targets = vec![BanTarget::Session(session_id)];
+        targets.extend(user_id.map(|user_id| BanTarget::User(user_id.to_string())));
+        targets.extend(ip.map(|ip| BanTarget::Ip(ip.to_string())));
+        targets
+    }
+}
+
+/// Keeps whoever it matches off of a board until it's lifted
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Told to the banned session when it's disconnected
+    pub reason: Option<String>,
+}
+
+/// Limits on how big a board can get, so that one client can't grow a board until the store runs
+/// out of memory, from the MAX_OBJECTS_PER_BOARD, MAX_OBJECT_BYTES, MAX_SESSIONS_PER_BOARD, and
+/// MAX_MESSAGE_BYTES environment variables. Any of them but the last can be left unset to not
+/// enforce it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BoardQuotas {
+    pub max_objects: Option<usize>,
+    /// How much JSON a single change can write into one object
+    pub max_object_bytes: Option<usize>,
+    /// How many sessions can be on a board at once, past which the rest wait for a spot
+    pub max_sessions: Option<usize>,
+    /// How big a single websocket message from a client can be, past which the connection is
+    /// closed before the message is even parsed
+    pub max_message_bytes: Option<usize>,
+}
+
+impl BoardQuotas {
+    pub fn from_env() -> Self {
+        let parse = |var: &str| {
+            env::var(var).ok().map(|value| {
+                value
+                    .parse::<usize>()
+                    .unwrap_or_else(|_| panic!("{var} must be a number"))
+            })
+        };
+        Self {
+            max_objects: parse("MAX_OBJECTS_PER_BOARD"),
+            max_object_bytes: parse("MAX_OBJECT_BYTES"),
+            max_sessions: parse("MAX_SESSIONS_PER_BOARD"),
+            max_message_bytes: parse("MAX_MESSAGE_BYTES").or(Some(DEFAULT_MAX_MESSAGE_BYTES)),
+        }
+    }
+}
+
+/// How big a websocket message from a client can be when MAX_MESSAGE_BYTES isn't set, which is
+/// far more than any change a person could make by hand
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+/// How long a session's lease on an object lasts before it has to be renewed
+pub const OBJECT_LOCK_TTL_SECONDS: usize = 30;
+
+/// How long a board's cursor positions are kept after the last time anyone moved theirs, in case
+/// the sessions they belong to are never cleaned up
+pub const CURSOR_TTL_SECONDS: usize = 10 * 60;
+
+/// How long a board's trash is kept after the last object was put in it
+pub const TRASH_TTL_SECONDS: usize = 24 * 60 * 60;
+
+/// How long an idempotency key is remembered after the change it was submitted with is accepted
+pub const IDEMPOTENCY_TTL_SECONDS: usize = 600;
+
+/// Everything the server needs to keep boards, their sessions, and their changes, and to tell
+/// every server about presence. `RedisRepository` lets any number of servers share boards, and
+/// `MemoryRepository` keeps them in a single process. Anything else that implements this can stand
+/// in for either without the rest of the server noticing.
+///
+/// Versions are change IDs of the form `{milliseconds}-{sequence}`, ordered first by time and
+/// then by sequence, where `0` comes before any change.
+#[async_trait]
+pub trait BoardStore: Send + Sync {
+    /// Record the metadata for a newly minted board
+    async fn create_board(&self, board_id: Uuid, meta: BoardMeta) -> RepositoryResult<()>;
+
+    /// Get the metadata for a board, if it was created with any
+    async fn get_meta_for_board(&self, board_id: Uuid) -> RepositoryResult<Option<BoardMeta>>;
+
+    /// Change the metadata for a board and return the result, or nothing if the board doesn't
+    /// have any metadata to change
+    async fn update_meta_for_board(
+        &self,
+        board_id: Uuid,
+        patch: BoardMetaPatch,
+    ) -> RepositoryResult<Option<BoardMeta>>;
+
+    /// Determine if a board is frozen, in which case sessions and API callers can't change it
+    async fn get_board_frozen(&self, board_id: Uuid) -> RepositoryResult<bool>;
+
+    /// Freeze a board so that it can't be changed, or thaw it out again
+    async fn set_board_frozen(&self, board_id: Uuid, frozen: bool) -> RepositoryResult<()>;
+
+    /// Send a board's presence through a stream so that sessions don't miss any of it, or go back
+    /// to sending it once. Stores that can't fail with `Conflict`.
+    async fn set_board_reliable_presence(
+        &self,
+        board_id: Uuid,
+        reliable: bool,
+    ) -> RepositoryResult<()>;
+
+    /// Remove every trace of a board and tell everyone connected to it that it's gone. Returns
+    /// whether there was anything to delete.
+    async fn delete_board(&self, board_id: Uuid) -> RepositoryResult<bool>;
+
+    /// Copy the contents of a board into a new board, leaving out its sessions and presence.
+    /// Returns whether the source board existed.
+    async fn duplicate_board(&self, board_id: Uuid, new_board_id: Uuid) -> RepositoryResult<bool>;
+
+    /// Get a page of boards from the registry, most recently active first. The cursor is the
+    /// position in the registry to start from, and the cursor for the next page is returned if
+    /// there might be more boards after this page.
+    async fn get_boards(
+        &self,
+        cursor: usize,
+        limit: usize,
+    ) -> RepositoryResult<(Vec<BoardSummary>, Option<usize>)>;
 
     /// Given a session ID and username from the client, add that session to a board and broadcast
-    /// a notification about the new session
-    #[tracing::instrument(skip(self), err)]
-    pub async fn create_session_for_board(
+    /// a notification about the new session. The user ID is the authenticated user behind the
+    /// session, if authentication is turned on, and the client info is kept alongside it until the
+    /// session is removed.
+    async fn create_session_for_board(
         &self,
         board_id: Uuid,
         session_id: Uuid,
         username: String,
-    ) -> Result<()> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-            let sessions_key = Self::board_sessions_key(board_id);
-
-            // Add the session ID and username as a key-value pair to the hash at
-            // board/{board_id}/sessions
-            connection
-                .hset::<_, _, _, ()>(&sessions_key, session_id.to_string(), username.clone())
-                .await?;
-
-            // Start keeping the session alive by bumping the expiration at
-            // sessions/{session_id}/checkin
-            self.touch_session(session_id).await?;
-
-            // Broadcast UserJoined notification
-            Self::publish_presence_message_for_board(
-                &mut connection,
-                board_id,
-                PresenceMessage {
-                    source_session: session_id,
-                    message: ServerMessage::UserJoined {
-                        session_id,
-                        username: username.clone(),
-                    },
-                },
-            )
-            .await?;
-
-            Ok(())
-        })
-        .await
-    }
+        user_id: Option<String>,
+        client: ClientInfo,
+    ) -> RepositoryResult<()>;
 
     /// Retrieve all of the session ID - username pairs currently active on a board
-    #[tracing::instrument(skip(self), err)]
-    pub async fn get_sessions_for_board(&self, board_id: Uuid) -> Result<Vec<(Uuid, String)>> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-            let sessions_key = Self::board_sessions_key(board_id);
-
-            // Read all of the session ID - username pairs from the hash at
-            // board/{board_id}/sessions
-            let sessions = connection
-                .hgetall::<_, HashMap<String, String>>(&sessions_key)
-                .await?
-                .into_iter()
-                .filter_map(|(session_id_string, username)| {
-                    session_id_string
-                        .parse::<Uuid>()
-                        .ok()
-                        .map(|session_id| (session_id, username))
-                })
-                .collect::<Vec<_>>();
-
-            Ok(sessions)
-        })
-        .await
-    }
+    async fn get_sessions_for_board(&self, board_id: Uuid)
+        -> RepositoryResult<Vec<(Uuid, String)>>;
+
+    /// Retrieve the authenticated user behind a session on a board. Empty if the session joined
+    /// without one or isn't on the board.
+    async fn get_session_user_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<Option<String>>;
 
     /// Remove a session from a board, clean up its checkin state, and broadcast a message
     /// notifying of session removal
-    #[tracing::instrument(skip(self), err)]
-    pub async fn delete_session_for_board(&self, board_id: Uuid, session_id: Uuid) -> Result<()> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-
-            // Delete the session ID from the hash at board/{board_id}/sessions
-            connection
-                .hdel::<String, String, ()>(
-                    Self::board_sessions_key(board_id),
-                    session_id.to_string(),
-                )
-                .await?;
-
-            // Delete the checkin state at sessions/{session_id}/checkin
-            connection
-                .del::<_, ()>(Self::session_checkin_key(session_id))
-                .await?;
-
-            // Broadcast UserLeft notification
-            Self::publish_presence_message_for_board(
-                &mut *connection,
-                board_id,
-                PresenceMessage {
-                    source_session: session_id,
-                    message: ServerMessage::UserLeft { session_id },
-                },
-            )
-            .await?;
+    async fn delete_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()>;
 
-            Ok(())
-        })
-        .await
-    }
+    /// Force a session off of a board by telling its handler to disconnect, then remove it.
+    /// Returns whether the session was on the board.
+    async fn kick_session_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        reason: Option<String>,
+    ) -> RepositoryResult<bool>;
 
-    #[tracing::instrument(skip(self), err)]
-    pub async fn touch_session(&self, session_id: Uuid) -> Result<()> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-            connection
-                .set_ex(Self::session_checkin_key(session_id), 1, 30)
-                .await?;
-            Ok(())
-        })
-        .await
-    }
+    /// Keep whoever a ban matches off of a board, and tell the handlers of any sessions it matches
+    /// right now to disconnect. Banning the same target again replaces the reason.
+    async fn add_ban_for_board(&self, board_id: Uuid, ban: Ban) -> RepositoryResult<()>;
 
-    /// Determine if a session still exists
-    #[tracing::instrument(skip(self), err)]
-    pub async fn get_session_exists(&self, session_id: Uuid) -> Result<bool> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
+    /// Lift a ban on a board. Returns whether there was one.
+    async fn remove_ban_for_board(
+        &self,
+        board_id: Uuid,
+        target: BanTarget,
+    ) -> RepositoryResult<bool>;
 
-            // Simply check EXISTS at sessions/{session_id}/checkin and let the expiration handle
-            let exists = connection
-                .exists::<_, bool>(Self::session_checkin_key(session_id))
-                .await?;
+    /// Retrieve every ban on a board
+    async fn get_bans_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Ban>>;
 
-            Ok(exists)
-        })
-        .await
-    }
+    async fn touch_session(&self, session_id: Uuid) -> RepositoryResult<()>;
+
+    /// Determine if a session still exists
+    async fn get_session_exists(&self, session_id: Uuid) -> RepositoryResult<bool>;
+
+    /// Get a stream of sessions as their checkins expire, each with the boards it was on or
+    /// waiting for. Every server hears about each session but only one of them gets its boards,
+    /// so the rest are left out. The stream ends when the store stops sending them, after which
+    /// some could have been missed. Empty if the store can't tell when a checkin expires, which
+    /// leaves stale sessions to be found by looking through every board.
+    async fn stream_expired_sessions(
+        &self,
+    ) -> RepositoryResult<Option<BoxStream<'static, (Uuid, Vec<Uuid>)>>>;
 
     /// Send notification about a change to a user's cursor position for a particular session in a
     /// particular board. The x and y coordinates are in the pixel space of the board, top-left
     /// origin
-    #[tracing::instrument(skip(self), err)]
-    pub async fn update_session_cursor_for_board(
+    async fn update_session_cursor_for_board(
         &self,
         board_id: Uuid,
         session_id: Uuid,
         x: f64,
         y: f64,
-    ) -> Result<()> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-            Self::publish_presence_message_for_board(
-                &mut *connection,
-                board_id,
-                PresenceMessage {
-                    source_session: session_id,
-                    message: ServerMessage::UserCursorChanged { session_id, x, y },
-                },
-            )
-            .await?;
-            Ok(())
-        })
-        .await
-    }
+    ) -> RepositoryResult<()>;
 
     /// Send a notification that a user's cursor has left the area of a board
-    #[tracing::instrument(skip(self), err)]
-    pub async fn delete_session_cursor_for_board(
+    async fn delete_session_cursor_for_board(
         &self,
         board_id: Uuid,
         session_id: Uuid,
-    ) -> Result<()> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-            Self::publish_presence_message_for_board(
-                &mut connection,
-                board_id,
-                PresenceMessage {
-                    source_session: session_id,
-                    message: ServerMessage::UserCursorLeft { session_id },
-                },
-            )
-            .await?;
-            Ok(())
-        })
-        .await
-    }
+    ) -> RepositoryResult<()>;
+
+    /// Record the round-trip latency most recently measured by a session's client, in
+    /// milliseconds, and let everyone else on the board know about it
+    async fn update_session_latency_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        rtt: f64,
+    ) -> RepositoryResult<()>;
+
+    /// Retrieve the latest round-trip latency of every session on a board that has reported one
+    async fn get_session_latencies_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, f64)>>;
+
+    /// Retrieve the last known cursor position of every session on a board that has one
+    async fn get_session_cursors_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Cursor)>>;
+
+    /// Retrieve what every session on a board connected with, for sessions that recorded it
+    async fn get_session_clients_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, ClientInfo)>>;
+
+    /// Try to take out a lease on an object for a session, or renew the lease if the session
+    /// already holds it. Returns the session that holds the lock afterwards, which will be some
+    /// other session if the object was already locked.
+    async fn lock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Uuid>;
+
+    /// Release a session's lease on an object. Does nothing if the lease is held by some other
+    /// session or has already expired. Returns whether a lease was actually released.
+    async fn unlock_object_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<bool>;
+
+    /// Get the session currently holding a lease on an object, if any
+    async fn get_object_lock_for_board(
+        &self,
+        board_id: Uuid,
+        object_id: Uuid,
+    ) -> RepositoryResult<Option<Uuid>>;
+
+    /// Find a lease held by some other session on any object that a change edits, which means the
+    /// change has to be refused. Inserts never conflict since nobody can have locked an object that
+    /// doesn't exist yet.
+    async fn get_conflicting_lock_for_change(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+        change: &Change,
+    ) -> RepositoryResult<Option<Uuid>>;
+
+    /// Retrieve every object ID - session ID pair for the leases currently held on a board
+    async fn get_object_locks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, Uuid)>>;
 
     /// Get a stream of every board ID that exists in the system
-    #[tracing::instrument(skip(self))]
-    pub async fn stream_all_board_ids(&self) -> impl Stream<Item = Result<Uuid>> + Unpin {
-        let pool = self.pool.clone();
-        Box::pin(try_stream! {
-            let mut connection = pool.get().await?;
-
-            // SCAN over keys that match board/*/changes - the /changes key is for the change stream
-            // and is the ultimate source of truth about whether a board actually exists
-            let mut stream_keys =  connection
-                .scan_match::<_, String>("board/*/changes")
-                .await?;
-            while let Some(stream_key) = stream_keys.next().await {
-                if let Ok(board_id) = Self::parse_board_id_from_key(stream_key.as_str()) {
-                    yield board_id;
-                }
-            }
-        })
-    }
+    async fn stream_all_board_ids(&self) -> BoxStream<'static, RepositoryResult<Uuid>>;
 
-    /// Poll the latest `count` changes for a board, optionally starting at a given stream ID. If
-    /// no stream ID is provided, start from the beginning.
-    #[tracing::instrument(skip(self), err)]
-    pub async fn get_changes_for_board(
+    /// Get every board with changes that haven't been checkpointed yet, along with about how many,
+    /// so that the checkpointer can leave every other board alone
+    async fn get_pending_checkpoints(&self) -> RepositoryResult<Vec<(Uuid, usize)>>;
+
+    /// Record that `count` of a board's pending changes were checkpointed, forgetting about the
+    /// board once none are left
+    async fn clear_pending_checkpoint(&self, board_id: Uuid, count: usize) -> RepositoryResult<()>;
+
+    /// Poll the latest `count` changes for a board, optionally starting after a given version. If
+    /// no version is provided, start from the beginning.
+    async fn get_changes_for_board(
         &self,
         board_id: Uuid,
         count: usize,
         version: Option<String>,
-    ) -> Result<Vec<(String, Uuid, Change)>> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-            let actual_version = version.clone().unwrap_or_else(|| "0".to_string());
-
-            // XREAD the next `count` items, blocking for 1 second. This means that no messages may
-            // be returned even though some may be added immediately after calling this method and
-            // therefore it is up to the caller to poll in an appropriate loop.
-            let read_reply = connection
-                .xread_options::<_, _, StreamReadReply>(
-                    &[Self::board_changes_key(board_id)],
-                    &[actual_version],
-                    &StreamReadOptions::default().block(1000).count(count),
-                )
-                .await?;
-
-            let changes = read_reply
-                .keys
-                .into_iter()
-                // next() gets the very next item in the iterator and returns Some if there is one
-                // or None if the iterator is empty. We only expect one set of stream entries to be
-                // returned because we only requested one key.
-                .next()
-                .into_iter()
-                .flat_map(|key| key.ids)
-                // Parse the contents of each entry into a session ID and a change
-                .filter_map(|id| {
-                    Some((
-                        id.id,
-                        id.map
-                            .get("session_id")
-                            .and_then(|value| String::from_redis_value(value).ok())
-                            .and_then(|string| string.parse::<Uuid>().ok())?,
-                        id.map
-                            .get("change")
-                            .and_then(|value| String::from_redis_value(value).ok())
-                            .and_then(|string| serde_json::from_str::<Change>(&string).ok())?,
-                    ))
-                })
-                .collect::<Vec<_>>();
-
-            Ok(changes)
-        })
-        .await
-    }
+    ) -> RepositoryResult<Vec<ChangeEntry>>;
 
-    // Bulk-apply a set of changes to the materialized objects of a board, and persist the stream ID
-    // of the latest change to help future readers know where to pick up the stream after reading
-    // the objects.
-    #[tracing::instrument(skip(self, changes), err)]
-    pub async fn apply_changes_to_board(
+    /// Read the changes made to a board between two versions or millisecond timestamps, including
+    /// ones that have already been checkpointed as long as they are still in the board's history.
+    /// Only changes from the given session are included if there is one.
+    async fn get_change_history_for_board(
+        &self,
+        board_id: Uuid,
+        since: Option<String>,
+        until: Option<String>,
+        session_id: Option<Uuid>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<ChangeEntry>>;
+
+    /// Read every change still in a board's change stream after the given version, without
+    /// waiting for new ones to arrive
+    async fn get_pending_changes_for_board(
         &self,
         board_id: Uuid,
         version: String,
-        changes: Vec<Change>,
-    ) -> Result<()> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-
-            let board_changes_key = Self::board_changes_key(board_id);
-            let board_objects_key = Self::board_objects_key(board_id);
-            let board_version_key = Self::board_version_key(board_id);
-
-            // Start a pipeline of commands. Calling `atomic` instructs the client to wrap those
-            // commands in a MULTI/EXEC.
-            let mut pipeline = redis::pipe();
-            pipeline.atomic();
-
-            // First ensure that there is at least an empty JSON object at board/{board_id}/objects
-            // to update. NX prevents it from being overwritten if it already exists.
-            pipeline
-                .cmd("JSON.SET")
-                .arg(&board_objects_key)
-                .arg(".")
-                .arg("{}")
-                .arg("NX");
-
-            // Translate each change in to a JSON operation. Deletes are translated into a JSON.DEL
-            // for the given object ID. Inserts are translated into a JSON.SET for the entire object
-            // ID, passing the new object as the value. Updates are translated into a JSON.SET for
-            // the key nested under the object ID.
-            for change in changes.clone() {
-                match change {
-                    Change::Delete { id } => {
-                        pipeline
-                            .cmd("JSON.DEL")
-                            .arg(&board_objects_key)
-                            .arg(format!("$.{id}"))
-                            .ignore();
-                    }
-                    Change::Insert { id, object } => {
-                        pipeline
-                            .cmd("JSON.SET")
-                            .arg(&board_objects_key)
-                            .arg(format!("$.{id}"))
-                            .arg(serde_json::to_string(&object).unwrap())
-                            .ignore();
-                    }
-                    Change::Update { id, key, value } => {
-                        pipeline
-                            .cmd("JSON.SET")
-                            .arg(&board_objects_key)
-                            .arg(format!("$.{id}.{key}"))
-                            .arg(serde_json::to_string(&value).unwrap())
-                            .ignore();
-                    }
-                }
-            }
+    ) -> RepositoryResult<Vec<ChangeEntry>>;
 
-            // Finally, drop all of the changes from the change stream prior to the entry ID given
-            // as the version associated with these changes. All of these operations are applied
-            // atomically we know that if they succeed then we have no need for the changes in the
-            // stream anymore. Future reads will start with the new version of
-            // board/{board_id}/objects and then start streaming changes that have been added since
-            // this operation was performed and everything remains fast and consistent.
-            pipeline
-                .set(&board_version_key, &version)
-                .cmd("XTRIM")
-                .arg(board_changes_key)
-                .arg("MINID")
-                .arg(version.clone());
-
-            pipeline.query_async::<_, ()>(&mut *connection).await?;
-
-            Ok(())
-        })
-        .await
-    }
-
-    /// Add a change to the board from the given session
-    #[tracing::instrument(skip(self), err)]
-    pub async fn publish_change_for_board(
+    /// Checkpoint a batch of changes that were read from the change stream after `since_version`.
+    /// Nothing is applied if the board's version has moved on from there in the meantime, so that
+    /// two checkpoints of the same board can never apply the same changes twice or move the
+    /// version backwards. Returns whether the changes were applied.
+    async fn apply_changes_to_board(
+        &self,
+        board_id: Uuid,
+        since_version: &str,
+        entries: Vec<ChangeEntry>,
+    ) -> RepositoryResult<bool>;
+
+    /// Add a change to the board from the given session, bumping the revision of every object it
+    /// applies to. Changes that expect an object to be at a particular revision are only added if
+    /// all of their objects still are. If an idempotency key is given and a change has already
+    /// been added with the same key recently, nothing is added and the earlier version is
+    /// returned instead. Archived boards are restored first.
+    async fn publish_change_for_board(
         &self,
         board_id: Uuid,
         session_id: Uuid,
         change: Change,
-    ) -> Result<String> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
-
-            // XADD the change and session_id to the stream. Passing `*` as the entry ID is perhaps
-            // the most important detail of this design, as it allows Redis to fully determine the
-            // global ordering of changes to a board. Clients are responsible for rearranging any
-            // optimistic updates to match the order that the Redis stream decides.
-            Ok(connection
-                .xadd::<_, _, _, _, String>(
-                    Self::board_changes_key(board_id),
-                    "*".to_string(),
-                    &[
-                        ("change", serde_json::to_string(&change.clone())?),
-                        ("session_id", session_id.to_string()),
-                    ],
-                )
-                .await?)
-        })
-        .await
-    }
+        idempotency_key: Option<String>,
+    ) -> RepositoryResult<PublishOutcome>;
+
+    /// Retrieve the current revision of every object on a board that has ever been changed
+    async fn get_revisions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, u64)>>;
+
+    /// Retrieve the revision of every object as of the board's checkpointed version, which is kept
+    /// up to date as changes are checkpointed. Revisions are still bumped when changes are
+    /// published so that conditional changes are checked against everything accepted before them,
+    /// but these are the ones that go with the board's objects in a snapshot.
+    async fn get_checkpointed_revisions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, u64)>>;
 
     /// Get the latest change stream entry ID for a board so that streaming can begin from a point
     /// that maintains consistency with respect to the contents of the board's materialized object
     /// snapshot
-    #[tracing::instrument(skip(self), err)]
-    pub async fn get_version_for_board(&self, board_id: Uuid) -> Result<String> {
-        Self::with_redis_retry(|| async {
-            let mut connection = self.pool.get().await?;
+    async fn get_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String>;
 
-            let board_version_key = Self::board_version_key(board_id);
+    /// Get the version of the most recent change published to a board, whether or not it has been
+    /// checkpointed yet. Reading a board's changes from here on skips everything already published.
+    async fn get_latest_version_for_board(&self, board_id: Uuid) -> RepositoryResult<String>;
 
-            // Simple GET, with a default value of 0 if it does not exist
-            let version = connection
-                .get::<_, Option<String>>(board_version_key.as_str())
-                .await?
-                .unwrap_or_else(|| "0".to_string());
+    /// Determine whether every change after the given version is still present in a board's change
+    /// stream, meaning a client at that version can catch up by replaying the stream rather than
+    /// downloading a whole new snapshot
+    async fn get_version_available_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<bool>;
 
-            Ok(version)
-        })
-        .await
-    }
+    /// Count the objects in a board's materialized snapshot, leaving out any pending changes
+    async fn get_object_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize>;
+
+    /// Count the changes in a board's change stream that the checkpointer hasn't applied yet
+    async fn get_pending_change_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize>;
+
+    /// Count the sessions on a board, including ones that have gone away but haven't been cleaned
+    /// up by the session checker yet
+    async fn get_session_count_for_board(&self, board_id: Uuid) -> RepositoryResult<usize>;
+
+    /// Put a session in line for a spot on a full board, or leave it where it is if it's already
+    /// waiting. Returns its place in line, starting at 1.
+    async fn join_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<usize>;
+
+    /// Take a session out of line for a board, once it gets a spot or gives up
+    async fn leave_waiting_room_for_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()>;
+
+    /// Retrieve every session waiting for a spot on a board, in the order they got in line
+    async fn get_waiting_sessions_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<Uuid>>;
+
+    /// Get the time of a board's last activity from the registry, if it's registered
+    async fn get_last_activity_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Option<DateTime<Utc>>>;
+
+    /// Estimate how many bytes of memory a board takes up in the store
+    async fn get_memory_usage_for_board(&self, board_id: Uuid) -> RepositoryResult<u64>;
+
+    /// Report on the store's connection pool, for stores that have one
+    fn get_pool_stats(&self) -> Option<PoolStats>;
+
+    /// Get how many presence messages this server has dropped for sessions that fell behind
+    fn get_presence_stats(&self) -> PresenceStats;
+
+    /// Get told whenever the store can't be reached and when it can again, with `true` while it's
+    /// degraded. Stores that are always reachable don't have anything to tell.
+    fn watch_degraded(&self) -> Option<WatchReceiver<bool>>;
+
+    /// How long a session lives without checking in
+    fn session_ttl(&self) -> Duration;
+
+    /// Get the versions of the checkpoints kept in a board's checkpoint history, newest first
+    async fn get_checkpoint_versions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<String>>;
+
+    /// Get the contents of a board as they were at one of the checkpoints in its checkpoint
+    /// history, if that checkpoint is still there
+    async fn get_checkpoint_for_board(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<Option<BoardSnapshot>>;
+
+    /// Get every object in a board's trash, which is where checkpointed deletes put objects so that
+    /// they can be brought back with a `RestoreObject` change until the trash expires
+    async fn get_trash_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, TrashedObject)>>;
+
+    /// Get the materialized stacking order of a board as object ID - index pairs, back to front.
+    /// Objects that have never been given an index are not included.
+    async fn get_order_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<(Uuid, f64)>>;
+
+    /// Get the checkpointed objects whose bounds intersect the rectangle, using the board's
+    /// spatial index. Objects without bounds are never included.
+    async fn get_objects_in_rect(
+        &self,
+        board_id: Uuid,
+        rect: Rect,
+    ) -> RepositoryResult<Vec<(Uuid, JsonObject)>>;
+
+    /// Find checkpointed objects on any board whose text has every one of the terms, most
+    /// recently changed first
+    async fn search_objects(
+        &self,
+        terms: &BTreeSet<String>,
+        limit: usize,
+    ) -> RepositoryResult<Vec<SearchHit>>;
 
     /// Get a stream of chunks of objects in a board's materialized object snapshot. Splitting up
     /// into chunks allows the caller to provide a high level of perceived performance even when a
     /// board has a ton of objects.
-    #[tracing::instrument(skip(self))]
-    pub async fn stream_object_chunks_for_board(
-        &self,
-        board_id: Uuid,
-    ) -> impl Stream<Item = Result<Vec<(Uuid, JsonObject)>>> + Unpin {
-        let pool = self.pool.clone();
-        Box::pin(try_stream! {
-            let board_objects_key = Self::board_objects_key(board_id);
-
-            let object_key_chunks = Self::with_redis_retry(|| async {
-                let mut connection = pool.get().await?;
-
-                // First get all of the object IDs in the board and split them into groups of 100
-                let object_key_chunks = redis::cmd("JSON.OBJKEYS")
-                    .arg(board_objects_key.as_str())
-                    .arg(".")
-                    .query_async::<_, Option<Vec<String>>>(&mut *connection)
-                    .await?
-                    .unwrap_or_default()
-                    .into_iter()
-                    .map(|key| format!("$.{key}"))
-                    .chunks(100);
-
-                let object_key_chunks = object_key_chunks
-                    .into_iter()
-                    .map(|chunk| chunk.collect::<Vec<_>>())
-                    .collect::<Vec<_>>();
-
-                Ok(object_key_chunks)
-            }).await?;
-
-            for keys in object_key_chunks {
-                let keys_length = keys.len();
-
-                if keys_length == 0 {
-                    continue;
-                }
+    async fn stream_object_chunks_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> BoxStream<'static, RepositoryResult<Vec<(Uuid, JsonObject)>>>;
 
-                let entries = Self::with_redis_retry(|| async {
-                    let mut connection = pool.get().await?;
-
-                    // Retrieve the values of each object ID at once by passing them as variadic
-                    // args to JSON.GET. JSON.GET returns a different JSON data structure depending
-                    // on whether there is a single key or many keys the returned. A single key will
-                    // come back as a single object inside of an array. Multiple keys will come back
-                    // as a JSON object that maps keys onto a similar one-value array.
-                    let entries_string = redis::cmd("JSON.GET")
-                        .arg(board_objects_key.clone())
-                        .arg(&keys)
-                        .query_async::<_, Option<String>>(&mut *connection)
-                        .await?;
-
-                    if keys_length == 1 {
-                        Ok(entries_string
-                            // Single-key input case: the return value is just whatever JSON data is
-                            // at that key, but inside of an array. It will look like
-                            // [ { "property1": "hello", "property2": "world" } ]
-                            .and_then(|string| serde_json::from_str::<Vec<JsonObject>>(&string).ok())
-                            .and_then(|mut values| {
-                                Some(vec![(
-                                    keys[0].trim_start_matches("$.").parse::<Uuid>().ok()?,
-                                    values.pop()?,
-                                )])
-                            })
-                            .unwrap_or_default())
-                    } else {
-                        Ok(entries_string
-                            // Multiple-key input case: the return value is a mapping of input keys
-                            // to values inside arrays
-                            // {
-                            //   "$.<UUID>": [ { "property1": "hello", "property2": "world" } ],
-                            //   "$.<UUID>": [ { "propety1": "foo", "property2": "bar" } ]
-                            // }
-                            .and_then(|string| {
-                                serde_json::from_str::<HashMap<String, Vec<JsonObject>>>(&string).ok()
-                            })
-                            .unwrap_or_default()
-                            .into_iter()
-                            .filter_map(|(key, mut values)| {
-                                Some((
-                                    key.trim_start_matches("$.").parse::<Uuid>().ok()?,
-                                    values.pop()?,
-                                ))
-                            })
-                            .collect::<Vec<_>>())
-                    }
-                }).await?;
-
-                yield entries;
-            }
-        })
-    }
+    /// Get a never-ending stream of the changes published to a board after the given version.
+    /// Trouble reading from the store is waited out rather than ending the stream.
+    fn stream_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> BoxStream<'static, ChangeEntry>;
 
     /// Get a stream of all of the messages published to describe user activity for a particular
     /// board
-    #[tracing::instrument(skip(self))]
-    pub async fn stream_presence_messages_for_board(
+    async fn stream_presence_messages_for_board(
         &self,
         board_id: Uuid,
-    ) -> impl Stream<Item = PresenceMessage> + Unpin {
-        let sender = self.presence_sender.clone();
-        Box::pin(stream! {
-            let mut receiver = sender.subscribe();
-            loop {
-                // recv() fails if either the corresponding sender has been dropped, meaning the
-                // channel is closed, or if the current receiver is too far behind. If the channel
-                // is closed then so is the stream and we can break and exit. If the receiver is
-                // laggy we can simply coninue, and then next recv() will return the oldest value on
-                // the channel. Some lossiness in presence messages is fine so this behavior is
-                // acceptable.
-                let (next_board_id, next_message) = match receiver.recv().await {
-                    Err(RecvError::Closed) => break,
-                    Err(RecvError::Lagged(_)) => continue,
-                    Ok(message) => message,
-                };
-                if next_board_id == board_id {
-                    yield next_message;
-                }
-            }
-        })
+    ) -> BoxStream<'static, PresenceMessage>;
+
+    /// Take the oldest webhook event off of the queue, waiting up to a second for one to arrive
+    async fn pop_webhook_event(&self) -> RepositoryResult<Option<WebhookEvent>>;
+
+    /// Get the webhook URLs that only receive events for a board
+    async fn get_webhooks_for_board(&self, board_id: Uuid) -> RepositoryResult<Vec<String>>;
+
+    /// Start sending a board's events to a webhook URL
+    async fn add_webhook_for_board(&self, board_id: Uuid, url: String) -> RepositoryResult<()>;
+
+    /// Stop sending a board's events to a webhook URL. Returns whether the URL was configured.
+    async fn delete_webhook_for_board(&self, board_id: Uuid, url: String)
+        -> RepositoryResult<bool>;
+
+    /// Look up an API key by the hash of the key
+    async fn get_api_key(&self, hash: &str) -> RepositoryResult<Option<ApiKey>>;
+
+    /// Get every stored API key along with the hash of each key
+    async fn get_api_keys(&self) -> RepositoryResult<Vec<(String, ApiKey)>>;
+
+    /// Store a new API key by the hash of the key
+    async fn add_api_key(&self, hash: &str, api_key: ApiKey) -> RepositoryResult<()>;
+
+    /// Revoke an API key by the hash of the key. Returns whether there was such a key.
+    async fn delete_api_key(&self, hash: &str) -> RepositoryResult<bool>;
+
+    /// Get the secret that share tokens are signed with, making one up the first time it's needed
+    /// so that every server agrees on it
+    async fn get_share_secret(&self) -> RepositoryResult<String>;
+
+    /// Take the named lock for the given holder until `ttl` from now, or extend it if the holder
+    /// already has it, so that only one server does a piece of background work at a time. Returns
+    /// whether the holder has the lock afterwards.
+    async fn acquire_lock(&self, name: &str, holder: Uuid, ttl: Duration)
+        -> RepositoryResult<bool>;
+
+    /// Let go of the named lock, if the given holder still has it
+    async fn release_lock(&self, name: &str, holder: Uuid) -> RepositoryResult<()>;
+
+    /// Get every board in the registry whose last activity was before the cutoff, archived or not
+    async fn get_idle_board_ids(&self, cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>>;
+
+    /// Get every board in the registry whose last activity was before the cutoff and that hasn't
+    /// already been archived
+    async fn get_inactive_board_ids(&self, cutoff: DateTime<Utc>) -> RepositoryResult<Vec<Uuid>>;
+
+    /// Drop a board's contents from the store once they've been uploaded to the archive at the
+    /// given version. Nothing is dropped if the board has moved past that version or has anyone on
+    /// it. Returns whether the board was archived.
+    async fn archive_board(&self, board_id: Uuid, version: &str) -> RepositoryResult<bool>;
+
+    /// Determine whether a board's contents are in the archive rather than in the store
+    async fn get_board_archived(&self, board_id: Uuid) -> RepositoryResult<bool>;
+
+    /// Bring an archived board's contents back from the archive so that it can be used like any
+    /// other board. Does nothing for boards that aren't archived.
+    async fn restore_board(&self, board_id: Uuid) -> RepositoryResult<()>;
+}
+
+/// A handle on the store the server was started with, which is cheap to clone into every task
+#[derive(Clone)]
+pub struct Repository {
+    store: Arc<dyn BoardStore>,
+    quotas: BoardQuotas,
+    /// What pending changes add to each board's object count, for checking the object quota
+    pending_counts: PendingCounts,
+    change_readers: ChangeReaders,
+    /// Boards kept in memory for snapshots, if that's turned on
+    board_caches: Option<BoardCaches>,
+    /// Snapshots read recently, if that's turned on
+    snapshot_cache: Option<SnapshotCache>,
+}
+
+impl Repository {
+    pub fn new(store: impl BoardStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            quotas: BoardQuotas::default(),
+            pending_counts: PendingCounts::default(),
+            change_readers: ChangeReaders::default(),
+            board_caches: None,
+            snapshot_cache: None,
+        }
     }
 
-    // ---- Private helpers
+    /// Enforce the given limits on how big boards can get
+    pub fn with_quotas(self, quotas: BoardQuotas) -> Self {
+        Self { quotas, ..self }
+    }
 
-    /// Publish a presence message for a board using Pub/Sub
-    #[tracing::instrument(skip(connection), err)]
-    async fn publish_presence_message_for_board(
-        connection: &mut Connection,
-        board_id: Uuid,
-        message: PresenceMessage,
-    ) -> Result<()> {
-        // Convert the message to a JSON string and publish it to board/{board_id}/presence
-        connection
-            .publish::<String, String, ()>(
-                Self::board_presence_key(board_id),
-                serde_json::to_string(&message)?,
-            )
-            .await?;
+    pub fn quotas(&self) -> &BoardQuotas {
+        &self.quotas
+    }
 
-        Ok(())
+    /// Keep boards in memory for snapshots until `idle_timeout` after the last one
+    pub fn with_board_cache(self, idle_timeout: Duration) -> Self {
+        Self {
+            board_caches: Some(BoardCaches::new(idle_timeout)),
+            ..self
+        }
     }
 
-    #[tracing::instrument(err)]
-    fn parse_board_id_from_key(stream_key: &str) -> Result<Uuid> {
-        lazy_static! {
-            static ref BOARD_ID_REGEX: Regex = Regex::new(r"^board/([^/]+)/.*$").unwrap();
+    /// Get a board's contents from memory, if boards are kept there and this one could be loaded
+    pub async fn get_cached_board(&self, board_id: Uuid) -> Option<Arc<CachedBoard>> {
+        self.board_caches.as_ref()?.get(self, board_id).await
+    }
+
+    /// Share each board's snapshot between the sessions that join it within `ttl` of each other,
+    /// as long as the board isn't checkpointed in between
+    pub fn with_snapshot_cache(self, ttl: Duration) -> Self {
+        Self {
+            snapshot_cache: Some(SnapshotCache::new(ttl)),
+            ..self
         }
+    }
 
-        Ok(BOARD_ID_REGEX
-            .captures(stream_key)
-            .ok_or_else(|| anyhow!("No UUID found in stream key"))?
-            .get(1)
-            .ok_or_else(|| anyhow!("No UUID found in stream key"))?
-            .as_str()
-            .parse::<Uuid>()?)
+    pub fn snapshot_cache(&self) -> Option<&SnapshotCache> {
+        self.snapshot_cache.as_ref()
     }
 
-    fn board_objects_key(board_id: Uuid) -> String {
-        format!("board/{board_id}/objects")
+    /// Forget the snapshot kept for a board, if any, after its contents were checkpointed
+    pub fn invalidate_snapshot(&self, board_id: Uuid) {
+        if let Some(snapshot_cache) = &self.snapshot_cache {
+            snapshot_cache.invalidate(board_id);
+        }
     }
 
-    fn board_version_key(board_id: Uuid) -> String {
-        format!("board/{board_id}/version")
+    /// Get a board's changes as they're added after `version`, from the one reader this server
+    /// keeps for the board. Also returns the last version that reader sent out, since anything up
+    /// to it has to be read separately.
+    pub fn subscribe_to_changes_for_board(
+        &self,
+        board_id: Uuid,
+        version: String,
+    ) -> (BroadcastReceiver<ChangeBatch>, String) {
+        self.change_readers.subscribe(self, board_id, version)
     }
 
-    fn board_presence_key(board_id: Uuid) -> String {
-        format!("board/{board_id}/presence")
+    /// Take a session that's gone for good off of a board, along with everything it left behind
+    /// that everyone else can see, which is its cursor and the objects it had locked. Otherwise
+    /// its cursor would stay on everyone's screen and its locks would hold up everyone until they
+    /// expired.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn remove_session_from_board(
+        &self,
+        board_id: Uuid,
+        session_id: Uuid,
+    ) -> RepositoryResult<()> {
+        let locks = self.get_object_locks_for_board(board_id).await?;
+        for (object_id, holder) in locks {
+            if holder == session_id {
+                self.unlock_object_for_board(board_id, session_id, object_id)
+                    .await?;
+            }
+        }
+        self.delete_session_cursor_for_board(board_id, session_id)
+            .await?;
+        self.delete_session_for_board(board_id, session_id).await?;
+        Ok(())
     }
 
-    fn board_changes_key(board_id: Uuid) -> String {
-        format!("board/{board_id}/changes")
+    /// Split a stream entry ID like `1660000000000-0` into its timestamp and sequence number so
+    /// that IDs can be compared. A bare `0` is accepted as the beginning of the stream.
+    pub fn parse_stream_id(stream_id: &str) -> Option<(u64, u64)> {
+        let (timestamp, sequence) = stream_id.split_once('-').unwrap_or((stream_id, "0"));
+        Some((timestamp.parse().ok()?, sequence.parse().ok()?))
     }
 
-    fn board_sessions_key(board_id: Uuid) -> String {
-        format!("board/{board_id}/sessions")
+    /// Get the revision of every object to go along with a snapshot of a board. Objects that
+    /// haven't been checkpointed since checkpointed revisions started being kept only have their
+    /// latest revision.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn get_snapshot_revisions_for_board(
+        &self,
+        board_id: Uuid,
+    ) -> RepositoryResult<Vec<(Uuid, u64)>> {
+        let mut revisions = self
+            .get_revisions_for_board(board_id)
+            .await?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        revisions.extend(self.get_checkpointed_revisions_for_board(board_id).await?);
+        Ok(revisions.into_iter().collect())
     }
 
-    fn session_checkin_key(session_id: Uuid) -> String {
-        format!("session/{session_id}/checkin")
+    /// Fill in every object that a change restores from the board's trash, so that everyone who
+    /// receives the change can show the objects again. Deletes only reach the trash once they're
+    /// checkpointed, so an object deleted since the last checkpoint can't be restored yet. Returns
+    /// the ID of an object that isn't in the trash, in which case the change can't be published.
+    #[tracing::instrument(skip(self, change), err)]
+    pub async fn fill_restored_objects(
+        &self,
+        board_id: Uuid,
+        change: &mut Change,
+    ) -> RepositoryResult<Option<Uuid>> {
+        if change.restored_object_ids().is_empty() {
+            return Ok(None);
+        }
+
+        let trash = self
+            .get_trash_for_board(board_id)
+            .await?
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        Ok(change.fill_restored_objects(&trash))
     }
 
-    /// The redis-rs client doesn't handle retries particularly well. Wrapping a Redis call with
-    /// this method enables retries in cases where I observed errors that seemed to be transient.
-    async fn with_redis_retry<F, T, O>(mut action: F) -> Result<T>
-    where
-        F: FnMut() -> O,
-        O: Future<Output = Result<T>>,
-    {
-        let mut retries = 5;
-        loop {
-            match action().await {
-                Ok(ret) => return Ok(ret),
-                Err(error) => {
-                    match error.downcast_ref::<RedisError>() {
-                        None => return Err(error),
-                        Some(redis_error) => match redis_error.kind() {
-                            // For some reason, sometimes a slow connection or the database getting
-                            // overloaded with too many connections would result in Redis returning
-                            // the wrong type for a key that should have correct data. Rust's types
-                            // help to prevent incorrect formats from being stored so my best
-                            // conclusion is sometimes Redis is unable to send the correct data, or
-                            // the client is unable to deal with that data correctly. When that
-                            // happened a retry would resolve the issue. If we ever had to start
-                            // being more flexible about stored formats this might be untenable but
-                            // for now it works.
-                            redis::ErrorKind::TypeError => {}
+    /// Find a ban that keeps a connection off of a board, given every way the connection can be
+    /// banned
+    #[tracing::instrument(skip(self), err)]
+    pub async fn get_ban_for_connection(
+        &self,
+        board_id: Uuid,
+        targets: &[BanTarget],
+    ) -> RepositoryResult<Option<Ban>> {
+        let ban = self
+            .get_bans_for_board(board_id)
+            .await?
+            .into_iter()
+            .find(|ban| targets.contains(&ban.target));
+        Ok(ban)
+    }
 
-                            // Sometimes Redis or the client just tells us directly to try again.
-                            redis::ErrorKind::TryAgain => {}
+    /// Check a change against the board's quotas before it's published. The object count includes
+    /// changes that haven't been checkpointed yet, each of which is only read and counted once
+    /// rather than for every change after it, and is only checked for changes that add objects so
+    /// that a board over its limit can always be cleaned up. Returns why the change can't be
+    /// published, if it can't.
+    #[tracing::instrument(skip(self, change), err)]
+    pub async fn check_quotas(
+        &self,
+        board_id: Uuid,
+        change: &Change,
+    ) -> RepositoryResult<Option<RejectionReason>> {
+        if let Some(max_size) = self.quotas.max_object_bytes {
+            let oversized = change
+                .payload_sizes()
+                .into_iter()
+                .find(|(_, size)| *size > max_size);
+            if let Some((id, size)) = oversized {
+                return Ok(Some(RejectionReason::ObjectTooLarge { id, size, max_size }));
+            }
+        }
 
-                            // Timeouts and dropped connections are typically transient
-                            redis::ErrorKind::ResponseError => {}
-                            _ if redis_error.is_timeout()
-                                || redis_error.is_connection_dropped() => {}
+        if let Some(max_objects) = self.quotas.max_objects {
+            let added = change.object_count_delta();
+            if added > 0 {
+                // Only the changes published since the last check are read
+                let version = self.get_version_for_board(board_id).await?;
+                let (counted, pending) = self.pending_counts.get(board_id, &version);
+                let entries = self
+                    .get_pending_changes_for_board(board_id, counted)
+                    .await?;
+                self.pending_counts.add(board_id, &version, &entries);
+                let pending = pending
+                    + entries
+                        .iter()
+                        .map(|entry| entry.change.object_count_delta())
+                        .sum::<i64>();
+                let count = self.get_object_count_for_board(board_id).await? as i64 + pending;
+                if count + added > max_objects as i64 {
+                    return Ok(Some(RejectionReason::TooManyObjects { max_objects }));
+                }
+            }
+        }
 
-                            // Otherwise, the error is real and should be propagated
-                            _ => return Err(error),
-                        },
-                    }
+        Ok(None)
+    }
+
+    /// Run a task while holding the named lock, renewing the lock in the background for as long as
+    /// the task runs and letting go of it afterwards. Returns nothing without running the task if
+    /// somebody else holds the lock. If the lock is lost partway through, because it couldn't be
+    /// renewed before it expired, the task is stopped where it is and nothing is returned either.
+    #[tracing::instrument(skip(self, task), err)]
+    pub async fn with_lock<T>(
+        &self,
+        name: &str,
+        holder: Uuid,
+        ttl: Duration,
+        task: impl Future<Output = RepositoryResult<T>>,
+    ) -> RepositoryResult<Option<T>> {
+        if !self.acquire_lock(name, holder, ttl).await? {
+            return Ok(None);
+        }
 
-                    // Try 5 times and then give up
-                    retries -= 1;
-                    if retries == 0 {
-                        return Err(error);
-                    }
+        let renewer = async {
+            let mut renewed_at = Instant::now();
+            loop {
+                tokio::time::sleep(ttl / 3).await;
+                match self.acquire_lock(name, holder, ttl).await {
+                    Ok(true) => renewed_at = Instant::now(),
+                    Ok(false) => return,
+                    Err(error) => tracing::warn!(%name, %error, "Could not renew lock"),
+                }
+                // Somebody else could have taken it once it expired
+                if renewed_at.elapsed() >= ttl {
+                    return;
                 }
             }
+        };
+
+        let result = tokio::select! {
+            result = task => result,
+            _ = renewer => {
+                tracing::warn!(%name, "Lost lock, stopping");
+                return Ok(None);
+            }
+        };
+        self.release_lock(name, holder).await?;
+
+        Ok(Some(result?))
+    }
+
+    /// Roll a board back to one of the checkpoints in its checkpoint history by publishing
+    /// whatever it takes to get from its current contents back to the checkpoint as a single
+    /// transaction, so that everyone on the board sees the rollback like any other change and it
+    /// stays in the board's history. The nil session ID means it didn't come from any session.
+    /// Returns the version after the rollback, or nothing if the checkpoint isn't in the history.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn restore_board_to_version(
+        &self,
+        board_id: Uuid,
+        version: &str,
+    ) -> RepositoryResult<Option<String>> {
+        let checkpoint = match self.get_checkpoint_for_board(board_id, version).await? {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(None),
+        };
+        let current = snapshot::read_snapshot(board_id, self).await?;
+
+        // Deleting an object takes it out of the stacking order too, and everything else is
+        // inserted whole over whatever is there now
+        let mut changes = Vec::new();
+        for id in current.objects.keys() {
+            if !checkpoint.objects.contains_key(id) {
+                changes.push(Change::Delete { id: *id });
+            }
+        }
+        for (id, object) in &checkpoint.objects {
+            if current.objects.get(id) != Some(object) {
+                changes.push(Change::Insert {
+                    id: *id,
+                    object: object.clone(),
+                });
+            }
+        }
+        let current_order = current.order.into_iter().collect::<HashMap<_, _>>();
+        for (id, index) in checkpoint.order {
+            if current_order.get(&id) != Some(&index) {
+                changes.push(Change::SetIndex { id, index });
+            }
+        }
+
+        if changes.is_empty() {
+            return Ok(Some(current.version));
+        }
+
+        let change = Change::Transaction { changes };
+        match self
+            .publish_change_for_board(board_id, Uuid::nil(), change, None)
+            .await?
+        {
+            PublishOutcome::Accepted { version, .. } | PublishOutcome::Duplicate { version } => {
+                Ok(Some(version))
+            }
+            PublishOutcome::RevisionMismatch { id, .. } => Err(RepositoryError::Conflict(format!(
+                "Object {id} was expected to be at a different revision"
+            ))),
         }
     }
 
-    /// Start the presence subscription loop. The presence Pub/Sub subscription runs in a background
-    /// task and forwards messages to an in-memory channel that can be more efficiently streamed by
-    /// each connected session.
-    #[tracing::instrument(skip_all)]
-    async fn start_presence(
-        pool: Pool<RedisConnectionManager>,
-        sender: BroadcastSender<(Uuid, PresenceMessage)>,
-    ) {
-        loop {
-            let _ = Self::run_presence(pool.clone(), sender.clone()).await;
-        }
-    }
-
-    /// Listen to messages on all presence channels and forward them into a tokio broadcast channel.
-    /// This approach allows each instance of the server to use only one dedicated connection for
-    /// subscribing to presence, reducing the number of connections used overall. The alternatives
-    /// are either much more complicated than is warranted for a hackathon, or else to open a new
-    /// subscription for every sesssion - potentially overloading the database with connections.
-    #[tracing::instrument(skip_all, err)]
-    async fn run_presence(
-        pool: Pool<RedisConnectionManager>,
-        sender: BroadcastSender<(Uuid, PresenceMessage)>,
-    ) -> Result<()> {
-        let dedicated_connection = pool.dedicated_connection().await?;
-        let mut pubsub = dedicated_connection.into_pubsub();
-        pubsub.psubscribe("board/*/presence").await?;
-        let mut stream = pubsub.into_on_message();
-        while let Some(msg) = stream.next().await {
-            let channel_name = msg.get_channel::<String>()?;
-            let board_id = Self::parse_board_id_from_key(channel_name.as_str())?;
-            let message = serde_json::from_slice::<PresenceMessage>(msg.get_payload_bytes())?;
-            let _ = sender.send((board_id, message));
+    /// Cross-check a board's version against its change stream and its objects against its
+    /// stacking order. With `repair`, whatever can be fixed is fixed by publishing a single change
+    /// from the nil session, the same way a rollback is, so everyone on the board sees the fix.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn verify_board(
+        &self,
+        board_id: Uuid,
+        repair: bool,
+    ) -> RepositoryResult<BoardVerification> {
+        let mut inconsistencies = Vec::new();
+
+        let version = self.get_version_for_board(board_id).await?;
+        let parsed_version = match Self::parse_stream_id(&version) {
+            Some(parsed_version) => parsed_version,
+            None => {
+                // Nothing past this point can be read without a usable version
+                return Ok(BoardVerification {
+                    inconsistencies: vec![BoardInconsistency::InvalidVersion { version }],
+                    repaired_version: None,
+                });
+            }
+        };
+
+        // An empty stream is left behind by boards that were duplicated or restored, and new
+        // changes always get IDs past the version anyway
+        let latest_version = self.get_latest_version_for_board(board_id).await?;
+        if latest_version != "0"
+            && matches!(Self::parse_stream_id(&latest_version), Some(latest) if latest < parsed_version)
+        {
+            inconsistencies.push(BoardInconsistency::VersionAheadOfChanges {
+                version,
+                latest_version,
+            });
         }
-        Ok(())
+
+        // Compare against the board as it will be once everything pending is checkpointed, so that
+        // a change that simply hasn't been checkpointed yet doesn't look like a problem
+        let current = snapshot::read_snapshot(board_id, self).await?;
+        let mut changes = Vec::new();
+        for (id, _) in current.order {
+            if !current.objects.contains_key(&id) {
+                inconsistencies.push(BoardInconsistency::OrderedWithoutObject { id });
+                changes.push(Change::Delete { id });
+            }
+        }
+
+        if inconsistencies.is_empty() {
+            return Ok(BoardVerification {
+                inconsistencies,
+                repaired_version: None,
+            });
+        }
+        tracing::warn!(%board_id, ?inconsistencies, "Board is inconsistent");
+        if !repair || changes.is_empty() {
+            return Ok(BoardVerification {
+                inconsistencies,
+                repaired_version: None,
+            });
+        }
+
+        // Deleting an object that doesn't exist only takes it out of the stacking order
+        let change = Change::Transaction { changes };
+        let repaired_version = match self
+            .publish_change_for_board(board_id, Uuid::nil(), change, None)
+            .await?
+        {
+            PublishOutcome::Accepted { version, .. } | PublishOutcome::Duplicate { version } => {
+                version
+            }
+            PublishOutcome::RevisionMismatch { id, .. } => {
+                return Err(RepositoryError::Conflict(format!(
+                    "Object {id} was expected to be at a different revision"
+                )));
+            }
+        };
+
+        Ok(BoardVerification {
+            inconsistencies,
+            repaired_version: Some(repaired_version),
+        })
+    }
+}
+
+impl Deref for Repository {
+    type Target = dyn BoardStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.store.as_ref()
     }
 }
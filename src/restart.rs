@@ -0,0 +1,95 @@
+use anyhow::Error;
+use std::time::{Duration, Instant};
+
+/// How a long running task is started again after it fails. Each restart in a row waits twice as
+/// long as the one before, up to the maximum, so a failure that doesn't go away turns into a slow
+/// trickle of retries and log lines rather than a busy loop.
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    /// How long to wait before the first restart
+    pub backoff_base: Duration,
+    /// The longest to ever wait between restarts
+    pub backoff_max: Duration,
+    /// A task that runs this long before failing is considered healthy again, and starts over
+    /// from the shortest wait
+    pub reset_after: Duration,
+    /// How many failures in a row before the task gives up, or never if empty
+    pub max_restarts: Option<u32>,
+}
+
+impl RestartPolicy {
+    /// For tasks that serve one connection, which are better off closing it so the client can
+    /// reconnect than retrying forever
+    pub const CONNECTION: Self = Self {
+        backoff_base: Duration::from_millis(50),
+        backoff_max: Duration::from_secs(5),
+        reset_after: Duration::from_secs(30),
+        max_restarts: Some(10),
+    };
+
+    /// For tasks that keep the whole server running, which have nothing better to do than keep
+    /// trying
+    pub const BACKGROUND: Self = Self {
+        backoff_base: Duration::from_secs(1),
+        backoff_max: Duration::from_secs(60),
+        reset_after: Duration::from_secs(5 * 60),
+        max_restarts: None,
+    };
+}
+
+/// Keeps track of how often a task has failed in a row
+pub struct Restarts {
+    /// What the task is called in the logs
+    task: &'static str,
+    policy: RestartPolicy,
+    failures: u32,
+    started_at: Instant,
+}
+
+impl Restarts {
+    pub fn new(task: &'static str, policy: RestartPolicy) -> Self {
+        Self {
+            task,
+            policy,
+            failures: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Log why the task stopped and wait before it's started again. Returns false once the task
+    /// has failed too many times in a row to keep trying.
+    pub async fn failed(&mut self, error: &Error) -> bool {
+        if self.started_at.elapsed() >= self.policy.reset_after {
+            self.failures = 0;
+        }
+        self.failures += 1;
+
+        if let Some(max_restarts) = self.policy.max_restarts {
+            if self.failures > max_restarts {
+                tracing::error!(
+                    task = self.task,
+                    failures = self.failures,
+                    error = format!("{error:#}"),
+                    "Giving up on task after too many failures in a row"
+                );
+                return false;
+            }
+        }
+
+        let backoff = self
+            .policy
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(self.policy.backoff_max);
+        tracing::warn!(
+            task = self.task,
+            failures = self.failures,
+            backoff_ms = backoff.as_millis() as u64,
+            error = format!("{error:#}"),
+            "Task failed, restarting"
+        );
+        tokio::time::sleep(backoff).await;
+        self.started_at = Instant::now();
+        true
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::Result;
+use futures::Future;
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_retry::strategy::jitter;
+
+use crate::repository::{RepositoryError, RepositoryResult};
+
+/// The kinds of store errors that are worth trying again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryClass {
+    /// The store didn't answer in time or the connection dropped
+    Timeout,
+    /// The store said something went wrong that usually goes away on its own, like a cluster
+    /// failing over
+    Transient,
+}
+
+/// How store calls that fail with a transient error are tried again, from the
+/// RETRY_MAX_ATTEMPTS, RETRY_BACKOFF_BASE_MS, RETRY_BACKOFF_MAX_MS, RETRY_JITTER_PERCENT, and
+/// RETRY_ON environment variables. Each retry waits twice as long as the one before, up to the
+/// maximum, so that a store that's struggling isn't hit by every caller again right away.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times a call is made in total before giving up, including the first
+    pub max_attempts: u32,
+    /// How long to wait before the first retry
+    pub backoff_base: Duration,
+    /// The longest to ever wait between attempts
+    pub backoff_max: Duration,
+    /// How much of each wait is random, so that callers that failed together don't all retry
+    /// together
+    pub jitter_percent: u32,
+    pub retry_on: Vec<RetryClass>,
+    metrics: Arc<RetryMetrics>,
+}
+
+#[derive(Debug, Default)]
+struct RetryMetrics {
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff_base: Duration::from_millis(10),
+            backoff_max: Duration::from_secs(1),
+            jitter_percent: 50,
+            retry_on: vec![RetryClass::Timeout, RetryClass::Transient],
+            metrics: Arc::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        let parse = |var: &str| {
+            env::var(var).ok().map(|value| {
+                value
+                    .parse::<u64>()
+                    .unwrap_or_else(|_| panic!("{var} must be a number"))
+            })
+        };
+        let default = Self::default();
+        let jitter_percent =
+            parse("RETRY_JITTER_PERCENT").map_or(default.jitter_percent, |percent| {
+                assert!(percent <= 100, "RETRY_JITTER_PERCENT must be at most 100");
+                percent as u32
+            });
+        let retry_on = env::var("RETRY_ON").ok().map(|classes| {
+            classes
+                .split(',')
+                .map(str::trim)
+                .filter(|class| !class.is_empty())
+                .map(|class| match class {
+                    "timeout" => RetryClass::Timeout,
+                    "transient" => RetryClass::Transient,
+                    class => panic!("RETRY_ON can only list timeout and transient, not {class}"),
+                })
+                .collect()
+        });
+        Self {
+            max_attempts: parse("RETRY_MAX_ATTEMPTS")
+                .map_or(default.max_attempts, |attempts| attempts.max(1) as u32),
+            backoff_base: parse("RETRY_BACKOFF_BASE_MS")
+                .map_or(default.backoff_base, Duration::from_millis),
+            backoff_max: parse("RETRY_BACKOFF_MAX_MS")
+                .map_or(default.backoff_max, Duration::from_millis),
+            jitter_percent,
+            retry_on: retry_on.unwrap_or(default.retry_on),
+            metrics: default.metrics,
+        }
+    }
+
+    /// Make a call until it succeeds, fails with an error that isn't worth retrying, or runs out
+    /// of attempts. Errors are sorted into classes when they're converted into a
+    /// `RepositoryError`.
+    pub async fn run<F, T, O>(&self, mut action: F) -> RepositoryResult<T>
+    where
+        F: FnMut() -> O,
+        O: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let error = match action().await {
+                Ok(ret) => return Ok(ret),
+                Err(error) => RepositoryError::from(error),
+            };
+            if !self.is_retryable(&error) {
+                return Err(error);
+            }
+            if attempt >= self.max_attempts {
+                self.metrics.exhausted.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(%error, attempts = attempt, "Giving up on the store");
+                return Err(error);
+            }
+
+            let delay = self.delay(attempt);
+            self.metrics.retries.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!(%error, attempt, ?delay, "Retrying");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// How many calls have been retried, and how many were given up on after running out of
+    /// attempts, since the server started
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.metrics.retries.load(Ordering::Relaxed),
+            self.metrics.exhausted.load(Ordering::Relaxed),
+        )
+    }
+
+    fn is_retryable(&self, error: &RepositoryError) -> bool {
+        let class = match error {
+            RepositoryError::Timeout(_) => RetryClass::Timeout,
+            RepositoryError::Backend {
+                transient: true, ..
+            } => RetryClass::Transient,
+            _ => return false,
+        };
+        self.retry_on.contains(&class)
+    }
+
+    /// How long to wait after the given attempt failed
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .backoff_base
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.backoff_max);
+        let random = backoff * self.jitter_percent / 100;
+        backoff - random + jitter(random)
+    }
+}
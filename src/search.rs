@@ -0,0 +1,58 @@
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::message::JsonObject;
+
+/// The properties whose text is searchable, like a textbox's `content`
+const TEXT_KEYS: &[&str] = &["content", "text", "title", "label"];
+
+/// Terms shorter than this aren't worth indexing
+const MIN_TERM_LENGTH: usize = 2;
+
+/// Longer terms are cut down to this many characters, both when indexing and searching
+const MAX_TERM_LENGTH: usize = 64;
+
+/// Only this many distinct terms are indexed for each object, so that pasting a novel into a
+/// textbox can't bloat the index
+const MAX_TERMS_PER_OBJECT: usize = 256;
+
+/// Searches only look through this many of the most recently changed objects that have the
+/// rarest of their terms
+pub const MAX_CANDIDATES: usize = 1000;
+
+/// An object that matched a search, along with the board it's on
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct SearchHit {
+    pub board_id: Uuid,
+    pub object_id: Uuid,
+    #[schema(value_type = Object)]
+    pub object: JsonObject,
+}
+
+/// Split text into lowercase words, the same way for objects and queries
+pub fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|character: char| !character.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TERM_LENGTH)
+        .map(|word| word.to_lowercase().chars().take(MAX_TERM_LENGTH).collect())
+        .collect()
+}
+
+/// The terms an object is found by
+pub fn object_terms(object: &JsonObject) -> BTreeSet<String> {
+    TEXT_KEYS
+        .iter()
+        .filter_map(|key| object.get(*key).and_then(JsonValue::as_str))
+        .flat_map(tokenize)
+        .take(MAX_TERMS_PER_OBJECT)
+        .collect()
+}
+
+/// Determine whether an object has every one of the terms
+pub fn matches(object: &JsonObject, terms: &BTreeSet<String>) -> bool {
+    let object_terms = object_terms(object);
+    terms.iter().all(|term| object_terms.contains(term))
+}
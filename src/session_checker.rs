@@ -1,44 +1,137 @@
 use std::time::Duration;
 
-use futures::TryStreamExt;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use uuid::Uuid;
 
-use crate::repository::Repository;
+use crate::repository::{Repository, RepositoryResult};
+use crate::restart::{RestartPolicy, Restarts};
+
+/// Only one server needs to look for stale sessions at a time
+const LOCK_NAME: &str = "session_checker";
+const LOCK_TTL: Duration = Duration::from_secs(30);
+
+/// How long to wait between looks through every board when the store says when sessions expire,
+/// which only has to catch the ones it didn't say anything about
+const FALLBACK_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 pub struct SessionChecker {
     repo: Repository,
+    /// How long to wait between looks for stale sessions when the store can't say when they
+    /// expire
+    interval: Duration,
+    id: Uuid,
 }
 
 impl SessionChecker {
     #[tracing::instrument(skip_all)]
-    pub fn new(repo: Repository) -> Self {
-        Self { repo }
+    pub fn new(repo: Repository, interval: Duration) -> Self {
+        Self {
+            repo,
+            interval,
+            id: Uuid::new_v4(),
+        }
     }
 
     #[tracing::instrument(skip_all)]
     pub async fn start(self) {
+        let mut restarts = Restarts::new("session_checker", RestartPolicy::BACKGROUND);
         loop {
-            self.run().await.ok();
+            if let Err(error) = self.run().await {
+                restarts.failed(&error).await;
+            }
         }
     }
 
+    /// Remove sessions as soon as the store says they expired if it can, and look through every
+    /// board for any that it didn't. Starting over after a failure looks through every board right
+    /// away, since sessions could have expired without a word in the meantime.
     #[tracing::instrument(skip(self), err)]
     async fn run(&self) -> Result<()> {
+        match self.repo.stream_expired_sessions().await? {
+            Some(expired_sessions) => {
+                tokio::try_join!(
+                    self.sweep(FALLBACK_INTERVAL),
+                    self.remove_expired_sessions(expired_sessions),
+                )?;
+            }
+            None => self.sweep(self.interval).await?,
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn sweep(&self, interval: Duration) -> Result<()> {
         loop {
-            let mut board_id_stream = self.repo.stream_all_board_ids().await;
-            while let Some(board_id) = board_id_stream.try_next().await? {
-                let session_ids = self.repo.get_sessions_for_board(board_id).await?;
-                for (session_id, _) in session_ids {
-                    let exists = self.repo.get_session_exists(session_id).await?;
-                    if !exists {
-                        self.repo
-                            .delete_session_for_board(board_id, session_id)
-                            .await?;
-                    }
+            self.repo
+                .with_lock(LOCK_NAME, self.id, LOCK_TTL, self.remove_stale_sessions())
+                .await?;
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn remove_expired_sessions(
+        &self,
+        mut expired_sessions: BoxStream<'static, (Uuid, Vec<Uuid>)>,
+    ) -> Result<()> {
+        while let Some((session_id, board_ids)) = expired_sessions.next().await {
+            for board_id in board_ids {
+                self.remove_session_if_stale(board_id, session_id).await?;
+            }
+        }
+        Err(anyhow!("Stopped hearing about expired sessions"))
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    async fn remove_stale_sessions(&self) -> RepositoryResult<()> {
+        let mut board_id_stream = self.repo.stream_all_board_ids().await;
+        while let Some(board_id) = board_id_stream.try_next().await? {
+            let session_ids = self.repo.get_sessions_for_board(board_id).await?;
+            for (session_id, _) in session_ids {
+                let exists = self.repo.get_session_exists(session_id).await?;
+                if !exists {
+                    self.repo
+                        .remove_session_from_board(board_id, session_id)
+                        .await?;
                 }
             }
-            tokio::time::sleep(Duration::from_secs(10)).await;
+
+            // Sessions that went away while waiting for a spot would hold up everyone behind them
+            let waiting_session_ids = self.repo.get_waiting_sessions_for_board(board_id).await?;
+            for session_id in waiting_session_ids {
+                let exists = self.repo.get_session_exists(session_id).await?;
+                if !exists {
+                    self.repo
+                        .leave_waiting_room_for_board(board_id, session_id)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take a session whose checkin expired off of a board and out of its line, wherever it is
+    #[tracing::instrument(skip(self), err)]
+    async fn remove_session_if_stale(&self, board_id: Uuid, session_id: Uuid) -> Result<()> {
+        // A session that checked in again since is still around
+        if self.repo.get_session_exists(session_id).await? {
+            return Ok(());
         }
+
+        let session_ids = self.repo.get_sessions_for_board(board_id).await?;
+        if session_ids.iter().any(|(id, _)| *id == session_id) {
+            self.repo
+                .remove_session_from_board(board_id, session_id)
+                .await?;
+        }
+        self.repo
+            .leave_waiting_room_for_board(board_id, session_id)
+            .await?;
+
+        Ok(())
     }
 }
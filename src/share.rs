@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Share links can be made to last up to a year, or forever
+pub const MAX_EXPIRES_IN_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+/// What someone who opens a board's websocket, through a share link or otherwise, can do with it
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+}
+
+/// The contents of a share token, which only grants access to the one board it was made for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareClaims {
+    pub board_id: Uuid,
+    pub role: Role,
+    /// Seconds since the Unix epoch, or empty for links that never expire
+    pub expires_at: Option<i64>,
+}
+
+/// Sign the claims into a token of the form `{payload}.{signature}`, both base64url encoded, where
+/// the signature is the HMAC-SHA256 of the encoded payload
+pub fn sign(claims: &ShareClaims, secret: &[u8]) -> Result<String> {
+    let payload = base64::encode_config(serde_json::to_vec(claims)?, base64::URL_SAFE_NO_PAD);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take a key of any size");
+    mac.update(payload.as_bytes());
+    let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+
+    Ok(format!("{payload}.{signature}"))
+}
+
+/// Check a token's signature and expiration and make sure it's for the given board
+pub fn verify(token: &str, board_id: Uuid, secret: &[u8]) -> Result<ShareClaims> {
+    let (payload, signature) = token
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Malformed share token"))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take a key of any size");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&base64::decode_config(signature, base64::URL_SAFE_NO_PAD)?)
+        .map_err(|_| anyhow!("Invalid share token signature"))?;
+
+    let claims = serde_json::from_slice::<ShareClaims>(&base64::decode_config(
+        payload,
+        base64::URL_SAFE_NO_PAD,
+    )?)?;
+    if claims.board_id != board_id {
+        return Err(anyhow!("Share token is for another board"));
+    }
+    if matches!(claims.expires_at, Some(expires_at) if expires_at <= Utc::now().timestamp()) {
+        return Err(anyhow!("Share token has expired"));
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn claims(board_id: Uuid, expires_at: Option<i64>) -> ShareClaims {
+        ShareClaims {
+            board_id,
+            role: Role::Editor,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn verifies_signed_token() {
+        let board_id = Uuid::new_v4();
+        let expires_at = Utc::now().timestamp() + 60;
+        let token = sign(&claims(board_id, Some(expires_at)), SECRET).unwrap();
+
+        let verified = verify(&token, board_id, SECRET).unwrap();
+        assert_eq!(verified.board_id, board_id);
+        assert_eq!(verified.role, Role::Editor);
+        assert_eq!(verified.expires_at, Some(expires_at));
+    }
+
+    #[test]
+    fn verifies_token_that_never_expires() {
+        let board_id = Uuid::new_v4();
+        let token = sign(&claims(board_id, None), SECRET).unwrap();
+
+        assert!(verify(&token, board_id, SECRET).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let board_id = Uuid::new_v4();
+        let expires_at = Utc::now().timestamp() - 1;
+        let token = sign(&claims(board_id, Some(expires_at)), SECRET).unwrap();
+
+        assert!(verify(&token, board_id, SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_token_for_another_board() {
+        let token = sign(&claims(Uuid::new_v4(), None), SECRET).unwrap();
+
+        assert!(verify(&token, Uuid::new_v4(), SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_token_signed_with_another_secret() {
+        let board_id = Uuid::new_v4();
+        let token = sign(&claims(board_id, None), b"other secret").unwrap();
+
+        assert!(verify(&token, board_id, SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let board_id = Uuid::new_v4();
+        let expires_at = Utc::now().timestamp() + 60;
+        let token = sign(&claims(board_id, Some(expires_at)), SECRET).unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+        let payload = base64::encode_config(
+            serde_json::to_vec(&claims(board_id, Some(i64::MAX))).unwrap(),
+            base64::URL_SAFE_NO_PAD,
+        );
+
+        assert!(verify(&format!("{payload}.{signature}"), board_id, SECRET).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_token() {
+        assert!(verify("not a token", Uuid::new_v4(), SECRET).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+use tokio::signal::unix::{self, SignalKind};
+use tokio::sync::mpsc::{
+    self, Receiver as MpscReceiver, Sender as MpscSender, WeakSender as WeakMpscSender,
+};
+use tokio::sync::watch::{self, Receiver as WatchReceiver, Sender as WatchSender};
+
+/// How long clients are told to wait before reconnecting, which leaves time for whatever replaces
+/// this server to start taking connections
+pub const RECONNECT_AFTER: Duration = Duration::from_secs(2);
+
+/// How long connections get to wrap up once the server starts shutting down, before it stops
+/// waiting for them
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Tells connections when the server is shutting down
+#[derive(Clone)]
+pub struct Shutdown {
+    shutdown_receiver: WatchReceiver<bool>,
+    /// Upgraded into a guard for each open connection, without holding anything open itself
+    open_sender: WeakMpscSender<()>,
+}
+
+/// Keeps the server waiting for a connection to wrap up until it's dropped
+pub struct ShutdownGuard {
+    _open_sender: MpscSender<()>,
+}
+
+impl Shutdown {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown_receiver.borrow()
+    }
+
+    /// Wait until the server starts shutting down
+    pub async fn wait(&mut self) {
+        // The trigger only goes away once the server is done, which counts as shutting down too
+        self.shutdown_receiver
+            .wait_for(|shutting_down| *shutting_down)
+            .await
+            .ok();
+    }
+
+    /// Have the server wait for the connection holding the guard when it shuts down
+    pub fn guard(&self) -> Option<ShutdownGuard> {
+        Some(ShutdownGuard {
+            _open_sender: self.open_sender.upgrade()?,
+        })
+    }
+}
+
+/// Starts shutting the server down and waits for its connections to finish
+pub struct ShutdownTrigger {
+    shutdown_sender: WatchSender<bool>,
+    open_sender: MpscSender<()>,
+    open_receiver: MpscReceiver<()>,
+}
+
+impl Default for ShutdownTrigger {
+    fn default() -> Self {
+        let (open_sender, open_receiver) = mpsc::channel(1);
+        Self {
+            shutdown_sender: watch::channel(false).0,
+            open_sender,
+            open_receiver,
+        }
+    }
+}
+
+impl ShutdownTrigger {
+    /// Get a handle for connections to find out about the shutdown through
+    pub fn shutdown(&self) -> Shutdown {
+        Shutdown {
+            shutdown_receiver: self.shutdown_sender.subscribe(),
+            open_sender: self.open_sender.downgrade(),
+        }
+    }
+
+    /// Tell every connection that the server is shutting down, and wait for all of them to close
+    /// or for the grace period to run out, whichever comes first
+    #[tracing::instrument(skip_all)]
+    pub async fn trigger(self) {
+        let Self {
+            shutdown_sender,
+            open_sender,
+            mut open_receiver,
+        } = self;
+        drop(open_sender);
+        shutdown_sender.send_replace(true);
+
+        // Nothing is ever sent, so this only returns once every guard has been dropped
+        if tokio::time::timeout(GRACE_PERIOD, open_receiver.recv())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Gave up waiting for connections to close");
+        }
+    }
+}
+
+/// Wait for SIGTERM or SIGINT
+pub async fn signal() {
+    let mut terminate =
+        unix::signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
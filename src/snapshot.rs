@@ -0,0 +1,280 @@
+use anyhow::Result;
+use futures::stream::TryStreamExt;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::board_cache::CachedBoard;
+use crate::change::Change;
+use crate::message::{JsonObject, ServerMessage};
+use crate::repository::Repository;
+use crate::socket::SocketSender;
+use crate::spatial::Rect;
+
+/// Send the full materialized contents of a board to the client in chunks, then its stacking
+/// order and object revisions, followed by a SnapshotFinished message. With a viewport, the
+/// objects in it are sent first so that the client can draw them while the rest load. Returns the
+/// version of the board that the snapshot was taken at so the caller can start streaming changes
+/// from there. Archived boards are restored first.
+#[tracing::instrument(skip(repo, socket_sender), err)]
+pub async fn send_snapshot(
+    board_id: Uuid,
+    repo: &Repository,
+    socket_sender: &SocketSender,
+    viewport: Option<Rect>,
+) -> Result<String> {
+    repo.restore_board(board_id).await?;
+    if let Some(board) = repo.get_cached_board(board_id).await {
+        return send_cached_snapshot(&board, socket_sender, viewport).await;
+    }
+    let version = repo.get_version_for_board(board_id).await?;
+
+    let mut visible_ids = HashSet::new();
+    if let Some(viewport) = viewport {
+        let visible = repo.get_objects_in_rect(board_id, viewport).await?;
+        visible_ids.extend(visible.iter().map(|(id, _)| *id));
+        for entries in visible.chunks(100) {
+            socket_sender
+                .send(ServerMessage::SnapshotChunk {
+                    entries: entries.to_vec(),
+                })
+                .await?;
+        }
+    }
+
+    let mut object_ids = visible_ids.iter().copied().collect::<Vec<_>>();
+    let cached = repo
+        .snapshot_cache()
+        .and_then(|snapshot_cache| snapshot_cache.get(board_id, &version));
+    let order = match cached {
+        Some(cached) => {
+            for entries in &cached.chunks {
+                send_remaining_chunk(
+                    entries.clone(),
+                    &visible_ids,
+                    &mut object_ids,
+                    socket_sender,
+                )
+                .await?;
+            }
+            cached.order.clone()
+        }
+        None => {
+            // Keep the chunks as they were read, so that the next session can be sent them no
+            // matter which part of the board it's looking at
+            let mut chunks = Vec::new();
+            let mut chunks_stream = repo.stream_object_chunks_for_board(board_id).await;
+            while let Some(entries) = chunks_stream.try_next().await? {
+                if repo.snapshot_cache().is_some() {
+                    chunks.push(entries.clone());
+                }
+                send_remaining_chunk(entries, &visible_ids, &mut object_ids, socket_sender).await?;
+            }
+            let order = repo.get_order_for_board(board_id).await?;
+            // Objects read while the checkpointer was writing could be from either version
+            if let Some(snapshot_cache) = repo.snapshot_cache() {
+                if repo.get_version_for_board(board_id).await? == version {
+                    snapshot_cache.put(board_id, version.clone(), chunks, order.clone());
+                }
+            }
+            order
+        }
+    };
+    socket_sender
+        .send(ServerMessage::SnapshotOrder { order })
+        .await?;
+
+    let revisions = repo.get_snapshot_revisions_for_board(board_id).await?;
+    socket_sender
+        .send(ServerMessage::SnapshotRevisions { revisions })
+        .await?;
+
+    socket_sender
+        .send(ServerMessage::SnapshotFinished {
+            version: Some(version.clone()),
+            object_count: object_ids.len(),
+            hash: snapshot_hash(object_ids),
+        })
+        .await?;
+
+    Ok(version)
+}
+
+/// Send the objects in a chunk that weren't already sent because they're in the viewport
+async fn send_remaining_chunk(
+    entries: Vec<(Uuid, JsonObject)>,
+    visible_ids: &HashSet<Uuid>,
+    object_ids: &mut Vec<Uuid>,
+    socket_sender: &SocketSender,
+) -> Result<()> {
+    let entries = entries
+        .into_iter()
+        .filter(|(id, _)| !visible_ids.contains(id))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(());
+    }
+    object_ids.extend(entries.iter().map(|(id, _)| *id));
+    socket_sender
+        .send(ServerMessage::SnapshotChunk { entries })
+        .await?;
+    Ok(())
+}
+
+/// Send a snapshot the same way as `send_snapshot`, from a board this server keeps in memory
+async fn send_cached_snapshot(
+    board: &CachedBoard,
+    socket_sender: &SocketSender,
+    viewport: Option<Rect>,
+) -> Result<String> {
+    let is_visible = |object: &JsonObject| {
+        matches!(
+            (viewport, Rect::of_object(object)),
+            (Some(viewport), Some(bounds)) if bounds.intersects(&viewport)
+        )
+    };
+    let (visible, rest): (Vec<_>, Vec<_>) = board
+        .objects
+        .iter()
+        .map(|(id, object)| (*id, object.clone()))
+        .partition(|(_, object)| is_visible(object));
+    for entries in visible.chunks(100).chain(rest.chunks(100)) {
+        socket_sender
+            .send(ServerMessage::SnapshotChunk {
+                entries: entries.to_vec(),
+            })
+            .await?;
+    }
+
+    let mut order = board
+        .order
+        .iter()
+        .map(|(id, index)| (*id, *index))
+        .collect::<Vec<_>>();
+    order.sort_by(|(_, left), (_, right)| left.total_cmp(right));
+    socket_sender
+        .send(ServerMessage::SnapshotOrder { order })
+        .await?;
+
+    let revisions = board
+        .revisions
+        .iter()
+        .map(|(id, revision)| (*id, *revision))
+        .collect();
+    socket_sender
+        .send(ServerMessage::SnapshotRevisions { revisions })
+        .await?;
+
+    socket_sender
+        .send(ServerMessage::SnapshotFinished {
+            version: Some(board.version.clone()),
+            object_count: board.objects.len(),
+            hash: snapshot_hash(board.objects.keys().copied().collect()),
+        })
+        .await?;
+
+    Ok(board.version.clone())
+}
+
+/// Summarize the set of objects sent in a snapshot so the client can check that it received every
+/// chunk. The hash is the hex SHA-256 of the sorted object IDs, each followed by a newline, which
+/// is easy to reproduce with SubtleCrypto in the browser.
+fn snapshot_hash(mut object_ids: Vec<Uuid>) -> String {
+    object_ids.sort();
+    let mut hasher = Sha256::new();
+    for object_id in object_ids {
+        hasher.update(format!("{object_id}\n"));
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// The complete contents of a board as of a particular version, for consumers that want the whole
+/// thing at once rather than a snapshot followed by a stream of changes
+#[derive(Serialize, ToSchema, Debug, Clone)]
+pub struct BoardSnapshot {
+    pub version: String,
+    #[schema(value_type = Object)]
+    pub objects: BTreeMap<Uuid, JsonObject>,
+    /// Object ID - index pairs, back to front
+    #[schema(value_type = Vec<Object>)]
+    pub order: Vec<(Uuid, f64)>,
+}
+
+/// Read the materialized contents of a board and bring them up to date with any changes that the
+/// checkpointer hasn't gotten to yet, restoring the board first if it's archived
+#[tracing::instrument(skip(repo), err)]
+pub async fn read_snapshot(board_id: Uuid, repo: &Repository) -> Result<BoardSnapshot> {
+    repo.restore_board(board_id).await?;
+    let mut version = repo.get_version_for_board(board_id).await?;
+
+    let mut objects = BTreeMap::new();
+    let mut chunks_stream = repo.stream_object_chunks_for_board(board_id).await;
+    while let Some(entries) = chunks_stream.try_next().await? {
+        objects.extend(entries);
+    }
+
+    let mut order = repo
+        .get_order_for_board(board_id)
+        .await?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    // Apply the pending changes the same way the checkpointer would
+    for entry in repo
+        .get_pending_changes_for_board(board_id, version.clone())
+        .await?
+    {
+        for change in entry.change.flatten() {
+            apply_change(&mut objects, &mut order, change);
+        }
+        version = entry.version;
+    }
+
+    let mut order = order.into_iter().collect::<Vec<_>>();
+    order.sort_by(|(_, left), (_, right)| left.total_cmp(right));
+
+    Ok(BoardSnapshot {
+        version,
+        objects,
+        order,
+    })
+}
+
+/// Apply a flattened change to a board's objects and stacking order the same way the checkpointer
+/// would
+pub fn apply_change(
+    objects: &mut BTreeMap<Uuid, JsonObject>,
+    order: &mut HashMap<Uuid, f64>,
+    change: Change,
+) {
+    match change {
+        Change::Insert { id, object } | Change::Replace { id, object, .. } => {
+            objects.insert(id, object);
+        }
+        Change::Update { id, key, value } => {
+            if let Some(object) = objects.get_mut(&id) {
+                object.insert(key, value);
+            }
+        }
+        Change::Delete { id } => {
+            objects.remove(&id);
+            order.remove(&id);
+        }
+        Change::SetIndex { id, index } => {
+            order.insert(id, index);
+        }
+        Change::RestoreObject { id, object, index } => {
+            if let Some(object) = object {
+                objects.insert(id, object);
+            }
+            if let Some(index) = index {
+                order.insert(id, index);
+            }
+        }
+        Change::Transaction { .. } => {
+            unreachable!("Transactions are flattened before being applied")
+        }
+    }
+}
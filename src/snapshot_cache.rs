@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::message::JsonObject;
+
+/// How many boards' snapshots are kept at once, after which the oldest is dropped to make room
+const MAX_BOARDS: usize = 64;
+
+/// The checkpointed contents of a board as they were read for a snapshot, in the chunks they were
+/// read in
+#[derive(Debug)]
+pub struct CachedSnapshot {
+    /// The checkpointed version the objects were read at
+    pub version: String,
+    pub chunks: Vec<Vec<(Uuid, JsonObject)>>,
+    pub order: Vec<(Uuid, f64)>,
+    cached_at: Instant,
+}
+
+/// The snapshots this server read most recently, so that sessions joining the same board one
+/// after another share a single read of its objects from the store. A snapshot is only used while
+/// the board is still at the version it was read at, so checkpointing the board anywhere replaces
+/// it, and checkpoints made here drop it right away.
+#[derive(Clone)]
+pub struct SnapshotCache {
+    entries: Arc<Mutex<HashMap<Uuid, Arc<CachedSnapshot>>>>,
+    /// How long a snapshot is used for after it was read
+    ttl: Duration,
+}
+
+impl SnapshotCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// Get a board's snapshot if one was read at `version` recently enough
+    pub fn get(&self, board_id: Uuid, version: &str) -> Option<Arc<CachedSnapshot>> {
+        let mut entries = self.lock();
+        let snapshot = entries.get(&board_id)?;
+        if snapshot.cached_at.elapsed() >= self.ttl {
+            entries.remove(&board_id);
+            return None;
+        }
+        (snapshot.version == version).then(|| snapshot.clone())
+    }
+
+    pub fn put(
+        &self,
+        board_id: Uuid,
+        version: String,
+        chunks: Vec<Vec<(Uuid, JsonObject)>>,
+        order: Vec<(Uuid, f64)>,
+    ) {
+        let mut entries = self.lock();
+        if entries.len() >= MAX_BOARDS && !entries.contains_key(&board_id) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, snapshot)| snapshot.cached_at)
+                .map(|(board_id, _)| *board_id);
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            board_id,
+            Arc::new(CachedSnapshot {
+                version,
+                chunks,
+                order,
+                cached_at: Instant::now(),
+            }),
+        );
+    }
+
+    /// Forget a board's snapshot, because the board was just checkpointed
+    pub fn invalidate(&self, board_id: Uuid) {
+        self.lock().remove(&board_id);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, Arc<CachedSnapshot>>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
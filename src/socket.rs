@@ -1,58 +1,282 @@
 use anyhow::{Error, Result};
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use futures::{
     sink::SinkExt,
     stream::{SplitSink, SplitStream, Stream, StreamExt},
 };
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use std::{error::Error as _, pin::Pin, sync::Arc};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{
+    self, error::SendTimeoutError, Receiver as MpscReceiver, Sender as MpscSender,
+};
+use tokio::sync::watch::{self, Sender as WatchSender};
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use uuid::Uuid;
 
 use crate::message::{ClientMessage, ServerMessage};
 
+/// Close frames only have room for this much of a reason after the close code
+const MAX_CLOSE_REASON_BYTES: usize = 123;
+
+/// How many frames can wait to be written to a socket before whoever sends the next one has to
+/// wait for room
+const QUEUE_CAPACITY: usize = 256;
+
+/// How long a frame waits for room in a full queue before the client is considered too slow to
+/// keep up and is disconnected
+const SATURATED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a frame that only matters until the next one like it is about, so a slow client can be
+/// sent just the latest one
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum CoalesceKey {
+    Cursor { board_id: Uuid, session_id: Uuid },
+    Latency { board_id: Uuid, session_id: Uuid },
+}
+
+impl CoalesceKey {
+    fn for_message(board_id: Uuid, message: &ServerMessage) -> Option<Self> {
+        match message {
+            ServerMessage::UserCursorChanged { session_id, .. }
+            | ServerMessage::UserCursorLeft { session_id } => Some(Self::Cursor {
+                board_id,
+                session_id: *session_id,
+            }),
+            ServerMessage::UserLatencyChanged { session_id, .. } => Some(Self::Latency {
+                board_id,
+                session_id: *session_id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The latest frame for each `CoalesceKey` that hasn't been written yet
+#[derive(Default)]
+struct Coalesced {
+    frames: Mutex<HashMap<CoalesceKey, Message>>,
+    notify: Notify,
+}
+
+impl Coalesced {
+    fn put(&self, key: CoalesceKey, frame: Message) {
+        self.frames
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .insert(key, frame);
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> Vec<Message> {
+        self.frames
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .drain()
+            .map(|(_, frame)| frame)
+            .collect()
+    }
+}
+
+/// Every message sent to the client is tagged with the board it's about, since a single socket
+/// may be subscribed to several boards at once
+#[derive(Serialize)]
+struct TaggedServerMessage {
+    board_id: Uuid,
+    #[serde(flatten)]
+    message: ServerMessage,
+}
+
+/// Sends messages to a client through a queue, which a task of its own writes to the socket, so
+/// that a slow client only holds up whoever is sending to it once the queue is full. Cursor and
+/// latency updates skip the queue and are merged while they wait, so a slow client gets the
+/// latest ones rather than falling further behind. Everything else waits for room, and if there
+/// isn't any for `SATURATED_TIMEOUT` the connection is cut.
 #[derive(Clone)]
 pub struct SocketSender {
-    inner: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-    closed: Arc<Mutex<bool>>,
+    board_id: Uuid,
+    queue: MpscSender<Message>,
+    coalesced: Arc<Coalesced>,
+    closed: Arc<AtomicBool>,
+    saturated: Arc<WatchSender<bool>>,
+    writer: AbortHandle,
 }
 
 impl SocketSender {
-    #[tracing::instrument(skip_all)]
-    pub fn new(socket_sink: SplitSink<WebSocket, Message>) -> Self {
+    #[tracing::instrument(skip(socket_sink))]
+    pub fn new(board_id: Uuid, socket_sink: SplitSink<WebSocket, Message>) -> Self {
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let coalesced = Arc::new(Coalesced::default());
+        let writer = tokio::task::spawn(write(socket_sink, receiver, coalesced.clone()));
         Self {
-            inner: Arc::new(Mutex::new(socket_sink)),
-            closed: Arc::new(Mutex::new(false)),
+            board_id,
+            queue,
+            coalesced,
+            closed: Arc::new(AtomicBool::new(false)),
+            saturated: Arc::new(watch::channel(false).0),
+            writer: writer.abort_handle(),
+        }
+    }
+
+    /// Get a sender for the same socket that tags its messages with a different board
+    #[tracing::instrument(skip(self))]
+    pub fn for_board(&self, board_id: Uuid) -> Self {
+        Self {
+            board_id,
+            queue: self.queue.clone(),
+            coalesced: self.coalesced.clone(),
+            closed: self.closed.clone(),
+            saturated: self.saturated.clone(),
+            writer: self.writer.clone(),
         }
     }
 
     #[tracing::instrument(skip_all)]
-    pub async fn close(&self) {
-        let mut closed = self.closed.lock().await;
-        *closed = true;
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
     }
 
+    /// Wait until the client has fallen so far behind that its connection was cut
+    pub async fn wait_saturated(&self) {
+        self.saturated
+            .subscribe()
+            .wait_for(|saturated| *saturated)
+            .await
+            .ok();
+    }
+
+    /// Ask the client to close the connection, and stop sending it anything else
     #[tracing::instrument(skip_all, err)]
-    pub async fn send(&self, message: ServerMessage) -> Result<()> {
-        let closed = self.closed.lock().await;
-        if *closed {
+    pub async fn disconnect(&self) -> Result<()> {
+        self.close_with(None).await
+    }
+
+    /// Close the connection because the client sent a message bigger than it's allowed to
+    #[tracing::instrument(skip_all, err)]
+    pub async fn disconnect_too_large(&self) -> Result<()> {
+        self.close_with(Some(CloseFrame {
+            code: close_code::SIZE,
+            reason: "Message too large".into(),
+        }))
+        .await
+    }
+
+    /// Close the connection because the session was kicked or banned, passing along why
+    #[tracing::instrument(skip_all, err)]
+    pub async fn disconnect_kicked(&self, reason: Option<String>) -> Result<()> {
+        let mut reason = reason.unwrap_or_default();
+        while reason.len() > MAX_CLOSE_REASON_BYTES {
+            reason.pop();
+        }
+        self.close_with(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.into(),
+        }))
+        .await
+    }
+
+    async fn close_with(&self, close_frame: Option<CloseFrame<'static>>) -> Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
             return Ok(());
         }
+        self.enqueue(Message::Close(close_frame)).await;
+        Ok(())
+    }
 
-        let mut sink = self.inner.lock().await;
-        match sink
-            .send(Message::Text(serde_json::to_string(&message)?))
-            .await
-            .map_err(From::from)
-        {
-            Ok(()) => Ok(()),
-            Err(error) if is_broken_connection_error(&error) => Ok(()),
-            Err(error) => Err(error),
+    #[tracing::instrument(skip_all, err)]
+    pub async fn send(&self, message: ServerMessage) -> Result<()> {
+        let coalesce_key = CoalesceKey::for_message(self.board_id, &message);
+        let frame = Message::Text(serde_json::to_string(&TaggedServerMessage {
+            board_id: self.board_id,
+            message,
+        })?);
+        match coalesce_key {
+            Some(_) if self.closed.load(Ordering::SeqCst) => Ok(()),
+            Some(coalesce_key) => {
+                self.coalesced.put(coalesce_key, frame);
+                Ok(())
+            }
+            None => self.send_frame(frame).await,
+        }
+    }
+
+    /// Send a websocket ping, which proxies count as traffic even when nothing else is happening
+    #[tracing::instrument(skip_all, err)]
+    pub async fn ping(&self) -> Result<()> {
+        self.send_frame(Message::Ping(Vec::new())).await
+    }
+
+    /// Answer a websocket ping from the client with the same payload
+    #[tracing::instrument(skip_all, err)]
+    pub async fn pong(&self, payload: Vec<u8>) -> Result<()> {
+        self.send_frame(Message::Pong(payload)).await
+    }
+
+    async fn send_frame(&self, frame: Message) -> Result<()> {
+        if !self.closed.load(Ordering::SeqCst) {
+            self.enqueue(frame).await;
+        }
+        Ok(())
+    }
+
+    /// Wait for room in the queue, and cut the connection if there isn't any in time
+    async fn enqueue(&self, frame: Message) {
+        match self.queue.send_timeout(frame, SATURATED_TIMEOUT).await {
+            Ok(()) => {}
+            // The writer only stops once the connection is closed or broken
+            Err(SendTimeoutError::Closed(_)) => {}
+            Err(SendTimeoutError::Timeout(_)) => {
+                tracing::warn!("Disconnecting a client that can't keep up with its messages");
+                self.closed.store(true, Ordering::SeqCst);
+                self.writer.abort();
+                self.saturated.send_replace(true);
+            }
+        }
+    }
+}
+
+/// Write everything sent to a client to its socket until the connection is closed, cursor and
+/// latency updates only once nothing else is waiting
+#[tracing::instrument(skip_all)]
+async fn write(
+    mut socket_sink: SplitSink<WebSocket, Message>,
+    mut receiver: MpscReceiver<Message>,
+    coalesced: Arc<Coalesced>,
+) {
+    loop {
+        let frames = tokio::select! {
+            biased;
+            frame = receiver.recv() => match frame {
+                Some(frame) => vec![frame],
+                None => return,
+            },
+            _ = coalesced.notify.notified() => coalesced.take(),
+        };
+        for frame in frames {
+            let is_close = matches!(frame, Message::Close(_));
+            if let Err(error) = socket_sink.send(frame).await.map_err(Error::from) {
+                if !is_broken_connection_error(&error) {
+                    tracing::warn!(?error, "Failed to write to socket");
+                }
+                return;
+            }
+            if is_close {
+                return;
+            }
         }
     }
 }
 
 pub enum SocketMessage {
     Data(ClientMessage),
-    Ping,
+    /// A websocket ping, with the payload the pong has to echo
+    Ping(Vec<u8>),
+    /// A message bigger than the limit the stream was given, which was thrown away unread
+    TooLarge,
     Close,
     Unknown,
 }
@@ -62,19 +286,38 @@ pub struct SocketStream {
 }
 
 impl SocketStream {
+    /// Messages longer than `max_message_bytes` come out as `TooLarge`, whether the socket caught
+    /// them first or not
     #[tracing::instrument(skip_all)]
-    pub fn new(socket_stream: SplitStream<WebSocket>) -> Self {
+    pub fn new(socket_stream: SplitStream<WebSocket>, max_message_bytes: Option<usize>) -> Self {
+        let is_too_large =
+            move |length: usize| matches!(max_message_bytes, Some(max) if length > max);
         Self {
-            inner: Box::pin(socket_stream.map(|message_result| {
-                let message = message_result?;
+            inner: Box::pin(socket_stream.map(move |message_result| {
+                let message = match message_result.map_err(Error::from) {
+                    Ok(message) => message,
+                    Err(error) if is_too_large_error(&error) => return Ok(SocketMessage::TooLarge),
+                    Err(error) => return Err(error),
+                };
                 match message {
+                    Message::Text(text) if is_too_large(text.len()) => Ok(SocketMessage::TooLarge),
+                    Message::Binary(bytes) if is_too_large(bytes.len()) => {
+                        Ok(SocketMessage::TooLarge)
+                    }
                     Message::Close(_) => Ok(SocketMessage::Close),
-                    Message::Ping(_) => Ok(SocketMessage::Ping),
+                    Message::Ping(payload) => Ok(SocketMessage::Ping(payload)),
                     Message::Text(text) => {
                         Ok(SocketMessage::Data(serde_json::from_str::<ClientMessage>(
                             text.as_str(),
                         )?))
                     }
+                    // Clients that would rather not encode JSON can send the same messages as
+                    // MessagePack maps
+                    Message::Binary(bytes) => {
+                        Ok(SocketMessage::Data(rmp_serde::from_slice::<ClientMessage>(
+                            &bytes,
+                        )?))
+                    }
                     _ => Ok(SocketMessage::Unknown),
                 }
             })),
@@ -103,3 +346,13 @@ pub fn is_broken_connection_error(error: &Error) -> bool {
         .map(|actual_error| matches!(actual_error.kind(), std::io::ErrorKind::BrokenPipe))
         .unwrap_or_default()
 }
+
+/// Whether the socket gave up on a message because it was over the size limit set on the upgrade
+fn is_too_large_error(error: &Error) -> bool {
+    error
+        .downcast_ref::<axum::Error>()
+        .and_then(|error| error.source())
+        .and_then(|error| error.downcast_ref::<tungstenite::Error>())
+        .map(|actual_error| matches!(actual_error, tungstenite::Error::Capacity(_)))
+        .unwrap_or_default()
+}
@@ -0,0 +1,42 @@
+use axum::{
+    http::{header, HeaderValue, Request},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use axum_extra::routing::SpaRouter;
+use std::path::Path;
+
+/// Where the built client's hashed assets are served from
+const ASSETS_PATH: &str = "/assets";
+
+/// Serve the built client from a directory laid out the way Vite builds it, with hashed assets
+/// under `assets` and the index file served for every path that isn't a route or an asset
+pub fn router(static_dir: &str, index_file: &str) -> Router {
+    let assets_dir = Path::new(static_dir).join("assets");
+    let spa_router: Router = SpaRouter::new(ASSETS_PATH, assets_dir)
+        .index_file(Path::new("..").join(index_file))
+        .into();
+    spa_router.layer(middleware::from_fn(set_cache_headers))
+}
+
+/// Assets have a hash of their contents in their names, so they can be cached forever. The index
+/// file refers to the current assets, so it has to be checked every time.
+async fn set_cache_headers<B>(request: Request<B>, next: Next<B>) -> Response {
+    let is_asset = request.uri().path().starts_with(&format!("{ASSETS_PATH}/"));
+    let mut response = next.run(request).await;
+
+    if response.status().is_success() {
+        let cache_control = if is_asset {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        };
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(cache_control),
+        );
+    }
+
+    response
+}
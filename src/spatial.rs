@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::message::JsonObject;
+
+/// Boards are split into square cells this many units across for the spatial index, which records
+/// every cell that an object's bounds touch
+pub const CELL_SIZE: f64 = 512.0;
+
+/// Objects that touch more cells than this are indexed under `OVERSIZED_CELL` instead, so that one
+/// huge object can't bloat the index
+const MAX_OBJECT_CELLS: usize = 64;
+
+/// The cell that every query looks in on top of the ones it covers
+pub const OVERSIZED_CELL: &str = "*";
+
+/// Viewports that cover more cells than this are answered by checking the bounds of every object
+/// instead of looking up each cell
+pub const MAX_QUERY_CELLS: usize = 1024;
+
+/// An axis-aligned rectangle in board coordinates
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+}
+
+impl Rect {
+    /// The area an object covers on the board, from its position and the size of its type, the
+    /// same as it's drawn. Objects without a position or a size have no bounds.
+    pub fn of_object(object: &JsonObject) -> Option<Self> {
+        let number = |key: &str| object.get(key).and_then(JsonValue::as_f64);
+        let position = object.get("position")?;
+        let x = position.get("x")?.as_f64()?;
+        let y = position.get("y")?.as_f64()?;
+
+        let (width, height) = match object.get("type")?.as_str()? {
+            "Square" | "Star" | "Triangle" => (number("size")?, number("size")?),
+            "Circle" => (number("radius")? * 2.0, number("radius")? * 2.0),
+            "Textbox" => (number("width")?, number("height")?),
+            _ => return None,
+        };
+
+        Some(Self {
+            min_x: x,
+            min_y: y,
+            max_x: x + width,
+            max_y: y + height,
+        })
+    }
+
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.min_x <= other.max_x
+            && other.min_x <= self.max_x
+            && self.min_y <= other.max_y
+            && other.min_y <= self.max_y
+    }
+
+    /// The cells of the spatial index that the rectangle touches, named `{x}:{y}` by their
+    /// position in the grid, or nothing if there are more than `max_cells` of them
+    pub fn cells(&self, max_cells: usize) -> Option<Vec<String>> {
+        let (min_x, min_y) = (cell_of(self.min_x), cell_of(self.min_y));
+        let (max_x, max_y) = (cell_of(self.max_x), cell_of(self.max_y));
+
+        // Counted as floats so that absurd coordinates can't overflow, and NaN fails the check
+        let count = (max_x - min_x + 1.0).max(0.0) * (max_y - min_y + 1.0).max(0.0);
+        if count.is_nan() || count > max_cells as f64 {
+            return None;
+        }
+
+        let mut cells = Vec::with_capacity(count as usize);
+        for y in min_y as i64..=max_y as i64 {
+            for x in min_x as i64..=max_x as i64 {
+                cells.push(format!("{x}:{y}"));
+            }
+        }
+        Some(cells)
+    }
+
+    /// The cells an object with these bounds is indexed under
+    pub fn object_cells(&self) -> Vec<String> {
+        self.cells(MAX_OBJECT_CELLS)
+            .unwrap_or_else(|| vec![OVERSIZED_CELL.to_string()])
+    }
+}
+
+fn cell_of(coordinate: f64) -> f64 {
+    (coordinate / CELL_SIZE).floor()
+}
@@ -0,0 +1,291 @@
+//! Checks that every `BoardStore` has to pass, so that the stores behave the same no matter which
+//! one a deployment runs on. Each store's tests call these with a fresh store.
+
+use futures::TryStreamExt;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::change::Change;
+use crate::checkpointer::Checkpointer;
+use crate::message::JsonObject;
+use crate::repository::{ClientInfo, PublishOutcome, Repository};
+
+fn object(value: serde_json::Value) -> JsonObject {
+    value
+        .as_object()
+        .cloned()
+        .expect("Objects are JSON objects")
+}
+
+async fn publish(repo: &Repository, board_id: Uuid, change: Change) -> PublishOutcome {
+    repo.publish_change_for_board(board_id, Uuid::new_v4(), change, None)
+        .await
+        .unwrap()
+}
+
+async fn objects(repo: &Repository, board_id: Uuid) -> Vec<(Uuid, JsonObject)> {
+    repo.stream_object_chunks_for_board(board_id)
+        .await
+        .try_concat()
+        .await
+        .unwrap()
+}
+
+/// Published changes wait in the change stream until they're checkpointed into the objects
+pub async fn publishes_and_checkpoints_changes(repo: Repository) {
+    let board_id = Uuid::new_v4();
+    let id = Uuid::new_v4();
+    publish(
+        &repo,
+        board_id,
+        Change::Insert {
+            id,
+            object: object(json!({ "x": 1 })),
+        },
+    )
+    .await;
+    publish(
+        &repo,
+        board_id,
+        Change::Update {
+            id,
+            key: "x".to_string(),
+            value: json!(2),
+        },
+    )
+    .await;
+
+    let latest_version = repo.get_latest_version_for_board(board_id).await.unwrap();
+    assert_eq!(repo.get_version_for_board(board_id).await.unwrap(), "0");
+    assert_eq!(
+        repo.get_pending_changes_for_board(board_id, "0".to_string())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    assert!(objects(&repo, board_id).await.is_empty());
+
+    let applied_count = Checkpointer::new(repo.clone())
+        .checkpoint_board_to_latest(board_id)
+        .await
+        .unwrap();
+    assert_eq!(applied_count, 2);
+    assert_eq!(
+        repo.get_version_for_board(board_id).await.unwrap(),
+        latest_version
+    );
+    assert_eq!(
+        objects(&repo, board_id).await,
+        vec![(id, object(json!({ "x": 2 })))]
+    );
+    assert!(repo
+        .get_pending_changes_for_board(board_id, latest_version)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+/// Revisions are bumped as soon as a change is published, while checkpointed revisions only
+/// catch up once the change is checkpointed
+pub async fn keeps_revisions(repo: Repository) {
+    let board_id = Uuid::new_v4();
+    let id = Uuid::new_v4();
+    let outcome = publish(
+        &repo,
+        board_id,
+        Change::Insert {
+            id,
+            object: object(json!({})),
+        },
+    )
+    .await;
+    assert!(matches!(
+        outcome,
+        PublishOutcome::Accepted { revisions, .. } if revisions == vec![(id, 1)]
+    ));
+    Checkpointer::new(repo.clone())
+        .checkpoint_board_to_latest(board_id)
+        .await
+        .unwrap();
+
+    publish(&repo, board_id, Change::SetIndex { id, index: 1.0 }).await;
+    assert_eq!(
+        repo.get_revisions_for_board(board_id).await.unwrap(),
+        vec![(id, 2)]
+    );
+    assert_eq!(
+        repo.get_checkpointed_revisions_for_board(board_id)
+            .await
+            .unwrap(),
+        vec![(id, 1)]
+    );
+
+    Checkpointer::new(repo.clone())
+        .checkpoint_board_to_latest(board_id)
+        .await
+        .unwrap();
+    assert_eq!(
+        repo.get_checkpointed_revisions_for_board(board_id)
+            .await
+            .unwrap(),
+        vec![(id, 2)]
+    );
+}
+
+/// A replace only goes through against the revision the object is at
+pub async fn checks_expected_revisions(repo: Repository) {
+    let board_id = Uuid::new_v4();
+    let id = Uuid::new_v4();
+    publish(
+        &repo,
+        board_id,
+        Change::Insert {
+            id,
+            object: object(json!({})),
+        },
+    )
+    .await;
+
+    let outcome = publish(
+        &repo,
+        board_id,
+        Change::Replace {
+            id,
+            object: object(json!({ "x": 1 })),
+            expected_revision: 0,
+        },
+    )
+    .await;
+    assert!(matches!(
+        outcome,
+        PublishOutcome::RevisionMismatch {
+            current_revision: 1,
+            ..
+        }
+    ));
+
+    let outcome = publish(
+        &repo,
+        board_id,
+        Change::Replace {
+            id,
+            object: object(json!({ "x": 1 })),
+            expected_revision: 1,
+        },
+    )
+    .await;
+    assert!(matches!(
+        outcome,
+        PublishOutcome::Accepted { revisions, .. } if revisions == vec![(id, 2)]
+    ));
+}
+
+/// Publishing with an idempotency key that was already used gives back the earlier version
+pub async fn skips_duplicate_changes(repo: Repository) {
+    let board_id = Uuid::new_v4();
+    let session_id = Uuid::new_v4();
+    let change = Change::Delete { id: Uuid::new_v4() };
+    let key = Some("key".to_string());
+
+    let first = repo
+        .publish_change_for_board(board_id, session_id, change.clone(), key.clone())
+        .await
+        .unwrap();
+    let second = repo
+        .publish_change_for_board(board_id, session_id, change, key)
+        .await
+        .unwrap();
+
+    let version = match first {
+        PublishOutcome::Accepted { version, .. } => version,
+        outcome => panic!("Expected the change to be accepted, got {outcome:?}"),
+    };
+    assert!(matches!(
+        second,
+        PublishOutcome::Duplicate { version: duplicate_version } if duplicate_version == version
+    ));
+    assert_eq!(
+        repo.get_pending_changes_for_board(board_id, "0".to_string())
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+/// Sessions are listed with their usernames and users until they're deleted
+pub async fn keeps_sessions(repo: Repository) {
+    let board_id = Uuid::new_v4();
+    let session_id = Uuid::new_v4();
+    repo.create_session_for_board(
+        board_id,
+        session_id,
+        "someone".to_string(),
+        Some("user".to_string()),
+        ClientInfo::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        repo.get_sessions_for_board(board_id).await.unwrap(),
+        vec![(session_id, "someone".to_string())]
+    );
+    assert_eq!(
+        repo.get_session_user_for_board(board_id, session_id)
+            .await
+            .unwrap(),
+        Some("user".to_string())
+    );
+
+    repo.delete_session_for_board(board_id, session_id)
+        .await
+        .unwrap();
+    assert!(repo
+        .get_sessions_for_board(board_id)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        repo.get_session_user_for_board(board_id, session_id)
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+/// Boards can be found once something is published to them, and are gone once deleted
+pub async fn lists_and_deletes_boards(repo: Repository) {
+    let board_id = Uuid::new_v4();
+    publish(
+        &repo,
+        board_id,
+        Change::Insert {
+            id: Uuid::new_v4(),
+            object: object(json!({})),
+        },
+    )
+    .await;
+
+    let board_ids = repo
+        .stream_all_board_ids()
+        .await
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(board_ids.contains(&board_id));
+
+    assert!(repo.delete_board(board_id).await.unwrap());
+    let board_ids = repo
+        .stream_all_board_ids()
+        .await
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert!(!board_ids.contains(&board_id));
+    assert_eq!(
+        repo.get_latest_version_for_board(board_id).await.unwrap(),
+        "0"
+    );
+}
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use uuid::Uuid;
+
+use crate::broadcaster::Broadcaster;
+use crate::presence::Presence;
+use crate::repository::{BanTarget, Repository};
+use crate::restart::{RestartPolicy, Restarts};
+use crate::snapshot;
+use crate::socket::SocketSender;
+
+/// A read-only view of some other board over an existing socket. The subscription snapshots the
+/// board and then follows its changes and presence, tagging everything it sends with the
+/// subscribed board's ID, until the session is kicked or banned from that board.
+pub struct Subscription {
+    board_id: Uuid,
+    session_id: Uuid,
+    repo: Repository,
+    socket_sender: SocketSender,
+    /// Every way the connection can be banned from the subscribed board
+    ban_targets: Vec<BanTarget>,
+}
+
+impl Subscription {
+    #[tracing::instrument(skip(repo, socket_sender))]
+    pub fn new(
+        board_id: Uuid,
+        session_id: Uuid,
+        repo: Repository,
+        socket_sender: SocketSender,
+        ban_targets: Vec<BanTarget>,
+    ) -> Self {
+        Self {
+            board_id,
+            session_id,
+            repo,
+            socket_sender: socket_sender.for_board(board_id),
+            ban_targets,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(self) {
+        let mut restarts = Restarts::new("subscription", RestartPolicy::CONNECTION);
+        while let Err(error) = self.run().await {
+            if !restarts.failed(&error).await {
+                self.socket_sender.disconnect().await.ok();
+                return;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn run(&self) -> Result<()> {
+        let version =
+            snapshot::send_snapshot(self.board_id, &self.repo, &self.socket_sender, None).await?;
+
+        // The broadcaster runs until the subscription is aborted, and presence until then or
+        // until the session is kicked or banned from the board
+        tokio::select! {
+            _ = Broadcaster::new(
+                self.board_id,
+                version,
+                self.repo.clone(),
+                self.socket_sender.clone(),
+            )
+            .start() => {}
+            _ = Presence::new(
+                self.board_id,
+                self.session_id,
+                self.repo.clone(),
+                self.socket_sender.clone(),
+                false,
+                self.ban_targets.clone(),
+            )
+            .start() => {}
+        }
+
+        Ok(())
+    }
+}
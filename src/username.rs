@@ -0,0 +1,89 @@
+use std::env;
+use uuid::Uuid;
+
+/// How many characters a username can have once it's trimmed
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+const GUEST_ADJECTIVES: &[&str] = &[
+    "Blue", "Brave", "Calm", "Clever", "Cosmic", "Gentle", "Golden", "Happy", "Jolly", "Lucky",
+    "Mighty", "Quiet", "Rapid", "Silver", "Swift", "Witty",
+];
+
+const GUEST_ANIMALS: &[&str] = &[
+    "Badger", "Falcon", "Fox", "Heron", "Koala", "Lynx", "Narwhal", "Otter", "Owl", "Panda",
+    "Puffin", "Raven", "Seal", "Tiger", "Walrus", "Wombat",
+];
+
+/// What usernames have to look like before they're shown to everyone else on a board. Words in
+/// the comma separated USERNAME_BLOCKLIST environment variable can't appear anywhere in one,
+/// ignoring case.
+#[derive(Clone, Debug, Default)]
+pub struct UsernamePolicy {
+    blocked_words: Vec<String>,
+}
+
+impl UsernamePolicy {
+    pub fn from_env() -> Self {
+        let blocked_words = env::var("USERNAME_BLOCKLIST")
+            .map(|words| {
+                words
+                    .split(',')
+                    .map(|word| word.trim().to_lowercase())
+                    .filter(|word| !word.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { blocked_words }
+    }
+
+    /// Trim a username and check it against the policy, giving back the trimmed username if it
+    /// passes
+    pub fn validate(&self, username: &str) -> Option<String> {
+        let username = username.trim();
+        let length = username.chars().count();
+        if length == 0 || length > MAX_USERNAME_LENGTH {
+            return None;
+        }
+        if username.chars().any(char::is_control) {
+            return None;
+        }
+
+        let lowercase = username.to_lowercase();
+        if self
+            .blocked_words
+            .iter()
+            .any(|word| lowercase.contains(word.as_str()))
+        {
+            return None;
+        }
+
+        Some(username.to_string())
+    }
+}
+
+/// Make up a friendly name for a guest who didn't pick one, like `Blue Falcon`
+pub fn guest() -> String {
+    // The low bits of each half of a v4 UUID are all random
+    let random = Uuid::new_v4().as_u128();
+    let adjective = GUEST_ADJECTIVES[(random % GUEST_ADJECTIVES.len() as u128) as usize];
+    let animal = GUEST_ANIMALS[((random >> 64) % GUEST_ANIMALS.len() as u128) as usize];
+    format!("{adjective} {animal}")
+}
+
+/// Number a username so that it doesn't match any of the ones already taken on a board, ignoring
+/// case, like `Ada (2)`
+pub fn disambiguate(username: String, taken: &[String]) -> String {
+    let is_taken = |candidate: &str| {
+        taken
+            .iter()
+            .any(|taken| taken.to_lowercase() == candidate.to_lowercase())
+    };
+    if !is_taken(&username) {
+        return username;
+    }
+
+    (2..)
+        .map(|number| format!("{username} ({number})"))
+        .find(|candidate| !is_taken(candidate))
+        .expect("There are only so many sessions on a board")
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use reqwest::Client as HttpClient;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio_retry::{
+    strategy::{jitter, ExponentialBackoff},
+    Retry,
+};
+use uuid::Uuid;
+
+use crate::board_meta::BoardMeta;
+use crate::message::AcceptedChange;
+use crate::repository::Repository;
+use crate::restart::{RestartPolicy, Restarts};
+
+/// Something that happened on a board that webhooks are told about
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum WebhookEvent {
+    #[serde(rename = "board.created")]
+    BoardCreated { board_id: Uuid, meta: BoardMeta },
+    #[serde(rename = "user.joined")]
+    UserJoined {
+        board_id: Uuid,
+        session_id: Uuid,
+        username: String,
+    },
+    /// Every change applied in one checkpoint of the board
+    #[serde(rename = "change.applied")]
+    ChangeApplied {
+        board_id: Uuid,
+        changes: Vec<AcceptedChange>,
+    },
+}
+
+impl WebhookEvent {
+    pub fn board_id(&self) -> Uuid {
+        match self {
+            WebhookEvent::BoardCreated { board_id, .. }
+            | WebhookEvent::UserJoined { board_id, .. }
+            | WebhookEvent::ChangeApplied { board_id, .. } => *board_id,
+        }
+    }
+}
+
+/// Takes webhook events off of the queue and POSTs them to every URL configured for all boards
+/// along with every URL configured for the event's board. When a secret is configured each body
+/// is signed with HMAC-SHA256 in the `X-Redboard-Signature` header so receivers can check where it
+/// came from.
+pub struct WebhookDispatcher {
+    repo: Repository,
+    http_client: HttpClient,
+    global_urls: Vec<String>,
+    secret: Option<String>,
+}
+
+impl WebhookDispatcher {
+    #[tracing::instrument(skip(repo, secret))]
+    pub fn new(repo: Repository, global_urls: Vec<String>, secret: Option<String>) -> Self {
+        Self {
+            repo,
+            http_client: HttpClient::new(),
+            global_urls,
+            secret,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn start(self) {
+        let mut restarts = Restarts::new("webhook_dispatcher", RestartPolicy::BACKGROUND);
+        loop {
+            if let Err(error) = self.run().await {
+                restarts.failed(&error).await;
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn run(&self) -> Result<()> {
+        loop {
+            let event = match self.repo.pop_webhook_event().await? {
+                Some(event) => event,
+                None => continue,
+            };
+
+            let board_urls = self.repo.get_webhooks_for_board(event.board_id()).await?;
+            let body = serde_json::to_string(&event)?;
+            let signature = self.secret.as_ref().map(|secret| sign(secret, &body));
+
+            // Deliver to each URL in its own task so that one slow receiver can't hold up the rest
+            for url in self.global_urls.iter().chain(board_urls.iter()) {
+                tokio::task::spawn(deliver(
+                    self.http_client.clone(),
+                    url.clone(),
+                    body.clone(),
+                    signature.clone(),
+                ));
+            }
+        }
+    }
+}
+
+/// POST a webhook body to a URL, backing off exponentially between failed attempts before giving
+/// up after a handful of retries
+#[tracing::instrument(skip(http_client, body, signature))]
+async fn deliver(http_client: HttpClient, url: String, body: String, signature: Option<String>) {
+    let strategy = ExponentialBackoff::from_millis(2)
+        .factor(500)
+        .max_delay(Duration::from_secs(60))
+        .map(jitter)
+        .take(5);
+
+    let result = Retry::spawn(strategy, || async {
+        let mut request = http_client
+            .post(&url)
+            .header("content-type", "application/json")
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header("x-redboard-signature", format!("sha256={signature}"));
+        }
+        request.send().await?.error_for_status()
+    })
+    .await;
+
+    if let Err(error) = result {
+        tracing::warn!(%error, "Giving up on webhook delivery");
+    }
+}
+
+/// The hex HMAC-SHA256 of a webhook body, keyed with the shared secret
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}